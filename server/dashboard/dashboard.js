@@ -0,0 +1,208 @@
+// Built-in dashboard: subscribes to the multiplexed /realtime socket and
+// renders the latest sample of each topic. An API key can be passed as
+// ?token=... in the page URL. When agents report to this server, a picker
+// switches to one of them (?host=...), whose reports are polled instead.
+
+"use strict";
+
+const TOPICS = ["cpus", "memory", "loadavg", "processes"];
+const UPDATE_INTERVAL_MS = 1000;
+const RECONNECT_DELAY_MS = 2000;
+const MAX_PROCESSES = 25;
+
+const params = new URLSearchParams(location.search);
+const token = params.get("token");
+const host = params.get("host");
+const headers = token ? { Authorization: `Bearer ${token}` } : {};
+
+function element(tag, props = {}, children = []) {
+  const node = Object.assign(document.createElement(tag), props);
+  node.append(...children);
+  return node;
+}
+
+function bar(label, percent, value) {
+  const fill = element("div", { className: "fill" });
+  fill.style.width = `${Math.min(Math.max(percent, 0), 100)}%`;
+  return element("div", { className: "bar" }, [
+    element("span", { textContent: label }),
+    element("div", { className: "track" }, [fill]),
+    element("span", { className: "value", textContent: value }),
+  ]);
+}
+
+function percentOf(used, total) {
+  return total > 0 ? (used / total) * 100 : 0;
+}
+
+const render = {
+  cpus(cpus) {
+    document.getElementById("cpus").replaceChildren(
+      ...cpus.map((cpu, index) =>
+        bar(`#${index}`, cpu.cpu_usage, `${cpu.cpu_usage.toFixed(1)} %`)
+      )
+    );
+  },
+  memory(memory) {
+    document.getElementById("memory").replaceChildren(
+      bar(
+        "Memory",
+        percentOf(memory.used_memory_bytes, memory.total_memory_bytes),
+        `${memory.used_memory} / ${memory.total_memory}`
+      ),
+      bar(
+        "Swap",
+        percentOf(memory.used_swap_bytes, memory.total_swap_bytes),
+        `${memory.used_swap} / ${memory.total_swap}`
+      )
+    );
+  },
+  loadavg(loadavg) {
+    document.getElementById("loadavg").replaceChildren(
+      ...[
+        ["1 min", loadavg.one],
+        ["5 min", loadavg.five],
+        ["15 min", loadavg.fifteen],
+      ].map(([label, value]) =>
+        element("div", { textContent: value.toFixed(2) }, [
+          element("small", { textContent: label }),
+        ])
+      )
+    );
+  },
+  processes(processes) {
+    const top = [...processes]
+      .sort((a, b) => b.cpu_usage - a.cpu_usage)
+      .slice(0, MAX_PROCESSES);
+    document.getElementById("processes").replaceChildren(
+      ...top.map((process) =>
+        element("tr", {}, [
+          element("td", { textContent: process.pid }),
+          element("td", { textContent: process.name }),
+          element("td", { textContent: process.user ?? "" }),
+          element("td", { textContent: process.status }),
+          element("td", {
+            className: "number",
+            textContent: process.cpu_usage.toFixed(1),
+          }),
+          element("td", { className: "number", textContent: process.memory }),
+        ])
+      )
+    );
+  },
+};
+
+function setStatus(text, live) {
+  const status = document.getElementById("status");
+  status.textContent = text;
+  status.classList.toggle("live", live);
+}
+
+async function loadSystem() {
+  const url = host
+    ? `/api/v1/hosts/${encodeURIComponent(host)}/system`
+    : "/api/v1/system";
+  const response = await fetch(url, { headers });
+  if (!response.ok) {
+    return;
+  }
+  const system = await response.json();
+  if (system.hostname) {
+    document.getElementById("hostname").textContent = system.hostname;
+    document.title = `${system.hostname} - Process Viewer`;
+  }
+  document.getElementById("os").textContent = [
+    system.os_name,
+    system.os_version,
+    system.architecture,
+  ]
+    .filter(Boolean)
+    .join(" ");
+}
+
+function connect() {
+  const params = new URLSearchParams({ interval_ms: UPDATE_INTERVAL_MS });
+  if (token) {
+    params.set("token", token);
+  }
+  const scheme = location.protocol === "https:" ? "wss:" : "ws:";
+  const socket = new WebSocket(
+    `${scheme}//${location.host}/realtime?${params}`
+  );
+  socket.onopen = () => {
+    setStatus("live", true);
+    socket.send(JSON.stringify({ subscribe: TOPICS }));
+  };
+  socket.onmessage = (event) => {
+    const message = JSON.parse(event.data);
+    if (message.error) {
+      setStatus(message.error, false);
+    } else if (message.data !== undefined && render[message.topic]) {
+      render[message.topic](message.data);
+    }
+  };
+  socket.onclose = () => {
+    setStatus("disconnected", false);
+    setTimeout(connect, RECONNECT_DELAY_MS);
+  };
+}
+
+async function loadHosts() {
+  const response = await fetch("/api/v1/hosts", { headers });
+  if (!response.ok) {
+    return;
+  }
+  const hosts = await response.json();
+  if (hosts.length === 0 && !host) {
+    return;
+  }
+  const picker = document.getElementById("host");
+  picker.replaceChildren(
+    element("option", { value: "", textContent: "This server" }),
+    ...hosts.map((entry) =>
+      element("option", {
+        value: entry.id,
+        textContent: entry.online ? entry.id : `${entry.id} (offline)`,
+      })
+    )
+  );
+  picker.value = host ?? "";
+  picker.hidden = false;
+  picker.onchange = () => {
+    if (picker.value) {
+      params.set("host", picker.value);
+    } else {
+      params.delete("host");
+    }
+    location.search = params.toString();
+  };
+}
+
+// Agents' reports are only kept on the hub, so they are polled rather than
+// streamed.
+async function pollHost() {
+  try {
+    const response = await fetch(
+      `/api/v1/hosts/${encodeURIComponent(host)}`,
+      { headers }
+    );
+    if (response.ok) {
+      const report = await response.json();
+      TOPICS.forEach((topic) => render[topic](report[topic]));
+      setStatus("live", true);
+    } else {
+      setStatus(response.status === 404 ? "unknown host" : "error", false);
+    }
+  } catch {
+    setStatus("disconnected", false);
+  }
+  setTimeout(pollHost, UPDATE_INTERVAL_MS);
+}
+
+loadSystem().catch(() => {});
+loadHosts().catch(() => {});
+if (host) {
+  pollHost();
+} else {
+  connect();
+}
@@ -0,0 +1,288 @@
+//! IP allow and deny lists, checked on every HTTP request before
+//! authentication. A client in a `deny` range is refused, and so is one
+//! outside every `allow` range when there are any.
+//!
+//! The client is the peer address unless the peer is a `trusted_proxies`
+//! address, in which case `X-Forwarded-For` is read from the right, skipping
+//! trusted proxies, and the first other address is the client. Entries left
+//! of it were written by the client itself and can't be believed. The
+//! resolved address is added to the request's extensions as `ClientIp`.
+
+use crate::config::AccessConfig;
+use crate::{api_error, AppError, AppState};
+use axum::extract::{ConnectInfo, State};
+use axum::http::{HeaderMap, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// An address range such as `10.0.0.0/8` or `fd00::/8`; a bare address is a
+/// range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Dual-stack listeners report IPv4 clients as `::ffff:a.b.c.d`.
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid address range {s:?}, expected e.g. 10.0.0.0/8");
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = IpAddr::from_str(addr.trim())
+            .map_err(|_| invalid())?
+            .to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(invalid)?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// The client's address, see the module docs.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+impl AccessConfig {
+    fn allows(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|range| range.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|range| range.contains(ip)))
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|range| range.contains(ip))
+    }
+
+    fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut client = peer.to_canonical();
+        if !self.is_trusted(client) {
+            return client;
+        }
+        // Several headers count as one list, in order.
+        let hops: Vec<&str> = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect();
+        for hop in hops.into_iter().rev() {
+            // Garbage means the chain can't be followed further.
+            let Some(ip) = parse_hop(hop.trim()) else {
+                break;
+            };
+            client = ip.to_canonical();
+            if !self.is_trusted(client) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+/// An `X-Forwarded-For` entry, which some proxies write with a port.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    hop.parse()
+        .ok()
+        .or_else(|| hop.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+/// Refuses clients the allow and deny lists exclude with 403.
+pub async fn filter_ips<B>(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let access = &state.config.access;
+    let client = access.client_ip(peer.ip(), request.headers());
+    if !access.allows(client) {
+        tracing::debug!(%client, "Refused a request from a disallowed address");
+        let error: AppError = api_error(
+            StatusCode::FORBIDDEN,
+            "Requests from this address aren't allowed",
+        );
+        return error.into_response();
+    }
+    request.extensions_mut().insert(ClientIp(client));
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn behind_proxy() -> AccessConfig {
+        AccessConfig {
+            trusted_proxies: vec![cidr("10.0.0.0/8")],
+            ..Default::default()
+        }
+    }
+
+    fn forwarded_for(hops: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for hop in hops {
+            headers.append(X_FORWARDED_FOR, HeaderValue::from_str(hop).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn prefix_zero_matches_everything_of_its_family() {
+        let any = cidr("0.0.0.0/0");
+        assert!(any.contains(ip("203.0.113.7")));
+        assert!(any.contains(ip("255.255.255.255")));
+        assert!(!any.contains(ip("2001:db8::1")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn full_prefix_matches_one_address() {
+        let one = cidr("192.168.1.10/32");
+        assert_eq!(one, cidr("192.168.1.10"));
+        assert!(one.contains(ip("192.168.1.10")));
+        assert!(!one.contains(ip("192.168.1.11")));
+        assert!(cidr("fd00::1/128").contains(ip("fd00::1")));
+        assert!(!cidr("fd00::1/128").contains(ip("fd00::2")));
+    }
+
+    #[test]
+    fn invalid_ranges_are_rejected() {
+        for range in [
+            "10.0.0.0/33",
+            "fd00::/129",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "10.0.0.0/8/8",
+            "10.0.0/8",
+            "localhost",
+            "",
+        ] {
+            assert!(range.parse::<Cidr>().is_err(), "{range} was accepted");
+        }
+    }
+
+    #[test]
+    fn mapped_ipv6_clients_match_ipv4_rules() {
+        let access = AccessConfig {
+            allow: vec![cidr("192.168.0.0/16")],
+            ..Default::default()
+        };
+        assert!(access.allows(ip("::ffff:192.168.3.4")));
+        assert!(!access.allows(ip("::ffff:10.1.2.3")));
+        // A mapped range is read as the IPv4 one.
+        assert!(cidr("::ffff:10.0.0.0/8").contains(ip("10.1.2.3")));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let access = AccessConfig {
+            allow: vec![cidr("10.0.0.0/8")],
+            deny: vec![cidr("10.6.6.0/24")],
+            ..Default::default()
+        };
+        assert!(access.allows(ip("10.1.1.1")));
+        assert!(!access.allows(ip("10.6.6.6")));
+        assert!(!access.allows(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn forwarded_for_is_read_from_the_right() {
+        let headers = forwarded_for(&["198.51.100.9, 203.0.113.7", "10.0.0.2"]);
+        assert_eq!(
+            behind_proxy().client_ip(ip("10.0.0.1"), &headers),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn spoofed_leftmost_entries_are_ignored() {
+        // The client wrote a trusted-looking address itself; the proxy
+        // appended the address it really connected from.
+        let headers = forwarded_for(&["10.9.9.9, 203.0.113.7"]);
+        assert_eq!(
+            behind_proxy().client_ip(ip("10.0.0.1"), &headers),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn untrusted_peers_cant_forward() {
+        let headers = forwarded_for(&["10.1.1.1"]);
+        assert_eq!(
+            behind_proxy().client_ip(ip("203.0.113.7"), &headers),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            AccessConfig::default().client_ip(ip("10.0.0.1"), &headers),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn garbage_hops_stop_the_chain() {
+        let headers = forwarded_for(&["203.0.113.7, unknown, 10.0.0.2"]);
+        assert_eq!(
+            behind_proxy().client_ip(ip("10.0.0.1"), &headers),
+            ip("10.0.0.2")
+        );
+        let headers = forwarded_for(&["[2001:db8::1]:4711"]);
+        assert_eq!(
+            behind_proxy().client_ip(ip("10.0.0.1"), &headers),
+            ip("2001:db8::1")
+        );
+    }
+}
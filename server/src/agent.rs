@@ -0,0 +1,90 @@
+//! Agent side of the agent/hub mode, enabled with `--agent --hub-url`:
+//! pushes the latest samples to the hub's `/api/v1/hosts/{id}` on every
+//! interval, see `hub.rs`. The agent keeps serving its own API as well.
+
+use crate::config::AgentConfig;
+use crate::history::now_millis;
+use crate::hub::HostReport;
+use crate::system::collect_system;
+use crate::AppState;
+use std::time::Duration;
+use sysinfo::SystemExt;
+use tokio::time::MissedTickBehavior;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn spawn(config: AgentConfig, app_state: AppState) {
+    tokio::spawn(run(config, app_state));
+}
+
+async fn run(config: AgentConfig, app_state: AppState) {
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .expect("Failed to build hub client");
+    let host_id = match config.host_id {
+        Some(id) => id,
+        None => app_state
+            .system
+            .lock()
+            .await
+            .host_name()
+            .unwrap_or_else(|| "unknown".to_owned()),
+    };
+    let mut url = config.hub_url.clone();
+    url.path_segments_mut()
+        .expect("Hub URL can't be a base")
+        .pop_if_empty()
+        .extend(["api", "v1", "hosts", &host_id]);
+    tracing::info!(hub = %config.hub_url, host = %host_id, "Reporting to hub");
+    let mut ticker = tokio::time::interval(config.interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut reachable = true;
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = app_state.shutdown.cancelled() => break,
+        }
+        // Nothing has been sampled yet.
+        if app_state.cpus.latest().seq == 0 {
+            continue;
+        }
+        let report = HostReport {
+            timestamp: now_millis(),
+            interval_ms: config.interval.as_millis() as u64,
+            system: collect_system(&*app_state.system.lock().await, &app_state.config.labels),
+            cpus: app_state.cpus.latest().value().clone(),
+            memory: app_state.memory.latest().value().clone(),
+            loadavg: app_state.loadavg.latest().value().clone(),
+            processes: app_state.processes.latest().value().clone(),
+            disks: app_state.disks.latest().value().clone(),
+            networks: app_state.networks.latest().value().clone(),
+            temperatures: app_state.temperatures.latest().value().clone(),
+        };
+        let mut request = client.put(url.clone()).json(&report);
+        if let Some(api_key) = &config.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let result = request
+            .send()
+            .await
+            .map_err(|err| err.to_string())
+            .and_then(|response| match response.status() {
+                status if status.is_success() => Ok(()),
+                status => Err(format!("The hub answered {status}")),
+            });
+        match result {
+            Ok(()) if !reachable => {
+                tracing::info!(hub = %config.hub_url, "The hub is reachable again");
+                reachable = true;
+            }
+            Ok(()) => {}
+            // Logged once per outage rather than every report.
+            Err(err) if reachable => {
+                tracing::warn!(hub = %config.hub_url, %err, "Failed to report to the hub");
+                reachable = false;
+            }
+            Err(_) => {}
+        }
+    }
+}
@@ -0,0 +1,374 @@
+//! Threshold alerting evaluated against every sample.
+//!
+//! A rule such as `{"id": "busy", "metric": "cpu_usage", "op": ">",
+//! "threshold": 90, "for_seconds": 60}` becomes pending as soon as the
+//! condition holds and firing once it has held for `for_seconds`. Rules can
+//! be given in the config file or a JSON file with `--alert-rules` and
+//! managed at runtime under `/api/v1/alerts/rules`.
+
+use crate::models::{CpuInfo, Memory};
+use crate::{api_error, AppError, AppState};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use axum_macros::debug_handler;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertMetric {
+    /// Average usage over all cores, in percent.
+    CpuUsage,
+    /// Used memory, in percent of total memory.
+    UsedMemory,
+    /// Used swap, in percent of total swap.
+    UsedSwap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum Comparison {
+    #[serde(rename = ">")]
+    Above,
+    #[serde(rename = ">=")]
+    AtLeast,
+    #[serde(rename = "<")]
+    Below,
+    #[serde(rename = "<=")]
+    AtMost,
+}
+
+impl Comparison {
+    fn holds(self, value: f64, threshold: f64) -> bool {
+        match self {
+            Comparison::Above => value > threshold,
+            Comparison::AtLeast => value >= threshold,
+            Comparison::Below => value < threshold,
+            Comparison::AtMost => value <= threshold,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct AlertRule {
+    pub id: String,
+    pub metric: AlertMetric,
+    pub op: Comparison,
+    pub threshold: f64,
+    #[serde(default)]
+    pub for_seconds: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertState {
+    Pending,
+    Firing,
+    Resolved,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ActiveAlert {
+    pub rule: AlertRule,
+    pub state: AlertState,
+    pub value: f64,
+    /// Milliseconds since the Unix epoch at which the condition started to hold.
+    pub since: u64,
+}
+
+/// A rule that started firing or resolved during an evaluation.
+#[derive(Debug, Clone, Serialize)]
+pub struct AlertEvent {
+    pub rule: AlertRule,
+    pub state: AlertState,
+    pub value: f64,
+    pub timestamp: u64,
+}
+
+struct RuleState {
+    since: u64,
+    firing: bool,
+    value: f64,
+}
+
+#[derive(Default)]
+struct Inner {
+    rules: Vec<AlertRule>,
+    states: HashMap<String, RuleState>,
+}
+
+#[derive(Clone, Default)]
+pub struct AlertEngine {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl AlertEngine {
+    pub fn with_rules(rules: Vec<AlertRule>) -> Result<Self, String> {
+        let engine = Self::default();
+        for rule in rules {
+            engine
+                .add_rule(rule)
+                .map_err(|rule| format!("Duplicate alert rule id {}", rule.id))?;
+        }
+        Ok(engine)
+    }
+
+    pub fn has_rules(&self) -> bool {
+        !self.inner.lock().unwrap().rules.is_empty()
+    }
+
+    pub fn rules(&self) -> Vec<AlertRule> {
+        self.inner.lock().unwrap().rules.clone()
+    }
+
+    pub fn rule(&self, id: &str) -> Option<AlertRule> {
+        let inner = self.inner.lock().unwrap();
+        inner.rules.iter().find(|rule| rule.id == id).cloned()
+    }
+
+    /// Fails with the rule when its id is already taken.
+    pub fn add_rule(&self, rule: AlertRule) -> Result<(), AlertRule> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.rules.iter().any(|existing| existing.id == rule.id) {
+            return Err(rule);
+        }
+        inner.rules.push(rule);
+        Ok(())
+    }
+
+    /// Returns `false` when there is no rule with this id.
+    pub fn replace_rule(&self, rule: AlertRule) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(index) = inner
+            .rules
+            .iter()
+            .position(|existing| existing.id == rule.id)
+        else {
+            return false;
+        };
+        inner.states.remove(&rule.id);
+        inner.rules[index] = rule;
+        true
+    }
+
+    pub fn remove_rule(&self, id: &str) -> Option<AlertRule> {
+        let mut inner = self.inner.lock().unwrap();
+        let index = inner.rules.iter().position(|rule| rule.id == id)?;
+        inner.states.remove(id);
+        Some(inner.rules.remove(index))
+    }
+
+    pub fn active(&self) -> Vec<ActiveAlert> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .rules
+            .iter()
+            .filter_map(|rule| {
+                let state = inner.states.get(&rule.id)?;
+                Some(ActiveAlert {
+                    rule: rule.clone(),
+                    state: if state.firing {
+                        AlertState::Firing
+                    } else {
+                        AlertState::Pending
+                    },
+                    value: state.value,
+                    since: state.since,
+                })
+            })
+            .collect()
+    }
+
+    pub fn evaluate(&self, timestamp: u64, cpus: &[CpuInfo], memory: &Memory) -> Vec<AlertEvent> {
+        let mut inner = self.inner.lock().unwrap();
+        let Inner { rules, states } = &mut *inner;
+        let mut events = vec![];
+        for rule in rules.iter() {
+            let Some(value) = metric_value(rule.metric, cpus, memory) else {
+                continue;
+            };
+            if rule.op.holds(value, rule.threshold) {
+                let state = states.entry(rule.id.clone()).or_insert(RuleState {
+                    since: timestamp,
+                    firing: false,
+                    value,
+                });
+                state.value = value;
+                let held_for = timestamp.saturating_sub(state.since);
+                if !state.firing && held_for >= rule.for_seconds * 1000 {
+                    state.firing = true;
+                    events.push(AlertEvent {
+                        rule: rule.clone(),
+                        state: AlertState::Firing,
+                        value,
+                        timestamp,
+                    });
+                }
+            } else if let Some(state) = states.remove(&rule.id) {
+                if state.firing {
+                    events.push(AlertEvent {
+                        rule: rule.clone(),
+                        state: AlertState::Resolved,
+                        value,
+                        timestamp,
+                    });
+                }
+            }
+        }
+        events
+    }
+}
+
+fn metric_value(metric: AlertMetric, cpus: &[CpuInfo], memory: &Memory) -> Option<f64> {
+    let percent = |used: u64, total: u64| (total > 0).then(|| used as f64 / total as f64 * 100f64);
+    match metric {
+        AlertMetric::CpuUsage => (!cpus.is_empty())
+            .then(|| cpus.iter().map(|cpu| cpu.cpu_usage as f64).sum::<f64>() / cpus.len() as f64),
+        AlertMetric::UsedMemory => percent(memory.used_memory_bytes, memory.total_memory_bytes),
+        AlertMetric::UsedSwap => percent(memory.used_swap_bytes, memory.total_swap_bytes),
+    }
+}
+
+fn validate(rule: &AlertRule) -> Result<(), AppError> {
+    if rule.id.is_empty() {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "Alert rule id must not be empty",
+        ));
+    }
+    if !rule.threshold.is_finite() {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "Alert threshold must be a number",
+        ));
+    }
+    Ok(())
+}
+
+fn not_found(id: &str) -> AppError {
+    api_error(StatusCode::NOT_FOUND, format!("No alert rule with id {id}"))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/alerts/rules",
+    tag = "alerts",
+    responses((status = 200, description = "All alert rules", body = [AlertRule]))
+)]
+#[debug_handler]
+pub async fn list_rules(State(state): State<AppState>) -> Json<Vec<AlertRule>> {
+    Json(state.alerts.rules())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/alerts/rules/{id}",
+    tag = "alerts",
+    params(("id" = String, Path, description = "Rule id")),
+    responses(
+        (status = 200, description = "The alert rule", body = AlertRule),
+        (status = 404, description = "No rule with this id", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_rule(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<AlertRule>, AppError> {
+    state
+        .alerts
+        .rule(&id)
+        .map(Json)
+        .ok_or_else(|| not_found(&id))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/alerts/rules",
+    tag = "alerts",
+    request_body = AlertRule,
+    responses(
+        (status = 201, description = "The rule was added", body = AlertRule),
+        (status = 400, description = "Invalid rule", body = ErrorResponse),
+        (status = 403, description = "Requires the admin role, or the server is read-only", body = ErrorResponse),
+        (status = 409, description = "A rule with this id already exists", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn create_rule(
+    State(state): State<AppState>,
+    Json(rule): Json<AlertRule>,
+) -> Result<(StatusCode, Json<AlertRule>), AppError> {
+    validate(&rule)?;
+    state.alerts.add_rule(rule.clone()).map_err(|rule| {
+        api_error(
+            StatusCode::CONFLICT,
+            format!("Alert rule {} already exists", rule.id),
+        )
+    })?;
+    Ok((StatusCode::CREATED, Json(rule)))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/alerts/rules/{id}",
+    tag = "alerts",
+    params(("id" = String, Path, description = "Rule id, overrides any id in the body")),
+    request_body = AlertRule,
+    responses(
+        (status = 200, description = "The rule was replaced", body = AlertRule),
+        (status = 400, description = "Invalid rule", body = ErrorResponse),
+        (status = 403, description = "Requires the admin role, or the server is read-only", body = ErrorResponse),
+        (status = 404, description = "No rule with this id", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn update_rule(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(mut rule): Json<AlertRule>,
+) -> Result<Json<AlertRule>, AppError> {
+    rule.id = id;
+    validate(&rule)?;
+    if state.alerts.replace_rule(rule.clone()) {
+        Ok(Json(rule))
+    } else {
+        Err(not_found(&rule.id))
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/alerts/rules/{id}",
+    tag = "alerts",
+    params(("id" = String, Path, description = "Rule id")),
+    responses(
+        (status = 204, description = "The rule was removed"),
+        (status = 403, description = "Requires the admin role, or the server is read-only", body = ErrorResponse),
+        (status = 404, description = "No rule with this id", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn delete_rule(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    state
+        .alerts
+        .remove_rule(&id)
+        .map(|_| StatusCode::NO_CONTENT)
+        .ok_or_else(|| not_found(&id))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/alerts/active",
+    tag = "alerts",
+    responses((status = 200, description = "Pending and firing alerts", body = [ActiveAlert]))
+)]
+#[debug_handler]
+pub async fn list_active(State(state): State<AppState>) -> Json<Vec<ActiveAlert>> {
+    Json(state.alerts.active())
+}
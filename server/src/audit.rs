@@ -0,0 +1,542 @@
+//! Audit log of the requests that act on the system: process signals and
+//! priorities, alert rule changes, snapshots taken or deleted and hosts
+//! removed from the hub. Each is recorded with who made it, from where,
+//! what it targeted and how it went, and served at `/api/v1/audit`.
+//!
+//! Agents' reports to `PUT /api/v1/hosts/:id` aren't recorded: each agent
+//! sends one every few seconds, which would bury everything else.
+//!
+//! Entries are appended to `--audit-log` as JSON lines when given, or else
+//! kept in the history database when there is one. Otherwise only the
+//! latest `MEMORY_ENTRIES` are kept, in memory. Queries of the file read it
+//! from the end, looking at no more than `MAX_FILE_SCAN` entries.
+
+use crate::access::ClientIp;
+use crate::auth::{Principal, Role};
+use crate::history::now_millis;
+use crate::{api_error, AppError, AppState};
+use axum::body::{self, Body, Bytes, Full};
+use axum::extract::{ConnectInfo, FromRequest, MatchedPath, Query, State};
+use axum::http::{Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use axum_macros::debug_handler;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use utoipa::{IntoParams, ToSchema};
+
+const MEMORY_ENTRIES: usize = 1000;
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+/// Newest entries of `--audit-log` a query looks through.
+const MAX_FILE_SCAN: usize = 100_000;
+/// Bytes read from the file at a time.
+const FILE_BLOCK: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Signal,
+    Priority,
+    CreateAlertRule,
+    UpdateAlertRule,
+    DeleteAlertRule,
+    CreateSnapshot,
+    DeleteSnapshot,
+    DeleteHost,
+}
+
+impl AuditAction {
+    fn of(method: &Method, path: &str) -> Option<Self> {
+        let action = match *method {
+            Method::POST if path.ends_with("/processes/:pid/signal") => Self::Signal,
+            Method::POST if path.ends_with("/processes/:pid/priority") => Self::Priority,
+            Method::POST if path.ends_with("/alerts/rules") => Self::CreateAlertRule,
+            Method::PUT if path.ends_with("/alerts/rules/:id") => Self::UpdateAlertRule,
+            Method::DELETE if path.ends_with("/alerts/rules/:id") => Self::DeleteAlertRule,
+            Method::POST if path.ends_with("/snapshots") => Self::CreateSnapshot,
+            Method::DELETE if path.ends_with("/snapshots/:id") => Self::DeleteSnapshot,
+            Method::DELETE if path.ends_with("/hosts/:id") => Self::DeleteHost,
+            _ => return None,
+        };
+        Some(action)
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Signal => "signal",
+            Self::Priority => "priority",
+            Self::CreateAlertRule => "create_alert_rule",
+            Self::UpdateAlertRule => "update_alert_rule",
+            Self::DeleteAlertRule => "delete_alert_rule",
+            Self::CreateSnapshot => "create_snapshot",
+            Self::DeleteSnapshot => "delete_snapshot",
+            Self::DeleteHost => "delete_host",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct AuditEntry {
+    /// Milliseconds since the epoch.
+    pub timestamp: u64,
+    /// Who made the request: the user of a token, `<role> API key`, or
+    /// `anonymous` without authentication.
+    pub principal: String,
+    pub role: Role,
+    /// The client's IP address.
+    pub address: String,
+    pub action: AuditAction,
+    /// The PID, alert rule, snapshot or host id acted on.
+    pub target: Option<String>,
+    /// The request's JSON body, such as the signal sent.
+    pub request: Option<Value>,
+    /// The response's HTTP status.
+    pub status: u16,
+    /// Why the action failed, if it did.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditQuery {
+    /// Start in milliseconds since the epoch.
+    from: Option<u64>,
+    /// End in milliseconds since the epoch.
+    to: Option<u64>,
+    action: Option<AuditAction>,
+    principal: Option<String>,
+    /// Most recent entries returned, 100 by default and at most 1000.
+    limit: Option<usize>,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.from.is_none_or(|from| entry.timestamp >= from)
+            && self.to.is_none_or(|to| entry.timestamp <= to)
+            && self.action.is_none_or(|action| entry.action == action)
+            && self
+                .principal
+                .as_ref()
+                .is_none_or(|principal| &entry.principal == principal)
+    }
+
+    fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT)
+    }
+}
+
+enum Store {
+    Memory(Mutex<VecDeque<AuditEntry>>),
+    File { path: PathBuf, file: Mutex<File> },
+    Db(Mutex<Connection>),
+}
+
+#[derive(Clone)]
+pub struct AuditLog {
+    store: Arc<Store>,
+}
+
+impl AuditLog {
+    /// Appends to `file` when given, or else keeps the entries in the
+    /// history database at `db`.
+    pub fn open(file: Option<&Path>, db: Option<&Path>) -> Result<Self, String> {
+        let store = match (file, db) {
+            (Some(path), _) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|err| format!("Failed to open audit log {}: {err}", path.display()))?;
+                Store::File {
+                    path: path.to_owned(),
+                    file: Mutex::new(file),
+                }
+            }
+            (None, Some(path)) => {
+                let failed = |err: rusqlite::Error| {
+                    format!("Failed to open the audit log in {}: {err}", path.display())
+                };
+                let conn = Connection::open(path).map_err(failed)?;
+                conn.execute_batch(
+                    "CREATE TABLE IF NOT EXISTS audit (
+                         timestamp INTEGER NOT NULL,
+                         action TEXT NOT NULL,
+                         principal TEXT NOT NULL,
+                         data TEXT NOT NULL
+                     );
+                     CREATE INDEX IF NOT EXISTS audit_timestamp ON audit (timestamp);",
+                )
+                .map_err(failed)?;
+                Store::Db(Mutex::new(conn))
+            }
+            (None, None) => Store::Memory(Mutex::default()),
+        };
+        Ok(Self {
+            store: Arc::new(store),
+        })
+    }
+
+    async fn record(&self, entry: AuditEntry) {
+        let store = self.store.clone();
+        let result = tokio::task::spawn_blocking(move || match &*store {
+            Store::Memory(entries) => {
+                let mut entries = entries.lock().unwrap();
+                if entries.len() == MEMORY_ENTRIES {
+                    entries.pop_front();
+                }
+                entries.push_back(entry);
+                Ok(())
+            }
+            Store::File { file, .. } => {
+                let mut line = serde_json::to_vec(&entry).unwrap();
+                line.push(b'\n');
+                file.lock()
+                    .unwrap()
+                    .write_all(&line)
+                    .map_err(|err| err.to_string())
+            }
+            Store::Db(conn) => conn
+                .lock()
+                .unwrap()
+                .execute(
+                    "INSERT INTO audit (timestamp, action, principal, data) VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        entry.timestamp as i64,
+                        entry.action.as_str(),
+                        entry.principal,
+                        serde_json::to_string(&entry).unwrap()
+                    ],
+                )
+                .map(|_| ())
+                .map_err(|err| err.to_string()),
+        })
+        .await
+        .expect("Audit task panicked");
+        if let Err(err) = result {
+            tracing::error!("Failed to write the audit log: {err}");
+        }
+    }
+
+    /// Matching entries, newest first.
+    async fn query(&self, query: AuditQuery) -> Result<Vec<AuditEntry>, String> {
+        let store = self.store.clone();
+        tokio::task::spawn_blocking(move || match &*store {
+            Store::Memory(entries) => Ok(entries
+                .lock()
+                .unwrap()
+                .iter()
+                .rev()
+                .filter(|entry| query.matches(entry))
+                .take(query.limit())
+                .cloned()
+                .collect()),
+            Store::File { path, .. } => {
+                let file = File::open(path).map_err(|err| err.to_string())?;
+                let mut entries: Vec<AuditEntry> = vec![];
+                let lines = RevLines::new(file).map_err(|err| err.to_string())?;
+                for line in lines.take(MAX_FILE_SCAN) {
+                    let line = line.map_err(|err| err.to_string())?;
+                    // Skips a line cut short by a crash rather than failing.
+                    if let Ok(entry) = serde_json::from_slice::<AuditEntry>(&line) {
+                        if query.matches(&entry) {
+                            entries.push(entry);
+                            if entries.len() == query.limit() {
+                                break;
+                            }
+                        }
+                    }
+                }
+                Ok(entries)
+            }
+            Store::Db(conn) => {
+                let conn = conn.lock().unwrap();
+                let mut stmt = conn
+                    .prepare_cached(
+                        "SELECT data FROM audit
+                         WHERE timestamp BETWEEN ?1 AND ?2
+                           AND (?3 IS NULL OR action = ?3)
+                           AND (?4 IS NULL OR principal = ?4)
+                         ORDER BY timestamp DESC LIMIT ?5",
+                    )
+                    .map_err(|err| err.to_string())?;
+                let rows = stmt
+                    .query_map(
+                        params![
+                            query.from.unwrap_or(0).min(i64::MAX as u64) as i64,
+                            query.to.unwrap_or(u64::MAX).min(i64::MAX as u64) as i64,
+                            query.action.map(AuditAction::as_str),
+                            query.principal,
+                            query.limit() as i64
+                        ],
+                        |row| row.get::<_, String>(0),
+                    )
+                    .map_err(|err| err.to_string())?;
+                let mut entries = vec![];
+                for data in rows {
+                    let data = data.map_err(|err| err.to_string())?;
+                    entries.extend(serde_json::from_str(&data).ok());
+                }
+                Ok(entries)
+            }
+        })
+        .await
+        .expect("Audit task panicked")
+    }
+}
+
+/// Lines of a file, the last first, read in blocks from its end.
+struct RevLines {
+    file: File,
+    /// Where the part not read yet ends.
+    pos: u64,
+    /// Read but not returned yet; starts with a line that may go on in the
+    /// previous block.
+    pending: Vec<u8>,
+}
+
+impl RevLines {
+    fn new(file: File) -> io::Result<Self> {
+        let pos = file.metadata()?.len();
+        Ok(Self {
+            file,
+            pos,
+            pending: vec![],
+        })
+    }
+}
+
+impl Iterator for RevLines {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(newline) = self.pending.iter().rposition(|&byte| byte == b'\n') {
+                let line = self.pending.split_off(newline + 1);
+                self.pending.truncate(newline);
+                if line.is_empty() {
+                    continue;
+                }
+                return Some(Ok(line));
+            }
+            if self.pos == 0 {
+                return (!self.pending.is_empty()).then(|| Ok(std::mem::take(&mut self.pending)));
+            }
+            let len = self.pos.min(FILE_BLOCK);
+            self.pos -= len;
+            let mut block = vec![0; len as usize];
+            let read = self
+                .file
+                .seek(SeekFrom::Start(self.pos))
+                .and_then(|_| self.file.read_exact(&mut block));
+            if let Err(err) = read {
+                return Some(Err(err));
+            }
+            block.append(&mut self.pending);
+            self.pending = block;
+        }
+    }
+}
+
+/// Route layer recording the request in the audit log once it's answered.
+/// The request and response bodies are buffered to do so; both are small.
+/// A request whose body can't be read is refused, and recorded as such,
+/// rather than handled unaudited.
+pub async fn record(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_default();
+    let Some(action) = AuditAction::of(request.method(), &path) else {
+        return next.run(request).await;
+    };
+    let (principal, role) = match request.extensions().get::<Principal>().cloned() {
+        Some(principal) => (principal.name, principal.role),
+        None => ("anonymous".to_owned(), Role::Admin),
+    };
+    let client = request
+        .extensions()
+        .get::<ClientIp>()
+        .map_or(peer.ip(), |client| client.0);
+    let (parts, request_body) = request.into_parts();
+    // The path parameter, matched from the end as nesting strips the
+    // router's prefix from the URI but not from the matched path.
+    let target = path
+        .rsplit('/')
+        .zip(parts.uri.path().rsplit('/'))
+        .find(|(pattern, _)| pattern.starts_with(':'))
+        .map(|(_, value)| value.to_owned());
+    let entry = |target, request, status: StatusCode, error| AuditEntry {
+        timestamp: now_millis(),
+        principal,
+        role,
+        address: client.to_string(),
+        action,
+        target,
+        request,
+        status: status.as_u16(),
+        error,
+    };
+    let request_body = match Bytes::from_request(Request::new(request_body), &()).await {
+        Ok(request_body) => request_body,
+        Err(rejection) => {
+            let entry = entry(
+                target,
+                None,
+                rejection.status(),
+                Some(rejection.body_text()),
+            );
+            state.audit.record(entry).await;
+            return rejection.into_response();
+        }
+    };
+    let request_json = serde_json::from_slice::<Value>(&request_body).ok();
+    let target = target.or_else(|| {
+        request_json
+            .as_ref()
+            .and_then(|json| json["id"].as_str().map(str::to_owned))
+    });
+    let response = next
+        .run(Request::from_parts(parts, Body::from(request_body)))
+        .await;
+    let status = response.status();
+    let (parts, response_body) = response.into_parts();
+    let response_body = Bytes::from_request(Request::new(response_body), &())
+        .await
+        .unwrap_or_default();
+    let error = (!status.is_success())
+        .then(|| {
+            serde_json::from_slice::<Value>(&response_body)
+                .ok()
+                .and_then(|json| json["detail"].as_str().map(str::to_owned))
+                // Extractor rejections answer in plain text.
+                .or_else(|| {
+                    let text = String::from_utf8_lossy(&response_body);
+                    (!text.trim().is_empty()).then(|| text.trim().to_owned())
+                })
+                .or_else(|| status.canonical_reason().map(str::to_owned))
+        })
+        .flatten();
+    state
+        .audit
+        .record(entry(target, request_json, status, error))
+        .await;
+    Response::from_parts(parts, body::boxed(Full::from(response_body)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/audit",
+    tag = "audit",
+    params(AuditQuery),
+    responses(
+        (status = 200, description = "Recorded actions, newest first", body = [AuditEntry]),
+        (status = 403, description = "Requires the admin role", body = ErrorResponse),
+        (status = 500, description = "The audit log couldn't be read", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_audit(
+    State(state): State<AppState>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<Vec<AuditEntry>>, AppError> {
+    state.audit.query(query).await.map(Json).map_err(|err| {
+        api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to read the audit log: {err}"),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(timestamp: u64, action: AuditAction) -> AuditEntry {
+        AuditEntry {
+            timestamp,
+            principal: "admin API key".to_owned(),
+            role: Role::Admin,
+            address: "127.0.0.1".to_owned(),
+            action,
+            target: None,
+            request: None,
+            status: 200,
+            error: None,
+        }
+    }
+
+    fn query(action: Option<AuditAction>, limit: usize) -> AuditQuery {
+        AuditQuery {
+            from: None,
+            to: None,
+            action,
+            principal: None,
+            limit: Some(limit),
+        }
+    }
+
+    #[test]
+    fn reads_lines_from_the_end() {
+        let path = std::env::temp_dir().join(format!("audit-lines-{}", std::process::id()));
+        // Lines longer than a block and a last one without a newline.
+        let long = "x".repeat(FILE_BLOCK as usize + 10);
+        std::fs::write(&path, format!("first\n{long}\n\nlast")).unwrap();
+        let lines: Vec<Vec<u8>> = RevLines::new(File::open(&path).unwrap())
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            lines,
+            [b"last".to_vec(), long.into_bytes(), b"first".to_vec()]
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn queries_the_file_newest_first() {
+        let path = std::env::temp_dir().join(format!("audit-query-{}.jsonl", std::process::id()));
+        let log = AuditLog::open(Some(&path), None).unwrap();
+        for timestamp in 1..=5 {
+            let action = match timestamp % 2 {
+                0 => AuditAction::Signal,
+                _ => AuditAction::DeleteSnapshot,
+            };
+            log.record(entry(timestamp, action)).await;
+        }
+        // Cut short, as by a crash.
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"timestamp\": 6")
+            .unwrap();
+        let timestamps = |entries: Vec<AuditEntry>| -> Vec<u64> {
+            entries.iter().map(|entry| entry.timestamp).collect()
+        };
+        let all = log.query(query(None, 10)).await.unwrap();
+        assert_eq!(timestamps(all), [5, 4, 3, 2, 1]);
+        let latest = log.query(query(None, 2)).await.unwrap();
+        assert_eq!(timestamps(latest), [5, 4]);
+        let signals = log
+            .query(query(Some(AuditAction::Signal), 10))
+            .await
+            .unwrap();
+        assert_eq!(timestamps(signals), [4, 2]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
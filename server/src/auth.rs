@@ -0,0 +1,166 @@
+//! Optional authentication for the data routes, enabled by setting `API_KEY`
+//! or `--api-key-file`, the per-role keys, or accepting JWTs (see
+//! `jwt.rs`).
+//!
+//! Every credential carries a role. Viewers can read everything, operators
+//! can also act on processes, take snapshots and push agent reports, and
+//! admins can also change the alert rules. The plain API key is an admin
+//! key, and without any credentials configured every client is an admin.
+
+use crate::{api_error, jwt, AppError, AppState};
+use axum::extract::State;
+use axum::http::{header, HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use utoipa::ToSchema;
+
+/// Ordered by privilege, each role allowing what the ones before it do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Operator,
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::Viewer => "viewer",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+        })
+    }
+}
+
+/// Who made a request, as recorded in the audit log.
+#[derive(Debug, Clone)]
+pub struct Principal {
+    /// `admin API key` and the like for keys, the user for tokens.
+    pub name: String,
+    pub role: Role,
+}
+
+impl Principal {
+    fn key(role: Role) -> Self {
+        Self {
+            name: format!("{role} API key"),
+            role,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+/// Accepts the credential from an `Authorization: Bearer` header or, for
+/// clients such as browsers opening a WebSocket that can't set headers, a
+/// `?token=` query parameter. The client's `Principal` is added to the
+/// request's extensions for `require_operator`, `require_admin` and the audit
+/// log.
+pub async fn require_api_key<B>(
+    State(state): State<AppState>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if !state.config.requires_auth() {
+        request.extensions_mut().insert(Principal {
+            name: "anonymous".to_owned(),
+            role: Role::Admin,
+        });
+        return next.run(request).await;
+    }
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_owned);
+    let token = bearer.or_else(|| {
+        let query = request.uri().query()?;
+        serde_urlencoded::from_str::<TokenQuery>(query)
+            .ok()
+            .and_then(|query| query.token)
+    });
+    let Some(token) = token else {
+        return unauthorized("Missing API key");
+    };
+    match authenticate(&state, &token) {
+        Ok(principal) => {
+            request.extensions_mut().insert(principal);
+            next.run(request).await
+        }
+        Err(err) => unauthorized(&err),
+    }
+}
+
+/// Route layer for actions on processes and other writes that don't change
+/// the server's configuration.
+pub async fn require_operator<B>(request: Request<B>, next: Next<B>) -> Response {
+    require_role(Role::Operator, request, next).await
+}
+
+/// Route layer for changes to the server's configuration.
+pub async fn require_admin<B>(request: Request<B>, next: Next<B>) -> Response {
+    require_role(Role::Admin, request, next).await
+}
+
+async fn require_role<B>(required: Role, request: Request<B>, next: Next<B>) -> Response {
+    // Missing only if the route isn't behind `require_api_key`.
+    let principal = request.extensions().get::<Principal>();
+    if principal.is_some_and(|principal| principal.role >= required) {
+        return next.run(request).await;
+    }
+    let error: AppError = api_error(
+        StatusCode::FORBIDDEN,
+        format!("This requires the {required} role"),
+    );
+    error.into_response()
+}
+
+/// Route layer around every write, refusing it with 403 in `--read-only`
+/// mode.
+pub async fn reject_writes<B>(
+    State(state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if !state.config.read_only {
+        return next.run(request).await;
+    }
+    let error: AppError = api_error(StatusCode::FORBIDDEN, "The server is read-only");
+    error.into_response()
+}
+
+/// Whom `token` belongs to, trying it as a JWT first when it looks like one.
+pub fn authenticate(state: &AppState, token: &str) -> Result<Principal, String> {
+    if let Some(config) = &state.config.jwt {
+        if token.split('.').count() == 3 {
+            return jwt::verify(config, &state.jwks, token);
+        }
+    }
+    state
+        .config
+        .api_keys
+        .iter()
+        .find(|key| constant_time_eq(token.as_bytes(), key.key.as_bytes()))
+        .map(|key| Principal::key(key.role))
+        .ok_or_else(|| "Invalid API key".to_owned())
+}
+
+fn unauthorized(message: &str) -> Response {
+    let error: AppError = api_error(StatusCode::UNAUTHORIZED, message);
+    let mut response = error.into_response();
+    response
+        .headers_mut()
+        .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    response
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,165 @@
+//! Control group membership of processes, the containers it reveals, and
+//! the cgroup v2 limits the server itself runs under. Only Linux has
+//! cgroups; elsewhere every lookup comes back empty.
+
+/// Length of the short container ids Docker shows.
+const SHORT_ID_LEN: usize = 12;
+
+/// Prefixes runtimes put in front of the container id in systemd scope
+/// names, e.g. `docker-<id>.scope`.
+const SCOPE_PREFIXES: &[&str] = &["docker-", "cri-containerd-", "crio-", "libpod-"];
+
+/// The cgroup a process belongs to: its cgroup v2 path, or on a v1 host the
+/// path in the systemd hierarchy.
+#[cfg(target_os = "linux")]
+pub fn process_cgroup(pid: u32) -> Option<String> {
+    let content = std::fs::read_to_string(format!("/proc/{pid}/cgroup")).ok()?;
+    let mut fallback = None;
+    for line in content.lines() {
+        let mut fields = line.splitn(3, ':');
+        let (Some(id), Some(controllers), Some(path)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        if id == "0" && controllers.is_empty() {
+            return Some(path.to_owned());
+        }
+        if controllers == "name=systemd" || fallback.is_none() {
+            fallback = Some(path.to_owned());
+        }
+    }
+    fallback
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn process_cgroup(_: u32) -> Option<String> {
+    None
+}
+
+/// Short id of the container a cgroup path belongs to, recognizing the
+/// layouts of Docker, containerd, CRI-O, Podman and Kubernetes: the
+/// innermost path segment that is a 64 digit hex id, bare or as a scope.
+pub fn container_id(cgroup: &str) -> Option<String> {
+    cgroup.rsplit('/').find_map(|segment| {
+        let segment = segment.strip_suffix(".scope").unwrap_or(segment);
+        let id = SCOPE_PREFIXES
+            .iter()
+            .find_map(|prefix| segment.strip_prefix(prefix))
+            .unwrap_or(segment);
+        (id.len() == 64 && id.bytes().all(|byte| byte.is_ascii_hexdigit()))
+            .then(|| id[..SHORT_ID_LEN].to_owned())
+    })
+}
+
+/// Where the cgroup v2 hierarchy is mounted.
+#[cfg(target_os = "linux")]
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Limits in effect for this server and what it uses of them. A limit set
+/// on any ancestor applies too, so the tightest one along the path wins and
+/// usage is read from the cgroup that set it.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Limits {
+    /// `memory.max` in bytes.
+    pub memory_max: Option<u64>,
+    /// `memory.current` of the limiting cgroup, without inactive page cache.
+    pub memory_used: Option<u64>,
+    /// `cpu.max` as a number of cores.
+    pub cpu_max: Option<f64>,
+    /// `usage_usec` from `cpu.stat` of the limiting cgroup.
+    pub cpu_usage_usec: Option<u64>,
+}
+
+/// The cgroup v2 directories of this server, innermost first.
+#[derive(Debug, Clone)]
+pub struct OwnCgroup {
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    dirs: Vec<std::path::PathBuf>,
+}
+
+impl OwnCgroup {
+    /// `None` outside of Linux and on hosts without the unified hierarchy.
+    #[cfg(target_os = "linux")]
+    pub fn detect() -> Option<Self> {
+        let root = std::path::Path::new(CGROUP_ROOT);
+        if !root.join("cgroup.controllers").exists() {
+            return None;
+        }
+        let path = process_cgroup(std::process::id())?;
+        // Inside a cgroup namespace the own cgroup shows up as `/` and is
+        // what's mounted at the root.
+        let mut dirs = vec![];
+        let mut dir = root.join(path.trim_start_matches('/'));
+        while dir.starts_with(root) {
+            dirs.push(dir.clone());
+            if !dir.pop() {
+                break;
+            }
+        }
+        Some(Self { dirs })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn detect() -> Option<Self> {
+        None
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn limits(&self) -> Limits {
+        use std::fs;
+
+        let read = |dir: &std::path::Path, file: &str| fs::read_to_string(dir.join(file)).ok();
+        // The root cgroup has no limit files, nor do cgroups without the
+        // controller enabled.
+        let memory = self
+            .dirs
+            .iter()
+            .filter_map(|dir| Some((read(dir, "memory.max")?.trim().parse::<u64>().ok()?, dir)))
+            .min_by_key(|(max, _)| *max);
+        let cpu = self
+            .dirs
+            .iter()
+            .filter_map(|dir| Some((parse_cpu_max(&read(dir, "cpu.max")?)?, dir)))
+            .min_by(|(a, _), (b, _)| a.total_cmp(b));
+        let memory_used = memory.and_then(|(_, dir)| {
+            let current: u64 = read(dir, "memory.current")?.trim().parse().ok()?;
+            let inactive = read(dir, "memory.stat")
+                .and_then(|stat| stat_value(&stat, "inactive_file"))
+                .unwrap_or_default();
+            Some(current.saturating_sub(inactive))
+        });
+        let cpu_usage_usec =
+            cpu.and_then(|(_, dir)| stat_value(&read(dir, "cpu.stat")?, "usage_usec"));
+        Limits {
+            memory_max: memory.map(|(max, _)| max),
+            memory_used,
+            cpu_max: cpu.map(|(max, _)| max),
+            cpu_usage_usec,
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn limits(&self) -> Limits {
+        Limits::default()
+    }
+}
+
+/// `cpu.max` holds `<quota> <period>` in microseconds, or `max <period>`
+/// without a limit.
+#[cfg(target_os = "linux")]
+fn parse_cpu_max(content: &str) -> Option<f64> {
+    let mut fields = content.split_whitespace();
+    let quota: f64 = fields.next()?.parse().ok()?;
+    let period: f64 = fields.next()?.parse().ok()?;
+    (period > 0f64).then(|| quota / period)
+}
+
+/// A `key value` line of `memory.stat` or `cpu.stat`.
+#[cfg(target_os = "linux")]
+fn stat_value(stat: &str, key: &str) -> Option<u64> {
+    stat.lines().find_map(|line| {
+        let (name, value) = line.split_once(' ')?;
+        (name == key).then(|| value.trim().parse().ok())?
+    })
+}
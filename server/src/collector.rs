@@ -0,0 +1,573 @@
+//! The sources of sampled data and their registry. CPU, memory, processes
+//! and the rest are refreshed together from one `System` on the sampler's
+//! tick; each is registered as a `Sampled` source, which the sampler
+//! publishes to. Collectors sample on a loop of their own instead, because
+//! they are slow (Docker), optional (GPUs) or external (plugins, see
+//! `plugin.rs`). A `Collector` only says what to sample and how often; the
+//! `Registry` runs it, pausing while sampling is idle, stopping on shutdown
+//! and logging failures once per outage rather than every tick.
+//!
+//! Either kind gets by its name, without further plumbing:
+//!
+//! - a topic, on `/realtime` subscriptions and `/sse/:name`
+//! - its latest sample at `/api/v1/collectors/:name`
+//! - in-memory history at `/api/v1/collectors/:name/history`, unless it is
+//!   a sampled source registered with `Retain::Latest`
+//!
+//! Collectors also get a `process_viewer_collector_value` gauge on
+//! `/metrics` for every numeric field of their latest sample. Sampled
+//! sources don't, since CPU and memory have metrics of their own.
+
+use crate::encoding::{Encoded, Encoding};
+use crate::history::{now_millis, History, HistoryEntry, HistoryQuery};
+use crate::idle;
+use crate::protobuf::ToProtobuf;
+use crate::realtime::{payloads, PayloadStream};
+use crate::sampler::{Topic, SAMPLE_TIMESTAMP_HEADER};
+use crate::telemetry::Telemetry;
+use crate::{AppError, AppState};
+use axum::async_trait;
+use axum::extract::{Path, Query, State};
+use axum::response::IntoResponse;
+use axum_macros::debug_handler;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashSet};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+use utoipa::ToSchema;
+
+#[async_trait]
+pub trait Collector: Send + 'static {
+    type Output: Serialize + ToProtobuf + Clone + Send + Sync + 'static;
+
+    /// Names the topic, routes and metrics; unique among collectors.
+    fn name(&self) -> &'static str;
+
+    fn interval(&self) -> Duration;
+
+    async fn sample(&mut self, state: &AppState) -> Result<Self::Output, String>;
+}
+
+/// What the registry keeps of a sampled source besides its latest sample.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retain {
+    /// In-memory history, as for collectors.
+    History,
+    /// Nothing more, for lists too long to keep or export every tick, such
+    /// as the processes.
+    Latest,
+}
+
+/// A built-in source, refreshed with the others on the sampler's tick.
+/// Handlers read it as the `Topic` it derefs to; the sampler publishes to it
+/// with a timestamp, which also keeps its history.
+#[derive(Clone)]
+pub struct Sampled<T> {
+    topic: Topic<T>,
+    /// `None` with `Retain::Latest`.
+    history: Option<History<T>>,
+}
+
+impl<T: Clone> Sampled<T> {
+    pub fn publish(&self, timestamp: u64, value: T) {
+        if !self.topic.is_enabled() {
+            return;
+        }
+        if let Some(history) = &self.history {
+            history.push(timestamp, value.clone());
+        }
+        self.topic.publish(timestamp, value);
+    }
+
+    /// Panics for a source registered with `Retain::Latest`.
+    pub fn history(&self) -> &History<T> {
+        self.history.as_ref().expect("Source keeps no history")
+    }
+}
+
+impl<T> Deref for Sampled<T> {
+    type Target = Topic<T>;
+
+    fn deref(&self) -> &Topic<T> {
+        &self.topic
+    }
+}
+
+/// A registered source, with its output type erased.
+trait Source: Send + Sync {
+    fn interval(&self) -> Duration;
+    fn is_enabled(&self) -> bool;
+    fn gauged(&self) -> bool;
+    fn samples(&self) -> u64;
+    fn subscriber_count(&self) -> usize;
+    /// `None` before the first sample.
+    fn latest(&self) -> Option<HistoryEntry<Value>>;
+    /// `None` when no history is kept.
+    fn history(&self, query: &HistoryQuery) -> Option<Vec<HistoryEntry<Value>>>;
+    fn stream(&self, name: &'static str, encoding: Encoding, telemetry: Telemetry)
+        -> PayloadStream;
+}
+
+struct Registered<T> {
+    interval: Duration,
+    topic: Topic<T>,
+    history: Option<History<T>>,
+    /// Only collectors are exported as gauges.
+    gauged: bool,
+}
+
+impl<T> Source for Registered<T>
+where
+    T: Serialize + ToProtobuf + Clone + Send + Sync + 'static,
+{
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.topic.is_enabled()
+    }
+
+    fn gauged(&self) -> bool {
+        self.gauged
+    }
+
+    fn samples(&self) -> u64 {
+        self.topic.latest().seq
+    }
+
+    fn subscriber_count(&self) -> usize {
+        self.topic.subscriber_count()
+    }
+
+    fn latest(&self) -> Option<HistoryEntry<Value>> {
+        let latest = self.topic.latest();
+        (latest.seq > 0).then(|| HistoryEntry {
+            timestamp: latest.timestamp,
+            data: serde_json::to_value(latest.value()).unwrap(),
+        })
+    }
+
+    fn history(&self, query: &HistoryQuery) -> Option<Vec<HistoryEntry<Value>>> {
+        let entries = query.in_memory(self.history.as_ref()?);
+        let entries = entries
+            .into_iter()
+            .map(|entry| HistoryEntry {
+                timestamp: entry.timestamp,
+                data: serde_json::to_value(entry.data).unwrap(),
+            })
+            .collect();
+        Some(entries)
+    }
+
+    fn stream(
+        &self,
+        name: &'static str,
+        encoding: Encoding,
+        telemetry: Telemetry,
+    ) -> PayloadStream {
+        payloads(&self.topic, name, encoding, telemetry)
+    }
+}
+
+type Start = Box<dyn FnOnce(AppState) + Send>;
+
+pub struct Registry {
+    history_retention: Duration,
+    /// The sampler's tick, at which sampled sources are refreshed.
+    sample_interval: Duration,
+    sources: BTreeMap<&'static str, Arc<dyn Source>>,
+    /// Collectors registered but not running yet, see `start`.
+    pending: Mutex<Vec<Start>>,
+}
+
+impl Registry {
+    pub fn new(history_retention: Duration, sample_interval: Duration) -> Self {
+        Self {
+            history_retention,
+            sample_interval,
+            sources: BTreeMap::new(),
+            pending: Mutex::new(vec![]),
+        }
+    }
+
+    /// Samples are published to `topic`, which may also be read elsewhere.
+    pub fn register<C: Collector>(&mut self, collector: C, topic: Topic<C::Output>) {
+        let name = collector.name();
+        let interval = collector.interval();
+        let history = History::new(self.history_retention, interval);
+        let registered = Registered {
+            interval,
+            topic: topic.clone(),
+            history: Some(history.clone()),
+            gauged: true,
+        };
+        self.insert(name, registered);
+        self.pending.lock().unwrap().push(Box::new(move |state| {
+            tokio::spawn(run(collector, topic, history, state));
+        }));
+    }
+
+    /// A source the sampler publishes to, which stays empty unless
+    /// `enabled`.
+    pub fn register_sampled<T>(
+        &mut self,
+        name: &'static str,
+        enabled: bool,
+        retain: Retain,
+    ) -> Sampled<T>
+    where
+        T: Serialize + ToProtobuf + Clone + Default + Send + Sync + 'static,
+    {
+        let topic = Topic::enabled_if(enabled);
+        let interval = self.sample_interval;
+        let history =
+            (retain == Retain::History).then(|| History::new(self.history_retention, interval));
+        let registered = Registered {
+            interval,
+            topic: topic.clone(),
+            history: history.clone(),
+            gauged: false,
+        };
+        self.insert(name, registered);
+        Sampled { topic, history }
+    }
+
+    fn insert<T>(&mut self, name: &'static str, registered: Registered<T>)
+    where
+        T: Serialize + ToProtobuf + Clone + Send + Sync + 'static,
+    {
+        assert!(
+            !self.sources.contains_key(name),
+            "Collector {name} is registered twice"
+        );
+        self.sources.insert(name, Arc::new(registered));
+    }
+
+    /// Runs every registered collector, once the state they sample into
+    /// exists.
+    pub fn start(&self, state: &AppState) {
+        for start in self.pending.lock().unwrap().drain(..) {
+            start(state.clone());
+        }
+    }
+
+    /// `name` as registered, if it is.
+    pub fn name(&self, name: &str) -> Option<&'static str> {
+        self.sources.get_key_value(name).map(|(name, _)| *name)
+    }
+
+    /// False for sources disabled in `[collectors]` and those not
+    /// registered.
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.sources
+            .get(name)
+            .is_some_and(|source| source.is_enabled())
+    }
+
+    pub fn stream(
+        &self,
+        name: &str,
+        encoding: Encoding,
+        telemetry: Telemetry,
+    ) -> Option<PayloadStream> {
+        let (name, source) = self.sources.get_key_value(name)?;
+        Some(source.stream(name, encoding, telemetry))
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.sources
+            .values()
+            .map(|source| source.subscriber_count())
+            .sum()
+    }
+
+    /// The latest sample of every collector that has one, by name, for the
+    /// gauges.
+    pub fn latest(&self) -> impl Iterator<Item = (&'static str, Value)> + '_ {
+        self.sources
+            .iter()
+            .filter(|(_, source)| source.gauged())
+            .filter_map(|(name, source)| Some((*name, source.latest()?.data)))
+    }
+
+    /// The latest sample of collector `name`, with its time.
+    pub fn latest_of(&self, name: &str) -> Result<HistoryEntry<Value>, AppError> {
+        self.source(name)?
+            .latest()
+            .ok_or_else(|| AppError::NotReady(format!("The {name} collector hasn't sampled yet")))
+    }
+
+    /// A 404 for unknown and disabled sources.
+    fn source(&self, name: &str) -> Result<&dyn Source, AppError> {
+        let source = self
+            .sources
+            .get(name)
+            .ok_or_else(|| AppError::NotFound(format!("No collector named {name}")))?;
+        if !source.is_enabled() {
+            return Err(AppError::NotFound(format!(
+                "The {name} collector is disabled"
+            )));
+        }
+        Ok(&**source)
+    }
+}
+
+async fn run<C: Collector>(
+    mut collector: C,
+    topic: Topic<C::Output>,
+    history: History<C::Output>,
+    state: AppState,
+) {
+    let name = collector.name();
+    let mut ticker = tokio::time::interval(collector.interval());
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut failing = false;
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = state.shutdown.cancelled() => break,
+        }
+        if !idle::wait_while_idle(&state, name).await {
+            break;
+        }
+        // Dropped on shutdown, which kills a plugin that is still running.
+        let result = tokio::select! {
+            result = collector.sample(&state) => result,
+            _ = state.shutdown.cancelled() => break,
+        };
+        match result {
+            Ok(output) => {
+                if failing {
+                    tracing::info!(collector = name, "Collector recovered");
+                    failing = false;
+                }
+                let timestamp = now_millis();
+                history.push(timestamp, output.clone());
+                topic.publish(timestamp, output);
+            }
+            Err(err) if !failing => {
+                tracing::warn!(collector = name, %err, "Collector failed");
+                failing = true;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CollectorInfo {
+    pub name: String,
+    pub interval_ms: u64,
+    /// Number of samples published so far.
+    pub samples: u64,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/collectors",
+    tag = "collectors",
+    responses((status = 200, description = "Registered collectors by name", body = [CollectorInfo], content_type = ["application/json", "application/msgpack"]))
+)]
+#[debug_handler]
+pub async fn list_collectors(
+    State(state): State<AppState>,
+    encoding: Encoding,
+) -> Encoded<Vec<CollectorInfo>> {
+    let collectors = state
+        .registry
+        .sources
+        .iter()
+        .map(|(name, source)| CollectorInfo {
+            name: (*name).to_owned(),
+            interval_ms: source.interval().as_millis() as u64,
+            samples: source.samples(),
+        })
+        .collect();
+    Encoded(encoding, collectors)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/collectors/{name}",
+    tag = "collectors",
+    params(("name" = String, Path, description = "Collector name")),
+    responses(
+        (status = 200, description = "The collector's latest sample", body = Object, content_type = ["application/json", "application/msgpack"],
+            headers(("x-sample-timestamp" = u64, description = "When the sample was taken, in milliseconds since the Unix epoch"))),
+        (status = 404, description = "No such collector, or it is disabled", body = ErrorResponse),
+        (status = 503, description = "No sample taken yet", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_collector(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    encoding: Encoding,
+) -> Result<impl IntoResponse, AppError> {
+    let latest = state.registry.latest_of(&name)?;
+    Ok(sampled_response(encoding, latest))
+}
+
+/// A collector's sample, with its time in the `x-sample-timestamp` header.
+pub fn sampled_response(encoding: Encoding, latest: HistoryEntry<Value>) -> impl IntoResponse {
+    (
+        [(SAMPLE_TIMESTAMP_HEADER, latest.timestamp.to_string())],
+        Encoded(encoding, latest.data),
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/collectors/{name}/history",
+    tag = "collectors",
+    params(("name" = String, Path, description = "Collector name"), HistoryQuery),
+    responses(
+        (status = 200, description = "Samples kept in memory, oldest first", body = [Object], content_type = ["application/json", "application/msgpack"]),
+        (status = 404, description = "No such collector, or it keeps no history", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_collector_history(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    encoding: Encoding,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Encoded<Vec<HistoryEntry<Value>>>, AppError> {
+    let history = state
+        .registry
+        .source(&name)?
+        .history(&query)
+        .ok_or_else(|| AppError::NotFound(format!("The {name} collector keeps no history")))?;
+    Ok(Encoded(encoding, history))
+}
+
+/// Fields naming the elements of a list, tried in order. The first that
+/// every element has, with distinct values, keys them on `/metrics`, so
+/// series don't move when the list is reordered.
+const ELEMENT_KEYS: &[&str] = &[
+    "id",
+    "uuid",
+    "mount_point",
+    "interface",
+    "name",
+    "label",
+    "pid",
+];
+
+/// Numbers that identify or date a sample rather than measure anything.
+const NOT_MEASURED: &[&str] = &["timestamp", "index", "pid"];
+
+/// Numeric leaves of `value` as `(field, value)`, with the path to them
+/// joined by dots, leaving out `NOT_MEASURED` fields. Elements of lists are
+/// named by one of `ELEMENT_KEYS`, or by their index when none fits.
+/// Booleans count as 0 and 1.
+pub fn numeric_fields(value: &Value) -> Vec<(String, f64)> {
+    fn walk(value: &Value, path: &mut Vec<String>, out: &mut Vec<(String, f64)>) {
+        match value {
+            Value::Number(number) => {
+                if let Some(number) = number.as_f64() {
+                    out.push((path.join("."), number));
+                }
+            }
+            Value::Bool(flag) => out.push((path.join("."), f64::from(u8::from(*flag)))),
+            Value::Array(items) => {
+                let keys = element_keys(items);
+                for (index, item) in items.iter().enumerate() {
+                    let segment = match &keys {
+                        Some(keys) => keys[index].clone(),
+                        None => index.to_string(),
+                    };
+                    path.push(segment);
+                    walk(item, path, out);
+                    path.pop();
+                }
+            }
+            Value::Object(fields) => {
+                for (key, field) in fields {
+                    if NOT_MEASURED.contains(&key.as_str()) {
+                        continue;
+                    }
+                    path.push(key.clone());
+                    walk(field, path, out);
+                    path.pop();
+                }
+            }
+            Value::Null | Value::String(_) => {}
+        }
+    }
+    let mut out = vec![];
+    walk(value, &mut vec![], &mut out);
+    out
+}
+
+/// The values of the first of `ELEMENT_KEYS` that names every item
+/// uniquely, as strings or numbers.
+fn element_keys(items: &[Value]) -> Option<Vec<String>> {
+    ELEMENT_KEYS.iter().find_map(|key| {
+        let keys: Vec<String> = items
+            .iter()
+            .map(|item| match item.get(key)? {
+                Value::String(key) => Some(key.clone()),
+                Value::Number(key) => Some(key.to_string()),
+                _ => None,
+            })
+            .collect::<Option<_>>()?;
+        let distinct: HashSet<&String> = keys.iter().collect();
+        (distinct.len() == keys.len()).then_some(keys)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn list_elements_are_keyed_by_name() {
+        let disks = json!([
+            { "mount_point": "/home", "name": "sda", "free": 2 },
+            { "mount_point": "/", "name": "sda", "free": 1 },
+        ]);
+        assert_eq!(
+            numeric_fields(&disks),
+            vec![("/home.free".to_owned(), 2.0), ("/.free".to_owned(), 1.0)]
+        );
+    }
+
+    #[test]
+    fn identifiers_and_timestamps_are_not_gauges() {
+        let gpus = json!([{
+            "index": 0,
+            "uuid": "GPU-5d1c",
+            "gpu_usage": 40,
+            "processes": [{ "pid": 4242, "memory_used_bytes": 1024 }],
+            "timestamp": 1700000000000u64,
+        }]);
+        assert_eq!(
+            numeric_fields(&gpus),
+            vec![
+                ("GPU-5d1c.gpu_usage".to_owned(), 40.0),
+                (
+                    "GPU-5d1c.processes.4242.memory_used_bytes".to_owned(),
+                    1024.0
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn list_elements_without_a_unique_key_are_indexed() {
+        let values = json!({ "readings": [{ "name": "a", "v": 1 }, { "name": "a", "v": true }] });
+        assert_eq!(
+            numeric_fields(&values),
+            vec![
+                ("readings.0.v".to_owned(), 1.0),
+                ("readings.1.v".to_owned(), 1.0),
+            ]
+        );
+    }
+}
@@ -0,0 +1,1835 @@
+//! Runtime configuration, resolved once at startup from three layers: an
+//! optional TOML file given with `--config`, then environment variables, then
+//! CLI flags. Later layers override earlier ones field by field. See
+//! `config.example.toml` for the file layout.
+
+use crate::access::Cidr;
+use crate::alerts::AlertRule;
+use crate::auth::Role;
+use crate::format::{self, Units};
+use crate::history_db::Rollup;
+use crate::logging::{LogFormat, LogLevel};
+use crate::realtime;
+use crate::statsd::StatsdFormat;
+use clap::{Parser, ValueEnum};
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use sysinfo::{System, SystemExt};
+
+const DEFAULT_PORT: u16 = 7070;
+const DEFAULT_BIND: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+const DEFAULT_HISTORY_RETENTION: Duration = Duration::from_secs(10 * 60);
+const DEFAULT_HISTORY_DB_RETENTION_DAYS: u64 = 7;
+const DEFAULT_HISTORY_DB_MINUTE_RETENTION_DAYS: u64 = 90;
+const DEFAULT_HISTORY_DB_HOUR_RETENTION_DAYS: u64 = 730;
+const DEFAULT_INFLUXDB_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_MQTT_PORT: u16 = 1883;
+const DEFAULT_MQTT_DISCOVERY_PREFIX: &str = "homeassistant";
+const DEFAULT_MQTT_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_STATSD_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_REPLAY_SPEED: f64 = 1.0;
+const DEFAULT_AGENT_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_REMOTE_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_JWT_ROLE_CLAIM: &str = "role";
+/// Labels and tags the exporters already put on their series.
+const RESERVED_LABELS: &[&str] = &["host", "core", "vendor", "brand", "name", "pid"];
+
+/// A `--bind` value: a full socket address such as `[::1]:7070`, or only an
+/// IP address which then listens on `--port`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub enum BindAddr {
+    Ip(IpAddr),
+    Socket(SocketAddr),
+}
+
+impl BindAddr {
+    fn with_default_port(self, port: u16) -> SocketAddr {
+        match self {
+            BindAddr::Ip(ip) => SocketAddr::new(ip, port),
+            BindAddr::Socket(addr) => addr,
+        }
+    }
+}
+
+impl FromStr for BindAddr {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        value
+            .parse()
+            .map(BindAddr::Socket)
+            .or_else(|_| value.parse().map(BindAddr::Ip))
+            .map_err(|_| format!("Invalid bind address {value}"))
+    }
+}
+
+impl TryFrom<String> for BindAddr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// `bind` in the config file may be a single address or a list.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Binds {
+    One(BindAddr),
+    Many(Vec<BindAddr>),
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// InfluxDB v2 bucket to export samples to, see `influx.rs`.
+#[derive(Debug, Clone)]
+pub struct InfluxConfig {
+    pub url: String,
+    pub org: String,
+    pub bucket: String,
+    pub token: String,
+    pub interval: Duration,
+}
+
+/// MQTT broker to publish samples to, see `mqtt.rs`.
+#[derive(Debug, Clone)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    /// Defaults to `process-viewer-<hostname>`.
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Defaults to `process-viewer/<hostname>`.
+    pub topic_prefix: Option<String>,
+    /// Home Assistant discovery prefix; `None` skips discovery.
+    pub discovery_prefix: Option<String>,
+    pub interval: Duration,
+}
+
+/// StatsD or Graphite server to push gauges to, see `statsd.rs`.
+#[derive(Debug, Clone)]
+pub struct StatsdConfig {
+    /// `host:port` of the UDP listener.
+    pub address: String,
+    pub format: StatsdFormat,
+    /// Defaults to `process_viewer.<hostname>`.
+    pub prefix: Option<String>,
+    pub interval: Duration,
+}
+
+/// A key clients may authenticate with and the role it grants, see
+/// `auth.rs`.
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+    pub key: String,
+    pub role: Role,
+}
+
+/// JWTs accepted besides the API keys, see `jwt.rs`. At least one of
+/// `secret` and `jwks` is set.
+#[derive(Debug, Clone)]
+pub struct JwtConfig {
+    /// Of HS256 tokens.
+    pub secret: Option<String>,
+    /// Where the public keys of asymmetrically signed tokens come from.
+    pub jwks: Option<JwksSource>,
+    /// Claim holding the role, or a list of roles.
+    pub role_claim: String,
+    /// Required `iss`, when set.
+    pub issuer: Option<String>,
+    /// Required among `aud`, when set.
+    pub audience: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum JwksSource {
+    /// A JWK set.
+    Url(reqwest::Url),
+    /// An OpenID Connect issuer, whose configuration names its JWK set.
+    Issuer(reqwest::Url),
+}
+
+/// Hub to push samples to as an agent, see `agent.rs`.
+#[derive(Debug, Clone)]
+pub struct AgentConfig {
+    pub hub_url: reqwest::Url,
+    /// Defaults to the hostname.
+    pub host_id: Option<String>,
+    /// The hub's API key, if it requires one.
+    pub api_key: Option<String>,
+    pub interval: Duration,
+}
+
+/// Process-viewer instance to poll, see `federation.rs`.
+#[derive(Debug, Clone)]
+pub struct RemoteConfig {
+    /// Host id it is served under.
+    pub name: String,
+    pub url: reqwest::Url,
+    pub api_key: Option<String>,
+}
+
+/// Executable polled for a JSON sample, see `plugin.rs`.
+#[derive(Debug, Clone)]
+pub struct PluginConfig {
+    pub name: String,
+    /// The program, then its arguments.
+    pub command: Vec<String>,
+    pub interval: Duration,
+    /// Longest a run may take before it is killed.
+    pub timeout: Duration,
+}
+
+/// A process to keep an eye on, see `watchdog.rs`.
+#[derive(Debug, Clone)]
+pub struct WatchConfig {
+    pub name: String,
+    /// Matched against process names.
+    pub pattern: Regex,
+}
+
+/// Zeroconf announcement and discovery, see `mdns.rs`.
+#[derive(Debug, Clone)]
+pub struct MdnsConfig {
+    /// Instance name to announce; defaults to the hostname.
+    pub name: Option<String>,
+}
+
+/// IP allow and deny lists, see `access.rs`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessConfig {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+    /// Reverse proxies whose `X-Forwarded-For` is believed.
+    pub trusted_proxies: Vec<Cidr>,
+}
+
+/// Collectors that can be switched off under `[collectors]`, all on by
+/// default. The topics of a disabled collector are never published and their
+/// routes answer 404.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Collectors {
+    /// Also process events.
+    pub processes: bool,
+    /// TCP connections and their events.
+    pub connections: bool,
+    pub disks: bool,
+    pub diskio: bool,
+    pub networks: bool,
+    pub temperatures: bool,
+    pub pressure: bool,
+    pub power: bool,
+    pub gpu: bool,
+}
+
+impl Default for Collectors {
+    fn default() -> Self {
+        Self {
+            processes: true,
+            connections: true,
+            disks: true,
+            diskio: true,
+            networks: true,
+            temperatures: true,
+            pressure: true,
+            power: true,
+            gpu: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Collector {
+    Processes,
+    Connections,
+    Disks,
+    Diskio,
+    Networks,
+    Temperatures,
+    Pressure,
+    Power,
+    Gpu,
+}
+
+impl Collectors {
+    fn disable(&mut self, collector: Collector) {
+        let enabled = match collector {
+            Collector::Processes => &mut self.processes,
+            Collector::Connections => &mut self.connections,
+            Collector::Disks => &mut self.disks,
+            Collector::Diskio => &mut self.diskio,
+            Collector::Networks => &mut self.networks,
+            Collector::Temperatures => &mut self.temperatures,
+            Collector::Pressure => &mut self.pressure,
+            Collector::Power => &mut self.power,
+            Collector::Gpu => &mut self.gpu,
+        };
+        *enabled = false;
+    }
+}
+
+/// Per client IP limits, see `limits.rs`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub requests_per_second: Option<u32>,
+    /// Requests a client may make at once before being held to the rate;
+    /// defaults to `requests_per_second`.
+    pub burst: Option<u32>,
+    pub websockets_per_ip: Option<usize>,
+    /// Realtime WebSockets open at once over all clients.
+    pub websockets: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Every address to listen on, with `--port` applied; never empty. Not
+    /// used when systemd passes the listening sockets.
+    pub bind: Vec<SocketAddr>,
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    /// Key/value pairs describing this host, e.g. `role=db`; attached to
+    /// `/api/v1/system` and every exported metric.
+    pub labels: BTreeMap<String, String>,
+    /// Of human-readable sizes, see `format.rs`.
+    pub units: Units,
+    /// Decimal places of human-readable sizes.
+    pub precision: u8,
+    pub sample_interval: Duration,
+    pub allow_control: bool,
+    /// Refuse every request that changes something, see `auth.rs`.
+    pub read_only: bool,
+    pub history_retention: Duration,
+    /// Pause sampling after this long without clients, see `idle.rs`.
+    pub idle_after: Option<Duration>,
+    pub history_db: Option<PathBuf>,
+    pub history_db_retention: Duration,
+    /// Finest first; see `history_db.rs`.
+    pub history_db_rollups: Vec<Rollup>,
+    pub alert_rules: Vec<AlertRule>,
+    pub webhook_urls: Vec<String>,
+    /// Serve HTTPS/WSS instead of plain HTTP when set.
+    pub tls: Option<TlsConfig>,
+    /// Accepted on `/api`, `/realtime` and `/sse` requests, which require a
+    /// key or token once there are any.
+    pub api_keys: Vec<ApiKey>,
+    pub jwt: Option<JwtConfig>,
+    /// Append audit entries to this file as JSON lines rather than keeping
+    /// them in the history database or memory, see `audit.rs`.
+    pub audit_log: Option<PathBuf>,
+    /// OTLP/HTTP collector to export traces and metrics to.
+    pub otlp_endpoint: Option<String>,
+    pub access: AccessConfig,
+    pub limits: LimitsConfig,
+    pub collectors: Collectors,
+    /// Docker Engine socket to collect container metrics from.
+    pub docker_socket: Option<PathBuf>,
+    pub influxdb: Option<InfluxConfig>,
+    pub mqtt: Option<MqttConfig>,
+    pub statsd: Option<StatsdConfig>,
+    /// Serve the gRPC API here, with the `grpc` feature.
+    pub grpc_bind: Option<SocketAddr>,
+    /// Append every sample to this file, see `recording.rs`.
+    pub record: Option<PathBuf>,
+    /// Serve this recording instead of sampling.
+    pub replay: Option<PathBuf>,
+    /// How many times faster than recorded `replay` plays.
+    pub replay_speed: f64,
+    pub agent: Option<AgentConfig>,
+    pub remotes: Vec<RemoteConfig>,
+    pub remote_interval: Duration,
+    pub mdns: Option<MdnsConfig>,
+    pub plugins: Vec<PluginConfig>,
+    pub watches: Vec<WatchConfig>,
+}
+
+/// The configuration as written in the TOML file; every field is optional so
+/// that the environment and CLI layers can be applied on top.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawConfig {
+    port: Option<u16>,
+    bind: Option<Binds>,
+    interval_ms: Option<u64>,
+    log_level: Option<LogLevel>,
+    log_format: Option<LogFormat>,
+    units: Option<Units>,
+    precision: Option<u8>,
+    allow_control: Option<bool>,
+    read_only: Option<bool>,
+    idle_after_secs: Option<u64>,
+    labels: BTreeMap<String, String>,
+    history: RawHistory,
+    alerts: RawAlerts,
+    tls: RawTls,
+    auth: RawAuth,
+    audit: RawAudit,
+    otlp: RawOtlp,
+    access: AccessConfig,
+    limits: LimitsConfig,
+    collectors: Collectors,
+    docker: RawDocker,
+    influxdb: RawInflux,
+    mqtt: RawMqtt,
+    statsd: RawStatsd,
+    grpc: RawGrpc,
+    recording: RawRecording,
+    agent: RawAgent,
+    federation: RawFederation,
+    mdns: RawMdns,
+    plugins: Vec<RawPlugin>,
+    watch: Vec<RawWatch>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawHistory {
+    seconds: Option<u64>,
+    db: Option<PathBuf>,
+    db_retention_days: Option<u64>,
+    /// Of the per-minute means, 0 to not keep any.
+    db_minute_retention_days: Option<u64>,
+    /// Of the per-hour means, 0 to not keep any.
+    db_hour_retention_days: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawAlerts {
+    /// JSON file with additional rules.
+    rules_file: Option<PathBuf>,
+    rules: Vec<AlertRule>,
+    webhook_urls: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawTls {
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawAuth {
+    /// Of the admin role.
+    api_key: Option<String>,
+    api_key_file: Option<PathBuf>,
+    operator_api_key: Option<String>,
+    operator_api_key_file: Option<PathBuf>,
+    viewer_api_key: Option<String>,
+    viewer_api_key_file: Option<PathBuf>,
+    jwt_secret: Option<String>,
+    jwt_secret_file: Option<PathBuf>,
+    jwt_role_claim: Option<String>,
+    jwt_issuer: Option<String>,
+    jwt_audience: Option<String>,
+    jwt_jwks_url: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawAudit {
+    file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawOtlp {
+    endpoint: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawDocker {
+    socket: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawInflux {
+    url: Option<String>,
+    org: Option<String>,
+    bucket: Option<String>,
+    token: Option<String>,
+    token_file: Option<PathBuf>,
+    interval_ms: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawMqtt {
+    host: Option<String>,
+    port: Option<u16>,
+    client_id: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    password_file: Option<PathBuf>,
+    topic_prefix: Option<String>,
+    /// An empty prefix turns discovery off.
+    discovery_prefix: Option<String>,
+    interval_ms: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawGrpc {
+    bind: Option<SocketAddr>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawAgent {
+    enabled: Option<bool>,
+    hub_url: Option<String>,
+    host_id: Option<String>,
+    api_key_file: Option<PathBuf>,
+    interval_ms: Option<u64>,
+    #[serde(skip)]
+    api_key: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawMdns {
+    enabled: Option<bool>,
+    name: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawFederation {
+    interval_ms: Option<u64>,
+    remotes: Vec<RawRemote>,
+    /// Of remotes without a key file of their own.
+    #[serde(skip)]
+    api_key: Option<String>,
+}
+
+/// A `--remote` value, `name=url`, or a `[[federation.remotes]]` table.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRemote {
+    name: String,
+    url: String,
+    #[serde(default)]
+    api_key_file: Option<PathBuf>,
+}
+
+impl FromStr for RawRemote {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (name, url) = value
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid remote {value:?}, expected name=url"))?;
+        Ok(Self {
+            name: name.trim().to_owned(),
+            url: url.trim().to_owned(),
+            api_key_file: None,
+        })
+    }
+}
+
+/// A `[[plugins]]` table.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPlugin {
+    name: String,
+    command: Vec<String>,
+    /// Defaults to the sampling interval.
+    #[serde(default)]
+    interval_ms: Option<u64>,
+    /// Defaults to the interval.
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+}
+
+/// A `[[watch]]` table.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawWatch {
+    name: String,
+    /// Regular expression; defaults to exactly the name.
+    #[serde(default)]
+    pattern: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawRecording {
+    record: Option<PathBuf>,
+    replay: Option<PathBuf>,
+    replay_speed: Option<f64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawStatsd {
+    address: Option<String>,
+    format: Option<StatsdFormat>,
+    prefix: Option<String>,
+    interval_ms: Option<u64>,
+}
+
+/// Command line interface. Every setting can also be given through the listed
+/// environment variable; flags win over the environment, which wins over the
+/// config file.
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Serve live system and process metrics over HTTP and WebSockets"
+)]
+struct Cli {
+    /// TOML configuration file
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Port to listen on [default: 7070]
+    #[arg(long, env = "PORT")]
+    port: Option<u16>,
+    /// Address to bind to, either an IP or IP:PORT; repeat it to listen on
+    /// several [default: 0.0.0.0]
+    #[arg(long, env = "BIND", value_name = "ADDR", value_delimiter = ',')]
+    bind: Vec<BindAddr>,
+    /// Sampling interval in milliseconds [default: 200]
+    #[arg(long, env = "SAMPLE_INTERVAL_MS", value_name = "MS")]
+    interval_ms: Option<u64>,
+    /// Minimum level of log messages to print [default: info]
+    #[arg(long, env = "LOG_LEVEL", value_enum, value_name = "LEVEL")]
+    log_level: Option<LogLevel>,
+    /// Write log events as plain text or one JSON object per line [default: text]
+    #[arg(long, env = "LOG_FORMAT", value_enum, value_name = "FORMAT")]
+    log_format: Option<LogFormat>,
+    /// Decimal (KB, MB, GB) or binary (KiB, MiB, GiB) units for human-readable
+    /// sizes [default: si]
+    #[arg(long, env = "UNITS", value_enum, value_name = "UNITS")]
+    units: Option<Units>,
+    /// Decimal places of human-readable sizes, at most 6 [default: 2]
+    #[arg(long, env = "PRECISION", value_name = "DIGITS")]
+    precision: Option<u8>,
+    /// Label describing this host, as key=value, e.g. role=db; repeatable.
+    /// Overrides the config file's label of the same key
+    #[arg(
+        long = "label",
+        env = "LABELS",
+        value_name = "KEY=VALUE",
+        value_delimiter = ',',
+        value_parser = parse_label
+    )]
+    labels: Vec<(String, String)>,
+    /// Allow sending signals to processes through the API
+    #[arg(long)]
+    allow_control: bool,
+    /// Refuse signals, priority changes, alert rule changes, snapshots and
+    /// agent reports, e.g. for a public demo
+    #[arg(long)]
+    read_only: bool,
+    /// Pause sampling once no client is subscribed and no request arrived
+    /// for this many seconds, until the next one
+    #[arg(long, env = "IDLE_AFTER_SECS", value_name = "SECONDS")]
+    idle_after_secs: Option<u64>,
+    /// Seconds of CPU and memory history kept in memory [default: 600]
+    #[arg(long, env = "HISTORY_SECONDS", value_name = "SECONDS")]
+    history_seconds: Option<u64>,
+    /// SQLite database to persist history in
+    #[arg(long, env = "HISTORY_DB", value_name = "PATH")]
+    history_db: Option<PathBuf>,
+    /// Days of history kept in the database [default: 7]
+    #[arg(long, env = "HISTORY_DB_RETENTION_DAYS", value_name = "DAYS")]
+    history_db_retention_days: Option<u64>,
+    /// Days per-minute means are kept in the database, 0 to not keep any [default: 90]
+    #[arg(long, env = "HISTORY_DB_MINUTE_RETENTION_DAYS", value_name = "DAYS")]
+    history_db_minute_retention_days: Option<u64>,
+    /// Days per-hour means are kept in the database, 0 to not keep any [default: 730]
+    #[arg(long, env = "HISTORY_DB_HOUR_RETENTION_DAYS", value_name = "DAYS")]
+    history_db_hour_retention_days: Option<u64>,
+    /// JSON file with alert rules
+    #[arg(long, env = "ALERT_RULES", value_name = "PATH")]
+    alert_rules: Option<PathBuf>,
+    /// URL to POST alert events to, repeatable
+    #[arg(long, env = "WEBHOOK_URLS", value_name = "URL", value_delimiter = ',')]
+    webhook_url: Vec<String>,
+    /// PEM certificate to serve HTTPS with
+    #[arg(long, env = "TLS_CERT", value_name = "PATH")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key for --tls-cert
+    #[arg(long, env = "TLS_KEY", value_name = "PATH")]
+    tls_key: Option<PathBuf>,
+    /// File containing the API key clients must send, which grants the admin
+    /// role; see also API_KEY
+    #[arg(long, env = "API_KEY_FILE", value_name = "PATH")]
+    api_key_file: Option<PathBuf>,
+    /// File containing an API key of the operator role, who may also act on
+    /// processes; see also OPERATOR_API_KEY
+    #[arg(long, env = "OPERATOR_API_KEY_FILE", value_name = "PATH")]
+    operator_api_key_file: Option<PathBuf>,
+    /// File containing an API key of the viewer role, who may only read; see
+    /// also VIEWER_API_KEY
+    #[arg(long, env = "VIEWER_API_KEY_FILE", value_name = "PATH")]
+    viewer_api_key_file: Option<PathBuf>,
+    /// File containing the secret of the HS256 JWTs to accept besides the
+    /// API keys; see also JWT_SECRET
+    #[arg(long, env = "JWT_SECRET_FILE", value_name = "PATH")]
+    jwt_secret_file: Option<PathBuf>,
+    /// JWT claim holding the role, or a list of roles [default: role]
+    #[arg(long, env = "JWT_ROLE_CLAIM", value_name = "CLAIM")]
+    jwt_role_claim: Option<String>,
+    /// Issuer JWTs must name in `iss`. Without --jwt-secret-file or
+    /// --jwt-jwks-url, an OpenID Connect issuer whose signing keys are
+    /// discovered from its configuration
+    #[arg(long, env = "JWT_ISSUER", value_name = "ISSUER")]
+    jwt_issuer: Option<String>,
+    /// Audience JWTs must include in `aud`
+    #[arg(long, env = "JWT_AUDIENCE", value_name = "AUDIENCE")]
+    jwt_audience: Option<String>,
+    /// JWK set to verify RS, PS, ES and EdDSA signed JWTs with, e.g.
+    /// https://sso.example.com/realms/lab/protocol/openid-connect/certs
+    #[arg(long, env = "JWT_JWKS_URL", value_name = "URL")]
+    jwt_jwks_url: Option<String>,
+    /// File to append the audit log of signals, priority changes and alert
+    /// rule changes to [default: the history database, else memory]
+    #[arg(long, env = "AUDIT_LOG", value_name = "PATH")]
+    audit_log: Option<PathBuf>,
+    /// OpenTelemetry collector to push traces and metrics to over OTLP/HTTP,
+    /// e.g. http://localhost:4318
+    #[arg(long, env = "OTLP_ENDPOINT", value_name = "URL")]
+    otlp_endpoint: Option<String>,
+    /// Only serve clients in this address range, e.g. 10.0.0.0/8; repeatable
+    #[arg(long, env = "ALLOW_IPS", value_name = "CIDR", value_delimiter = ',')]
+    allow_ip: Vec<Cidr>,
+    /// Refuse clients in this address range; repeatable
+    #[arg(long, env = "DENY_IPS", value_name = "CIDR", value_delimiter = ',')]
+    deny_ip: Vec<Cidr>,
+    /// Reverse proxy whose X-Forwarded-For header names the client, e.g.
+    /// 127.0.0.1; repeatable
+    #[arg(long, env = "TRUST_PROXY", value_name = "CIDR", value_delimiter = ',')]
+    trust_proxy: Vec<Cidr>,
+    /// Requests per second allowed per client IP
+    #[arg(long, env = "RATE_LIMIT", value_name = "RPS")]
+    rate_limit: Option<u32>,
+    /// Requests a client may burst above --rate-limit [default: the rate]
+    #[arg(long, env = "RATE_LIMIT_BURST", value_name = "REQUESTS")]
+    rate_limit_burst: Option<u32>,
+    /// Concurrent realtime WebSockets allowed per client IP
+    #[arg(long, env = "MAX_WEBSOCKETS_PER_IP", value_name = "COUNT")]
+    max_websockets_per_ip: Option<usize>,
+    /// Concurrent realtime WebSockets allowed in total
+    #[arg(long, env = "MAX_WEBSOCKETS", value_name = "COUNT")]
+    max_websockets: Option<usize>,
+    /// Collector to switch off, with its topics and routes; repeatable
+    #[arg(
+        long,
+        env = "DISABLE_COLLECTORS",
+        value_enum,
+        value_name = "COLLECTOR",
+        value_delimiter = ','
+    )]
+    disable_collector: Vec<Collector>,
+    /// Docker Engine socket to collect container metrics from, e.g.
+    /// /var/run/docker.sock
+    #[arg(long, env = "DOCKER_SOCKET", value_name = "PATH")]
+    docker_socket: Option<PathBuf>,
+    /// InfluxDB v2 server to write samples to, e.g. http://localhost:8086;
+    /// the token is read from INFLUXDB_TOKEN
+    #[arg(long, env = "INFLUXDB_URL", value_name = "URL")]
+    influxdb_url: Option<String>,
+    /// Organization of --influxdb-bucket
+    #[arg(long, env = "INFLUXDB_ORG", value_name = "ORG")]
+    influxdb_org: Option<String>,
+    /// Bucket to write samples to
+    #[arg(long, env = "INFLUXDB_BUCKET", value_name = "BUCKET")]
+    influxdb_bucket: Option<String>,
+    /// File containing the InfluxDB API token
+    #[arg(long, env = "INFLUXDB_TOKEN_FILE", value_name = "PATH")]
+    influxdb_token_file: Option<PathBuf>,
+    /// Milliseconds between writes to InfluxDB [default: 10000]
+    #[arg(long, env = "INFLUXDB_INTERVAL_MS", value_name = "MS")]
+    influxdb_interval_ms: Option<u64>,
+    /// MQTT broker to publish samples to, plain TCP only; the password is
+    /// read from MQTT_PASSWORD
+    #[arg(long, env = "MQTT_HOST", value_name = "HOST")]
+    mqtt_host: Option<String>,
+    /// Port of --mqtt-host [default: 1883]
+    #[arg(long, env = "MQTT_PORT", value_name = "PORT")]
+    mqtt_port: Option<u16>,
+    /// User name to connect to the MQTT broker with
+    #[arg(long, env = "MQTT_USERNAME", value_name = "NAME")]
+    mqtt_username: Option<String>,
+    /// Topic samples are published under [default: process-viewer/<hostname>]
+    #[arg(long, env = "MQTT_TOPIC_PREFIX", value_name = "TOPIC")]
+    mqtt_topic_prefix: Option<String>,
+    /// Home Assistant discovery prefix, empty to skip discovery [default:
+    /// homeassistant]
+    #[arg(long, env = "MQTT_DISCOVERY_PREFIX", value_name = "TOPIC")]
+    mqtt_discovery_prefix: Option<String>,
+    /// Milliseconds between publishes to MQTT [default: 10000]
+    #[arg(long, env = "MQTT_INTERVAL_MS", value_name = "MS")]
+    mqtt_interval_ms: Option<u64>,
+    /// StatsD or Graphite UDP listener to push gauges to, e.g.
+    /// localhost:8125
+    #[arg(long, env = "STATSD_ADDRESS", value_name = "HOST:PORT")]
+    statsd_address: Option<String>,
+    /// Line format to push in [default: statsd]
+    #[arg(long, env = "STATSD_FORMAT", value_enum)]
+    statsd_format: Option<StatsdFormat>,
+    /// Prefix of every gauge name [default: process_viewer.<hostname>]
+    #[arg(long, env = "STATSD_PREFIX", value_name = "PREFIX")]
+    statsd_prefix: Option<String>,
+    /// Milliseconds between pushes [default: 10000]
+    #[arg(long, env = "STATSD_INTERVAL_MS", value_name = "MS")]
+    statsd_interval_ms: Option<u64>,
+    /// Address to serve the gRPC API on, e.g. 0.0.0.0:50051; needs a build
+    /// with --features grpc
+    #[arg(long, env = "GRPC_BIND", value_name = "ADDR")]
+    grpc_bind: Option<SocketAddr>,
+    /// File to record every sample to, for --replay
+    #[arg(long, env = "RECORD", value_name = "PATH")]
+    record: Option<PathBuf>,
+    /// Recording to serve instead of sampling this machine
+    #[arg(long, env = "REPLAY", value_name = "PATH")]
+    replay: Option<PathBuf>,
+    /// Speed up (or slow down) --replay by this factor [default: 1]
+    #[arg(long, env = "REPLAY_SPEED", value_name = "FACTOR")]
+    replay_speed: Option<f64>,
+    /// Push samples to the hub at --hub-url, which then serves this host
+    /// under /api/v1/hosts
+    #[arg(long)]
+    agent: bool,
+    /// Hub to report to with --agent, e.g. http://hub:7070; its API key is
+    /// read from HUB_API_KEY
+    #[arg(long, env = "HUB_URL", value_name = "URL")]
+    hub_url: Option<String>,
+    /// Id to report as [default: the hostname]
+    #[arg(long, env = "HOST_ID", value_name = "ID")]
+    host_id: Option<String>,
+    /// File containing the hub's API key
+    #[arg(long, env = "HUB_API_KEY_FILE", value_name = "PATH")]
+    hub_api_key_file: Option<PathBuf>,
+    /// Milliseconds between reports to the hub [default: 1000]
+    #[arg(long, env = "AGENT_INTERVAL_MS", value_name = "MS")]
+    agent_interval_ms: Option<u64>,
+    /// Process-viewer instance to poll and serve under /api/v1/hosts/<name>,
+    /// as name=url; repeatable. Their API key is read from REMOTE_API_KEY
+    #[arg(long, env = "REMOTES", value_name = "NAME=URL", value_delimiter = ',')]
+    remote: Vec<RawRemote>,
+    /// Milliseconds between polls of every --remote [default: 1000]
+    #[arg(long, env = "REMOTE_INTERVAL_MS", value_name = "MS")]
+    remote_interval_ms: Option<u64>,
+    /// Announce this server on the LAN over mDNS and list the other
+    /// instances found at /api/v1/discovered
+    #[arg(long)]
+    mdns: bool,
+    /// Instance name to announce with --mdns [default: the hostname]
+    #[arg(long, env = "MDNS_NAME", value_name = "NAME")]
+    mdns_name: Option<String>,
+}
+
+impl Config {
+    pub fn load() -> Result<Self, String> {
+        let cli = Cli::parse();
+        let mut raw = match &cli.config {
+            Some(path) => RawConfig::read(path)?,
+            None => RawConfig::default(),
+        };
+        raw.apply_env(|name| env::var(name).ok());
+        raw.merge(cli);
+        raw.resolve()
+    }
+
+    /// As if started without a config file or arguments.
+    #[cfg(test)]
+    pub fn defaults() -> Self {
+        let mut raw = RawConfig::default();
+        raw.merge(Cli::parse_from(["server"]));
+        raw.resolve().unwrap()
+    }
+
+    /// Whether clients must authenticate, see `auth.rs`.
+    pub fn requires_auth(&self) -> bool {
+        !self.api_keys.is_empty() || self.jwt.is_some()
+    }
+}
+
+impl RawConfig {
+    /// Overrides the file with the secrets given in the environment, which
+    /// `var` looks up.
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) {
+        // The key itself is only accepted from the environment so it doesn't
+        // show up in the process list.
+        if let Some(api_key) = var("API_KEY") {
+            self.auth.api_key = Some(api_key);
+            self.auth.api_key_file = None;
+        }
+        if let Some(api_key) = var("OPERATOR_API_KEY") {
+            self.auth.operator_api_key = Some(api_key);
+            self.auth.operator_api_key_file = None;
+        }
+        if let Some(api_key) = var("VIEWER_API_KEY") {
+            self.auth.viewer_api_key = Some(api_key);
+            self.auth.viewer_api_key_file = None;
+        }
+        if let Some(secret) = var("JWT_SECRET") {
+            self.auth.jwt_secret = Some(secret);
+            self.auth.jwt_secret_file = None;
+        }
+        if let Some(token) = var("INFLUXDB_TOKEN") {
+            self.influxdb.token = Some(token);
+            self.influxdb.token_file = None;
+        }
+        if let Some(password) = var("MQTT_PASSWORD") {
+            self.mqtt.password = Some(password);
+            self.mqtt.password_file = None;
+        }
+        if let Some(api_key) = var("REMOTE_API_KEY") {
+            self.federation.api_key = Some(api_key);
+        }
+        if let Some(api_key) = var("HUB_API_KEY") {
+            self.agent.api_key = Some(api_key);
+            self.agent.api_key_file = None;
+        }
+    }
+
+    fn read(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read config file {}: {err}", path.display()))?;
+        toml::from_str(&content)
+            .map_err(|err| format!("Invalid config file {}: {err}", path.display()))
+    }
+
+    fn merge(&mut self, cli: Cli) {
+        fn replace<T>(slot: &mut Option<T>, value: Option<T>) {
+            if value.is_some() {
+                *slot = value;
+            }
+        }
+        replace(&mut self.port, cli.port);
+        if !cli.bind.is_empty() {
+            self.bind = Some(Binds::Many(cli.bind));
+        }
+        replace(&mut self.interval_ms, cli.interval_ms);
+        replace(&mut self.log_level, cli.log_level);
+        replace(&mut self.log_format, cli.log_format);
+        replace(&mut self.units, cli.units);
+        replace(&mut self.precision, cli.precision);
+        self.labels.extend(cli.labels);
+        if cli.allow_control {
+            self.allow_control = Some(true);
+        }
+        if cli.read_only {
+            self.read_only = Some(true);
+        }
+        replace(&mut self.idle_after_secs, cli.idle_after_secs);
+        replace(&mut self.history.seconds, cli.history_seconds);
+        replace(&mut self.history.db, cli.history_db);
+        replace(
+            &mut self.history.db_retention_days,
+            cli.history_db_retention_days,
+        );
+        replace(
+            &mut self.history.db_minute_retention_days,
+            cli.history_db_minute_retention_days,
+        );
+        replace(
+            &mut self.history.db_hour_retention_days,
+            cli.history_db_hour_retention_days,
+        );
+        replace(&mut self.alerts.rules_file, cli.alert_rules);
+        let webhook_urls: Vec<String> = cli
+            .webhook_url
+            .into_iter()
+            .map(|url| url.trim().to_owned())
+            .filter(|url| !url.is_empty())
+            .collect();
+        if !webhook_urls.is_empty() {
+            self.alerts.webhook_urls = webhook_urls;
+        }
+        replace(&mut self.tls.cert, cli.tls_cert);
+        replace(&mut self.tls.key, cli.tls_key);
+        if cli.api_key_file.is_some() {
+            self.auth.api_key_file = cli.api_key_file;
+            self.auth.api_key = None;
+        }
+        if cli.operator_api_key_file.is_some() {
+            self.auth.operator_api_key_file = cli.operator_api_key_file;
+            self.auth.operator_api_key = None;
+        }
+        if cli.viewer_api_key_file.is_some() {
+            self.auth.viewer_api_key_file = cli.viewer_api_key_file;
+            self.auth.viewer_api_key = None;
+        }
+        if cli.jwt_secret_file.is_some() {
+            self.auth.jwt_secret_file = cli.jwt_secret_file;
+            self.auth.jwt_secret = None;
+        }
+        replace(&mut self.auth.jwt_role_claim, cli.jwt_role_claim);
+        replace(&mut self.auth.jwt_issuer, cli.jwt_issuer);
+        replace(&mut self.auth.jwt_audience, cli.jwt_audience);
+        replace(&mut self.auth.jwt_jwks_url, cli.jwt_jwks_url);
+        replace(&mut self.audit.file, cli.audit_log);
+        replace(&mut self.otlp.endpoint, cli.otlp_endpoint);
+        if !cli.allow_ip.is_empty() {
+            self.access.allow = cli.allow_ip;
+        }
+        if !cli.deny_ip.is_empty() {
+            self.access.deny = cli.deny_ip;
+        }
+        if !cli.trust_proxy.is_empty() {
+            self.access.trusted_proxies = cli.trust_proxy;
+        }
+        replace(&mut self.limits.requests_per_second, cli.rate_limit);
+        replace(&mut self.limits.burst, cli.rate_limit_burst);
+        replace(
+            &mut self.limits.websockets_per_ip,
+            cli.max_websockets_per_ip,
+        );
+        replace(&mut self.limits.websockets, cli.max_websockets);
+        for collector in cli.disable_collector {
+            self.collectors.disable(collector);
+        }
+        replace(&mut self.docker.socket, cli.docker_socket);
+        replace(&mut self.influxdb.url, cli.influxdb_url);
+        replace(&mut self.influxdb.org, cli.influxdb_org);
+        replace(&mut self.influxdb.bucket, cli.influxdb_bucket);
+        if cli.influxdb_token_file.is_some() {
+            self.influxdb.token_file = cli.influxdb_token_file;
+            self.influxdb.token = None;
+        }
+        replace(&mut self.influxdb.interval_ms, cli.influxdb_interval_ms);
+        replace(&mut self.mqtt.host, cli.mqtt_host);
+        replace(&mut self.mqtt.port, cli.mqtt_port);
+        replace(&mut self.mqtt.username, cli.mqtt_username);
+        replace(&mut self.mqtt.topic_prefix, cli.mqtt_topic_prefix);
+        replace(&mut self.mqtt.discovery_prefix, cli.mqtt_discovery_prefix);
+        replace(&mut self.mqtt.interval_ms, cli.mqtt_interval_ms);
+        replace(&mut self.statsd.address, cli.statsd_address);
+        replace(&mut self.statsd.format, cli.statsd_format);
+        replace(&mut self.statsd.prefix, cli.statsd_prefix);
+        replace(&mut self.statsd.interval_ms, cli.statsd_interval_ms);
+        replace(&mut self.grpc.bind, cli.grpc_bind);
+        replace(&mut self.recording.record, cli.record);
+        replace(&mut self.recording.replay, cli.replay);
+        replace(&mut self.recording.replay_speed, cli.replay_speed);
+        if cli.agent {
+            self.agent.enabled = Some(true);
+        }
+        replace(&mut self.agent.hub_url, cli.hub_url);
+        replace(&mut self.agent.host_id, cli.host_id);
+        if cli.hub_api_key_file.is_some() {
+            self.agent.api_key_file = cli.hub_api_key_file;
+            self.agent.api_key = None;
+        }
+        replace(&mut self.agent.interval_ms, cli.agent_interval_ms);
+        if !cli.remote.is_empty() {
+            self.federation.remotes = cli.remote;
+        }
+        replace(&mut self.federation.interval_ms, cli.remote_interval_ms);
+        if cli.mdns {
+            self.mdns.enabled = Some(true);
+        }
+        replace(&mut self.mdns.name, cli.mdns_name);
+    }
+
+    fn resolve(self) -> Result<Config, String> {
+        let sample_interval = match self.interval_ms {
+            Some(millis) => validate_interval(Duration::from_millis(millis))?,
+            None => System::MINIMUM_CPU_UPDATE_INTERVAL,
+        };
+        let tls = match (self.tls.cert, self.tls.key) {
+            (Some(cert), Some(key)) => Some(TlsConfig { cert, key }),
+            (None, None) => None,
+            _ => return Err("A TLS certificate and key must be given together".to_owned()),
+        };
+        let (api_keys, jwt) = self.auth.resolve()?;
+        for (key, value) in &self.labels {
+            validate_label(key, value)?;
+        }
+        if self.limits.requests_per_second == Some(0) {
+            return Err("The rate limit must be at least 1 request per second".to_owned());
+        }
+        let mut alert_rules = self.alerts.rules;
+        if let Some(path) = self.alerts.rules_file {
+            let content = fs::read_to_string(&path)
+                .map_err(|err| format!("Failed to read alert rules {}: {err}", path.display()))?;
+            let rules: Vec<AlertRule> = serde_json::from_str(&content)
+                .map_err(|err| format!("Invalid alert rules {}: {err}", path.display()))?;
+            alert_rules.extend(rules);
+        }
+        let history_db_retention_days = self
+            .history
+            .db_retention_days
+            .unwrap_or(DEFAULT_HISTORY_DB_RETENTION_DAYS);
+        let history_db_rollups = [
+            (
+                60,
+                self.history.db_minute_retention_days,
+                DEFAULT_HISTORY_DB_MINUTE_RETENTION_DAYS,
+            ),
+            (
+                60 * 60,
+                self.history.db_hour_retention_days,
+                DEFAULT_HISTORY_DB_HOUR_RETENTION_DAYS,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(resolution, days, default)| {
+            let days = days.unwrap_or(default);
+            (days > 0).then(|| Rollup {
+                resolution: Duration::from_secs(resolution),
+                retention: Duration::from_secs(days * 24 * 60 * 60),
+            })
+        })
+        .collect();
+        if self.recording.record.is_some() && self.recording.replay.is_some() {
+            return Err("A recording can't be made while replaying one".to_owned());
+        }
+        let replay_speed = self.recording.replay_speed.unwrap_or(DEFAULT_REPLAY_SPEED);
+        if !(replay_speed.is_finite() && replay_speed > 0f64) {
+            return Err("The replay speed must be positive".to_owned());
+        }
+        let precision = self.precision.unwrap_or(format::DEFAULT_PRECISION);
+        if precision > format::MAX_PRECISION {
+            return Err(format!(
+                "The precision must be at most {}",
+                format::MAX_PRECISION
+            ));
+        }
+        let idle_after = self.idle_after_secs.map(Duration::from_secs);
+        if idle_after.is_some_and(|idle_after| idle_after.is_zero()) {
+            return Err("The idle time must be positive".to_owned());
+        }
+        let influxdb = self.influxdb.resolve()?;
+        let mqtt = self.mqtt.resolve()?;
+        let statsd = self.statsd.resolve()?;
+        let agent = self.agent.resolve()?;
+        // These want every sample, whether anybody is looking or not.
+        let always_sampled = influxdb.is_some()
+            || mqtt.is_some()
+            || statsd.is_some()
+            || agent.is_some()
+            || self.recording.record.is_some()
+            || self.history.db.is_some();
+        if idle_after.is_some() && always_sampled {
+            return Err(
+                "Sampling can't idle while samples are exported, recorded or stored in a history database"
+                    .to_owned(),
+            );
+        }
+        let remote_interval = self
+            .federation
+            .interval_ms
+            .map_or(DEFAULT_REMOTE_INTERVAL, Duration::from_millis);
+        if remote_interval.is_zero() {
+            return Err("The remote interval must be positive".to_owned());
+        }
+        let remotes = self.federation.resolve()?;
+        let mdns = self.mdns.resolve()?;
+        let plugins = resolve_plugins(self.plugins, sample_interval)?;
+        if !self.watch.is_empty() && !self.collectors.processes {
+            return Err("Watching processes requires the process collector".to_owned());
+        }
+        let watches = resolve_watches(self.watch)?;
+        let port = self.port.unwrap_or(DEFAULT_PORT);
+        let binds = match self.bind {
+            Some(Binds::One(bind)) => vec![bind],
+            Some(Binds::Many(binds)) if !binds.is_empty() => binds,
+            _ => vec![BindAddr::Ip(DEFAULT_BIND)],
+        };
+        let mut bind: Vec<SocketAddr> = vec![];
+        for addr in binds.into_iter().map(|bind| bind.with_default_port(port)) {
+            if bind.contains(&addr) {
+                return Err(format!("Bind address {addr} is given twice"));
+            }
+            bind.push(addr);
+        }
+        Ok(Config {
+            bind,
+            log_level: self.log_level.unwrap_or_default(),
+            log_format: self.log_format.unwrap_or_default(),
+            labels: self.labels,
+            units: self.units.unwrap_or_default(),
+            precision,
+            sample_interval,
+            allow_control: self.allow_control.unwrap_or(false),
+            read_only: self.read_only.unwrap_or(false),
+            history_retention: self
+                .history
+                .seconds
+                .map_or(DEFAULT_HISTORY_RETENTION, Duration::from_secs),
+            idle_after,
+            history_db: self.history.db,
+            history_db_retention: Duration::from_secs(history_db_retention_days * 24 * 60 * 60),
+            history_db_rollups,
+            alert_rules,
+            webhook_urls: self.alerts.webhook_urls,
+            tls,
+            api_keys,
+            jwt,
+            audit_log: self.audit.file,
+            otlp_endpoint: self.otlp.endpoint,
+            access: self.access,
+            limits: self.limits,
+            collectors: self.collectors,
+            docker_socket: self.docker.socket,
+            influxdb,
+            mqtt,
+            statsd,
+            grpc_bind: self.grpc.bind,
+            record: self.recording.record,
+            replay: self.recording.replay,
+            replay_speed,
+            agent,
+            remotes,
+            remote_interval,
+            mdns,
+            plugins,
+            watches,
+        })
+    }
+}
+
+impl RawInflux {
+    /// `None` without a URL; with one, the org, bucket and token are
+    /// required.
+    fn resolve(self) -> Result<Option<InfluxConfig>, String> {
+        let Some(url) = self.url else {
+            return Ok(None);
+        };
+        let token = match self.token_file {
+            Some(path) => fs::read_to_string(&path)
+                .map_err(|err| {
+                    format!(
+                        "Failed to read InfluxDB token file {}: {err}",
+                        path.display()
+                    )
+                })?
+                .trim()
+                .to_owned(),
+            None => self.token.unwrap_or_default(),
+        };
+        let (Some(org), Some(bucket), false) = (self.org, self.bucket, token.is_empty()) else {
+            return Err("InfluxDB needs an org, a bucket and a token next to its URL".to_owned());
+        };
+        let interval = self
+            .interval_ms
+            .map_or(DEFAULT_INFLUXDB_INTERVAL, Duration::from_millis);
+        if interval.is_zero() {
+            return Err("The InfluxDB interval must be positive".to_owned());
+        }
+        Ok(Some(InfluxConfig {
+            url,
+            org,
+            bucket,
+            token,
+            interval,
+        }))
+    }
+}
+
+impl RawMqtt {
+    /// `None` without a host.
+    fn resolve(self) -> Result<Option<MqttConfig>, String> {
+        let Some(host) = self.host else {
+            return Ok(None);
+        };
+        let password = match self.password_file {
+            Some(path) => Some(
+                fs::read_to_string(&path)
+                    .map_err(|err| {
+                        format!(
+                            "Failed to read MQTT password file {}: {err}",
+                            path.display()
+                        )
+                    })?
+                    .trim()
+                    .to_owned(),
+            ),
+            None => self.password,
+        };
+        if password.is_some() && self.username.is_none() {
+            return Err("An MQTT password needs a username".to_owned());
+        }
+        let interval = self
+            .interval_ms
+            .map_or(DEFAULT_MQTT_INTERVAL, Duration::from_millis);
+        if interval.is_zero() {
+            return Err("The MQTT interval must be positive".to_owned());
+        }
+        let discovery_prefix = match self.discovery_prefix {
+            Some(prefix) if prefix.is_empty() => None,
+            Some(prefix) => Some(prefix),
+            None => Some(DEFAULT_MQTT_DISCOVERY_PREFIX.to_owned()),
+        };
+        Ok(Some(MqttConfig {
+            host,
+            port: self.port.unwrap_or(DEFAULT_MQTT_PORT),
+            client_id: self.client_id,
+            username: self.username,
+            password,
+            topic_prefix: self.topic_prefix,
+            discovery_prefix,
+            interval,
+        }))
+    }
+}
+
+impl RawAgent {
+    /// `None` unless enabled, which then needs a hub URL.
+    fn resolve(self) -> Result<Option<AgentConfig>, String> {
+        if !self.enabled.unwrap_or(false) {
+            return Ok(None);
+        }
+        let Some(hub_url) = self.hub_url else {
+            return Err("An agent needs a hub URL to report to".to_owned());
+        };
+        let hub_url = reqwest::Url::parse(&hub_url)
+            .ok()
+            .filter(|url| matches!(url.scheme(), "http" | "https"))
+            .ok_or_else(|| format!("Invalid hub URL {hub_url:?}"))?;
+        if self.host_id.as_deref() == Some("") {
+            return Err("The host id must not be empty".to_owned());
+        }
+        let api_key = match self.api_key_file {
+            Some(path) => Some(
+                fs::read_to_string(&path)
+                    .map_err(|err| {
+                        format!("Failed to read hub API key file {}: {err}", path.display())
+                    })?
+                    .trim()
+                    .to_owned(),
+            ),
+            None => self.api_key,
+        };
+        let interval = self
+            .interval_ms
+            .map_or(DEFAULT_AGENT_INTERVAL, Duration::from_millis);
+        if interval.is_zero() {
+            return Err("The agent interval must be positive".to_owned());
+        }
+        Ok(Some(AgentConfig {
+            hub_url,
+            host_id: self.host_id,
+            api_key,
+            interval,
+        }))
+    }
+}
+
+impl RawAuth {
+    fn resolve(self) -> Result<(Vec<ApiKey>, Option<JwtConfig>), String> {
+        let read = |path: PathBuf, what: &str| {
+            fs::read_to_string(&path)
+                .map(|content| content.trim().to_owned())
+                .map_err(|err| format!("Failed to read {what} file {}: {err}", path.display()))
+        };
+        let mut api_keys: Vec<ApiKey> = vec![];
+        for (role, key, file) in [
+            (Role::Admin, self.api_key, self.api_key_file),
+            (
+                Role::Operator,
+                self.operator_api_key,
+                self.operator_api_key_file,
+            ),
+            (Role::Viewer, self.viewer_api_key, self.viewer_api_key_file),
+        ] {
+            let key = match file {
+                Some(path) => read(path, "API key")?,
+                None => match key {
+                    Some(key) => key,
+                    None => continue,
+                },
+            };
+            if key.is_empty() {
+                return Err(format!("The {role} API key must not be empty"));
+            }
+            if api_keys.iter().any(|other| other.key == key) {
+                return Err(format!("The {role} API key is given for another role too"));
+            }
+            api_keys.push(ApiKey { key, role });
+        }
+        let secret = match self.jwt_secret_file {
+            Some(path) => Some(read(path, "JWT secret")?),
+            None => self.jwt_secret,
+        };
+        if secret.as_deref() == Some("") {
+            return Err("The JWT secret must not be empty".to_owned());
+        }
+        let url = |url: &str, what: &str| {
+            reqwest::Url::parse(url)
+                .ok()
+                .filter(|url| matches!(url.scheme(), "http" | "https"))
+                .ok_or_else(|| format!("Invalid {what} {url:?}"))
+        };
+        let jwks = match (&self.jwt_jwks_url, &self.jwt_issuer) {
+            (Some(jwks_url), _) => Some(JwksSource::Url(url(jwks_url, "JWKS URL")?)),
+            (None, Some(issuer)) if secret.is_none() => {
+                Some(JwksSource::Issuer(url(issuer, "JWT issuer")?))
+            }
+            _ => None,
+        };
+        if secret.is_none() && jwks.is_none() {
+            return Ok((api_keys, None));
+        }
+        let jwt = JwtConfig {
+            secret,
+            jwks,
+            role_claim: self
+                .jwt_role_claim
+                .unwrap_or_else(|| DEFAULT_JWT_ROLE_CLAIM.to_owned()),
+            issuer: self.jwt_issuer,
+            audience: self.jwt_audience,
+        };
+        Ok((api_keys, Some(jwt)))
+    }
+}
+
+impl RawMdns {
+    /// `None` unless enabled.
+    fn resolve(self) -> Result<Option<MdnsConfig>, String> {
+        if !self.enabled.unwrap_or(false) {
+            return Ok(None);
+        }
+        if self
+            .name
+            .as_deref()
+            .is_some_and(|name| name.trim().is_empty())
+        {
+            return Err("The mDNS instance name must not be empty".to_owned());
+        }
+        Ok(Some(MdnsConfig { name: self.name }))
+    }
+}
+
+/// Plugin names become topic names and metric labels, so they are kept to
+/// lowercase letters, digits, `_` and `-`, and mustn't shadow a built-in
+/// topic.
+fn resolve_plugins(
+    plugins: Vec<RawPlugin>,
+    sample_interval: Duration,
+) -> Result<Vec<PluginConfig>, String> {
+    let mut resolved: Vec<PluginConfig> = vec![];
+    for plugin in plugins {
+        let name = plugin.name;
+        let valid = !name.is_empty()
+            && name.bytes().all(|byte| {
+                byte.is_ascii_lowercase() || byte.is_ascii_digit() || matches!(byte, b'_' | b'-')
+            });
+        if !valid {
+            return Err(format!(
+                "Invalid plugin name {name:?}, expected lowercase letters, digits, _ and -"
+            ));
+        }
+        if realtime::TOPICS.contains(&name.as_str()) {
+            return Err(format!("Plugin name {name} is taken by a built-in topic"));
+        }
+        if resolved.iter().any(|other| other.name == name) {
+            return Err(format!("Plugin name {name} is given twice"));
+        }
+        if plugin
+            .command
+            .first()
+            .is_none_or(|program| program.is_empty())
+        {
+            return Err(format!("The plugin {name} needs a command"));
+        }
+        let interval = plugin
+            .interval_ms
+            .map_or(sample_interval, Duration::from_millis);
+        let timeout = plugin.timeout_ms.map_or(interval, Duration::from_millis);
+        if interval.is_zero() || timeout.is_zero() {
+            return Err(format!(
+                "The interval and timeout of plugin {name} must be positive"
+            ));
+        }
+        resolved.push(PluginConfig {
+            name,
+            command: plugin.command,
+            interval,
+            timeout,
+        });
+    }
+    Ok(resolved)
+}
+
+fn resolve_watches(watches: Vec<RawWatch>) -> Result<Vec<WatchConfig>, String> {
+    let mut resolved: Vec<WatchConfig> = vec![];
+    for watch in watches {
+        let name = watch.name;
+        if name.trim().is_empty() {
+            return Err("Watched process names must not be empty".to_owned());
+        }
+        if resolved.iter().any(|other| other.name == name) {
+            return Err(format!("Watched process {name} is given twice"));
+        }
+        let pattern = watch
+            .pattern
+            .unwrap_or_else(|| format!("^{}$", regex::escape(&name)));
+        let pattern = Regex::new(&pattern)
+            .map_err(|err| format!("Invalid pattern for watched process {name}: {err}"))?;
+        resolved.push(WatchConfig { name, pattern });
+    }
+    Ok(resolved)
+}
+
+impl RawFederation {
+    fn resolve(self) -> Result<Vec<RemoteConfig>, String> {
+        let mut remotes: Vec<RemoteConfig> = vec![];
+        for remote in self.remotes {
+            if remote.name.is_empty() {
+                return Err(format!("The remote {} needs a name", remote.url));
+            }
+            if remotes.iter().any(|other| other.name == remote.name) {
+                return Err(format!("Remote name {} is given twice", remote.name));
+            }
+            let url = reqwest::Url::parse(&remote.url)
+                .ok()
+                .filter(|url| matches!(url.scheme(), "http" | "https"))
+                .ok_or_else(|| format!("Invalid URL {:?} of remote {}", remote.url, remote.name))?;
+            let api_key = match remote.api_key_file {
+                Some(path) => Some(
+                    fs::read_to_string(&path)
+                        .map_err(|err| {
+                            format!(
+                                "Failed to read remote API key file {}: {err}",
+                                path.display()
+                            )
+                        })?
+                        .trim()
+                        .to_owned(),
+                ),
+                None => self.api_key.clone(),
+            };
+            remotes.push(RemoteConfig {
+                name: remote.name,
+                url,
+                api_key,
+            });
+        }
+        Ok(remotes)
+    }
+}
+
+impl RawStatsd {
+    /// `None` without an address.
+    fn resolve(self) -> Result<Option<StatsdConfig>, String> {
+        let Some(address) = self.address else {
+            return Ok(None);
+        };
+        if !address
+            .rsplit_once(':')
+            .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+        {
+            return Err(format!(
+                "Invalid StatsD address {address:?}, expected host:port"
+            ));
+        }
+        let interval = self
+            .interval_ms
+            .map_or(DEFAULT_STATSD_INTERVAL, Duration::from_millis);
+        if interval.is_zero() {
+            return Err("The StatsD interval must be positive".to_owned());
+        }
+        Ok(Some(StatsdConfig {
+            address,
+            format: self.format.unwrap_or_default(),
+            prefix: self.prefix,
+            interval,
+        }))
+    }
+}
+
+fn parse_label(value: &str) -> Result<(String, String), String> {
+    let (key, value) = value
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid label {value:?}, expected key=value"))?;
+    Ok((key.trim().to_owned(), value.trim().to_owned()))
+}
+
+/// Keys are limited to what Prometheus accepts as a label name, which every
+/// other exporter can carry as well.
+fn validate_label(key: &str, value: &str) -> Result<(), String> {
+    let mut chars = key.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|char| char.is_ascii_alphanumeric() || char == '_')
+        && !key.starts_with("__");
+    if !valid {
+        return Err(format!(
+            "Invalid label key {key:?}, use letters, digits and underscores"
+        ));
+    }
+    if RESERVED_LABELS.contains(&key) {
+        return Err(format!(
+            "The label {key} is set by the exporters themselves"
+        ));
+    }
+    if value.is_empty() {
+        return Err(format!("The label {key} needs a value"));
+    }
+    Ok(())
+}
+
+fn validate_interval(interval: Duration) -> Result<Duration, String> {
+    if interval < System::MINIMUM_CPU_UPDATE_INTERVAL {
+        return Err(format!(
+            "Sample interval must be at least {} ms",
+            System::MINIMUM_CPU_UPDATE_INTERVAL.as_millis()
+        ));
+    }
+    Ok(interval)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Env<'a> = &'a [(&'a str, &'a str)];
+    type Args<'a> = &'a [&'a str];
+
+    /// Resolves `file` with `env` applied and then `args`, as `Config::load`
+    /// does.
+    fn load(file: &str, env: Env, args: Args) -> Result<Config, String> {
+        let mut raw: RawConfig = toml::from_str(file).unwrap();
+        raw.apply_env(|name| {
+            env.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        });
+        raw.merge(Cli::parse_from(["server"].iter().chain(args)));
+        raw.resolve()
+    }
+
+    fn admin_key(config: &Config) -> Option<&str> {
+        config
+            .api_keys
+            .iter()
+            .find(|api_key| api_key.role == Role::Admin)
+            .map(|api_key| api_key.key.as_str())
+    }
+
+    #[test]
+    fn later_layers_override_earlier_ones() {
+        let key_file = env::temp_dir().join(format!("config-test-{}.key", std::process::id()));
+        fs::write(&key_file, "from-cli\n").unwrap();
+        let key_file = key_file.to_str().unwrap();
+        let file =
+            "port = 8000\n[labels]\nrole = \"db\"\nzone = \"a\"\n[auth]\napi_key = \"from-file\"\n";
+        let cases: &[(Env, Args, u16, &str, &str)] = &[
+            (&[], &[], 8000, "from-file", "a"),
+            (&[("API_KEY", "from-env")], &[], 8000, "from-env", "a"),
+            (
+                &[("API_KEY", "from-env")],
+                &["--port", "9000", "--api-key-file", key_file],
+                9000,
+                "from-cli",
+                "a",
+            ),
+            (&[], &["--label", "zone=b"], 8000, "from-file", "b"),
+        ];
+        for &(env, args, port, key, zone) in cases {
+            let config = load(file, env, args).unwrap();
+            assert_eq!(config.bind[0].port(), port, "{env:?} {args:?}");
+            assert_eq!(admin_key(&config), Some(key), "{env:?} {args:?}");
+            assert_eq!(config.labels["zone"], zone, "{env:?} {args:?}");
+            assert_eq!(config.labels["role"], "db", "{env:?} {args:?}");
+        }
+        fs::remove_file(key_file).unwrap();
+    }
+
+    #[test]
+    fn secrets_from_the_environment_replace_their_files() {
+        let file = "[auth]\napi_key_file = \"/nonexistent\"\n\
+            [influxdb]\nurl = \"http://influx\"\norg = \"o\"\nbucket = \"b\"\n\
+            token_file = \"/nonexistent\"\n";
+        assert!(load(file, &[], &[]).is_err());
+        let config = load(
+            file,
+            &[("API_KEY", "key"), ("INFLUXDB_TOKEN", "token")],
+            &[],
+        )
+        .unwrap();
+        assert_eq!(admin_key(&config), Some("key"));
+        assert_eq!(config.influxdb.unwrap().token, "token");
+    }
+
+    #[test]
+    fn rejects_conflicting_settings() {
+        const INFLUX: &str =
+            "[influxdb]\nurl = \"http://influx\"\norg = \"o\"\nbucket = \"b\"\ntoken = \"t\"\n";
+        const IDLE: &str =
+            "Sampling can't idle while samples are exported, recorded or stored in a history database";
+        let cases: &[(&str, Args, &str)] = &[
+            (
+                "[tls]\ncert = \"cert.pem\"\n",
+                &[],
+                "A TLS certificate and key must be given together",
+            ),
+            (
+                "[tls]\nkey = \"key.pem\"\n",
+                &[],
+                "A TLS certificate and key must be given together",
+            ),
+            (
+                "",
+                &["--tls-key", "key.pem"],
+                "A TLS certificate and key must be given together",
+            ),
+            (INFLUX, &["--idle-after-secs", "60"], IDLE),
+            (
+                "",
+                &["--idle-after-secs", "60", "--mqtt-host", "broker"],
+                IDLE,
+            ),
+            (
+                "",
+                &[
+                    "--idle-after-secs",
+                    "60",
+                    "--statsd-address",
+                    "localhost:8125",
+                ],
+                IDLE,
+            ),
+            (
+                "",
+                &[
+                    "--idle-after-secs",
+                    "60",
+                    "--agent",
+                    "--hub-url",
+                    "http://hub",
+                ],
+                IDLE,
+            ),
+            (
+                "",
+                &["--idle-after-secs", "60", "--record", "samples.rec"],
+                IDLE,
+            ),
+            (
+                "idle_after_secs = 60\n[history]\ndb = \"history.db\"\n",
+                &[],
+                IDLE,
+            ),
+            (
+                "",
+                &["--idle-after-secs", "0"],
+                "The idle time must be positive",
+            ),
+            (
+                "",
+                &["--record", "a.rec", "--replay", "b.rec"],
+                "A recording can't be made while replaying one",
+            ),
+            (
+                "",
+                &["--replay-speed", "0"],
+                "The replay speed must be positive",
+            ),
+            (
+                "",
+                &["--replay-speed", "NaN"],
+                "The replay speed must be positive",
+            ),
+            (
+                "",
+                &["--rate-limit", "0"],
+                "The rate limit must be at least 1 request per second",
+            ),
+            ("", &["--precision", "99"], "The precision must be at most"),
+            (
+                "",
+                &["--interval-ms", "1"],
+                "Sample interval must be at least",
+            ),
+            (
+                "",
+                &["--remote-interval-ms", "0"],
+                "The remote interval must be positive",
+            ),
+            (
+                "[collectors]\nprocesses = false\n[[watch]]\nname = \"nginx\"\n",
+                &[],
+                "Watching processes requires the process collector",
+            ),
+            (
+                "",
+                &["--bind", "127.0.0.1,127.0.0.1"],
+                "Bind address 127.0.0.1:7070 is given twice",
+            ),
+        ];
+        for &(file, args, error) in cases {
+            match load(file, &[], args) {
+                Ok(_) => panic!("{file:?} {args:?} was accepted"),
+                Err(err) => assert!(err.starts_with(error), "{file:?} {args:?}: {err}"),
+            }
+        }
+    }
+
+    #[test]
+    fn idles_without_exporters() {
+        let config = load("", &[], &["--idle-after-secs", "60"]).unwrap();
+        assert_eq!(config.idle_after, Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn rollup_tiers_with_zero_days_are_dropped() {
+        let day = 24 * 60 * 60;
+        let cases: &[(Args, &[(u64, u64)])] = &[
+            (
+                &[],
+                &[
+                    (60, DEFAULT_HISTORY_DB_MINUTE_RETENTION_DAYS * day),
+                    (60 * 60, DEFAULT_HISTORY_DB_HOUR_RETENTION_DAYS * day),
+                ],
+            ),
+            (
+                &["--history-db-minute-retention-days", "0"],
+                &[(60 * 60, DEFAULT_HISTORY_DB_HOUR_RETENTION_DAYS * day)],
+            ),
+            (
+                &[
+                    "--history-db-minute-retention-days",
+                    "30",
+                    "--history-db-hour-retention-days",
+                    "0",
+                ],
+                &[(60, 30 * day)],
+            ),
+            (
+                &[
+                    "--history-db-minute-retention-days",
+                    "0",
+                    "--history-db-hour-retention-days",
+                    "0",
+                ],
+                &[],
+            ),
+        ];
+        for &(args, tiers) in cases {
+            let config = load("", &[], args).unwrap();
+            let rollups: Vec<(u64, u64)> = config
+                .history_db_rollups
+                .iter()
+                .map(|rollup| (rollup.resolution.as_secs(), rollup.retention.as_secs()))
+                .collect();
+            assert_eq!(rollups, tiers, "{args:?}");
+        }
+    }
+}
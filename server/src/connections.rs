@@ -0,0 +1,154 @@
+//! TCP connections of the host and the processes owning them. They are
+//! sampled with everything else, so opened and closed connections can be
+//! reported between samples. Linux only; elsewhere the list stays empty.
+
+use crate::encoding::{Encoded, Encoding};
+use crate::models::{ConnectionEvent, ConnectionEventKind, ConnectionInfo, ProcessInfo};
+use crate::{AppError, AppState};
+use axum::extract::{Query, State};
+use axum::response::IntoResponse;
+use axum_macros::debug_handler;
+use serde::Deserialize;
+use std::collections::HashSet;
+use utoipa::IntoParams;
+
+/// All given conditions must match.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ConnectionFilter {
+    /// Pid of the owning process.
+    pid: Option<u32>,
+    /// Case-insensitive substring of the owning process's name.
+    process: Option<String>,
+    /// Local or remote port.
+    port: Option<u16>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/connections",
+    tag = "system",
+    params(ConnectionFilter),
+    responses(
+        (status = 200, description = "TCP connections of the latest sample", body = [ConnectionInfo], content_type = ["application/json", "application/msgpack"],
+            headers(("x-sample-timestamp" = u64, description = "When the sample was taken, in milliseconds since the Unix epoch"))),
+        (status = 404, description = "The connection collector is disabled", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_connections(
+    State(state): State<AppState>,
+    encoding: Encoding,
+    Query(filter): Query<ConnectionFilter>,
+) -> Result<impl IntoResponse, AppError> {
+    state.tcp_connections.require("connection")?;
+    let process = filter.process.as_deref().map(str::to_lowercase);
+    let latest = state.tcp_connections.latest();
+    let connections: Vec<ConnectionInfo> = latest
+        .value()
+        .iter()
+        .filter(|connection| {
+            filter.pid.is_none_or(|pid| connection.pid == Some(pid))
+                && process.as_ref().is_none_or(|name| {
+                    connection
+                        .process
+                        .as_ref()
+                        .is_some_and(|process| process.to_lowercase().contains(name))
+                })
+                && filter.port.is_none_or(|port| {
+                    connection.local_port == port || connection.remote_port == port
+                })
+        })
+        .cloned()
+        .collect();
+    Ok((latest.timestamp_header(), Encoded(encoding, connections)))
+}
+
+/// Connections that are not listening, ordered by local then remote
+/// address. Owners are named from `processes`, which is ordered by pid.
+#[cfg(target_os = "linux")]
+pub fn collect(processes: &[ProcessInfo]) -> Vec<ConnectionInfo> {
+    use crate::open_files::{socket_owners, socket_table};
+
+    let owners = socket_owners().unwrap_or_default();
+    let mut connections: Vec<ConnectionInfo> = socket_table("/proc/net")
+        .into_iter()
+        .filter(|entry| entry.protocol.starts_with("tcp"))
+        .filter_map(|entry| {
+            let remote = entry.remote?;
+            let pid = owners.get(&entry.inode).copied();
+            let process = pid.and_then(|pid| {
+                let index = processes
+                    .binary_search_by_key(&pid, |process| process.pid)
+                    .ok()?;
+                Some(processes[index].name.clone())
+            });
+            Some(ConnectionInfo {
+                protocol: entry.protocol.to_owned(),
+                local_address: entry.local.ip().to_string(),
+                local_port: entry.local.port(),
+                remote_address: remote.ip().to_string(),
+                remote_port: remote.port(),
+                state: entry.state.to_owned(),
+                pid,
+                process,
+            })
+        })
+        .collect();
+    connections.sort_by(|a, b| {
+        (
+            &a.local_address,
+            a.local_port,
+            &a.remote_address,
+            a.remote_port,
+        )
+            .cmp(&(
+                &b.local_address,
+                b.local_port,
+                &b.remote_address,
+                b.remote_port,
+            ))
+    });
+    connections
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn collect(_: &[ProcessInfo]) -> Vec<ConnectionInfo> {
+    vec![]
+}
+
+/// Connections are told apart by their addresses only, so a state change is
+/// not an event.
+pub fn diff(
+    previous: &[ConnectionInfo],
+    current: &[ConnectionInfo],
+    timestamp: u64,
+) -> Vec<ConnectionEvent> {
+    let key = |connection: &ConnectionInfo| {
+        (
+            connection.protocol.clone(),
+            connection.local_address.clone(),
+            connection.local_port,
+            connection.remote_address.clone(),
+            connection.remote_port,
+        )
+    };
+    let event = |event, connection: &ConnectionInfo| ConnectionEvent {
+        event,
+        connection: connection.clone(),
+        timestamp,
+    };
+    let before: HashSet<_> = previous.iter().map(key).collect();
+    let after: HashSet<_> = current.iter().map(key).collect();
+    previous
+        .iter()
+        .filter(|connection| !after.contains(&key(connection)))
+        .map(|connection| event(ConnectionEventKind::Closed, connection))
+        .chain(
+            current
+                .iter()
+                .filter(|connection| !before.contains(&key(connection)))
+                .map(|connection| event(ConnectionEventKind::Opened, connection)),
+        )
+        .collect()
+}
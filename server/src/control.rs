@@ -0,0 +1,335 @@
+//! Endpoints that act on processes. They are disabled unless the server is
+//! started with `--allow-control`.
+
+use crate::{api_error, AppError, AppState};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use axum_macros::debug_handler;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::ops::RangeInclusive;
+use sysinfo::{Pid, PidExt, System, SystemExt};
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum SignalName {
+    #[serde(alias = "SIGTERM")]
+    Term,
+    #[serde(alias = "SIGKILL")]
+    Kill,
+    #[serde(alias = "SIGSTOP")]
+    Stop,
+    #[serde(alias = "SIGCONT")]
+    Cont,
+}
+
+#[cfg(not(unix))]
+impl From<SignalName> for sysinfo::Signal {
+    fn from(signal: SignalName) -> Self {
+        match signal {
+            SignalName::Term => sysinfo::Signal::Term,
+            SignalName::Kill => sysinfo::Signal::Kill,
+            SignalName::Stop => sysinfo::Signal::Stop,
+            SignalName::Cont => sysinfo::Signal::Continue,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SignalRequest {
+    signal: SignalName,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SignalResponse {
+    pid: u32,
+    signal: SignalName,
+}
+
+/// Valid nice values, from highest to lowest priority.
+const NICE_RANGE: RangeInclusive<i32> = -20..=19;
+
+/// Windows priority classes, each mapped to a nice value on Unix.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PriorityClass {
+    Idle,
+    BelowNormal,
+    Normal,
+    AboveNormal,
+    High,
+    Realtime,
+}
+
+impl PriorityClass {
+    fn nice(self) -> i32 {
+        match self {
+            PriorityClass::Idle => 19,
+            PriorityClass::BelowNormal => 10,
+            PriorityClass::Normal => 0,
+            PriorityClass::AboveNormal => -5,
+            PriorityClass::High => -10,
+            PriorityClass::Realtime => -20,
+        }
+    }
+
+    /// The class closest to a nice value.
+    fn from_nice(nice: i32) -> Self {
+        match nice {
+            15.. => PriorityClass::Idle,
+            5..=14 => PriorityClass::BelowNormal,
+            -4..=4 => PriorityClass::Normal,
+            -9..=-5 => PriorityClass::AboveNormal,
+            -19..=-10 => PriorityClass::High,
+            _ => PriorityClass::Realtime,
+        }
+    }
+}
+
+/// Exactly one of `nice` and `priority_class`; the other one is derived.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct PriorityRequest {
+    /// From -20 (highest priority) to 19 (lowest).
+    nice: Option<i32>,
+    priority_class: Option<PriorityClass>,
+}
+
+/// The priority now in effect, in both notations. Unix applies `nice`,
+/// Windows `priority_class`.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PriorityResponse {
+    pid: u32,
+    nice: i32,
+    priority_class: PriorityClass,
+}
+
+fn ensure_allowed(state: &AppState) -> Result<(), AppError> {
+    if state.config.allow_control {
+        Ok(())
+    } else {
+        Err(AppError::Forbidden(
+            "Process control is disabled, start the server with --allow-control".to_owned(),
+        ))
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/processes/{pid}/signal",
+    tag = "processes",
+    params(("pid" = u32, Path, description = "Process id")),
+    request_body = SignalRequest,
+    responses(
+        (status = 200, description = "The signal was sent", body = SignalResponse),
+        (status = 403, description = "Process control is disabled, not permitted, requires the operator role, or the server is read-only", body = ErrorResponse),
+        (status = 404, description = "No process with this pid, or the process collector is disabled", body = ErrorResponse),
+        (status = 501, description = "The signal is not supported on this platform", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn send_signal(
+    State(state): State<AppState>,
+    Path(pid): Path<u32>,
+    Json(request): Json<SignalRequest>,
+) -> Result<Json<SignalResponse>, AppError> {
+    ensure_allowed(&state)?;
+    state.processes.require("process")?;
+    let sys = state.system.lock().await;
+    if sys.process(Pid::from_u32(pid)).is_none() {
+        return Err(AppError::NotFound(format!("No process with pid {pid}")));
+    }
+    let signal = request.signal;
+    send(&sys, pid, signal).map_err(|err| match err.kind() {
+        io::ErrorKind::PermissionDenied => {
+            AppError::Forbidden(format!("Permission denied sending {signal:?} to pid {pid}"))
+        }
+        // Exited since the last sample.
+        io::ErrorKind::NotFound => AppError::NotFound(format!("No process with pid {pid}")),
+        io::ErrorKind::Unsupported => api_error(
+            StatusCode::NOT_IMPLEMENTED,
+            format!("{signal:?} is not supported on this platform"),
+        ),
+        _ => api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to send {signal:?} to pid {pid}: {err}"),
+        ),
+    })?;
+    Ok(Json(SignalResponse { pid, signal }))
+}
+
+/// Errors for a process that no longer exists are reported as `NotFound`.
+#[cfg(unix)]
+fn send(_: &System, pid: u32, signal: SignalName) -> io::Result<()> {
+    // 0 and negative pids would signal process groups.
+    let pid = libc::pid_t::try_from(pid)
+        .ok()
+        .filter(|pid| *pid > 0)
+        .ok_or(io::ErrorKind::NotFound)?;
+    let signal = match signal {
+        SignalName::Term => libc::SIGTERM,
+        SignalName::Kill => libc::SIGKILL,
+        SignalName::Stop => libc::SIGSTOP,
+        SignalName::Cont => libc::SIGCONT,
+    };
+    // SAFETY: a plain syscall on integers.
+    if unsafe { libc::kill(pid, signal) } == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    Err(match err.raw_os_error() {
+        Some(libc::ESRCH) => io::Error::new(io::ErrorKind::NotFound, err),
+        _ => err,
+    })
+}
+
+/// sysinfo reports neither why sending failed nor a process that exited
+/// since the last sample, so failures count as denied.
+#[cfg(not(unix))]
+fn send(sys: &System, pid: u32, signal: SignalName) -> io::Result<()> {
+    use sysinfo::ProcessExt;
+
+    let process = sys
+        .process(Pid::from_u32(pid))
+        .ok_or(io::ErrorKind::NotFound)?;
+    match process.kill_with(signal.into()) {
+        Some(true) => Ok(()),
+        Some(false) => Err(io::ErrorKind::PermissionDenied.into()),
+        None => Err(io::ErrorKind::Unsupported.into()),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/processes/{pid}/priority",
+    tag = "processes",
+    params(("pid" = u32, Path, description = "Process id")),
+    request_body = PriorityRequest,
+    responses(
+        (status = 200, description = "The priority was changed", body = PriorityResponse),
+        (status = 400, description = "Invalid priority", body = ErrorResponse),
+        (status = 403, description = "Process control is disabled, not permitted, requires the operator role, or the server is read-only", body = ErrorResponse),
+        (status = 404, description = "No process with this pid, or the process collector is disabled", body = ErrorResponse),
+        (status = 501, description = "Changing priorities is not supported on this platform", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn set_priority(
+    State(state): State<AppState>,
+    Path(pid): Path<u32>,
+    Json(request): Json<PriorityRequest>,
+) -> Result<Json<PriorityResponse>, AppError> {
+    ensure_allowed(&state)?;
+    state.processes.require("process")?;
+    let (nice, priority_class) = match (request.nice, request.priority_class) {
+        (Some(nice), None) if NICE_RANGE.contains(&nice) => (nice, PriorityClass::from_nice(nice)),
+        (Some(nice), None) => {
+            return Err(api_error(
+                StatusCode::BAD_REQUEST,
+                format!("nice must be between -20 and 19, got {nice}"),
+            ))
+        }
+        (None, Some(class)) => (class.nice(), class),
+        _ => {
+            return Err(api_error(
+                StatusCode::BAD_REQUEST,
+                "Give either nice or priority_class",
+            ))
+        }
+    };
+    if state
+        .system
+        .lock()
+        .await
+        .process(Pid::from_u32(pid))
+        .is_none()
+    {
+        return Err(AppError::NotFound(format!("No process with pid {pid}")));
+    }
+    // Windows only has the classes, so a nice value is applied as the
+    // closest one. Unix applies a class as its nice value, which maps back
+    // to the same class.
+    #[cfg(windows)]
+    let nice = priority_class.nice();
+    apply_priority(pid, nice, priority_class).map_err(|err| match err.kind() {
+        io::ErrorKind::PermissionDenied => AppError::Forbidden(format!(
+            "Permission denied changing the priority of pid {pid}"
+        )),
+        // Exited since the check above.
+        io::ErrorKind::NotFound => AppError::NotFound(format!("No process with pid {pid}")),
+        io::ErrorKind::Unsupported => api_error(
+            StatusCode::NOT_IMPLEMENTED,
+            "Changing priorities is not supported on this platform",
+        ),
+        _ => api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to change the priority of pid {pid}: {err}"),
+        ),
+    })?;
+    tracing::info!(pid, nice, ?priority_class, "Changed process priority");
+    Ok(Json(PriorityResponse {
+        pid,
+        nice,
+        priority_class,
+    }))
+}
+
+/// Errors for a process that no longer exists are reported as `NotFound`.
+#[cfg(unix)]
+fn apply_priority(pid: u32, nice: i32, _: PriorityClass) -> io::Result<()> {
+    // SAFETY: a plain syscall on integers.
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, pid as libc::id_t, nice) } == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    Err(match err.raw_os_error() {
+        Some(libc::ESRCH) => io::Error::new(io::ErrorKind::NotFound, err),
+        _ => err,
+    })
+}
+
+#[cfg(windows)]
+fn apply_priority(pid: u32, _: i32, class: PriorityClass) -> io::Result<()> {
+    use windows_sys::Win32::Foundation::{CloseHandle, ERROR_INVALID_PARAMETER};
+    use windows_sys::Win32::System::Threading::{
+        OpenProcess, SetPriorityClass, ABOVE_NORMAL_PRIORITY_CLASS, BELOW_NORMAL_PRIORITY_CLASS,
+        HIGH_PRIORITY_CLASS, IDLE_PRIORITY_CLASS, NORMAL_PRIORITY_CLASS, PROCESS_SET_INFORMATION,
+        REALTIME_PRIORITY_CLASS,
+    };
+
+    let flags = match class {
+        PriorityClass::Idle => IDLE_PRIORITY_CLASS,
+        PriorityClass::BelowNormal => BELOW_NORMAL_PRIORITY_CLASS,
+        PriorityClass::Normal => NORMAL_PRIORITY_CLASS,
+        PriorityClass::AboveNormal => ABOVE_NORMAL_PRIORITY_CLASS,
+        PriorityClass::High => HIGH_PRIORITY_CLASS,
+        PriorityClass::Realtime => REALTIME_PRIORITY_CLASS,
+    };
+    // SAFETY: the handle is checked before use and closed right after.
+    unsafe {
+        let handle = OpenProcess(PROCESS_SET_INFORMATION, 0, pid);
+        if handle == 0 {
+            let err = io::Error::last_os_error();
+            return Err(match err.raw_os_error() {
+                Some(code) if code == ERROR_INVALID_PARAMETER as i32 => {
+                    io::Error::new(io::ErrorKind::NotFound, err)
+                }
+                _ => err,
+            });
+        }
+        let set = SetPriorityClass(handle, flags);
+        let err = io::Error::last_os_error();
+        CloseHandle(handle);
+        if set == 0 {
+            Err(err)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn apply_priority(_: u32, _: i32, _: PriorityClass) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
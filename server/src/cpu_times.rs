@@ -0,0 +1,71 @@
+//! Per-core CPU time by state, from `/proc/stat` on Linux. Other platforms
+//! leave `CpuInfo::times` unset.
+
+use crate::models::{CpuInfo, CpuTimes};
+
+/// Jiffies per core spent in user, nice, system, idle, iowait, irq,
+/// softirq and steal, in `/proc/stat` order.
+pub type Counters = [u64; 8];
+
+/// Counters of the previous sample, by core.
+pub type Previous = Vec<Counters>;
+
+/// Sets the share of each state since the previous sample, or since boot on
+/// the first one. `previous` is replaced with the current counters.
+pub fn apply(cpus: &mut [CpuInfo], previous: &mut Previous) {
+    let current = read_counters();
+    if current.len() != cpus.len() {
+        // Cores went on- or offline and no longer line up with sysinfo's.
+        previous.clear();
+        return;
+    }
+    for (index, (cpu, now)) in cpus.iter_mut().zip(&current).enumerate() {
+        let last = previous.get(index).copied().unwrap_or_default();
+        let deltas: Vec<u64> = now
+            .iter()
+            .zip(last)
+            .map(|(now, last)| now.saturating_sub(last))
+            .collect();
+        let total: u64 = deltas.iter().sum();
+        if total == 0 {
+            continue;
+        }
+        let percent = |index: usize| (deltas[index] as f64 / total as f64 * 100f64) as f32;
+        cpu.times = Some(CpuTimes {
+            user: percent(0),
+            nice: percent(1),
+            system: percent(2),
+            idle: percent(3),
+            iowait: percent(4),
+            irq: percent(5),
+            softirq: percent(6),
+            steal: percent(7),
+        });
+    }
+    *previous = current;
+}
+
+#[cfg(target_os = "linux")]
+fn read_counters() -> Previous {
+    let Ok(content) = std::fs::read_to_string("/proc/stat") else {
+        return Previous::new();
+    };
+    content
+        .lines()
+        // `cpu` alone is the total over all cores.
+        .filter(|line| line.starts_with("cpu") && !line.starts_with("cpu "))
+        .map(|line| {
+            let mut counters = Counters::default();
+            // Older kernels have fewer columns; missing ones stay zero.
+            for (counter, field) in counters.iter_mut().zip(line.split_whitespace().skip(1)) {
+                *counter = field.parse().unwrap_or_default();
+            }
+            counters
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_counters() -> Previous {
+    Previous::new()
+}
@@ -0,0 +1,44 @@
+//! The built-in web dashboard. The files under `dashboard/` are compiled into
+//! the binary and served from `/`, so no separate frontend has to be deployed.
+
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use rust_embed::RustEmbed;
+
+#[derive(RustEmbed)]
+#[folder = "dashboard/"]
+struct Assets;
+
+/// Router fallback serving the embedded files; `/` maps to `index.html`.
+pub async fn serve(uri: Uri, headers: HeaderMap) -> Response {
+    let path = match uri.path().trim_start_matches('/') {
+        "" => "index.html",
+        path => path,
+    };
+    let Some(file) = Assets::get(path) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let etag = format!(
+        "\"{}\"",
+        file.metadata
+            .sha256_hash()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>()
+    );
+    let etag = HeaderValue::from_str(&etag).unwrap();
+    let cache_headers = [
+        (header::ETAG, etag.clone()),
+        (header::CACHE_CONTROL, HeaderValue::from_static("no-cache")),
+    ];
+    if headers.get(header::IF_NONE_MATCH) == Some(&etag) {
+        return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+    }
+    let content_type = HeaderValue::from_str(file.metadata.mimetype()).unwrap();
+    (
+        [(header::CONTENT_TYPE, content_type)],
+        cache_headers,
+        file.data,
+    )
+        .into_response()
+}
@@ -0,0 +1,120 @@
+//! Per-device disk throughput, from `/proc/diskstats` on Linux. Other
+//! platforms report no devices.
+
+use crate::encoding::{Encoded, Encoding};
+use crate::models::DiskIoInfo;
+use crate::{AppError, AppState};
+use axum::extract::State;
+use axum::response::IntoResponse;
+use axum_macros::debug_handler;
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/diskio",
+    tag = "disks",
+    responses(
+        (status = 200, description = "Throughput per block device", body = [DiskIoInfo], content_type = ["application/json", "application/msgpack"],
+            headers(("x-sample-timestamp" = u64, description = "When the sample was taken, in milliseconds since the Unix epoch"))),
+        (status = 404, description = "The disk IO collector is disabled", body = ErrorResponse),
+        (status = 503, description = "No sample taken yet", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_diskio(
+    State(state): State<AppState>,
+    encoding: Encoding,
+) -> Result<impl IntoResponse, AppError> {
+    let latest = state.diskio.sampled("disk IO")?;
+    Ok((
+        latest.timestamp_header(),
+        Encoded(encoding, latest.value().clone()),
+    ))
+}
+
+/// Cumulative counters of one device.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Counters {
+    reads: u64,
+    writes: u64,
+    read_bytes: u64,
+    write_bytes: u64,
+    /// Milliseconds the device had requests in flight.
+    busy_ms: u64,
+}
+
+/// Counters of the previous sample by device name.
+pub type Previous = HashMap<String, Counters>;
+
+/// `elapsed` is the time since the previous sample; rates are zero on the
+/// first one and for new devices. `previous` is replaced with the current
+/// counters.
+pub fn collect(previous: &mut Previous, elapsed: Option<Duration>) -> Vec<DiskIoInfo> {
+    let current = read_counters();
+    let per_sec = |delta: u64| match elapsed {
+        Some(elapsed) if !elapsed.is_zero() => delta as f64 / elapsed.as_secs_f64(),
+        _ => 0f64,
+    };
+    let mut devices: Vec<DiskIoInfo> = current
+        .iter()
+        .map(|(device, now)| {
+            let last = previous.get(device).copied().unwrap_or(*now);
+            let busy = per_sec(now.busy_ms.saturating_sub(last.busy_ms)) / 1000f64;
+            DiskIoInfo {
+                device: device.clone(),
+                read_bytes: now.read_bytes,
+                write_bytes: now.write_bytes,
+                read_bytes_per_sec: per_sec(now.read_bytes.saturating_sub(last.read_bytes)),
+                write_bytes_per_sec: per_sec(now.write_bytes.saturating_sub(last.write_bytes)),
+                read_iops: per_sec(now.reads.saturating_sub(last.reads)),
+                write_iops: per_sec(now.writes.saturating_sub(last.writes)),
+                utilization: (busy * 100f64).min(100f64) as f32,
+            }
+        })
+        .collect();
+    devices.sort_by(|a, b| a.device.cmp(&b.device));
+    *previous = current;
+    devices
+}
+
+/// Whole disks only: partitions would count the same IO twice, and loop and
+/// RAM devices aren't interesting.
+#[cfg(target_os = "linux")]
+fn read_counters() -> Previous {
+    use std::path::Path;
+
+    /// `/proc/diskstats` counts in 512 byte sectors whatever the device's.
+    const SECTOR_SIZE: u64 = 512;
+
+    let Ok(content) = std::fs::read_to_string("/proc/diskstats") else {
+        return Previous::new();
+    };
+    content
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let name = *fields.get(2)?;
+            if name.starts_with("loop")
+                || name.starts_with("ram")
+                || !Path::new("/sys/block").join(name).exists()
+            {
+                return None;
+            }
+            let number = |index: usize| fields.get(index)?.parse::<u64>().ok();
+            let counters = Counters {
+                reads: number(3)?,
+                read_bytes: number(5)? * SECTOR_SIZE,
+                writes: number(7)?,
+                write_bytes: number(9)? * SECTOR_SIZE,
+                busy_ms: number(12)?,
+            };
+            Some((name.to_owned(), counters))
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_counters() -> Previous {
+    Previous::new()
+}
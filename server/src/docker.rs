@@ -0,0 +1,318 @@
+//! Optional collector for Docker containers, enabled with `--docker-socket`.
+//! It polls the Engine API over its Unix socket on its own loop, since a
+//! round of stats calls takes far longer than a regular sample.
+
+use crate::collector::Registry;
+use crate::encoding::{Encoded, Encoding};
+use crate::models::{ContainerInfo, HumanReadable};
+use crate::sampler::Topic;
+use crate::{api_error, AppError, AppState};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum_macros::debug_handler;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Containers are never polled more often than this, whatever the sampling
+/// interval.
+const MIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/containers",
+    tag = "containers",
+    responses(
+        (status = 200, description = "Running containers, ordered by name", body = [ContainerInfo], content_type = ["application/json", "application/msgpack"],
+            headers(("x-sample-timestamp" = u64, description = "When the sample was taken, in milliseconds since the Unix epoch"))),
+        (status = 404, description = "The Docker integration is disabled", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_containers(
+    State(state): State<AppState>,
+    encoding: Encoding,
+) -> Result<impl IntoResponse, AppError> {
+    if state.config.docker_socket.is_none() {
+        return Err(api_error(
+            StatusCode::NOT_FOUND,
+            "The Docker integration is disabled, start the server with --docker-socket",
+        ));
+    }
+    let latest = state.containers.latest();
+    Ok((
+        latest.timestamp_header(),
+        Encoded(encoding, latest.value().clone()),
+    ))
+}
+
+#[cfg(unix)]
+pub fn register(
+    socket: PathBuf,
+    sample_interval: Duration,
+    registry: &mut Registry,
+    topic: Topic<Vec<ContainerInfo>>,
+) {
+    registry.register(collector::Docker::new(socket, sample_interval), topic);
+}
+
+#[cfg(not(unix))]
+pub fn register(socket: PathBuf, _: Duration, _: &mut Registry, _: Topic<Vec<ContainerInfo>>) {
+    tracing::warn!(
+        socket = %socket.display(),
+        "The Docker integration needs a Unix socket and is not supported on this platform"
+    );
+}
+
+#[cfg(unix)]
+mod collector {
+    use super::*;
+    use crate::collector::Collector;
+    use axum::async_trait;
+    use hyper::body::Buf;
+    use hyper::{Body, Request};
+    use serde::de::DeserializeOwned;
+    use serde::Deserialize;
+    use std::collections::HashMap;
+    use std::time::Instant;
+    use tokio::net::UnixStream;
+
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct ListedContainer {
+        id: String,
+        names: Vec<String>,
+        image: String,
+        state: String,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct Inspect {
+        restart_count: u64,
+    }
+
+    #[derive(Debug, Default, Deserialize)]
+    #[serde(default)]
+    struct Stats {
+        cpu_stats: CpuStats,
+        memory_stats: MemoryStats,
+        pids_stats: PidsStats,
+        networks: HashMap<String, NetworkStats>,
+    }
+
+    #[derive(Debug, Default, Deserialize)]
+    #[serde(default)]
+    struct CpuStats {
+        cpu_usage: CpuUsage,
+        /// Nanoseconds of host CPU time over all cores.
+        system_cpu_usage: u64,
+        online_cpus: u32,
+    }
+
+    #[derive(Debug, Default, Deserialize)]
+    #[serde(default)]
+    struct CpuUsage {
+        /// Nanoseconds of CPU time used by the container.
+        total_usage: u64,
+    }
+
+    #[derive(Debug, Default, Deserialize)]
+    #[serde(default)]
+    struct MemoryStats {
+        usage: u64,
+        limit: u64,
+        stats: HashMap<String, u64>,
+    }
+
+    #[derive(Debug, Default, Deserialize)]
+    #[serde(default)]
+    struct PidsStats {
+        current: u64,
+    }
+
+    #[derive(Debug, Default, Deserialize)]
+    #[serde(default)]
+    struct NetworkStats {
+        rx_bytes: u64,
+        tx_bytes: u64,
+    }
+
+    /// Counters of the previous poll, turned into rates on the next one.
+    struct Previous {
+        at: Instant,
+        cpu_total: u64,
+        cpu_system: u64,
+        rx_bytes: u64,
+        tx_bytes: u64,
+    }
+
+    pub struct Docker {
+        socket: PathBuf,
+        interval: Duration,
+        previous: HashMap<String, Previous>,
+    }
+
+    impl Docker {
+        pub fn new(socket: PathBuf, sample_interval: Duration) -> Self {
+            Self {
+                socket,
+                interval: sample_interval.max(MIN_POLL_INTERVAL),
+                previous: HashMap::new(),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Collector for Docker {
+        type Output = Vec<ContainerInfo>;
+
+        fn name(&self) -> &'static str {
+            "containers"
+        }
+
+        fn interval(&self) -> Duration {
+            self.interval
+        }
+
+        async fn sample(&mut self, _: &AppState) -> Result<Vec<ContainerInfo>, String> {
+            poll(&self.socket, &mut self.previous).await.map_err(|err| {
+                format!("Failed to query Docker at {}: {err}", self.socket.display())
+            })
+        }
+    }
+
+    async fn poll(
+        socket: &Path,
+        previous: &mut HashMap<String, Previous>,
+    ) -> Result<Vec<ContainerInfo>, String> {
+        let listed: Vec<ListedContainer> = get(socket, "/containers/json").await?;
+        let details = futures_util::future::join_all(listed.iter().map(|container| async move {
+            let stats: Stats = get(
+                socket,
+                &format!(
+                    "/containers/{}/stats?stream=false&one-shot=true",
+                    container.id
+                ),
+            )
+            .await?;
+            let inspect: Inspect =
+                get(socket, &format!("/containers/{}/json", container.id)).await?;
+            Ok::<_, String>((stats, inspect))
+        }))
+        .await;
+        let now = Instant::now();
+        let mut containers = vec![];
+        let mut current = HashMap::new();
+        for (container, details) in listed.into_iter().zip(details) {
+            // Most likely stopped between listing and querying it.
+            let Ok((stats, inspect)) = details else {
+                continue;
+            };
+            let (rx_bytes, tx_bytes) = stats.networks.values().fold((0, 0), |(rx, tx), network| {
+                (rx + network.rx_bytes, tx + network.tx_bytes)
+            });
+            let counters = Previous {
+                at: now,
+                cpu_total: stats.cpu_stats.cpu_usage.total_usage,
+                cpu_system: stats.cpu_stats.system_cpu_usage,
+                rx_bytes,
+                tx_bytes,
+            };
+            let (cpu_usage, rx_bytes_per_sec, tx_bytes_per_sec) = match previous.get(&container.id)
+            {
+                Some(last) => rates(last, &counters, stats.cpu_stats.online_cpus),
+                None => (0f32, 0f64, 0f64),
+            };
+            // Page cache is reclaimable, so it's left out like `docker stats`
+            // does: `inactive_file` on cgroup v2, `total_inactive_file` on v1.
+            let cache = stats
+                .memory_stats
+                .stats
+                .get("inactive_file")
+                .or_else(|| stats.memory_stats.stats.get("total_inactive_file"))
+                .copied()
+                .unwrap_or_default();
+            let memory_bytes = stats.memory_stats.usage.saturating_sub(cache);
+            let name = container
+                .names
+                .first()
+                .map(|name| name.trim_start_matches('/').to_owned())
+                .unwrap_or_default();
+            containers.push(ContainerInfo {
+                id: container.id.chars().take(12).collect(),
+                name,
+                image: container.image,
+                state: container.state,
+                cpu_usage,
+                memory: memory_bytes.to_human(None),
+                memory_bytes,
+                memory_limit_bytes: stats.memory_stats.limit,
+                pids: stats.pids_stats.current,
+                rx_bytes,
+                tx_bytes,
+                rx_bytes_per_sec,
+                tx_bytes_per_sec,
+                restart_count: inspect.restart_count,
+            });
+            current.insert(container.id, counters);
+        }
+        // Removed containers are forgotten.
+        *previous = current;
+        containers.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(containers)
+    }
+
+    /// CPU usage in percent of one core, like a process's, and network rates
+    /// per second.
+    fn rates(last: &Previous, now: &Previous, online_cpus: u32) -> (f32, f64, f64) {
+        let cpu_delta = now.cpu_total.saturating_sub(last.cpu_total) as f64;
+        let system_delta = now.cpu_system.saturating_sub(last.cpu_system) as f64;
+        let cpu_usage = if system_delta > 0f64 {
+            (cpu_delta / system_delta * f64::from(online_cpus.max(1)) * 100f64) as f32
+        } else {
+            0f32
+        };
+        let seconds = (now.at - last.at).as_secs_f64();
+        let per_sec = |delta: u64| {
+            if seconds > 0f64 {
+                delta as f64 / seconds
+            } else {
+                0f64
+            }
+        };
+        (
+            cpu_usage,
+            per_sec(now.rx_bytes.saturating_sub(last.rx_bytes)),
+            per_sec(now.tx_bytes.saturating_sub(last.tx_bytes)),
+        )
+    }
+
+    /// One request per connection, so the stats calls can run concurrently.
+    async fn get<T: DeserializeOwned>(socket: &Path, path: &str) -> Result<T, String> {
+        let stream = UnixStream::connect(socket)
+            .await
+            .map_err(|err| format!("Failed to connect: {err}"))?;
+        let (mut sender, connection) = hyper::client::conn::handshake(stream)
+            .await
+            .map_err(|err| err.to_string())?;
+        tokio::spawn(connection);
+        let request = Request::get(path)
+            .header(hyper::header::HOST, "docker")
+            .body(Body::empty())
+            .expect("Invalid Docker request");
+        let response = sender
+            .send_request(request)
+            .await
+            .map_err(|err| err.to_string())?;
+        let status = response.status();
+        let body = hyper::body::aggregate(response.into_body())
+            .await
+            .map_err(|err| err.to_string())?;
+        if !status.is_success() {
+            return Err(format!("GET {path} returned {status}"));
+        }
+        serde_json::from_reader(body.reader())
+            .map_err(|err| format!("Invalid response to {path}: {err}"))
+    }
+}
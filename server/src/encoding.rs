@@ -0,0 +1,78 @@
+//! Wire encodings. Responses are JSON unless the client asks for MessagePack
+//! with `Accept: application/msgpack`; realtime sockets opened with
+//! `?format=msgpack` receive binary MessagePack frames instead of text, and
+//! with `?format=protobuf` the messages of `proto/process_viewer.proto`.
+
+use crate::api_error;
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+
+pub const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Msgpack,
+    /// Only for realtime sockets.
+    Protobuf,
+}
+
+impl Encoding {
+    /// Quality values are ignored: listing MessagePack at all selects it.
+    fn from_accept(headers: &HeaderMap) -> Self {
+        let accepts_msgpack = headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|media_type| media_type.split(';').next().unwrap_or_default().trim())
+            .any(|media_type| {
+                media_type.eq_ignore_ascii_case(MSGPACK_CONTENT_TYPE)
+                    || media_type.eq_ignore_ascii_case("application/x-msgpack")
+            });
+        if accepts_msgpack {
+            Self::Msgpack
+        } else {
+            Self::Json
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Encoding {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self::from_accept(&parts.headers))
+    }
+}
+
+/// A response body in the negotiated encoding.
+pub struct Encoded<T>(pub Encoding, pub T);
+
+impl<T: Serialize> IntoResponse for Encoded<T> {
+    fn into_response(self) -> Response {
+        let vary = [(header::VARY, "accept")];
+        match self.0 {
+            Encoding::Json | Encoding::Protobuf => (vary, Json(self.1)).into_response(),
+            Encoding::Msgpack => match rmp_serde::to_vec_named(&self.1) {
+                Ok(body) => {
+                    (vary, [(header::CONTENT_TYPE, MSGPACK_CONTENT_TYPE)], body).into_response()
+                }
+                Err(err) => api_error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to encode response: {err}"),
+                )
+                .into_response(),
+            },
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! The error every handler returns, answered as an RFC 7807
+//! `application/problem+json` body:
+//!
+//! ```json
+//! { "type": "about:blank", "title": "Not Found", "status": 404, "detail": "No process with pid 42" }
+//! ```
+//!
+//! Extractor rejections, which axum answers in plain text, and panics in
+//! handlers are turned into the same shape by `problem_json` and
+//! `handle_panic`. Bodies also carry the `request_id` of the request, see
+//! `request_id.rs`.
+
+use crate::request_id;
+use axum::body::{Body, Bytes};
+use axum::extract::FromRequest;
+use axum::http::{header, HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde_json::json;
+use std::any::Any;
+
+const PROBLEM_JSON: &str = "application/problem+json";
+
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(String),
+    Unauthorized(String),
+    /// Not allowed to the caller's role, or refused by the OS, e.g. signaling
+    /// another user's process.
+    Forbidden(String),
+    /// No such process, rule, snapshot or host, or a disabled feature.
+    NotFound(String),
+    Conflict(String),
+    TooManyRequests(String),
+    /// A collector hasn't taken its first sample yet, or a backend such as
+    /// Docker can't be reached.
+    NotReady(String),
+    Internal(String),
+    Other(StatusCode, String),
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::NotReady(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Other(status, _) => *status,
+        }
+    }
+
+    pub fn detail(&self) -> &str {
+        match self {
+            Self::BadRequest(detail)
+            | Self::Unauthorized(detail)
+            | Self::Forbidden(detail)
+            | Self::NotFound(detail)
+            | Self::Conflict(detail)
+            | Self::TooManyRequests(detail)
+            | Self::NotReady(detail)
+            | Self::Internal(detail)
+            | Self::Other(_, detail) => detail,
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        problem(self.status(), self.detail())
+    }
+}
+
+/// The variant for `status`, for call sites that pick it at runtime.
+pub fn api_error(status: StatusCode, message: impl Into<String>) -> AppError {
+    let message = message.into();
+    match status {
+        StatusCode::BAD_REQUEST => AppError::BadRequest(message),
+        StatusCode::UNAUTHORIZED => AppError::Unauthorized(message),
+        StatusCode::FORBIDDEN => AppError::Forbidden(message),
+        StatusCode::NOT_FOUND => AppError::NotFound(message),
+        StatusCode::CONFLICT => AppError::Conflict(message),
+        StatusCode::TOO_MANY_REQUESTS => AppError::TooManyRequests(message),
+        StatusCode::SERVICE_UNAVAILABLE => AppError::NotReady(message),
+        StatusCode::INTERNAL_SERVER_ERROR => AppError::Internal(message),
+        status => AppError::Other(status, message),
+    }
+}
+
+fn problem(status: StatusCode, detail: &str) -> Response {
+    let mut body = json!({
+        "type": "about:blank",
+        "title": status.canonical_reason().unwrap_or("Error"),
+        "status": status.as_u16(),
+        "detail": detail,
+    });
+    if let Some(id) = request_id::current() {
+        body["request_id"] = id.into();
+    }
+    let mut response = (status, body.to_string()).into_response();
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+    response
+}
+
+/// Route layer rewriting plain text error responses, such as a `Json` or
+/// `Path` extractor's rejection, as problem details.
+pub async fn problem_json(request: Request<Body>, next: Next<Body>) -> Response {
+    let response = next.run(request).await;
+    let status = response.status();
+    let is_text = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/plain"));
+    if !(status.is_client_error() || status.is_server_error()) || !is_text {
+        return response;
+    }
+    let text = Bytes::from_request(Request::new(response.into_body()), &())
+        .await
+        .unwrap_or_default();
+    problem(status, String::from_utf8_lossy(&text).trim())
+}
+
+/// Answers a panicking handler with a 500 rather than dropping the
+/// connection; the panic itself is logged by the panic hook.
+pub fn handle_panic(_: Box<dyn Any + Send + 'static>) -> Response {
+    AppError::Internal("The request handler panicked".to_owned()).into_response()
+}
+
+/// Answers requests for unknown `/api` paths.
+pub async fn not_found() -> AppError {
+    AppError::NotFound("No such endpoint".to_owned())
+}
@@ -0,0 +1,212 @@
+//! `/api/v1/export`, the history as CSV or newline-delimited JSON for
+//! spreadsheets and ad-hoc analysis: one row per sample with a column per
+//! metric, e.g. `?metric=memory&from=..&to=..&format=csv`. Served from the
+//! same history as `/api/v1/stats`.
+
+use crate::grafana::{self, Metric};
+use crate::history::{self, now_millis, HistoryEntry};
+use crate::models::{CpuInfo, Memory};
+use crate::{api_error, AppError, AppState};
+use axum::body::{Bytes, StreamBody};
+use axum::extract::{Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum_macros::debug_handler;
+use futures_util::stream;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use utoipa::{IntoParams, ToSchema};
+
+const DEFAULT_RANGE_MS: u64 = 60 * 60 * 1000;
+/// Rows serialized per chunk of the response body.
+const CHUNK_ROWS: usize = 1000;
+
+#[derive(Debug, Default, Clone, Copy, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Ndjson,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportQuery {
+    /// Comma separated metric names as listed by `/grafana/search`; `cpu`
+    /// stands for the mean and every core, `memory` for all memory and swap
+    /// metrics.
+    metric: String,
+    /// Start in milliseconds since the epoch, an hour before `to` by default.
+    from: Option<u64>,
+    /// End in milliseconds since the epoch, now by default.
+    to: Option<u64>,
+    /// `csv` by default.
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/export",
+    tag = "system",
+    params(ExportQuery),
+    responses(
+        (status = 200, description = "One row per sample, oldest first, with `timestamp` (ms since the epoch), `time` (RFC 3339) and a column per metric; empty where a sample lacks it", content_type = ["text/csv", "application/x-ndjson"]),
+        (status = 400, description = "Unknown metric", body = ErrorResponse),
+        (status = 500, description = "The history database query failed", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_export(
+    State(state): State<AppState>,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, AppError> {
+    let cores = state.cpus.latest().value().len();
+    let mut columns: Vec<(String, Metric)> = vec![];
+    for name in query.metric.split(',').map(str::trim) {
+        let expanded: Vec<String> = match name {
+            "cpu" => grafana::metric_names(cores)
+                .filter(|name| name.starts_with("cpu."))
+                .collect(),
+            "memory" => grafana::metric_names(0)
+                .filter(|name| !name.starts_with("cpu."))
+                .collect(),
+            name => vec![name.to_owned()],
+        };
+        for name in expanded {
+            let metric = Metric::parse(&name).ok_or_else(|| {
+                api_error(StatusCode::BAD_REQUEST, format!("Unknown metric {name:?}"))
+            })?;
+            if !columns.iter().any(|(column, _)| *column == name) {
+                columns.push((name, metric));
+            }
+        }
+    }
+    let to = query.to.unwrap_or_else(now_millis);
+    let from = query.from.unwrap_or(to.saturating_sub(DEFAULT_RANGE_MS));
+    // Each history is only fetched when a column needs it.
+    let mut cpus: Vec<HistoryEntry<Vec<CpuInfo>>> = vec![];
+    let mut memory: Vec<HistoryEntry<Memory>> = vec![];
+    if columns.iter().any(|(_, metric)| !metric.is_memory()) {
+        cpus = history::range(&state, state.cpus.history(), "cpus", from, to).await?;
+    }
+    if columns.iter().any(|(_, metric)| metric.is_memory()) {
+        memory = history::range(&state, state.memory.history(), "memory", from, to).await?;
+    }
+    // CPU and memory are sampled together, so their timestamps line up.
+    let mut rows: BTreeMap<u64, Vec<Option<f64>>> = BTreeMap::new();
+    let empty = vec![None; columns.len()];
+    for entry in &cpus {
+        let values = rows.entry(entry.timestamp).or_insert_with(|| empty.clone());
+        for (index, (_, metric)) in columns.iter().enumerate() {
+            if !metric.is_memory() {
+                values[index] = metric.cpu_value(&entry.data);
+            }
+        }
+    }
+    for entry in &memory {
+        let values = rows.entry(entry.timestamp).or_insert_with(|| empty.clone());
+        for (index, (_, metric)) in columns.iter().enumerate() {
+            if metric.is_memory() {
+                values[index] = metric.memory_value(&entry.data);
+            }
+        }
+    }
+    let names: Vec<String> = columns.into_iter().map(|(name, _)| name).collect();
+    let (content_type, extension) = match query.format {
+        ExportFormat::Csv => ("text/csv; charset=utf-8", "csv"),
+        ExportFormat::Ndjson => ("application/x-ndjson", "ndjson"),
+    };
+    let header_line = match query.format {
+        ExportFormat::Csv => Some(format!("timestamp,time,{}\n", names.join(","))),
+        ExportFormat::Ndjson => None,
+    };
+    // Rows are serialized as the body is sent rather than all up front.
+    let format = query.format;
+    let mut rows = rows.into_iter();
+    let chunks = std::iter::from_fn(move || {
+        let mut text = String::new();
+        for (timestamp, values) in rows.by_ref().take(CHUNK_ROWS) {
+            write_row(&mut text, format, &names, timestamp, &values);
+        }
+        (!text.is_empty()).then_some(text)
+    });
+    let body = stream::iter(
+        header_line
+            .into_iter()
+            .chain(chunks)
+            .map(|text| Ok::<_, Infallible>(Bytes::from(text))),
+    );
+    let disposition = format!("attachment; filename=\"process-viewer-{from}-{to}.{extension}\"");
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_owned()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        StreamBody::new(body),
+    )
+        .into_response())
+}
+
+fn write_row(
+    out: &mut String,
+    format: ExportFormat,
+    names: &[String],
+    timestamp: u64,
+    values: &[Option<f64>],
+) {
+    let time = format_timestamp(timestamp);
+    match format {
+        ExportFormat::Csv => {
+            let _ = write!(out, "{timestamp},{time}");
+            for value in values {
+                out.push(',');
+                if let Some(value) = value {
+                    let _ = write!(out, "{value}");
+                }
+            }
+            out.push('\n');
+        }
+        ExportFormat::Ndjson => {
+            let mut object = Map::new();
+            object.insert("timestamp".to_owned(), Value::from(timestamp));
+            object.insert("time".to_owned(), Value::from(time));
+            for (name, value) in names.iter().zip(values) {
+                object.insert(name.clone(), value.map_or(Value::Null, Value::from));
+            }
+            let _ = writeln!(out, "{}", Value::Object(object));
+        }
+    }
+}
+
+/// `YYYY-MM-DDTHH:MM:SS.fffZ` of milliseconds since the epoch.
+fn format_timestamp(millis: u64) -> String {
+    let days = (millis / 86_400_000) as i64;
+    let millis_of_day = millis % 86_400_000;
+    // Howard Hinnant's `civil_from_days`, the inverse of the conversion in
+    // `grafana::parse_timestamp`.
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        millis_of_day / 3_600_000,
+        millis_of_day / 60_000 % 60,
+        millis_of_day / 1000 % 60,
+        millis_of_day % 1000,
+    )
+}
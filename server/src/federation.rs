@@ -0,0 +1,103 @@
+//! Federation, the pull counterpart of `agent.rs`: the process-viewer
+//! instances given with `--remote` are polled through their REST API and
+//! served under `/api/v1/hosts` like agents, so a hub can watch machines
+//! that run an unmodified server. See `hub.rs`.
+
+use crate::config::RemoteConfig;
+use crate::history::now_millis;
+use crate::hub::{HostReport, Remote};
+use crate::AppState;
+use serde::de::DeserializeOwned;
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn spawn(remotes: Vec<RemoteConfig>, interval: Duration, app_state: AppState) {
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .expect("Failed to build remote client");
+    for config in remotes {
+        let remote = Remote {
+            url: config.url,
+            api_key: config.api_key,
+        };
+        app_state.hosts.add_remote(&config.name, remote.clone());
+        tokio::spawn(poll(
+            config.name,
+            remote,
+            client.clone(),
+            interval,
+            app_state.clone(),
+        ));
+    }
+}
+
+async fn poll(
+    name: String,
+    remote: Remote,
+    client: reqwest::Client,
+    interval: Duration,
+    app_state: AppState,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = app_state.shutdown.cancelled() => break,
+        }
+        let result = report(&client, &remote, interval).await;
+        app_state.hosts.update_remote(&name, result);
+    }
+}
+
+/// The remote's current samples, fetched all at once.
+async fn report(
+    client: &reqwest::Client,
+    remote: &Remote,
+    interval: Duration,
+) -> Result<HostReport, String> {
+    let (system, cpus, memory, loadavg, processes, disks, networks, temperatures) = tokio::try_join!(
+        fetch(client, remote, "system"),
+        fetch(client, remote, "cpus"),
+        fetch(client, remote, "memory"),
+        fetch(client, remote, "loadavg"),
+        fetch(client, remote, "processes"),
+        fetch(client, remote, "disks"),
+        fetch(client, remote, "networks"),
+        fetch(client, remote, "temperatures"),
+    )?;
+    Ok(HostReport {
+        timestamp: now_millis(),
+        interval_ms: interval.as_millis() as u64,
+        system,
+        cpus,
+        memory,
+        loadavg,
+        processes,
+        disks,
+        networks,
+        temperatures,
+    })
+}
+
+async fn fetch<T: DeserializeOwned>(
+    client: &reqwest::Client,
+    remote: &Remote,
+    path: &str,
+) -> Result<T, String> {
+    let response = remote
+        .get(client, remote.endpoint(path))
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+    match response.status() {
+        status if status.is_success() => response
+            .json()
+            .await
+            .map_err(|err| format!("Invalid /api/v1/{path} response: {err}")),
+        status => Err(format!("/api/v1/{path} answered {status}")),
+    }
+}
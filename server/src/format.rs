@@ -0,0 +1,171 @@
+//! Per-request selection of raw and human-readable values.
+//!
+//! Payloads carry raw values in `*_bytes` fields next to a human-readable
+//! field of the same name without the suffix, e.g. `used_memory_bytes` and
+//! `used_memory`. Both are serialized by default; `?format=raw` or
+//! `?format=human` keeps only one side of each pair.
+//!
+//! Human-readable sizes are in decimal units (`KB`, powers of 1000) unless
+//! `--units iec` picks binary ones (`KiB`, powers of 1024), with `--precision`
+//! decimal places. `?units=` and `?precision=` render one response
+//! differently, from the raw side of each pair.
+
+use crate::models::HumanReadable;
+use crate::AppError;
+use clap::ValueEnum;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::sync::OnceLock;
+use utoipa::{IntoParams, ToSchema};
+
+const RAW_SUFFIX: &str = "_bytes";
+pub const DEFAULT_PRECISION: u8 = 2;
+pub const MAX_PRECISION: u8 = 6;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Human,
+    Raw,
+    #[default]
+    Both,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Units {
+    /// KB, MB and GB.
+    #[default]
+    Si,
+    /// KiB, MiB and GiB.
+    Iec,
+}
+
+/// Units and precision from the configuration, read by every `to_human`.
+static DEFAULTS: OnceLock<(Units, u8)> = OnceLock::new();
+
+/// Called once at startup, before anything is sampled.
+pub fn set_defaults(units: Units, precision: u8) {
+    DEFAULTS
+        .set((units, precision))
+        .expect("The formatting defaults are set once");
+}
+
+pub fn default_units() -> Units {
+    DEFAULTS
+        .get()
+        .map_or_else(Units::default, |(units, _)| *units)
+}
+
+pub fn default_precision() -> u8 {
+    DEFAULTS
+        .get()
+        .map_or(DEFAULT_PRECISION, |(_, precision)| *precision)
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct FormatQuery {
+    /// Which side of each raw/human-readable pair to keep.
+    #[serde(default)]
+    pub format: Format,
+    /// Units of the human-readable sizes; defaults to the server's `--units`.
+    pub units: Option<Units>,
+    /// Decimal places of the human-readable sizes, at most 6; defaults to
+    /// the server's `--precision`.
+    pub precision: Option<u8>,
+}
+
+impl FormatQuery {
+    /// Renders the human-readable side of each pair in `value` in the
+    /// requested units and precision, then keeps the requested sides.
+    pub fn apply(&self, value: Value) -> Result<Value, AppError> {
+        if self
+            .precision
+            .is_some_and(|precision| precision > MAX_PRECISION)
+        {
+            return Err(AppError::BadRequest(format!(
+                "The precision must be at most {MAX_PRECISION}"
+            )));
+        }
+        let units = self.units.unwrap_or_else(default_units);
+        let precision = self.precision.unwrap_or_else(default_precision);
+        let value = if units != default_units() || precision != default_precision() {
+            humanize(value, units, precision)
+        } else {
+            value
+        };
+        Ok(apply(value, self.format))
+    }
+}
+
+/// Renders the human-readable side of each pair in `value` anew from its raw
+/// side, in the server's units and precision.
+pub fn rehumanize(value: Value) -> Value {
+    humanize(value, default_units(), default_precision())
+}
+
+fn humanize(value: Value, units: Units, precision: u8) -> Value {
+    match value {
+        Value::Array(items) => Value::Array(
+            items
+                .into_iter()
+                .map(|v| humanize(v, units, precision))
+                .collect(),
+        ),
+        Value::Object(mut fields) => {
+            let rendered: Vec<(String, String)> = fields
+                .iter()
+                .filter_map(|(key, raw)| {
+                    let human = key.strip_suffix(RAW_SUFFIX)?;
+                    fields.get(human)?.as_str()?;
+                    let text = raw.as_u64()?.to_human_in(units, Some(precision));
+                    Some((human.to_owned(), text))
+                })
+                .collect();
+            for (human, text) in rendered {
+                fields.insert(human, Value::String(text));
+            }
+            Value::Object(
+                fields
+                    .into_iter()
+                    .map(|(key, v)| (key, humanize(v, units, precision)))
+                    .collect(),
+            )
+        }
+        other => other,
+    }
+}
+
+pub fn apply(value: Value, format: Format) -> Value {
+    if format == Format::Both {
+        return value;
+    }
+    match value {
+        Value::Array(items) => Value::Array(items.into_iter().map(|v| apply(v, format)).collect()),
+        Value::Object(fields) => {
+            let dropped: HashSet<String> = fields
+                .keys()
+                .filter_map(|key| {
+                    let human = key.strip_suffix(RAW_SUFFIX)?;
+                    if !fields.contains_key(human) {
+                        return None;
+                    }
+                    match format {
+                        Format::Human => Some(key.clone()),
+                        _ => Some(human.to_owned()),
+                    }
+                })
+                .collect();
+            Value::Object(
+                fields
+                    .into_iter()
+                    .filter(|(key, _)| !dropped.contains(key))
+                    .map(|(key, v)| (key, apply(v, format)))
+                    .collect(),
+            )
+        }
+        other => other,
+    }
+}
@@ -0,0 +1,471 @@
+//! NVIDIA GPU metrics, compiled in with the `gpu` feature. NVML is loaded
+//! from the driver at startup rather than linked, so the same binary runs on
+//! hosts without a GPU; there the list simply stays empty.
+
+use crate::collector::Registry;
+use crate::encoding::{Encoded, Encoding};
+use crate::models::GpuInfo;
+use crate::sampler::Topic;
+use crate::{api_error, AppError, AppState};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum_macros::debug_handler;
+use std::time::Duration;
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/gpus",
+    tag = "gpus",
+    responses(
+        (status = 200, description = "NVIDIA GPUs by index; empty without a driver", body = [GpuInfo], content_type = ["application/json", "application/msgpack"],
+            headers(("x-sample-timestamp" = u64, description = "When the sample was taken, in milliseconds since the Unix epoch"))),
+        (status = 404, description = "The server was built without the `gpu` feature, or the GPU collector is disabled", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_gpus(
+    State(state): State<AppState>,
+    encoding: Encoding,
+) -> Result<impl IntoResponse, AppError> {
+    if !cfg!(feature = "gpu") {
+        return Err(api_error(
+            StatusCode::NOT_FOUND,
+            "GPU support is not compiled in, build the server with --features gpu",
+        ));
+    }
+    state.gpus.require("GPU")?;
+    let latest = state.gpus.latest();
+    Ok((
+        latest.timestamp_header(),
+        Encoded(encoding, latest.value().clone()),
+    ))
+}
+
+#[cfg(all(feature = "gpu", unix))]
+pub fn register(sample_interval: Duration, registry: &mut Registry, topic: Topic<Vec<GpuInfo>>) {
+    match nvml::Nvml::load() {
+        Ok(nvml) => registry.register(collector::Gpus::new(nvml, sample_interval), topic),
+        Err(err) => tracing::info!(%err, "NVML is not available, GPUs are not collected"),
+    }
+}
+
+#[cfg(not(all(feature = "gpu", unix)))]
+pub fn register(_: Duration, _: &mut Registry, _: Topic<Vec<GpuInfo>>) {
+    #[cfg(feature = "gpu")]
+    tracing::warn!("GPU metrics are only collected on Linux");
+}
+
+#[cfg(all(feature = "gpu", unix))]
+mod collector {
+    use super::nvml::Nvml;
+    use crate::collector::Collector;
+    use crate::models::{GpuInfo, GpuProcess, HumanReadable};
+    use crate::AppState;
+    use axum::async_trait;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// NVML averages utilization over up to a second, so polling faster
+    /// doesn't show more.
+    const MIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+    pub struct Gpus {
+        nvml: Arc<Nvml>,
+        interval: Duration,
+    }
+
+    impl Gpus {
+        pub fn new(nvml: Nvml, sample_interval: Duration) -> Self {
+            Self {
+                nvml: Arc::new(nvml),
+                interval: sample_interval.max(MIN_POLL_INTERVAL),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Collector for Gpus {
+        type Output = Vec<GpuInfo>;
+
+        fn name(&self) -> &'static str {
+            "gpus"
+        }
+
+        fn interval(&self) -> Duration {
+            self.interval
+        }
+
+        async fn sample(&mut self, state: &AppState) -> Result<Vec<GpuInfo>, String> {
+            let nvml = self.nvml.clone();
+            let gpus = tokio::task::spawn_blocking(move || collect(&nvml))
+                .await
+                .expect("GPU sampling task panicked");
+            let processes = state.processes.latest();
+            let names: HashMap<u32, &str> = processes
+                .value()
+                .iter()
+                .map(|process| (process.pid, process.name.as_str()))
+                .collect();
+            Ok(gpus
+                .into_iter()
+                .map(|mut gpu| {
+                    for process in &mut gpu.processes {
+                        process.name = names.get(&process.pid).map(|name| (*name).to_owned());
+                    }
+                    gpu
+                })
+                .collect())
+        }
+    }
+
+    /// Devices that fail to answer are left out; single readings a device
+    /// doesn't support are `None`.
+    fn collect(nvml: &Nvml) -> Vec<GpuInfo> {
+        let count = match nvml.device_count() {
+            Ok(count) => count,
+            Err(err) => {
+                tracing::warn!(%err, "Failed to count GPUs");
+                return vec![];
+            }
+        };
+        (0..count)
+            .filter_map(|index| {
+                let device = nvml.device(index).ok()?;
+                let (memory_total_bytes, memory_used_bytes) = device.memory().unwrap_or_default();
+                let utilization = device.utilization().ok();
+                Some(GpuInfo {
+                    index,
+                    name: device.name().unwrap_or_default(),
+                    uuid: device.uuid().unwrap_or_default(),
+                    gpu_usage: utilization.map_or(0f32, |(gpu, _)| gpu as f32),
+                    memory_usage: utilization.map_or(0f32, |(_, memory)| memory as f32),
+                    memory_total: memory_total_bytes.to_human(None),
+                    memory_total_bytes,
+                    memory_used: memory_used_bytes.to_human(None),
+                    memory_used_bytes,
+                    temperature: device.temperature().ok().map(|celsius| celsius as f32),
+                    power_draw_watts: device
+                        .power_usage()
+                        .ok()
+                        .map(|milliwatts| f64::from(milliwatts) / 1000f64),
+                    power_limit_watts: device
+                        .power_limit()
+                        .ok()
+                        .map(|milliwatts| f64::from(milliwatts) / 1000f64),
+                    processes: device
+                        .processes()
+                        .into_iter()
+                        .map(|(pid, memory_used_bytes)| GpuProcess {
+                            pid,
+                            name: None,
+                            memory_used: memory_used_bytes.to_human(None),
+                            memory_used_bytes,
+                        })
+                        .collect(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// The few NVML entry points the collector needs, resolved with `dlopen`.
+#[cfg(all(feature = "gpu", unix))]
+mod nvml {
+    use std::collections::BTreeMap;
+    use std::ffi::{c_char, c_int, c_uint, c_void, CStr};
+    use std::fmt;
+
+    const LIBRARY: &CStr = c"libnvidia-ml.so.1";
+    const SUCCESS: c_int = 0;
+    const ERROR_INSUFFICIENT_SIZE: c_int = 7;
+    const TEMPERATURE_GPU: c_int = 0;
+    const NAME_BUFFER_SIZE: usize = 96;
+    const UUID_BUFFER_SIZE: usize = 80;
+    /// Value NVML reports for the memory of a process it can't attribute.
+    const UNAVAILABLE_MEMORY: u64 = u64::MAX;
+
+    type Device = *mut c_void;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct Utilization {
+        gpu: c_uint,
+        memory: c_uint,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct Memory {
+        total: u64,
+        free: u64,
+        used: u64,
+    }
+
+    #[repr(C)]
+    #[derive(Default, Clone, Copy)]
+    struct ProcessInfo {
+        pid: c_uint,
+        used_gpu_memory: u64,
+        gpu_instance_id: c_uint,
+        compute_instance_id: c_uint,
+    }
+
+    /// An NVML return code other than success.
+    #[derive(Debug)]
+    pub struct Error(String);
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(&self.0)
+        }
+    }
+
+    type VoidFn = unsafe extern "C" fn() -> c_int;
+    type CountFn = unsafe extern "C" fn(*mut c_uint) -> c_int;
+    type HandleFn = unsafe extern "C" fn(c_uint, *mut Device) -> c_int;
+    type StringFn = unsafe extern "C" fn(Device, *mut c_char, c_uint) -> c_int;
+    type UtilizationFn = unsafe extern "C" fn(Device, *mut Utilization) -> c_int;
+    type MemoryFn = unsafe extern "C" fn(Device, *mut Memory) -> c_int;
+    type TemperatureFn = unsafe extern "C" fn(Device, c_int, *mut c_uint) -> c_int;
+    type DeviceValueFn = unsafe extern "C" fn(Device, *mut c_uint) -> c_int;
+    type ProcessesFn = unsafe extern "C" fn(Device, *mut c_uint, *mut ProcessInfo) -> c_int;
+    type ErrorStringFn = unsafe extern "C" fn(c_int) -> *const c_char;
+
+    struct Functions {
+        shutdown: VoidFn,
+        device_count: CountFn,
+        device_by_index: HandleFn,
+        name: StringFn,
+        uuid: StringFn,
+        utilization: UtilizationFn,
+        memory: MemoryFn,
+        temperature: TemperatureFn,
+        power_usage: DeviceValueFn,
+        power_limit: DeviceValueFn,
+        /// Missing in drivers older than 510.
+        compute_processes: Option<ProcessesFn>,
+        graphics_processes: Option<ProcessesFn>,
+        error_string: ErrorStringFn,
+    }
+
+    pub struct Nvml {
+        library: *mut c_void,
+        functions: Functions,
+    }
+
+    // SAFETY: NVML is thread safe, and the library handle is only used to
+    // close it on drop.
+    unsafe impl Send for Nvml {}
+    unsafe impl Sync for Nvml {}
+
+    impl Nvml {
+        pub fn load() -> Result<Self, Error> {
+            // SAFETY: dlopen and dlsym are given NUL terminated names; every
+            // symbol is cast to the signature documented in nvml.h.
+            unsafe {
+                let library = libc::dlopen(LIBRARY.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL);
+                if library.is_null() {
+                    return Err(Error(format!(
+                        "Failed to load {}",
+                        LIBRARY.to_string_lossy()
+                    )));
+                }
+                let symbol = |name: &CStr| {
+                    let symbol = libc::dlsym(library, name.as_ptr());
+                    (!symbol.is_null()).then_some(symbol)
+                };
+                macro_rules! require {
+                    ($name:literal as $signature:ty) => {
+                        match symbol($name) {
+                            Some(symbol) => std::mem::transmute::<*mut c_void, $signature>(symbol),
+                            None => {
+                                libc::dlclose(library);
+                                return Err(Error(format!(
+                                    "NVML has no {}",
+                                    $name.to_string_lossy()
+                                )));
+                            }
+                        }
+                    };
+                }
+                let init = require!(c"nvmlInit_v2" as VoidFn);
+                let functions = Functions {
+                    shutdown: require!(c"nvmlShutdown" as VoidFn),
+                    device_count: require!(c"nvmlDeviceGetCount_v2" as CountFn),
+                    device_by_index: require!(c"nvmlDeviceGetHandleByIndex_v2" as HandleFn),
+                    name: require!(c"nvmlDeviceGetName" as StringFn),
+                    uuid: require!(c"nvmlDeviceGetUUID" as StringFn),
+                    utilization: require!(c"nvmlDeviceGetUtilizationRates" as UtilizationFn),
+                    memory: require!(c"nvmlDeviceGetMemoryInfo" as MemoryFn),
+                    temperature: require!(c"nvmlDeviceGetTemperature" as TemperatureFn),
+                    power_usage: require!(c"nvmlDeviceGetPowerUsage" as DeviceValueFn),
+                    power_limit: require!(c"nvmlDeviceGetEnforcedPowerLimit" as DeviceValueFn),
+                    compute_processes: symbol(c"nvmlDeviceGetComputeRunningProcesses_v3")
+                        .map(|symbol| std::mem::transmute::<*mut c_void, ProcessesFn>(symbol)),
+                    graphics_processes: symbol(c"nvmlDeviceGetGraphicsRunningProcesses_v3")
+                        .map(|symbol| std::mem::transmute::<*mut c_void, ProcessesFn>(symbol)),
+                    error_string: require!(c"nvmlErrorString" as ErrorStringFn),
+                };
+                let nvml = Self { library, functions };
+                nvml.check(init())?;
+                Ok(nvml)
+            }
+        }
+
+        fn check(&self, code: c_int) -> Result<(), Error> {
+            if code == SUCCESS {
+                return Ok(());
+            }
+            // SAFETY: NVML returns a static string for every code.
+            let message = unsafe { CStr::from_ptr((self.functions.error_string)(code)) };
+            Err(Error(message.to_string_lossy().into_owned()))
+        }
+
+        pub fn device_count(&self) -> Result<u32, Error> {
+            let mut count = 0;
+            // SAFETY: NVML writes one integer.
+            self.check(unsafe { (self.functions.device_count)(&mut count) })?;
+            Ok(count)
+        }
+
+        pub fn device(&self, index: u32) -> Result<DeviceRef<'_>, Error> {
+            let mut device = std::ptr::null_mut();
+            // SAFETY: NVML writes one handle, valid until shutdown.
+            self.check(unsafe { (self.functions.device_by_index)(index, &mut device) })?;
+            Ok(DeviceRef { nvml: self, device })
+        }
+    }
+
+    impl Drop for Nvml {
+        fn drop(&mut self) {
+            // SAFETY: no device handles outlive `self`.
+            unsafe {
+                (self.functions.shutdown)();
+                libc::dlclose(self.library);
+            }
+        }
+    }
+
+    /// A device handle, only valid while NVML is loaded.
+    pub struct DeviceRef<'a> {
+        nvml: &'a Nvml,
+        device: Device,
+    }
+
+    impl DeviceRef<'_> {
+        fn string(&self, get: StringFn, size: usize) -> Result<String, Error> {
+            let mut buffer = vec![0 as c_char; size];
+            // SAFETY: NVML writes a NUL terminated string of at most `size`
+            // bytes.
+            unsafe {
+                self.nvml
+                    .check(get(self.device, buffer.as_mut_ptr(), size as c_uint))?;
+                Ok(CStr::from_ptr(buffer.as_ptr())
+                    .to_string_lossy()
+                    .into_owned())
+            }
+        }
+
+        pub fn name(&self) -> Result<String, Error> {
+            self.string(self.nvml.functions.name, NAME_BUFFER_SIZE)
+        }
+
+        pub fn uuid(&self) -> Result<String, Error> {
+            self.string(self.nvml.functions.uuid, UUID_BUFFER_SIZE)
+        }
+
+        /// GPU and memory controller busy percentages.
+        pub fn utilization(&self) -> Result<(u32, u32), Error> {
+            let mut utilization = Utilization::default();
+            // SAFETY: NVML fills the struct.
+            self.nvml.check(unsafe {
+                (self.nvml.functions.utilization)(self.device, &mut utilization)
+            })?;
+            Ok((utilization.gpu, utilization.memory))
+        }
+
+        /// Total and used bytes of VRAM.
+        pub fn memory(&self) -> Result<(u64, u64), Error> {
+            let mut memory = Memory::default();
+            // SAFETY: NVML fills the struct.
+            self.nvml
+                .check(unsafe { (self.nvml.functions.memory)(self.device, &mut memory) })?;
+            Ok((memory.total, memory.used))
+        }
+
+        /// Core temperature in degrees Celsius.
+        pub fn temperature(&self) -> Result<u32, Error> {
+            let mut celsius = 0;
+            // SAFETY: NVML writes one integer.
+            self.nvml.check(unsafe {
+                (self.nvml.functions.temperature)(self.device, TEMPERATURE_GPU, &mut celsius)
+            })?;
+            Ok(celsius)
+        }
+
+        /// Milliwatts.
+        pub fn power_usage(&self) -> Result<u32, Error> {
+            let mut milliwatts = 0;
+            // SAFETY: NVML writes one integer.
+            self.nvml.check(unsafe {
+                (self.nvml.functions.power_usage)(self.device, &mut milliwatts)
+            })?;
+            Ok(milliwatts)
+        }
+
+        /// Milliwatts.
+        pub fn power_limit(&self) -> Result<u32, Error> {
+            let mut milliwatts = 0;
+            // SAFETY: NVML writes one integer.
+            self.nvml.check(unsafe {
+                (self.nvml.functions.power_limit)(self.device, &mut milliwatts)
+            })?;
+            Ok(milliwatts)
+        }
+
+        /// Pid and used VRAM of every compute and graphics process. A process
+        /// doing both is listed once, with its larger figure.
+        pub fn processes(&self) -> Vec<(u32, u64)> {
+            let functions = &self.nvml.functions;
+            let mut processes = BTreeMap::new();
+            for get in [functions.compute_processes, functions.graphics_processes]
+                .into_iter()
+                .flatten()
+            {
+                for info in self.running_processes(get) {
+                    let memory = match info.used_gpu_memory {
+                        UNAVAILABLE_MEMORY => 0,
+                        memory => memory,
+                    };
+                    let entry = processes.entry(info.pid).or_insert(0);
+                    *entry = memory.max(*entry);
+                }
+            }
+            processes.into_iter().collect()
+        }
+
+        fn running_processes(&self, get: ProcessesFn) -> Vec<ProcessInfo> {
+            let mut infos = vec![ProcessInfo::default(); 32];
+            // Processes may start between the calls, hence the retries.
+            for _ in 0..3 {
+                let mut count = infos.len() as c_uint;
+                // SAFETY: NVML writes at most `count` entries and sets
+                // `count` to the number needed.
+                let code = unsafe { get(self.device, &mut count, infos.as_mut_ptr()) };
+                match code {
+                    SUCCESS => {
+                        infos.truncate(count as usize);
+                        return infos;
+                    }
+                    ERROR_INSUFFICIENT_SIZE => {
+                        infos.resize(count as usize + 8, ProcessInfo::default())
+                    }
+                    _ => break,
+                }
+            }
+            vec![]
+        }
+    }
+}
@@ -0,0 +1,270 @@
+//! The SimpleJSON datasource contract, so Grafana can chart the history
+//! directly: `GET /grafana/` to test the connection, `POST /grafana/search`
+//! for the metric names and `POST /grafana/query` for time series. Served
+//! from the same history as `/api/v1/cpus/history` and
+//! `/api/v1/memory/history`.
+
+use crate::history::{self, HistoryEntry};
+use crate::models::{CpuInfo, Memory};
+use crate::{api_error, AppError, AppState};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use axum_macros::debug_handler;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// A series Grafana can ask for, also aggregated by `/api/v1/stats`.
+#[derive(Debug, Clone, Copy)]
+pub enum Metric {
+    /// Mean over all cores.
+    CpuUsage,
+    CoreUsage(usize),
+    MemoryUsed,
+    MemoryTotal,
+    MemoryUsedPercent,
+    SwapUsed,
+    SwapTotal,
+}
+
+const FIXED_METRICS: &[(&str, Metric)] = &[
+    ("cpu.usage", Metric::CpuUsage),
+    ("memory.used_bytes", Metric::MemoryUsed),
+    ("memory.total_bytes", Metric::MemoryTotal),
+    ("memory.used_percent", Metric::MemoryUsedPercent),
+    ("swap.used_bytes", Metric::SwapUsed),
+    ("swap.total_bytes", Metric::SwapTotal),
+];
+
+impl Metric {
+    /// One of `FIXED_METRICS` or `cpu.<core>.usage`.
+    pub fn parse(name: &str) -> Option<Self> {
+        if let Some((_, metric)) = FIXED_METRICS.iter().find(|(fixed, _)| *fixed == name) {
+            return Some(*metric);
+        }
+        let core = name.strip_prefix("cpu.")?.strip_suffix(".usage")?;
+        Some(Metric::CoreUsage(core.parse().ok()?))
+    }
+
+    pub fn is_memory(self) -> bool {
+        !matches!(self, Metric::CpuUsage | Metric::CoreUsage(_))
+    }
+
+    pub fn cpu_value(self, cpus: &[CpuInfo]) -> Option<f64> {
+        match self {
+            Metric::CpuUsage if !cpus.is_empty() => Some(
+                cpus.iter().map(|cpu| f64::from(cpu.cpu_usage)).sum::<f64>() / cpus.len() as f64,
+            ),
+            Metric::CoreUsage(core) => cpus.get(core).map(|cpu| f64::from(cpu.cpu_usage)),
+            _ => None,
+        }
+    }
+
+    pub fn memory_value(self, memory: &Memory) -> Option<f64> {
+        match self {
+            Metric::MemoryUsed => Some(memory.used_memory_bytes as f64),
+            Metric::MemoryTotal => Some(memory.total_memory_bytes as f64),
+            Metric::MemoryUsedPercent if memory.total_memory_bytes > 0 => {
+                Some(memory.used_memory_bytes as f64 / memory.total_memory_bytes as f64 * 100f64)
+            }
+            Metric::SwapUsed => Some(memory.used_swap_bytes as f64),
+            Metric::SwapTotal => Some(memory.total_swap_bytes as f64),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct SearchRequest {
+    /// Case-insensitive substring of the metric names to return.
+    #[serde(default)]
+    target: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryRequest {
+    range: QueryRange,
+    targets: Vec<QueryTarget>,
+    /// Series longer than this are thinned out evenly.
+    max_data_points: Option<usize>,
+}
+
+/// Bounds as RFC 3339 timestamps in UTC, e.g. `2024-01-31T12:00:00.000Z`,
+/// the way Grafana sends them.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct QueryRange {
+    from: String,
+    to: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct QueryTarget {
+    /// A metric name from `/grafana/search`.
+    target: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TimeSeries {
+    target: String,
+    /// `[value, milliseconds since the epoch]` pairs, oldest first.
+    datapoints: Vec<(f64, u64)>,
+}
+
+/// Grafana only checks for a 200 when the datasource is saved.
+#[utoipa::path(
+    get,
+    path = "/grafana/",
+    tag = "grafana",
+    responses((status = 200, description = "The datasource is reachable"))
+)]
+#[debug_handler]
+pub async fn test() -> &'static str {
+    "Ok"
+}
+
+#[utoipa::path(
+    post,
+    path = "/grafana/search",
+    tag = "grafana",
+    request_body = SearchRequest,
+    responses((status = 200, description = "Metric names", body = [String]))
+)]
+#[debug_handler]
+pub async fn search(
+    State(state): State<AppState>,
+    request: Option<Json<SearchRequest>>,
+) -> Json<Vec<String>> {
+    let wanted = request
+        .map(|Json(request)| request.target.to_lowercase())
+        .unwrap_or_default();
+    let cores = state.cpus.latest().value().len();
+    let names = metric_names(cores)
+        .filter(|name| name.contains(&wanted))
+        .collect();
+    Json(names)
+}
+
+/// Every metric name, with per-core ones for `cores` cores.
+pub fn metric_names(cores: usize) -> impl Iterator<Item = String> {
+    FIXED_METRICS
+        .iter()
+        .map(|(name, _)| (*name).to_owned())
+        .chain((0..cores).map(|core| format!("cpu.{core}.usage")))
+}
+
+#[utoipa::path(
+    post,
+    path = "/grafana/query",
+    tag = "grafana",
+    request_body = QueryRequest,
+    responses(
+        (status = 200, description = "One series per target", body = [TimeSeries]),
+        (status = 400, description = "Unknown metric or invalid range", body = ErrorResponse),
+        (status = 500, description = "The history database query failed", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn query(
+    State(state): State<AppState>,
+    Json(request): Json<QueryRequest>,
+) -> Result<Json<Vec<TimeSeries>>, AppError> {
+    let bound = |value: &str| {
+        parse_timestamp(value).ok_or_else(|| {
+            api_error(
+                StatusCode::BAD_REQUEST,
+                format!("Invalid time {value:?}, expected RFC 3339 in UTC"),
+            )
+        })
+    };
+    let from = bound(&request.range.from)?;
+    let to = bound(&request.range.to)?;
+    let metrics = request
+        .targets
+        .iter()
+        .map(|target| {
+            Metric::parse(&target.target)
+                .map(|metric| (target.target.clone(), metric))
+                .ok_or_else(|| {
+                    api_error(
+                        StatusCode::BAD_REQUEST,
+                        format!("Unknown metric {:?}", target.target),
+                    )
+                })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    // Each history is only fetched when a target needs it.
+    let mut cpus: Option<Vec<HistoryEntry<Vec<CpuInfo>>>> = None;
+    let mut memory: Option<Vec<HistoryEntry<Memory>>> = None;
+    let mut series = vec![];
+    for (target, metric) in metrics {
+        let datapoints: Vec<(f64, u64)> = if metric.is_memory() {
+            if memory.is_none() {
+                memory =
+                    Some(history::range(&state, state.memory.history(), "memory", from, to).await?);
+            }
+            points(memory.as_deref().unwrap_or_default(), |data| {
+                metric.memory_value(data)
+            })
+        } else {
+            if cpus.is_none() {
+                cpus = Some(history::range(&state, state.cpus.history(), "cpus", from, to).await?);
+            }
+            points(cpus.as_deref().unwrap_or_default(), |data| {
+                metric.cpu_value(data)
+            })
+        };
+        series.push(TimeSeries {
+            target,
+            datapoints: thin(datapoints, request.max_data_points),
+        });
+    }
+    Ok(Json(series))
+}
+
+pub fn points<T>(
+    entries: &[HistoryEntry<T>],
+    value: impl Fn(&T) -> Option<f64>,
+) -> Vec<(f64, u64)> {
+    entries
+        .iter()
+        .filter_map(|entry| Some((value(&entry.data)?, entry.timestamp)))
+        .collect()
+}
+
+/// Keeps every n-th point so at most `max` remain.
+fn thin(points: Vec<(f64, u64)>, max: Option<usize>) -> Vec<(f64, u64)> {
+    match max {
+        Some(max) if max > 0 && points.len() > max => {
+            let step = points.len().div_ceil(max);
+            points.into_iter().step_by(step).collect()
+        }
+        _ => points,
+    }
+}
+
+/// Milliseconds since the epoch of `YYYY-MM-DDTHH:MM:SS[.fff]Z`.
+fn parse_timestamp(value: &str) -> Option<u64> {
+    let value = value.strip_suffix('Z')?;
+    let (date, time) = value.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let (time, fraction) = time.split_once('.').unwrap_or((time, "0"));
+    let mut time = time.splitn(3, ':').map(str::parse::<i64>);
+    let (hour, minute, second) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+    // Only milliseconds count; `.5` is 500.
+    let millis: i64 = format!("{fraction:0<3}").get(..3)?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    // Days since the epoch of a proleptic Gregorian date, after Howard
+    // Hinnant's `days_from_civil`.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    let millis = ((days * 24 + hour) * 60 + minute) * 60_000 + second * 1000 + millis;
+    u64::try_from(millis).ok()
+}
@@ -0,0 +1,286 @@
+//! GraphQL at `/graphql`, for clients that want exactly the fields they
+//! render. Queries are POSTed as `{"query": ..., "variables": ...}`;
+//! subscriptions run over a WebSocket speaking `graphql-transport-ws` (or
+//! the older `graphql-ws`) and emit every new sample.
+//!
+//! Types and field names are those of the REST models. The schema is
+//! served by async-graphql, so the whole query language is understood,
+//! introspection included.
+
+use crate::limits::SocketSlot;
+use crate::models::{CpuInfo, CpuSummary, Memory, ProcessInfo, SystemInfo};
+use crate::processes::{self, ProcessFilter, SortKey};
+use crate::realtime::{accept, close_for_shutdown, ping, Heartbeat};
+use crate::sampler::Topic;
+use crate::system::collect_system;
+use crate::AppState;
+use async_graphql::http::{
+    WebSocket as GraphQlWebSocket, WebSocketProtocols, WsMessage, ALL_WEBSOCKET_PROTOCOLS,
+};
+use async_graphql::{Context, Data, EmptyMutation, Object, Subscription};
+use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
+use axum::extract::{State, WebSocketUpgrade};
+use axum::response::IntoResponse;
+use axum_macros::debug_handler;
+use futures_util::stream::{Stream, StreamExt};
+use std::sync::OnceLock;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{UnboundedReceiverStream, WatchStream};
+
+pub type Schema = async_graphql::Schema<Query, EmptyMutation, Subscriptions>;
+
+/// Built once; resolvers get the `AppState` with each request.
+fn schema() -> &'static Schema {
+    static SCHEMA: OnceLock<Schema> = OnceLock::new();
+    SCHEMA.get_or_init(|| Schema::new(Query, EmptyMutation, Subscriptions))
+}
+
+fn state<'a>(ctx: &Context<'a>) -> &'a AppState {
+    ctx.data_unchecked::<AppState>()
+}
+
+pub struct Query;
+
+#[Object(rename_fields = "snake_case", rename_args = "snake_case")]
+impl Query {
+    async fn system(&self, ctx: &Context<'_>) -> SystemInfo {
+        let state = state(ctx);
+        collect_system(&*state.system.lock().await, &state.config.labels)
+    }
+
+    async fn cpus(&self, ctx: &Context<'_>) -> Vec<CpuInfo> {
+        state(ctx).cpus.latest().value().clone()
+    }
+
+    async fn cpu_summary(&self, ctx: &Context<'_>) -> CpuSummary {
+        let state = state(ctx);
+        let limit = state.cpu_limit.latest();
+        CpuSummary::from_cpus(state.cpus.latest().value(), limit.value().as_ref())
+    }
+
+    async fn memory(&self, ctx: &Context<'_>) -> Memory {
+        state(ctx).memory.latest().value().clone()
+    }
+
+    /// The filters of `/api/v1/processes`, plus `sort` for heaviest first
+    /// and a `limit`.
+    #[allow(clippy::too_many_arguments)]
+    async fn processes(
+        &self,
+        ctx: &Context<'_>,
+        name: Option<String>,
+        name_regex: Option<String>,
+        user: Option<String>,
+        min_cpu: Option<f32>,
+        status: Option<String>,
+        container: Option<String>,
+        sort: Option<SortKey>,
+        limit: Option<usize>,
+    ) -> async_graphql::Result<Vec<ProcessInfo>> {
+        let selection = ProcessSelection {
+            filter: ProcessFilter {
+                name,
+                name_regex,
+                user,
+                min_cpu,
+                status,
+                container,
+            },
+            sort,
+            limit,
+        };
+        selection.select(state(ctx).processes.latest().value())
+    }
+}
+
+pub struct Subscriptions;
+
+#[Subscription(rename_fields = "snake_case", rename_args = "snake_case")]
+impl Subscriptions {
+    async fn cpus(&self, ctx: &Context<'_>) -> impl Stream<Item = Vec<CpuInfo>> {
+        updates(&state(ctx).cpus, |cpus| cpus.clone())
+    }
+
+    async fn memory(&self, ctx: &Context<'_>) -> impl Stream<Item = Memory> {
+        updates(&state(ctx).memory, |memory| memory.clone())
+    }
+
+    /// Takes the arguments of the `processes` query.
+    #[allow(clippy::too_many_arguments)]
+    async fn processes(
+        &self,
+        ctx: &Context<'_>,
+        name: Option<String>,
+        name_regex: Option<String>,
+        user: Option<String>,
+        min_cpu: Option<f32>,
+        status: Option<String>,
+        container: Option<String>,
+        sort: Option<SortKey>,
+        limit: Option<usize>,
+    ) -> impl Stream<Item = async_graphql::Result<Vec<ProcessInfo>>> {
+        let selection = ProcessSelection {
+            filter: ProcessFilter {
+                name,
+                name_regex,
+                user,
+                min_cpu,
+                status,
+                container,
+            },
+            sort,
+            limit,
+        };
+        updates(&state(ctx).processes, move |processes| {
+            selection.select(processes)
+        })
+    }
+}
+
+struct ProcessSelection {
+    filter: ProcessFilter,
+    sort: Option<SortKey>,
+    limit: Option<usize>,
+}
+
+impl ProcessSelection {
+    fn select(&self, processes: &[ProcessInfo]) -> async_graphql::Result<Vec<ProcessInfo>> {
+        let mut selected = self
+            .filter
+            .apply(processes)
+            .map_err(|err| async_graphql::Error::new(err.detail()))?;
+        if let Some(sort) = self.sort {
+            processes::sort_processes(&mut selected, sort);
+        }
+        selected.truncate(self.limit.unwrap_or(usize::MAX));
+        Ok(selected.into_iter().cloned().collect())
+    }
+}
+
+/// Every sample published to `topic`, starting with the latest if there is
+/// one yet. Holding the receiver counts as a subscriber, so sampling
+/// doesn't idle meanwhile.
+fn updates<T, R, F>(topic: &Topic<T>, resolve: F) -> impl Stream<Item = R>
+where
+    T: Send + Sync + 'static,
+    F: Fn(&T) -> R + Send + 'static,
+{
+    let rx = topic.subscribe();
+    let updates = if rx.borrow().seq > 0 {
+        WatchStream::new(rx)
+    } else {
+        WatchStream::from_changes(rx)
+    };
+    updates.map(move |snapshot| resolve(snapshot.value()))
+}
+
+#[debug_handler]
+pub async fn graphql_post(
+    State(state): State<AppState>,
+    request: GraphQLRequest,
+) -> GraphQLResponse {
+    let request = request.into_inner().data(state);
+    schema().execute(request).await.into()
+}
+
+#[debug_handler]
+pub async fn graphql_ws(
+    ws: WebSocketUpgrade,
+    slot: SocketSlot,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    accept(
+        ws.protocols(ALL_WEBSOCKET_PROTOCOLS),
+        slot,
+        state.clone(),
+        move |ws| session(state, ws),
+    )
+}
+
+/// Feeds client messages to async-graphql and sends what it answers, with
+/// the pings and shutdown close frames of the realtime endpoints.
+async fn session(state: AppState, mut ws: WebSocket) {
+    // Clients that don't ask for a subprotocol get the current one.
+    let protocol = ws
+        .protocol()
+        .and_then(|protocol| protocol.to_str().ok())
+        .and_then(|protocol| protocol.parse().ok())
+        .unwrap_or(WebSocketProtocols::GraphQLWS);
+    let (input, received) = mpsc::unbounded_channel::<Vec<u8>>();
+    let mut data = Data::default();
+    data.insert(state.clone());
+    let replies = GraphQlWebSocket::new(
+        schema().clone(),
+        UnboundedReceiverStream::new(received),
+        protocol,
+    )
+    .connection_data(data);
+    tokio::pin!(replies);
+    let mut heartbeat = Heartbeat::new(state.telemetry.clone());
+    loop {
+        tokio::select! {
+            _ = state.shutdown.cancelled() => {
+                close_for_shutdown(ws).await;
+                break;
+            }
+            alive = heartbeat.tick() => {
+                if !alive || ws.send(ping()).await.is_err() {
+                    break;
+                }
+            }
+            msg = ws.recv() => {
+                let message = match msg {
+                    Some(Ok(Message::Text(text))) => text.into_bytes(),
+                    Some(Ok(Message::Binary(bytes))) => bytes,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {
+                        heartbeat.seen();
+                        continue;
+                    }
+                };
+                heartbeat.seen();
+                // Only fails once the replies ended, which the next branch
+                // notices.
+                let _ = input.send(message);
+            }
+            reply = replies.next() => match reply {
+                Some(WsMessage::Text(text)) => {
+                    if ws.send(Message::Text(text)).await.is_err() {
+                        break;
+                    }
+                }
+                Some(WsMessage::Close(code, reason)) => {
+                    let frame = CloseFrame {
+                        code,
+                        reason: reason.into(),
+                    };
+                    ws.send(Message::Close(Some(frame)))
+                        .await
+                        .unwrap_or_default();
+                    break;
+                }
+                None => break,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schema_keeps_the_rest_field_names() {
+        let sdl = schema().sdl();
+        for field in [
+            "cpu_summary: CpuSummary!",
+            "used_memory_bytes: Int!",
+            "name_regex: String",
+            "sort: SortKey",
+        ] {
+            assert!(sdl.contains(field), "{field} missing from\n{sdl}");
+        }
+        assert!(!sdl.contains("cpuSummary"));
+    }
+}
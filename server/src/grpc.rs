@@ -0,0 +1,334 @@
+//! Optional gRPC API, compiled in with `--features grpc` and served on
+//! `--grpc-bind`. The service is described in `proto/process_viewer.proto`:
+//! unary RPCs mirror the REST endpoints and the `Watch` RPCs stream every
+//! new sample like the realtime WebSockets.
+//!
+//! The routing is written out by hand rather than generated by
+//! `tonic-build`, and the messages are those of `protobuf.rs`. Calls are
+//! served by axum like the REST API, on a listener of their own, and
+//! converted between the `http` versions of axum and tonic on the way in and
+//! out.
+
+use crate::AppState;
+use std::net::SocketAddr;
+
+#[cfg(feature = "grpc")]
+pub fn spawn(addr: SocketAddr, app_state: AppState) {
+    tokio::spawn(service::serve(addr, app_state));
+}
+
+#[cfg(not(feature = "grpc"))]
+pub fn spawn(_: SocketAddr, _: AppState) {
+    tracing::warn!("gRPC support is not compiled in, build the server with --features grpc");
+}
+
+#[cfg(feature = "grpc")]
+mod service {
+    use crate::auth::authenticate;
+    use crate::protobuf as pb;
+    use crate::sampler::{Snapshot, Topic};
+    use crate::system::collect_system;
+    use crate::AppState;
+    use axum::body::{self, BoxBody, Bytes, HttpBody};
+    use axum::extract::State;
+    use axum::http::{HeaderMap, Request, Response};
+    use axum::Router;
+    use axum_server::Handle;
+    use futures_util::stream::{self, BoxStream, StreamExt};
+    use futures_util::FutureExt;
+    use std::future::Future;
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use std::task::{ready, Context, Poll};
+    use tonic::server::{Grpc, ServerStreamingService, UnaryService};
+    use tonic::Status;
+    use tonic_prost::ProstCodec;
+
+    const SERVICE: &str = "/processviewer.v1.ProcessViewer/";
+
+    pub async fn serve(addr: SocketAddr, app_state: AppState) {
+        let handle = Handle::new();
+        tokio::spawn(crate::stop_server_on_shutdown(
+            handle.clone(),
+            app_state.shutdown.clone(),
+        ));
+        let app = Router::new()
+            .fallback(dispatch)
+            .with_state(app_state.clone());
+        let server = axum_server::bind(addr).handle(handle.clone());
+        tokio::spawn(async move {
+            if let Some(addr) = handle.listening().await {
+                tracing::info!(%addr, "Serving gRPC");
+            }
+        });
+        if let Err(err) = server.serve(app.into_make_service()).await {
+            tracing::error!(%addr, %err, "The gRPC server failed");
+        }
+    }
+
+    async fn dispatch(
+        State(state): State<AppState>,
+        request: Request<body::Body>,
+    ) -> Response<BoxBody> {
+        let request = into_tonic(request);
+        let method = request.uri().path().strip_prefix(SERVICE).unwrap_or("");
+        let response = if let Err(status) = authorize(&state, request.headers()) {
+            status.into_http()
+        } else {
+            match method {
+                "GetSystem" => {
+                    let system = pb::SystemInfo::from(&collect_system(
+                        &*state.system.lock().await,
+                        &state.config.labels,
+                    ));
+                    unary(request, system).await
+                }
+                "GetCpus" => unary(request, pb::CpuList::from(&*state.cpus.latest())).await,
+                "GetMemory" => unary(request, pb::Memory::from(&*state.memory.latest())).await,
+                "GetProcesses" => {
+                    unary(request, pb::ProcessList::from(&*state.processes.latest())).await
+                }
+                "GetDisks" => unary(request, pb::DiskList::from(&*state.disks.latest())).await,
+                "GetNetworks" => {
+                    unary(request, pb::NetworkList::from(&*state.networks.latest())).await
+                }
+                "GetTemperatures" => {
+                    unary(
+                        request,
+                        pb::TemperatureList::from(&*state.temperatures.latest()),
+                    )
+                    .await
+                }
+                "GetLoadAverage" => {
+                    unary(request, pb::LoadAverage::from(&*state.loadavg.latest())).await
+                }
+                "WatchCpus" => {
+                    streaming(request, updates::<_, pb::CpuList>(&state, &state.cpus)).await
+                }
+                "WatchMemory" => {
+                    streaming(request, updates::<_, pb::Memory>(&state, &state.memory)).await
+                }
+                "WatchProcesses" => {
+                    streaming(
+                        request,
+                        updates::<_, pb::ProcessList>(&state, &state.processes),
+                    )
+                    .await
+                }
+                _ => Status::unimplemented(format!("Unknown method {method:?}")).into_http(),
+            }
+        };
+        from_tonic(response)
+    }
+
+    /// The same API keys and tokens as the REST API, from `authorization:
+    /// Bearer` metadata. Every role may use the read-only gRPC API.
+    fn authorize(state: &AppState, headers: &http_1::HeaderMap) -> Result<(), Status> {
+        if !state.config.requires_auth() {
+            return Ok(());
+        }
+        let token = headers
+            .get(http_1::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match token {
+            Some(token) => authenticate(state, token)
+                .map(|_| ())
+                .map_err(Status::unauthenticated),
+            None => Err(Status::unauthenticated("Missing API key")),
+        }
+    }
+
+    async fn unary<M>(
+        request: http_1::Request<RequestBody>,
+        message: M,
+    ) -> http_1::Response<tonic::body::Body>
+    where
+        M: prost::Message + Send + 'static,
+    {
+        let mut grpc = Grpc::new(ProstCodec::<M, pb::Empty>::default());
+        grpc.unary(Reply(Some(message)), request).await
+    }
+
+    async fn streaming<M>(
+        request: http_1::Request<RequestBody>,
+        updates: BoxStream<'static, Result<M, Status>>,
+    ) -> http_1::Response<tonic::body::Body>
+    where
+        M: prost::Message + Send + 'static,
+    {
+        let mut grpc = Grpc::new(ProstCodec::<M, pb::Empty>::default());
+        grpc.server_streaming(ReplyStream(Some(updates)), request)
+            .await
+    }
+
+    /// Every sample published after the call, until the client hangs up or
+    /// the server shuts down.
+    fn updates<T, M>(state: &AppState, topic: &Topic<T>) -> BoxStream<'static, Result<M, Status>>
+    where
+        T: Send + Sync + 'static,
+        M: for<'a> From<&'a Snapshot<T>> + Send + 'static,
+    {
+        let shutdown = state.shutdown.clone();
+        stream::unfold(topic.subscribe(), |mut rx| async move {
+            rx.changed().await.ok()?;
+            let message = M::from(&**rx.borrow_and_update());
+            Some((Ok(message), rx))
+        })
+        .take_until(async move { shutdown.cancelled().await })
+        .boxed()
+    }
+
+    /// Answers a single call with a prepared message.
+    struct Reply<M>(Option<M>);
+
+    /// Answers a single call with a prepared stream.
+    struct ReplyStream<M>(Option<BoxStream<'static, Result<M, Status>>>);
+
+    impl<M: Send + 'static> UnaryService<pb::Empty> for Reply<M> {
+        type Response = M;
+        type Future = Pin<Box<dyn Future<Output = Result<tonic::Response<M>, Status>> + Send>>;
+
+        fn call(&mut self, _: tonic::Request<pb::Empty>) -> Self::Future {
+            let message = self.0.take().expect("Replied twice");
+            async move { Ok(tonic::Response::new(message)) }.boxed()
+        }
+    }
+
+    impl<M: Send + 'static> ServerStreamingService<pb::Empty> for ReplyStream<M> {
+        type Response = M;
+        type ResponseStream = BoxStream<'static, Result<M, Status>>;
+        type Future = Pin<
+            Box<dyn Future<Output = Result<tonic::Response<Self::ResponseStream>, Status>> + Send>,
+        >;
+
+        fn call(&mut self, _: tonic::Request<pb::Empty>) -> Self::Future {
+            let updates = self.0.take().expect("Replied twice");
+            async move { Ok(tonic::Response::new(updates)) }.boxed()
+        }
+    }
+
+    fn into_tonic(request: Request<body::Body>) -> http_1::Request<RequestBody> {
+        let (parts, body) = request.into_parts();
+        let mut request = http_1::Request::new(RequestBody {
+            body,
+            data_done: false,
+        });
+        *request.method_mut() =
+            http_1::Method::from_bytes(parts.method.as_str().as_bytes()).unwrap_or_default();
+        *request.uri_mut() = parts.uri.to_string().parse().unwrap_or_default();
+        *request.version_mut() = http_1::Version::HTTP_2;
+        *request.headers_mut() = headers_to_tonic(&parts.headers);
+        request
+    }
+
+    fn from_tonic(response: http_1::Response<tonic::body::Body>) -> Response<BoxBody> {
+        let (parts, body) = response.into_parts();
+        let mut response = Response::new(body::boxed(ResponseBody {
+            body,
+            trailers: None,
+        }));
+        *response.status_mut() = parts.status.as_u16().try_into().unwrap_or_default();
+        *response.headers_mut() = headers_from_tonic(&parts.headers);
+        response
+    }
+
+    fn headers_to_tonic(headers: &HeaderMap) -> http_1::HeaderMap {
+        headers
+            .iter()
+            .filter_map(|(name, value)| {
+                Some((
+                    http_1::HeaderName::from_bytes(name.as_str().as_bytes()).ok()?,
+                    http_1::HeaderValue::from_bytes(value.as_bytes()).ok()?,
+                ))
+            })
+            .collect()
+    }
+
+    fn headers_from_tonic(headers: &http_1::HeaderMap) -> HeaderMap {
+        headers
+            .iter()
+            .filter_map(|(name, value)| {
+                Some((
+                    name.as_str().parse().ok()?,
+                    value.as_bytes().try_into().ok()?,
+                ))
+            })
+            .collect()
+    }
+
+    /// A hyper request body seen through `http-body` 1.
+    struct RequestBody {
+        body: body::Body,
+        data_done: bool,
+    }
+
+    impl http_body_1::Body for RequestBody {
+        type Data = Bytes;
+        type Error = axum::Error;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<http_body_1::Frame<Bytes>, Self::Error>>> {
+            if !self.data_done {
+                match ready!(Pin::new(&mut self.body).poll_data(cx)) {
+                    Some(data) => {
+                        return Poll::Ready(Some(
+                            data.map(http_body_1::Frame::data).map_err(axum::Error::new),
+                        ))
+                    }
+                    None => self.data_done = true,
+                }
+            }
+            let trailers =
+                ready!(Pin::new(&mut self.body).poll_trailers(cx)).map_err(axum::Error::new)?;
+            Poll::Ready(
+                trailers
+                    .map(|trailers| Ok(http_body_1::Frame::trailers(headers_to_tonic(&trailers)))),
+            )
+        }
+    }
+
+    /// A tonic response body seen through `http-body` 0.4, which asks for
+    /// the trailers separately once the data is done.
+    struct ResponseBody {
+        body: tonic::body::Body,
+        trailers: Option<http_1::HeaderMap>,
+    }
+
+    impl HttpBody for ResponseBody {
+        type Data = Bytes;
+        type Error = Status;
+
+        fn poll_data(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Bytes, Status>>> {
+            loop {
+                let frame = ready!(http_body_1::Body::poll_frame(Pin::new(&mut self.body), cx));
+                let Some(frame) = frame else {
+                    return Poll::Ready(None);
+                };
+                match frame?.into_data() {
+                    Ok(data) => return Poll::Ready(Some(Ok(data))),
+                    Err(frame) => {
+                        if let Ok(trailers) = frame.into_trailers() {
+                            self.trailers = Some(trailers);
+                        }
+                    }
+                }
+            }
+        }
+
+        fn poll_trailers(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<Option<HeaderMap>, Status>> {
+            Poll::Ready(Ok(self
+                .trailers
+                .take()
+                .map(|trailers| headers_from_tonic(&trailers))))
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! Health probes. `/health/live` only tells that the process is serving
+//! requests; `/health/ready` additionally checks that every collector has
+//! produced a sample recently, so a stuck sampler takes the instance out of
+//! rotation. A sampler that is idle on purpose, see `idle.rs`, is ready.
+
+use crate::idle;
+use crate::sampler::Topic;
+use crate::AppState;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use axum_macros::debug_handler;
+use serde::Serialize;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+/// A collector is stale once it missed this many sampling intervals...
+const STALE_AFTER_INTERVALS: u32 = 5;
+/// ...but never sooner than this, since a tick can take a while on busy hosts.
+const MIN_STALE_AGE: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    Ok,
+    /// No sample yet, presumably still starting up.
+    Pending,
+    /// The last sample is older than the staleness limit.
+    Stale,
+    /// Sampling is paused for lack of clients, see `--idle-after-secs`.
+    Idle,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Liveness {
+    pub status: HealthStatus,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CollectorHealth {
+    pub name: &'static str,
+    pub status: HealthStatus,
+    /// Number of samples published so far.
+    pub samples: u64,
+    /// Milliseconds since the last sample, `null` before the first one.
+    pub last_sample_age_ms: Option<u64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Readiness {
+    /// `ok` only when every collector is.
+    pub status: HealthStatus,
+    pub collectors: Vec<CollectorHealth>,
+}
+
+/// `stale_after` is `None` while sampling is idle. Disabled collectors are
+/// left out.
+fn collector<T>(
+    name: &'static str,
+    topic: &Topic<T>,
+    stale_after: Option<Duration>,
+) -> Option<CollectorHealth> {
+    if !topic.is_enabled() {
+        return None;
+    }
+    let snapshot = topic.latest();
+    let age = snapshot.age();
+    let status = match (age, stale_after) {
+        (None, _) => HealthStatus::Pending,
+        (Some(_), None) => HealthStatus::Idle,
+        (Some(age), Some(stale_after)) if age > stale_after => HealthStatus::Stale,
+        (Some(_), Some(_)) => HealthStatus::Ok,
+    };
+    Some(CollectorHealth {
+        name,
+        status,
+        samples: snapshot.seq,
+        last_sample_age_ms: age.map(|age| age.as_millis() as u64),
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/health",
+    tag = "monitoring",
+    security(()),
+    responses((status = 200, description = "The server is up", body = String, example = json!("Ok")))
+)]
+#[debug_handler]
+pub async fn health() -> &'static str {
+    "Ok"
+}
+
+#[utoipa::path(
+    get,
+    path = "/health/live",
+    tag = "monitoring",
+    security(()),
+    responses((status = 200, description = "The process is up", body = Liveness))
+)]
+#[debug_handler]
+pub async fn live() -> Json<Liveness> {
+    Json(Liveness {
+        status: HealthStatus::Ok,
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/health/ready",
+    tag = "monitoring",
+    security(()),
+    responses(
+        (status = 200, description = "Every collector is producing samples, or sampling is idle", body = Readiness),
+        (status = 503, description = "A collector has not sampled yet or stopped sampling", body = Readiness),
+    )
+)]
+#[debug_handler]
+pub async fn ready(State(state): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    let stale_after = (!idle::is_idle(&state))
+        .then(|| (state.config.sample_interval * STALE_AFTER_INTERVALS).max(MIN_STALE_AGE));
+    let collectors: Vec<CollectorHealth> = [
+        collector("cpus", &state.cpus, stale_after),
+        collector("memory", &state.memory, stale_after),
+        collector("processes", &state.processes, stale_after),
+        collector("connections", &state.tcp_connections, stale_after),
+        collector("disks", &state.disks, stale_after),
+        collector("diskio", &state.diskio, stale_after),
+        collector("networks", &state.networks, stale_after),
+        collector("temperatures", &state.temperatures, stale_after),
+        collector("loadavg", &state.loadavg, stale_after),
+        collector("pressure", &state.pressure, stale_after),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    let any = |status| {
+        collectors
+            .iter()
+            .any(|collector| collector.status == status)
+    };
+    let (code, status) = if any(HealthStatus::Stale) {
+        (StatusCode::SERVICE_UNAVAILABLE, HealthStatus::Stale)
+    } else if any(HealthStatus::Pending) {
+        (StatusCode::SERVICE_UNAVAILABLE, HealthStatus::Pending)
+    } else if any(HealthStatus::Idle) {
+        (StatusCode::OK, HealthStatus::Idle)
+    } else {
+        (StatusCode::OK, HealthStatus::Ok)
+    };
+    (code, Json(Readiness { status, collectors }))
+}
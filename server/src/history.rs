@@ -0,0 +1,180 @@
+//! Bounded in-memory history of recent samples, so freshly connected clients
+//! can draw a chart right away.
+
+use crate::encoding::{Encoded, Encoding};
+use crate::models::{CpuInfo, Memory};
+use crate::{api_error, AppError, AppState};
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum_macros::debug_handler;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use utoipa::{IntoParams, ToSchema};
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[aliases(CpuHistoryEntry = HistoryEntry<Vec<CpuInfo>>, MemoryHistoryEntry = HistoryEntry<Memory>)]
+pub struct HistoryEntry<T> {
+    /// Milliseconds since the Unix epoch at which the sample was taken.
+    pub timestamp: u64,
+    pub data: T,
+}
+
+#[derive(Clone)]
+pub struct History<T> {
+    capacity: usize,
+    entries: Arc<Mutex<VecDeque<HistoryEntry<T>>>>,
+}
+
+impl<T: Clone> History<T> {
+    /// Keeps enough entries to cover `retention` when sampling every `interval`.
+    pub fn new(retention: Duration, interval: Duration) -> Self {
+        let capacity = (retention.as_millis() / interval.as_millis().max(1)).max(1) as usize;
+        Self {
+            capacity,
+            entries: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    pub fn push(&self, timestamp: u64, data: T) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(HistoryEntry { timestamp, data });
+    }
+
+    /// Entries from the last `window`, or everything retained when `None`.
+    pub fn since(&self, window: Option<Duration>) -> Vec<HistoryEntry<T>> {
+        let cutoff = window.map_or(0, |window| {
+            now_millis().saturating_sub(window.as_millis() as u64)
+        });
+        self.between(cutoff, u64::MAX)
+    }
+
+    /// Entries with `from <= timestamp <= to`.
+    pub fn between(&self, from: u64, to: u64) -> Vec<HistoryEntry<T>> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .filter(|entry| (from..=to).contains(&entry.timestamp))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Either a trailing window (`seconds`) over the in-memory buffer, or an
+/// absolute `from`/`to` range in epoch milliseconds which is served from the
+/// history database when one is configured.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct HistoryQuery {
+    /// Trailing window in seconds, all retained samples when omitted.
+    seconds: Option<u64>,
+    /// Start of an absolute range, in milliseconds since the epoch.
+    from: Option<u64>,
+    /// End of an absolute range, in milliseconds since the epoch.
+    to: Option<u64>,
+}
+
+impl HistoryQuery {
+    /// The entries asked for from the in-memory buffer only.
+    pub fn in_memory<T: Clone>(&self, history: &History<T>) -> Vec<HistoryEntry<T>> {
+        if self.from.is_none() && self.to.is_none() {
+            return history.since(self.seconds.map(Duration::from_secs));
+        }
+        history.between(self.from.unwrap_or(0), self.to.unwrap_or(u64::MAX))
+    }
+}
+
+type HistoryResult<T> = Result<Encoded<Vec<HistoryEntry<T>>>, AppError>;
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/cpus/history",
+    tag = "cpus",
+    params(HistoryQuery),
+    responses(
+        (status = 200, description = "Per-core samples, oldest first", body = [CpuHistoryEntry], content_type = ["application/json", "application/msgpack"]),
+        (status = 500, description = "The history database query failed", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_cpu_history(
+    State(state): State<AppState>,
+    encoding: Encoding,
+    Query(query): Query<HistoryQuery>,
+) -> HistoryResult<Vec<CpuInfo>> {
+    query_history(&state, state.cpus.history(), "cpus", query, encoding).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/memory/history",
+    tag = "memory",
+    params(HistoryQuery),
+    responses(
+        (status = 200, description = "Memory samples, oldest first", body = [MemoryHistoryEntry], content_type = ["application/json", "application/msgpack"]),
+        (status = 500, description = "The history database query failed", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_memory_history(
+    State(state): State<AppState>,
+    encoding: Encoding,
+    Query(query): Query<HistoryQuery>,
+) -> HistoryResult<Memory> {
+    query_history(&state, state.memory.history(), "memory", query, encoding).await
+}
+
+async fn query_history<T>(
+    state: &AppState,
+    history: &History<T>,
+    topic: &'static str,
+    query: HistoryQuery,
+    encoding: Encoding,
+) -> HistoryResult<T>
+where
+    T: Clone + Serialize + DeserializeOwned + Send + 'static,
+{
+    let encoded = |entries| Encoded(encoding, entries);
+    if query.from.is_none() && query.to.is_none() {
+        return Ok(encoded(
+            history.since(query.seconds.map(Duration::from_secs)),
+        ));
+    }
+    let from = query.from.unwrap_or(0);
+    let to = query.to.unwrap_or(u64::MAX);
+    range(state, history, topic, from, to).await.map(encoded)
+}
+
+/// Entries with `from <= timestamp <= to`, from the history database when
+/// one is configured and from the in-memory buffer otherwise.
+pub async fn range<T>(
+    state: &AppState,
+    history: &History<T>,
+    topic: &'static str,
+    from: u64,
+    to: u64,
+) -> Result<Vec<HistoryEntry<T>>, AppError>
+where
+    T: Clone + DeserializeOwned + Send + 'static,
+{
+    match &state.history_db {
+        Some(db) => db.query(topic, from, to).await.map_err(|err| {
+            api_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("History query failed: {err}"),
+            )
+        }),
+        None => Ok(history.between(from, to)),
+    }
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
@@ -0,0 +1,576 @@
+//! Optional persistent history in SQLite, enabled with `--history-db`.
+//!
+//! Samples are appended by a dedicated writer thread so the sampler never
+//! waits on disk; rows older than the retention period are pruned
+//! periodically.
+//!
+//! The same thread rolls the samples up into coarser tiers, by default the
+//! mean of every minute and of every hour, each kept for a retention of its
+//! own. A range query reads the finest tier that still reaches back to its
+//! start, so months of history stay queryable while raw samples are only
+//! kept for days.
+
+use crate::format;
+use crate::history::{now_millis, HistoryEntry};
+use rusqlite::{params, Connection};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+/// Samples still queued for the writer when a bucket ends have this long to
+/// arrive before the bucket is rolled up.
+const ROLLUP_DELAY: Duration = Duration::from_secs(10);
+/// Buckets rolled up per transaction, so catching up on a long backlog
+/// doesn't keep new samples waiting for long.
+const ROLLUP_BATCH: u64 = 360;
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS samples (
+        topic TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS samples_topic_timestamp ON samples (topic, timestamp);
+    CREATE TABLE IF NOT EXISTS rollups (
+        resolution INTEGER NOT NULL,
+        topic TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        data TEXT NOT NULL,
+        PRIMARY KEY (resolution, topic, timestamp)
+    );";
+
+/// A tier of means over every `resolution`, kept for `retention`.
+#[derive(Debug, Clone, Copy)]
+pub struct Rollup {
+    pub resolution: Duration,
+    pub retention: Duration,
+}
+
+impl Rollup {
+    fn resolution_ms(&self) -> u64 {
+        self.resolution.as_millis() as u64
+    }
+}
+
+struct Record {
+    topic: &'static str,
+    timestamp: u64,
+    data: String,
+}
+
+pub struct HistoryDb {
+    writer: mpsc::Sender<Record>,
+    reader: Arc<Mutex<Connection>>,
+    retention: Duration,
+    /// Finest first.
+    rollups: Vec<Rollup>,
+}
+
+impl HistoryDb {
+    pub fn open(
+        path: &Path,
+        retention: Duration,
+        mut rollups: Vec<Rollup>,
+    ) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             PRAGMA synchronous = NORMAL;",
+        )?;
+        conn.execute_batch(SCHEMA)?;
+        let reader = Connection::open(path)?;
+        let (writer, rx) = mpsc::channel();
+        rollups.sort_by_key(|rollup| rollup.resolution);
+        let writer_rollups = rollups.clone();
+        thread::spawn(move || write_loop(conn, rx, retention, writer_rollups));
+        Ok(Self {
+            writer,
+            reader: Arc::new(Mutex::new(reader)),
+            retention,
+            rollups,
+        })
+    }
+
+    /// Resolution in milliseconds of the finest rollup still holding
+    /// `from`, or `None` while the raw samples do. Past every retention the
+    /// longest kept tier is the best there is.
+    fn tier_for(&self, from: u64) -> Option<u64> {
+        let now = now_millis();
+        let holds = |retention: Duration| from >= now.saturating_sub(retention.as_millis() as u64);
+        if holds(self.retention) {
+            return None;
+        }
+        self.rollups
+            .iter()
+            .find(|rollup| holds(rollup.retention))
+            .or_else(|| self.rollups.iter().max_by_key(|rollup| rollup.retention))
+            .filter(|rollup| rollup.retention > self.retention)
+            .map(Rollup::resolution_ms)
+    }
+
+    pub fn record<T: Serialize>(&self, topic: &'static str, timestamp: u64, data: &T) {
+        let data = serde_json::to_string(data).unwrap();
+        self.writer
+            .send(Record {
+                topic,
+                timestamp,
+                data,
+            })
+            .unwrap_or_default();
+    }
+
+    /// Samples of `topic` with `from <= timestamp <= to`, oldest first. Once
+    /// `from` is older than the raw samples are kept, these are the means of
+    /// a rollup tier stamped with the start of their bucket.
+    pub async fn query<T>(
+        &self,
+        topic: &'static str,
+        from: u64,
+        to: u64,
+    ) -> rusqlite::Result<Vec<HistoryEntry<T>>>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let reader = self.reader.clone();
+        let tier = self.tier_for(from);
+        tokio::task::spawn_blocking(move || {
+            let conn = reader.lock().unwrap();
+            let row = |row: &rusqlite::Row| {
+                let timestamp: i64 = row.get(0)?;
+                let data: String = row.get(1)?;
+                Ok((timestamp, data))
+            };
+            let mut stmt;
+            let rows = match tier {
+                None => {
+                    stmt = conn.prepare_cached(
+                        "SELECT timestamp, data FROM samples
+                         WHERE topic = ?1 AND timestamp BETWEEN ?2 AND ?3
+                         ORDER BY timestamp",
+                    )?;
+                    stmt.query_map(params![topic, to_sql(from), to_sql(to)], row)?
+                }
+                Some(resolution) => {
+                    stmt = conn.prepare_cached(
+                        "SELECT timestamp, data FROM rollups
+                         WHERE resolution = ?1 AND topic = ?2 AND timestamp BETWEEN ?3 AND ?4
+                         ORDER BY timestamp",
+                    )?;
+                    let params = params![to_sql(resolution), topic, to_sql(from), to_sql(to)];
+                    stmt.query_map(params, row)?
+                }
+            };
+            let mut entries = vec![];
+            for row in rows {
+                let (timestamp, data) = row?;
+                let data = serde_json::from_str(&data).map_err(|err| {
+                    rusqlite::Error::FromSqlConversionFailure(
+                        1,
+                        rusqlite::types::Type::Text,
+                        Box::new(err),
+                    )
+                })?;
+                entries.push(HistoryEntry {
+                    timestamp: timestamp as u64,
+                    data,
+                });
+            }
+            Ok(entries)
+        })
+        .await
+        .expect("History query task panicked")
+    }
+}
+
+fn to_sql(timestamp: u64) -> i64 {
+    timestamp.min(i64::MAX as u64) as i64
+}
+
+fn write_loop(
+    mut conn: Connection,
+    rx: mpsc::Receiver<Record>,
+    retention: Duration,
+    rollups: Vec<Rollup>,
+) {
+    let mut last_prune = Instant::now() - PRUNE_INTERVAL;
+    // Topics written since startup, the ones worth rolling up.
+    let mut topics = BTreeSet::new();
+    while let Ok(record) = rx.recv() {
+        // Everything that queued up while we were busy goes into one transaction.
+        let records: Vec<Record> = std::iter::once(record).chain(rx.try_iter()).collect();
+        topics.extend(records.iter().map(|record| record.topic));
+        if let Err(err) = insert(&mut conn, &records) {
+            tracing::error!("Failed to write history: {err}");
+        }
+        if last_prune.elapsed() >= PRUNE_INTERVAL {
+            last_prune = Instant::now();
+            // Rolled up first, so nothing is pruned before it's been summarized.
+            if let Err(err) = roll_up(&mut conn, &topics, &rollups) {
+                tracing::error!("Failed to roll up history: {err}");
+            }
+            if let Err(err) = prune(&conn, retention, &rollups) {
+                tracing::error!("Failed to prune history: {err}");
+            }
+        }
+    }
+}
+
+fn prune(conn: &Connection, retention: Duration, rollups: &[Rollup]) -> rusqlite::Result<()> {
+    let cutoff =
+        |retention: Duration| to_sql(now_millis().saturating_sub(retention.as_millis() as u64));
+    conn.execute(
+        "DELETE FROM samples WHERE timestamp < ?1",
+        params![cutoff(retention)],
+    )?;
+    for rollup in rollups {
+        conn.execute(
+            "DELETE FROM rollups WHERE resolution = ?1 AND timestamp < ?2",
+            params![to_sql(rollup.resolution_ms()), cutoff(rollup.retention)],
+        )?;
+    }
+    // Tiers that were configured before but no longer are.
+    let resolutions: Vec<String> = rollups
+        .iter()
+        .map(|rollup| rollup.resolution_ms().to_string())
+        .collect();
+    conn.execute(
+        &format!(
+            "DELETE FROM rollups WHERE resolution NOT IN ({})",
+            resolutions.join(", ")
+        ),
+        [],
+    )?;
+    Ok(())
+}
+
+/// Fills every tier up to the last bucket that has ended, each from the
+/// next finer one.
+fn roll_up(
+    conn: &mut Connection,
+    topics: &BTreeSet<&'static str>,
+    rollups: &[Rollup],
+) -> rusqlite::Result<()> {
+    let ready = now_millis().saturating_sub(ROLLUP_DELAY.as_millis() as u64);
+    let mut source = None;
+    for rollup in rollups {
+        let resolution = rollup.resolution_ms();
+        let end = ready - ready % resolution;
+        for topic in topics {
+            // Continues after the newest bucket, or from the oldest source row.
+            let newest: Option<i64> = conn.query_row(
+                "SELECT MAX(timestamp) FROM rollups WHERE resolution = ?1 AND topic = ?2",
+                params![to_sql(resolution), topic],
+                |row| row.get(0),
+            )?;
+            let start = match newest {
+                Some(newest) => newest as u64 + resolution,
+                None => match oldest(conn, source, topic)? {
+                    Some(oldest) => oldest - oldest % resolution,
+                    None => continue,
+                },
+            };
+            let mut from = start;
+            while from < end {
+                let to = (from + ROLLUP_BATCH * resolution).min(end);
+                roll_up_range(conn, source, resolution, topic, from, to)?;
+                from = to;
+            }
+        }
+        source = Some(resolution);
+    }
+    Ok(())
+}
+
+/// Timestamp of the oldest row of `topic` in the samples, or in the rollup
+/// tier of `source` resolution.
+fn oldest(conn: &Connection, source: Option<u64>, topic: &str) -> rusqlite::Result<Option<u64>> {
+    let oldest: Option<i64> = match source {
+        None => conn.query_row(
+            "SELECT MIN(timestamp) FROM samples WHERE topic = ?1",
+            params![topic],
+            |row| row.get(0),
+        )?,
+        Some(resolution) => conn.query_row(
+            "SELECT MIN(timestamp) FROM rollups WHERE resolution = ?1 AND topic = ?2",
+            params![to_sql(resolution), topic],
+            |row| row.get(0),
+        )?,
+    };
+    Ok(oldest.map(|oldest| oldest as u64))
+}
+
+/// Writes the mean of every bucket of `resolution` with rows in
+/// `from <= timestamp < to`.
+fn roll_up_range(
+    conn: &mut Connection,
+    source: Option<u64>,
+    resolution: u64,
+    topic: &str,
+    from: u64,
+    to: u64,
+) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut select = match source {
+            None => tx.prepare_cached(
+                "SELECT timestamp, data FROM samples
+                 WHERE topic = ?1 AND timestamp >= ?2 AND timestamp < ?3
+                 ORDER BY timestamp",
+            )?,
+            Some(_) => tx.prepare_cached(
+                "SELECT timestamp, data FROM rollups
+                 WHERE resolution = ?4 AND topic = ?1 AND timestamp >= ?2 AND timestamp < ?3
+                 ORDER BY timestamp",
+            )?,
+        };
+        let mut insert = tx.prepare_cached(
+            "INSERT OR REPLACE INTO rollups (resolution, topic, timestamp, data)
+             VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        let mut write = |bucket: u64, mean: &Mean| {
+            insert.execute(params![
+                to_sql(resolution),
+                topic,
+                to_sql(bucket),
+                mean.sample().to_string()
+            ])
+        };
+        let mut rows = match source {
+            None => select.query(params![topic, to_sql(from), to_sql(to)])?,
+            Some(source) => {
+                select.query(params![topic, to_sql(from), to_sql(to), to_sql(source)])?
+            }
+        };
+        let mut current: Option<(u64, Mean)> = None;
+        while let Some(row) = rows.next()? {
+            let timestamp = row.get::<_, i64>(0)? as u64;
+            // A row that no longer parses is left out of the mean.
+            let Ok(data) = serde_json::from_str::<Value>(&row.get::<_, String>(1)?) else {
+                continue;
+            };
+            let bucket = timestamp - timestamp % resolution;
+            match &mut current {
+                Some((start, mean)) if *start == bucket => mean.add(&data),
+                _ => {
+                    if let Some((start, mean)) = current.replace((bucket, Mean::new(&data))) {
+                        write(start, &mean)?;
+                    }
+                }
+            }
+        }
+        if let Some((start, mean)) = current {
+            write(start, &mean)?;
+        }
+    }
+    tx.commit()
+}
+
+/// Running mean of samples of the same shape: numbers are averaged field by
+/// field and element by element, anything else keeps its latest value.
+/// Integers stay integers, so a mean deserializes like a sample; see
+/// `sample` for the human-readable sizes.
+enum Mean {
+    Number { sum: f64, count: u64, integer: bool },
+    Array(Vec<Mean>),
+    Object(Vec<(String, Mean)>),
+    Other(Value),
+}
+
+impl Mean {
+    fn new(value: &Value) -> Self {
+        match value {
+            Value::Number(number) => Mean::Number {
+                sum: number.as_f64().unwrap_or_default(),
+                count: 1,
+                integer: !number.is_f64(),
+            },
+            Value::Array(items) => Mean::Array(items.iter().map(Mean::new).collect()),
+            Value::Object(fields) => Mean::Object(
+                fields
+                    .iter()
+                    .map(|(name, value)| (name.clone(), Mean::new(value)))
+                    .collect(),
+            ),
+            value => Mean::Other(value.clone()),
+        }
+    }
+
+    fn add(&mut self, value: &Value) {
+        match (self, value) {
+            (
+                Mean::Number {
+                    sum,
+                    count,
+                    integer,
+                },
+                Value::Number(number),
+            ) => {
+                *sum += number.as_f64().unwrap_or_default();
+                *count += 1;
+                *integer &= !number.is_f64();
+            }
+            // A missing reading doesn't count towards the mean.
+            (Mean::Number { .. }, _) => {}
+            (Mean::Array(means), Value::Array(items)) => {
+                for (index, item) in items.iter().enumerate() {
+                    match means.get_mut(index) {
+                        Some(mean) => mean.add(item),
+                        None => means.push(Mean::new(item)),
+                    }
+                }
+            }
+            (Mean::Object(means), Value::Object(fields)) => {
+                for (name, value) in fields {
+                    match means.iter_mut().find(|(field, _)| field == name) {
+                        Some((_, mean)) => mean.add(value),
+                        None => means.push((name.clone(), Mean::new(value))),
+                    }
+                }
+            }
+            (mean, value) => *mean = Mean::new(value),
+        }
+    }
+
+    /// The mean as a sample, with every human-readable size rendered from
+    /// its averaged `*_bytes` rather than kept from the latest sample.
+    fn sample(&self) -> Value {
+        format::rehumanize(self.value())
+    }
+
+    fn value(&self) -> Value {
+        match self {
+            Mean::Number {
+                sum,
+                count,
+                integer: true,
+            } => Value::from((sum / *count as f64).round() as i64),
+            Mean::Number { sum, count, .. } => Value::from(sum / *count as f64),
+            Mean::Array(means) => means.iter().map(Mean::value).collect(),
+            Mean::Object(means) => Value::Object(
+                means
+                    .iter()
+                    .map(|(name, mean)| (name.clone(), mean.value()))
+                    .collect::<Map<_, _>>(),
+            ),
+            Mean::Other(value) => value.clone(),
+        }
+    }
+}
+
+fn insert(conn: &mut Connection, records: &[Record]) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut stmt =
+            tx.prepare_cached("INSERT INTO samples (topic, timestamp, data) VALUES (?1, ?2, ?3)")?;
+        for record in records {
+            stmt.execute(params![record.topic, to_sql(record.timestamp), record.data])?;
+        }
+    }
+    tx.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn mean(samples: &[Value]) -> Value {
+        let mut mean = Mean::new(&samples[0]);
+        for sample in &samples[1..] {
+            mean.add(sample);
+        }
+        mean.sample()
+    }
+
+    #[test]
+    fn averages_samples_of_the_same_shape() {
+        let cases = [
+            // Integers round and stay integers, floats don't.
+            (vec![json!(1), json!(2)], json!(2)),
+            (vec![json!(1), json!(2), json!(2)], json!(2)),
+            (vec![json!(1.0), json!(2.0)], json!(1.5)),
+            (vec![json!(1), json!(2.0)], json!(1.5)),
+            // Missing readings and fields don't count.
+            (vec![json!(4), json!(null), json!(2)], json!(3)),
+            (
+                vec![json!({"a": 1, "b": 2}), json!({"a": 3}), json!({"c": 5})],
+                json!({"a": 2, "b": 2, "c": 5}),
+            ),
+            // Arrays are averaged by index, whatever their length.
+            (vec![json!([1, 2]), json!([3])], json!([2, 2])),
+            (vec![json!([1]), json!([3, 5])], json!([2, 5])),
+            // Anything else keeps its latest value.
+            (
+                vec![json!({"s": "a"}), json!({"s": "b"})],
+                json!({"s": "b"}),
+            ),
+        ];
+        for (samples, expected) in cases {
+            assert_eq!(mean(&samples), expected, "{samples:?}");
+        }
+    }
+
+    #[test]
+    fn renders_human_sizes_from_the_mean() {
+        let samples = [
+            json!({"used_bytes": 1000, "used": "1.00 KB", "disks": [{"size_bytes": 4000, "size": "4.00 KB"}]}),
+            json!({"used_bytes": 3000, "used": "3.00 KB", "disks": [{"size_bytes": 8000, "size": "8.00 KB"}]}),
+        ];
+        assert_eq!(
+            mean(&samples),
+            json!({"used_bytes": 2000, "used": "2.00 KB", "disks": [{"size_bytes": 6000, "size": "6.00 KB"}]})
+        );
+    }
+
+    #[test]
+    fn rolls_up_into_buckets_of_each_tier() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(SCHEMA).unwrap();
+        let samples: Vec<Record> = [
+            (0, 1),
+            (30_000, 3),
+            (60_000, 10),
+            (119_999, 20),
+            (180_000, 7),
+        ]
+        .into_iter()
+        .map(|(timestamp, value)| Record {
+            topic: "load",
+            timestamp,
+            data: json!({ "value": value }).to_string(),
+        })
+        .collect();
+        insert(&mut conn, &samples).unwrap();
+        let rollups = |conn: &Connection, resolution: u64| -> Vec<(u64, Value)> {
+            let mut stmt = conn
+                .prepare(
+                    "SELECT timestamp, data FROM rollups WHERE resolution = ?1 ORDER BY timestamp",
+                )
+                .unwrap();
+            stmt.query_map(params![to_sql(resolution)], |row| {
+                let data: String = row.get(1)?;
+                Ok((
+                    row.get::<_, i64>(0)? as u64,
+                    serde_json::from_str(&data).unwrap(),
+                ))
+            })
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+        };
+        // The sample at the end of the range is left for the next one.
+        roll_up_range(&mut conn, None, 60_000, "load", 0, 180_000).unwrap();
+        assert_eq!(
+            rollups(&conn, 60_000),
+            vec![(0, json!({"value": 2})), (60_000, json!({"value": 15}))]
+        );
+        roll_up_range(&mut conn, Some(60_000), 3_600_000, "load", 0, 3_600_000).unwrap();
+        assert_eq!(rollups(&conn, 3_600_000), vec![(0, json!({"value": 9}))]);
+    }
+}
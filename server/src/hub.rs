@@ -0,0 +1,525 @@
+//! Hub side of the agent/hub mode: instances started with `--agent` push
+//! their latest samples here (see `agent.rs`), remotes given with
+//! `--remote` are polled instead (see `federation.rs`), and `/api/v1/hosts`
+//! serves both, so several machines can be watched through one API and one
+//! dashboard. Every instance accepts reports; only the latest one of each
+//! host is kept, in memory.
+//!
+//! Other `/api/v1` paths are forwarded to remotes with the hub's own
+//! credentials, so only the reads of sampled data in `FORWARDED` are open to
+//! every caller; anything else, such as the audit log or a process's open
+//! files, needs the admin role on the hub.
+
+use crate::auth::{Principal, Role};
+use crate::models::{
+    CpuInfo, DiskInfo, LoadAverage, Memory, NetworkInfo, ProcessInfo, SystemInfo, TemperatureInfo,
+};
+use crate::{api_error, AppError, AppState};
+use axum::extract::{ConnectInfo, Path, RawQuery, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use axum_macros::debug_handler;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+/// Reports an agent may miss, or polls a remote may fail, before the host
+/// is listed as offline.
+const MISSED_REPORTS: u32 = 3;
+const MAX_HOST_ID_LEN: usize = 128;
+const PROXY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Paths forwarded to remotes for any caller, `*` matching one segment.
+const FORWARDED: &[&str] = &[
+    "system",
+    "overview",
+    "cpus",
+    "cpus/summary",
+    "cpus/history",
+    "cpus/topology",
+    "memory",
+    "memory/history",
+    "memory/detail",
+    "processes",
+    "processes/top",
+    "watch",
+    "users",
+    "containers",
+    "gpus",
+    "power",
+    "disks",
+    "diskio",
+    "networks",
+    "temperatures",
+    "loadavg",
+    "pressure",
+    "stats",
+    "alerts/active",
+    "version",
+    "collectors",
+    "collectors/*",
+    "collectors/*/history",
+    "custom/*",
+];
+
+/// Everything an agent pushes at once.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct HostReport {
+    /// Milliseconds since the epoch, by the agent's clock or when a remote
+    /// was polled.
+    pub timestamp: u64,
+    /// Milliseconds until the next report or poll.
+    pub interval_ms: u64,
+    pub system: SystemInfo,
+    pub cpus: Vec<CpuInfo>,
+    pub memory: Memory,
+    pub loadavg: LoadAverage,
+    pub processes: Vec<ProcessInfo>,
+    pub disks: Vec<DiskInfo>,
+    pub networks: Vec<NetworkInfo>,
+    pub temperatures: Vec<TemperatureInfo>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum HostSource {
+    /// Pushes its reports with `--agent`.
+    Agent,
+    /// Polled by this server, see `federation.rs`.
+    Remote,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct HostSummary {
+    pub id: String,
+    pub source: HostSource,
+    /// Address the latest report came from, or the URL of a remote.
+    pub address: String,
+    /// False once the agent missed a few reports in a row, or while a
+    /// remote can't be polled.
+    pub online: bool,
+    /// Why the latest poll of a remote failed.
+    pub error: Option<String>,
+    /// The remaining fields are unset until the host first reported.
+    pub hostname: Option<String>,
+    /// `timestamp` of the latest report.
+    pub timestamp: Option<u64>,
+    /// Milliseconds since the latest report was received.
+    pub age_ms: Option<u64>,
+    /// Mean over all cores, in percent.
+    pub cpu_usage: Option<f32>,
+    pub memory_used_percent: Option<f64>,
+    pub process_count: Option<usize>,
+    /// The host's `--label`s, empty until it first reported.
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Fields of a host's latest report served by `/api/v1/hosts/{id}/{path}`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HostTopic {
+    System,
+    Cpus,
+    Memory,
+    Loadavg,
+    Processes,
+    Disks,
+    Networks,
+    Temperatures,
+}
+
+/// A process-viewer instance polled by this server.
+#[derive(Debug, Clone)]
+pub struct Remote {
+    pub url: reqwest::Url,
+    pub api_key: Option<String>,
+}
+
+impl Remote {
+    /// `path` under the remote's `/api/v1`.
+    pub fn endpoint(&self, path: &str) -> reqwest::Url {
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .expect("Remote URL can't be a base")
+            .pop_if_empty()
+            .extend(["api", "v1"])
+            .extend(path.split('/'));
+        url
+    }
+
+    pub fn get(&self, client: &reqwest::Client, url: reqwest::Url) -> reqwest::RequestBuilder {
+        let request = client.get(url);
+        match &self.api_key {
+            Some(api_key) => request.bearer_auth(api_key),
+            None => request,
+        }
+    }
+}
+
+enum Source {
+    Agent(SocketAddr),
+    Remote(Remote),
+}
+
+struct Host {
+    source: Source,
+    report: Option<Arc<HostReport>>,
+    received: Option<Instant>,
+    error: Option<String>,
+}
+
+impl Host {
+    fn summary(&self, id: &str) -> HostSummary {
+        let (source, address) = match &self.source {
+            Source::Agent(address) => (HostSource::Agent, address.to_string()),
+            Source::Remote(remote) => (HostSource::Remote, remote.url.to_string()),
+        };
+        let report = self.report.as_deref();
+        let age = self.received.map(|received| received.elapsed());
+        let online = match (report, age) {
+            (Some(report), Some(age)) => {
+                self.error.is_none()
+                    && age <= Duration::from_millis(report.interval_ms) * MISSED_REPORTS
+            }
+            _ => false,
+        };
+        HostSummary {
+            id: id.to_owned(),
+            source,
+            address,
+            online,
+            error: self.error.clone(),
+            hostname: report.and_then(|report| report.system.hostname.clone()),
+            timestamp: report.map(|report| report.timestamp),
+            age_ms: age.map(|age| age.as_millis() as u64),
+            cpu_usage: report.map(|report| {
+                let cores = report.cpus.len().max(1) as f32;
+                report.cpus.iter().map(|cpu| cpu.cpu_usage).sum::<f32>() / cores
+            }),
+            memory_used_percent: report.map(|report| {
+                let memory = &report.memory;
+                if memory.total_memory_bytes > 0 {
+                    memory.used_memory_bytes as f64 / memory.total_memory_bytes as f64 * 100f64
+                } else {
+                    0f64
+                }
+            }),
+            process_count: report.map(|report| report.processes.len()),
+            labels: report
+                .map(|report| report.system.labels.clone())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Latest report of every host that pushed one or is polled, by host id.
+#[derive(Clone)]
+pub struct HostRegistry {
+    hosts: Arc<Mutex<BTreeMap<String, Host>>>,
+    /// Forwards requests to remotes beyond their latest report.
+    client: reqwest::Client,
+}
+
+impl HostRegistry {
+    pub fn new() -> Self {
+        Self {
+            hosts: Arc::default(),
+            client: reqwest::Client::builder()
+                .timeout(PROXY_TIMEOUT)
+                .build()
+                .expect("Failed to build proxy client"),
+        }
+    }
+
+    /// Lists a remote before its first poll.
+    pub fn add_remote(&self, name: &str, remote: Remote) {
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts.insert(
+            name.to_owned(),
+            Host {
+                source: Source::Remote(remote),
+                report: None,
+                received: None,
+                error: None,
+            },
+        );
+    }
+
+    /// Records the outcome of polling a remote; a failure keeps its previous
+    /// report around.
+    pub fn update_remote(&self, name: &str, result: Result<HostReport, String>) {
+        let mut hosts = self.hosts.lock().unwrap();
+        let Some(host) = hosts.get_mut(name) else {
+            return;
+        };
+        match result {
+            Ok(report) => {
+                if host.error.take().is_some() {
+                    tracing::info!(host = %name, "Remote is reachable again");
+                }
+                host.report = Some(Arc::new(report));
+                host.received = Some(Instant::now());
+            }
+            Err(err) => {
+                // Logged once per outage rather than every poll.
+                if host.error.is_none() {
+                    tracing::warn!(host = %name, %err, "Failed to poll remote");
+                }
+                host.error = Some(err);
+            }
+        }
+    }
+
+    fn get(&self, id: &str) -> Result<Arc<HostReport>, AppError> {
+        let hosts = self.hosts.lock().unwrap();
+        let host = hosts.get(id).ok_or_else(|| not_found(id))?;
+        host.report.clone().ok_or_else(|| {
+            api_error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("Host {id:?} hasn't reported yet"),
+            )
+        })
+    }
+
+    fn remote(&self, id: &str) -> Result<Option<Remote>, AppError> {
+        let hosts = self.hosts.lock().unwrap();
+        match &hosts.get(id).ok_or_else(|| not_found(id))?.source {
+            Source::Remote(remote) => Ok(Some(remote.clone())),
+            Source::Agent(_) => Ok(None),
+        }
+    }
+}
+
+fn not_found(id: &str) -> AppError {
+    api_error(StatusCode::NOT_FOUND, format!("No host with id {id:?}"))
+}
+
+fn remote_conflict(id: &str) -> AppError {
+    api_error(
+        StatusCode::CONFLICT,
+        format!("Host {id:?} is a configured remote"),
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/hosts",
+    tag = "hosts",
+    responses((status = 200, description = "Every host that reported, ordered by id", body = [HostSummary]))
+)]
+#[debug_handler]
+pub async fn list_hosts(State(state): State<AppState>) -> Json<Vec<HostSummary>> {
+    let hosts = state.hosts.hosts.lock().unwrap();
+    Json(hosts.iter().map(|(id, host)| host.summary(id)).collect())
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/hosts/{id}",
+    tag = "hosts",
+    params(("id" = String, Path, description = "Host id, the agent's hostname unless set with --host-id")),
+    request_body = HostReport,
+    responses(
+        (status = 204, description = "The report replaced the host's previous one"),
+        (status = 400, description = "Invalid host id", body = ErrorResponse),
+        (status = 403, description = "Requires the operator role, or the server is read-only", body = ErrorResponse),
+        (status = 409, description = "The id belongs to a remote", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn put_host(
+    State(state): State<AppState>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Path(id): Path<String>,
+    Json(report): Json<HostReport>,
+) -> Result<StatusCode, AppError> {
+    if id.is_empty() || id.len() > MAX_HOST_ID_LEN {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("A host id must be 1 to {MAX_HOST_ID_LEN} bytes long"),
+        ));
+    }
+    let mut hosts = state.hosts.hosts.lock().unwrap();
+    match hosts.get(&id).map(|host| &host.source) {
+        Some(Source::Remote(_)) => return Err(remote_conflict(&id)),
+        Some(Source::Agent(_)) => {}
+        None => tracing::info!(host = %id, %address, "Agent reported for the first time"),
+    }
+    hosts.insert(
+        id,
+        Host {
+            source: Source::Agent(address),
+            report: Some(Arc::new(report)),
+            received: Some(Instant::now()),
+            error: None,
+        },
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/hosts/{id}",
+    tag = "hosts",
+    params(("id" = String, Path, description = "Host id")),
+    responses(
+        (status = 200, description = "The host's latest report", body = HostReport),
+        (status = 404, description = "No host with this id", body = ErrorResponse),
+        (status = 503, description = "The host hasn't reported yet", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_host(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<HostReport>, AppError> {
+    let report = state.hosts.get(&id)?;
+    Ok(Json((*report).clone()))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/hosts/{id}",
+    tag = "hosts",
+    params(("id" = String, Path, description = "Host id")),
+    responses(
+        (status = 204, description = "The host was forgotten until it reports again"),
+        (status = 403, description = "Requires the operator role, or the server is read-only", body = ErrorResponse),
+        (status = 404, description = "No host with this id", body = ErrorResponse),
+        (status = 409, description = "Remotes can't be removed", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn delete_host(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let mut hosts = state.hosts.hosts.lock().unwrap();
+    match hosts.get(&id).map(|host| &host.source) {
+        Some(Source::Agent(_)) => {
+            hosts.remove(&id);
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Some(Source::Remote(_)) => Err(remote_conflict(&id)),
+        None => Err(not_found(&id)),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/hosts/{id}/{path}",
+    tag = "hosts",
+    params(
+        ("id" = String, Path, description = "Host id"),
+        ("path" = String, Path, description = "One of `system`, `cpus`, `memory`, `loadavg`, `processes`, `disks`, `networks` and `temperatures`, served from the latest report; on remotes another `/api/v1` path, forwarded with its query"),
+    ),
+    responses(
+        (status = 200, description = "Shaped like the endpoint of the same name", body = Object),
+        (status = 400, description = "The path has empty, `.` or `..` segments", body = ErrorResponse),
+        (status = 403, description = "Forwarding this path requires the admin role", body = ErrorResponse),
+        (status = 404, description = "No host with this id, or a path only remotes forward", body = ErrorResponse),
+        (status = 502, description = "The remote couldn't be reached", body = ErrorResponse),
+        (status = 503, description = "The host hasn't reported yet", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_host_path(
+    State(state): State<AppState>,
+    Path((id, path)): Path<(String, String)>,
+    RawQuery(query): RawQuery,
+    principal: Option<Extension<Principal>>,
+) -> Result<Response, AppError> {
+    let topic = serde_json::from_value::<HostTopic>(Value::String(path.clone()));
+    if let Ok(topic) = topic {
+        let report = state.hosts.get(&id)?;
+        let value = match topic {
+            HostTopic::System => serde_json::to_value(&report.system),
+            HostTopic::Cpus => serde_json::to_value(&report.cpus),
+            HostTopic::Memory => serde_json::to_value(&report.memory),
+            HostTopic::Loadavg => serde_json::to_value(&report.loadavg),
+            HostTopic::Processes => serde_json::to_value(&report.processes),
+            HostTopic::Disks => serde_json::to_value(&report.disks),
+            HostTopic::Networks => serde_json::to_value(&report.networks),
+            HostTopic::Temperatures => serde_json::to_value(&report.temperatures),
+        };
+        return Ok(Json(value.unwrap()).into_response());
+    }
+    let Some(remote) = state.hosts.remote(&id)? else {
+        return Err(api_error(
+            StatusCode::NOT_FOUND,
+            format!("Only the latest report of agent {id:?} is served"),
+        ));
+    };
+    if path
+        .split('/')
+        .any(|segment| matches!(segment, "" | "." | ".."))
+    {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "Empty, . and .. path segments aren't forwarded",
+        ));
+    }
+    let admin = principal.is_some_and(|principal| principal.role >= Role::Admin);
+    if !admin && !forwarded(&path) {
+        return Err(api_error(
+            StatusCode::FORBIDDEN,
+            format!("Forwarding {path} requires the admin role"),
+        ));
+    }
+    let mut url = remote.endpoint(&path);
+    url.set_query(query.as_deref());
+    let bad_gateway = |err: reqwest::Error| {
+        api_error(
+            StatusCode::BAD_GATEWAY,
+            format!("Failed to reach remote {id:?}: {err}"),
+        )
+    };
+    let response = remote
+        .get(&state.hosts.client, url)
+        .send()
+        .await
+        .map_err(bad_gateway)?;
+    let status = response.status();
+    let content_type = response.headers().get(header::CONTENT_TYPE).cloned();
+    let body = response.bytes().await.map_err(bad_gateway)?;
+    let mut response = (status, body).into_response();
+    if let Some(content_type) = content_type {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_TYPE, content_type);
+    }
+    Ok(response)
+}
+
+/// Whether `path` matches one of `FORWARDED`.
+fn forwarded(path: &str) -> bool {
+    FORWARDED.iter().any(|pattern| {
+        let mut segments = path.split('/');
+        pattern.split('/').all(|expected| {
+            segments
+                .next()
+                .is_some_and(|segment| expected == "*" || expected == segment)
+        }) && segments.next().is_none()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forwards_only_the_listed_reads() {
+        assert!(forwarded("cpus"));
+        assert!(forwarded("cpus/summary"));
+        assert!(forwarded("collectors/docker/history"));
+        assert!(!forwarded("audit"));
+        assert!(!forwarded("processes/1/files"));
+        assert!(!forwarded("cpus/summary/extra"));
+        assert!(!forwarded("collect"));
+        assert!(!forwarded("hosts/other/cpus"));
+    }
+}
@@ -0,0 +1,124 @@
+//! Idle sampling, with `--idle-after-secs`. Once no realtime, SSE, GraphQL
+//! or gRPC client is subscribed to any topic and no API request arrived for
+//! that long, the sampler and the registered collectors (Docker, GPUs and
+//! plugins) stop until the next request or subscriber. Health probes don't
+//! count as activity, so a box nobody looks at costs next to nothing. Alert
+//! rules and watched processes are checked against every sample, so sampling
+//! never idles while there are any, including rules added at runtime.
+//!
+//! A request that wakes the sampler waits for the fresh sample, rather than
+//! being answered with the one taken before the pause.
+
+use crate::AppState;
+use axum::extract::State;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// How often paused loops check for subscribers that arrived without an
+/// HTTP request, i.e. over gRPC.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Longest a waking request waits for the fresh sample.
+const WAKE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Clone)]
+pub struct Activity {
+    idle_after: Option<Duration>,
+    last_request: Arc<Mutex<Instant>>,
+    wake: Arc<Notify>,
+    /// Waited on by waking requests; a subscription would count as a
+    /// subscriber and keep sampling from ever idling again.
+    sampled: Arc<Notify>,
+}
+
+impl Activity {
+    pub fn new(idle_after: Option<Duration>) -> Self {
+        Self {
+            idle_after,
+            last_request: Arc::new(Mutex::new(Instant::now())),
+            wake: Arc::new(Notify::new()),
+            sampled: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Called by the sampler once every topic got the latest sample.
+    pub fn sampled(&self) {
+        self.sampled.notify_waiters();
+    }
+
+    fn touch(&self) {
+        *self.last_request.lock().unwrap() = Instant::now();
+        self.wake.notify_waiters();
+    }
+}
+
+/// Whether sampling is paused, or would be at the next tick.
+pub fn is_idle(state: &AppState) -> bool {
+    let activity = &state.activity;
+    activity.idle_after.is_some_and(|idle_after| {
+        subscribers(state) == 0
+            && !state.alerts.has_rules()
+            && state.config.watches.is_empty()
+            && activity.last_request.lock().unwrap().elapsed() >= idle_after
+    })
+}
+
+fn subscribers(state: &AppState) -> usize {
+    [
+        state.cpu_limit.subscriber_count(),
+        state.tcp_connections.subscriber_count(),
+        state.containers.subscriber_count(),
+        state.gpus.subscriber_count(),
+        state.registry.subscriber_count(),
+    ]
+    .into_iter()
+    .sum()
+}
+
+/// Returns at once unless sampling is idle, otherwise when someone is
+/// listening again; `false` when the server shuts down meanwhile.
+pub async fn wait_while_idle(state: &AppState, collector: &'static str) -> bool {
+    if !is_idle(state) {
+        return true;
+    }
+    tracing::debug!(collector, "Nobody is listening, pausing");
+    loop {
+        // Registered before checking, so a request in between isn't missed.
+        let woken = state.activity.wake.notified();
+        tokio::pin!(woken);
+        woken.as_mut().enable();
+        if !is_idle(state) {
+            break;
+        }
+        tokio::select! {
+            _ = woken => {}
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            _ = state.shutdown.cancelled() => return false,
+        }
+    }
+    tracing::debug!(collector, "Resuming");
+    true
+}
+
+/// Route layer on everything but the health checks, counting requests as
+/// activity.
+pub async fn record_activity<B>(
+    State(state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if is_idle(&state) {
+        // Registered before waking the sampler, so the sample isn't missed.
+        let sampled = state.activity.sampled.notified();
+        tokio::pin!(sampled);
+        sampled.as_mut().enable();
+        state.activity.touch();
+        let _ = tokio::time::timeout(WAKE_TIMEOUT, sampled).await;
+    } else {
+        state.activity.touch();
+    }
+    next.run(request).await
+}
@@ -0,0 +1,157 @@
+//! Optional exporter that writes CPU, memory and process samples to an
+//! InfluxDB v2 bucket in line protocol, enabled with `--influxdb-url`.
+//! Every line is tagged with the host and its `--label`s.
+
+use crate::config::InfluxConfig;
+use crate::history::now_millis;
+use crate::models::{CpuInfo, Memory, ProcessInfo};
+use crate::AppState;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+use sysinfo::SystemExt;
+use tokio::time::MissedTickBehavior;
+
+/// Only the heaviest processes by CPU are written, to keep the number of
+/// series in the bucket bounded.
+const TOP_PROCESSES: usize = 20;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn spawn(config: InfluxConfig, app_state: AppState) {
+    tokio::spawn(run(config, app_state));
+}
+
+async fn run(config: InfluxConfig, app_state: AppState) {
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .expect("Failed to build InfluxDB client");
+    let url = format!("{}/api/v2/write", config.url.trim_end_matches('/'));
+    let host = app_state
+        .system
+        .lock()
+        .await
+        .host_name()
+        .unwrap_or_default();
+    let mut ticker = tokio::time::interval(config.interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut reachable = true;
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = app_state.shutdown.cancelled() => break,
+        }
+        // Nothing has been sampled yet.
+        if app_state.cpus.latest().seq == 0 {
+            continue;
+        }
+        let body = lines(
+            &host,
+            &app_state.config.labels,
+            app_state.cpus.latest().value(),
+            app_state.memory.latest().value(),
+            app_state.processes.latest().value(),
+            now_millis(),
+        );
+        let result = client
+            .post(&url)
+            .query(&[
+                ("org", config.org.as_str()),
+                ("bucket", config.bucket.as_str()),
+                ("precision", "ms"),
+            ])
+            .header(
+                reqwest::header::AUTHORIZATION,
+                format!("Token {}", config.token),
+            )
+            .header(reqwest::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(body)
+            .send()
+            .await
+            .map_err(|err| err.to_string())
+            .and_then(|response| match response.status() {
+                status if status.is_success() => Ok(()),
+                status => Err(format!("InfluxDB answered {status}")),
+            });
+        match result {
+            Ok(()) if !reachable => {
+                tracing::info!(url = %config.url, "InfluxDB is reachable again");
+                reachable = true;
+            }
+            Ok(()) => {}
+            // Logged once per outage rather than every write.
+            Err(err) if reachable => {
+                tracing::warn!(url = %config.url, %err, "Failed to write to InfluxDB");
+                reachable = false;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// One `cpu` line per core plus `core=all`, a `memory` line and a
+/// `process` line per process among the `TOP_PROCESSES`.
+fn lines(
+    host: &str,
+    labels: &BTreeMap<String, String>,
+    cpus: &[CpuInfo],
+    memory: &Memory,
+    processes: &[ProcessInfo],
+    timestamp: u64,
+) -> String {
+    let mut host = escape_tag(host);
+    for (key, value) in labels {
+        let _ = write!(host, ",{key}={}", escape_tag(value));
+    }
+    let mut out = String::new();
+    for (core, cpu) in cpus.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "cpu,host={host},core={core} usage={},frequency={}i {timestamp}",
+            cpu.cpu_usage, cpu.frequency
+        );
+    }
+    if !cpus.is_empty() {
+        let usage = cpus.iter().map(|cpu| cpu.cpu_usage).sum::<f32>() / cpus.len() as f32;
+        let _ = writeln!(out, "cpu,host={host},core=all usage={usage} {timestamp}");
+    }
+    let _ = writeln!(
+        out,
+        "memory,host={host} total={}i,used={}i,swap_total={}i,swap_used={}i {timestamp}",
+        memory.total_memory_bytes,
+        memory.used_memory_bytes,
+        memory.total_swap_bytes,
+        memory.used_swap_bytes
+    );
+    let mut heaviest: Vec<&ProcessInfo> = processes.iter().collect();
+    heaviest.sort_by_key(|process| Reverse(process.memory_bytes));
+    heaviest.sort_by(|a, b| b.cpu_usage.total_cmp(&a.cpu_usage));
+    for process in heaviest.into_iter().take(TOP_PROCESSES) {
+        let _ = writeln!(
+            out,
+            "process,host={host},name={},pid={} cpu_usage={},memory={}i {timestamp}",
+            escape_tag(&process.name),
+            process.pid,
+            process.cpu_usage,
+            process.memory_bytes
+        );
+    }
+    out
+}
+
+/// Commas, equal signs and spaces are escaped in tag values; an empty value
+/// isn't allowed at all.
+fn escape_tag(value: &str) -> String {
+    if value.is_empty() {
+        return "unknown".to_owned();
+    }
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, ',' | '=' | ' ' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
@@ -0,0 +1,549 @@
+//! JWT authentication, see `auth.rs` for the roles tokens grant. HS256
+//! tokens are checked against `--jwt-secret-file`, and RS, PS, ES256, ES384
+//! and EdDSA tokens against the public keys of a JWK set, fetched from
+//! `--jwt-jwks-url` or discovered from the OpenID Connect issuer given with
+//! `--jwt-issuer`. That lets the server sit behind an SSO provider without
+//! an auth proxy.
+//!
+//! The keys are fetched again every `REFRESH_INTERVAL`, and sooner when a
+//! token names a key id not seen yet, as providers rotate their keys.
+
+use crate::auth::{Principal, Role};
+use crate::config::{JwksSource, JwtConfig};
+use crate::history::now_millis;
+use crate::AppState;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::Deserialize;
+use serde_json::Value;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// Between fetches for unknown key ids, and between retries after a
+/// failed fetch.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Clock difference with the issuer tolerated when checking `exp` and
+/// `nbf`.
+const LEEWAY_SECS: u64 = 60;
+
+/// The signing keys of the configured JWK set, empty until fetched.
+#[derive(Clone, Default)]
+pub struct Jwks {
+    keys: Arc<RwLock<Vec<Jwk>>>,
+    refresh: Arc<Notify>,
+}
+
+struct Jwk {
+    kid: Option<String>,
+    /// The only algorithm the key may be used with, when the set says so.
+    alg: Option<String>,
+    key: PublicKey,
+}
+
+enum PublicKey {
+    Rsa {
+        n: Vec<u8>,
+        e: Vec<u8>,
+    },
+    /// An uncompressed point on P-256 or P-384.
+    Ec {
+        crv: String,
+        point: Vec<u8>,
+    },
+    Ed25519(Vec<u8>),
+}
+
+impl Jwk {
+    fn verify(&self, alg: &str, message: &[u8], signature: &[u8]) -> bool {
+        if self.alg.as_deref().is_some_and(|only| only != alg) {
+            return false;
+        }
+        let rsa = |n: &[u8], e: &[u8], params| {
+            RsaPublicKeyComponents { n, e }
+                .verify(params, message, signature)
+                .is_ok()
+        };
+        let other = |algorithm, key: &[u8]| {
+            UnparsedPublicKey::new(algorithm, key)
+                .verify(message, signature)
+                .is_ok()
+        };
+        match (&self.key, alg) {
+            (PublicKey::Rsa { n, e }, "RS256") => rsa(n, e, &signature::RSA_PKCS1_2048_8192_SHA256),
+            (PublicKey::Rsa { n, e }, "RS384") => rsa(n, e, &signature::RSA_PKCS1_2048_8192_SHA384),
+            (PublicKey::Rsa { n, e }, "RS512") => rsa(n, e, &signature::RSA_PKCS1_2048_8192_SHA512),
+            (PublicKey::Rsa { n, e }, "PS256") => rsa(n, e, &signature::RSA_PSS_2048_8192_SHA256),
+            (PublicKey::Rsa { n, e }, "PS384") => rsa(n, e, &signature::RSA_PSS_2048_8192_SHA384),
+            (PublicKey::Rsa { n, e }, "PS512") => rsa(n, e, &signature::RSA_PSS_2048_8192_SHA512),
+            (PublicKey::Ec { crv, point }, "ES256") if crv == "P-256" => {
+                other(&signature::ECDSA_P256_SHA256_FIXED, point)
+            }
+            (PublicKey::Ec { crv, point }, "ES384") if crv == "P-384" => {
+                other(&signature::ECDSA_P384_SHA384_FIXED, point)
+            }
+            (PublicKey::Ed25519(key), "EdDSA") => other(&signature::ED25519, key),
+            _ => false,
+        }
+    }
+}
+
+/// A key of a JWK set as published; the fields used depend on `kty`.
+#[derive(Debug, Deserialize)]
+struct RawJwk {
+    kty: String,
+    kid: Option<String>,
+    alg: Option<String>,
+    #[serde(rename = "use")]
+    usage: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+impl RawJwk {
+    /// `None` for encryption keys and key types that can't be used.
+    fn parse(self) -> Option<Jwk> {
+        if self.usage.as_deref().is_some_and(|usage| usage != "sig") {
+            return None;
+        }
+        let decode = |part: Option<String>| URL_SAFE_NO_PAD.decode(part?).ok();
+        let key = match (self.kty.as_str(), self.crv.as_deref()) {
+            ("RSA", _) => PublicKey::Rsa {
+                n: decode(self.n)?,
+                e: decode(self.e)?,
+            },
+            ("EC", Some(crv @ ("P-256" | "P-384"))) => {
+                let mut point = vec![0x04];
+                point.extend(decode(self.x)?);
+                point.extend(decode(self.y)?);
+                PublicKey::Ec {
+                    crv: crv.to_owned(),
+                    point,
+                }
+            }
+            ("OKP", Some("Ed25519")) => PublicKey::Ed25519(decode(self.x)?),
+            _ => return None,
+        };
+        Some(Jwk {
+            kid: self.kid,
+            alg: self.alg,
+            key,
+        })
+    }
+}
+
+/// Checks a token's signature, `exp` (required), `nbf` and the configured
+/// `iss` and `aud`, and reads the role from its role claim: a role name or a list of
+/// them, of which the highest counts. Tokens without one are viewers. The
+/// principal is named after the first of `preferred_username`, `email` and
+/// `sub` present.
+pub fn verify(config: &JwtConfig, jwks: &Jwks, token: &str) -> Result<Principal, String> {
+    let invalid = |reason: &str| format!("Invalid token: {reason}");
+    let (signed, signature) = token.rsplit_once('.').ok_or_else(|| invalid("malformed"))?;
+    let (header, claims) = signed.split_once('.').ok_or_else(|| invalid("malformed"))?;
+    let decode = |part: &str| -> Result<Value, String> {
+        let bytes = URL_SAFE_NO_PAD
+            .decode(part)
+            .map_err(|_| invalid("malformed"))?;
+        serde_json::from_slice(&bytes).map_err(|_| invalid("malformed"))
+    };
+    let header = decode(header)?;
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| invalid("malformed"))?;
+    match header["alg"].as_str() {
+        Some("HS256") => {
+            let Some(secret) = &config.secret else {
+                return Err(invalid("HS256 isn't accepted"));
+            };
+            let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes());
+            ring::hmac::verify(&key, signed.as_bytes(), &signature)
+                .map_err(|_| invalid("bad signature"))?;
+        }
+        Some(alg) if config.jwks.is_some() => {
+            let kid = header["kid"].as_str();
+            let keys = jwks.keys.read().unwrap();
+            let mut candidates = keys
+                .iter()
+                .filter(|key| kid.is_none() || key.kid.as_deref() == kid)
+                .peekable();
+            if candidates.peek().is_none() {
+                // Perhaps rotated in since the last fetch.
+                jwks.refresh.notify_one();
+                return Err(invalid("unknown signing key"));
+            }
+            if !candidates.any(|key| key.verify(alg, signed.as_bytes(), &signature)) {
+                return Err(invalid("bad signature"));
+            }
+        }
+        _ => return Err(invalid("unsupported algorithm")),
+    }
+    let claims = decode(claims)?;
+    // NumericDates may have a fraction, so they are compared as floats.
+    let now = now_millis() as f64 / 1000f64;
+    let leeway = LEEWAY_SECS as f64;
+    let exp = match &claims["exp"] {
+        Value::Null => return Err(invalid("no expiry")),
+        exp => exp.as_f64().ok_or_else(|| invalid("malformed exp"))?,
+    };
+    if exp + leeway <= now {
+        return Err(invalid("expired"));
+    }
+    match &claims["nbf"] {
+        Value::Null => {}
+        nbf => {
+            let nbf = nbf.as_f64().ok_or_else(|| invalid("malformed nbf"))?;
+            if nbf > now + leeway {
+                return Err(invalid("not valid yet"));
+            }
+        }
+    }
+    if let Some(issuer) = &config.issuer {
+        if claims["iss"].as_str() != Some(issuer) {
+            return Err(invalid("wrong issuer"));
+        }
+    }
+    if let Some(audience) = &config.audience {
+        let matches = match &claims["aud"] {
+            Value::String(aud) => aud == audience,
+            Value::Array(auds) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+            _ => false,
+        };
+        if !matches {
+            return Err(invalid("wrong audience"));
+        }
+    }
+    let role = |value: &Value| Role::deserialize(value).ok();
+    let role = match &claims[config.role_claim.as_str()] {
+        Value::Array(roles) => roles.iter().filter_map(role).max(),
+        value => role(value),
+    };
+    let name = ["preferred_username", "email", "sub"]
+        .into_iter()
+        .find_map(|claim| claims[claim].as_str())
+        .unwrap_or("unnamed token");
+    Ok(Principal {
+        name: name.to_owned(),
+        role: role.unwrap_or(Role::Viewer),
+    })
+}
+
+pub fn spawn(source: JwksSource, app_state: AppState) {
+    tokio::spawn(run(source, app_state));
+}
+
+async fn run(source: JwksSource, app_state: AppState) {
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .expect("Failed to build JWKS client");
+    let jwks = app_state.jwks.clone();
+    let mut reachable = true;
+    loop {
+        let delay = match fetch(&client, &source).await {
+            Ok(keys) => {
+                if !reachable || jwks.keys.read().unwrap().is_empty() {
+                    tracing::info!(keys = keys.len(), "Loaded the JWT signing keys");
+                }
+                *jwks.keys.write().unwrap() = keys;
+                reachable = true;
+                REFRESH_INTERVAL
+            }
+            // Logged once per outage rather than every retry.
+            Err(err) if reachable => {
+                tracing::warn!(%err, "Failed to fetch the JWT signing keys");
+                reachable = false;
+                MIN_REFRESH_INTERVAL
+            }
+            Err(_) => MIN_REFRESH_INTERVAL,
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = jwks.refresh.notified() => {
+                // Tokens with made up key ids mustn't cause a fetch each.
+                tokio::time::sleep(MIN_REFRESH_INTERVAL).await;
+            }
+            _ = app_state.shutdown.cancelled() => break,
+        }
+    }
+}
+
+async fn fetch(client: &reqwest::Client, source: &JwksSource) -> Result<Vec<Jwk>, String> {
+    #[derive(Deserialize)]
+    struct Discovery {
+        jwks_uri: String,
+    }
+    #[derive(Deserialize)]
+    struct Set {
+        keys: Vec<Value>,
+    }
+    let url = match source {
+        JwksSource::Url(url) => url.to_string(),
+        JwksSource::Issuer(issuer) => {
+            let url = format!(
+                "{}/.well-known/openid-configuration",
+                issuer.as_str().trim_end_matches('/')
+            );
+            get::<Discovery>(client, &url).await?.jwks_uri
+        }
+    };
+    let set: Set = get(client, &url).await?;
+    // Keys of other types are skipped rather than failing the whole set.
+    Ok(set
+        .keys
+        .into_iter()
+        .filter_map(|key| serde_json::from_value::<RawJwk>(key).ok()?.parse())
+        .collect())
+}
+
+async fn get<T: serde::de::DeserializeOwned>(
+    client: &reqwest::Client,
+    url: &str,
+) -> Result<T, String> {
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+    match response.status() {
+        status if status.is_success() => response
+            .json()
+            .await
+            .map_err(|err| format!("Invalid response from {url}: {err}")),
+        status => Err(format!("{url} answered {status}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use serde_json::json;
+
+    const SECRET: &str = "secret";
+
+    fn config() -> JwtConfig {
+        JwtConfig {
+            secret: Some(SECRET.to_owned()),
+            jwks: None,
+            role_claim: "role".to_owned(),
+            issuer: None,
+            audience: None,
+        }
+    }
+
+    fn encode(value: &Value) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(value).unwrap())
+    }
+
+    fn hs256(claims: Value) -> String {
+        let signed = format!("{}.{}", encode(&json!({"alg": "HS256"})), encode(&claims));
+        let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, SECRET.as_bytes());
+        let signature = ring::hmac::sign(&key, signed.as_bytes());
+        format!("{signed}.{}", URL_SAFE_NO_PAD.encode(signature.as_ref()))
+    }
+
+    fn in_an_hour() -> u64 {
+        now_millis() / 1000 + 3600
+    }
+
+    #[test]
+    fn accepts_valid_token() {
+        let token = hs256(json!({"sub": "alice", "role": "admin", "exp": in_an_hour()}));
+        let principal = verify(&config(), &Jwks::default(), &token).unwrap();
+        assert_eq!(principal.name, "alice");
+        assert_eq!(principal.role, Role::Admin);
+    }
+
+    fn role_of(config: &JwtConfig, claims: Value) -> Role {
+        let token = hs256(claims);
+        verify(config, &Jwks::default(), &token).unwrap().role
+    }
+
+    #[test]
+    fn role_claim_grants_its_role() {
+        for (claim, role) in [
+            (json!("viewer"), Role::Viewer),
+            (json!("operator"), Role::Operator),
+            (json!("admin"), Role::Admin),
+        ] {
+            assert_eq!(
+                role_of(&config(), json!({"role": claim, "exp": in_an_hour()})),
+                role
+            );
+        }
+    }
+
+    #[test]
+    fn highest_listed_role_counts() {
+        let claims = json!({"role": ["viewer", "admin", "operator"], "exp": in_an_hour()});
+        assert_eq!(role_of(&config(), claims), Role::Admin);
+        // Roles of other applications are skipped.
+        let claims = json!({"role": ["billing", "operator"], "exp": in_an_hour()});
+        assert_eq!(role_of(&config(), claims), Role::Operator);
+    }
+
+    #[test]
+    fn missing_or_unknown_role_is_viewer() {
+        for claims in [
+            json!({"exp": in_an_hour()}),
+            json!({"role": "root", "exp": in_an_hour()}),
+            json!({"role": "Admin", "exp": in_an_hour()}),
+            json!({"role": [], "exp": in_an_hour()}),
+            json!({"role": 3, "exp": in_an_hour()}),
+        ] {
+            assert_eq!(role_of(&config(), claims), Role::Viewer);
+        }
+    }
+
+    #[test]
+    fn role_is_read_from_the_configured_claim() {
+        let config = JwtConfig {
+            role_claim: "process_viewer_role".to_owned(),
+            ..config()
+        };
+        let claims =
+            json!({"role": "admin", "process_viewer_role": "operator", "exp": in_an_hour()});
+        assert_eq!(role_of(&config, claims), Role::Operator);
+    }
+
+    #[test]
+    fn roles_are_ordered_by_privilege() {
+        assert!(Role::Viewer < Role::Operator);
+        assert!(Role::Operator < Role::Admin);
+        assert_eq!(
+            [Role::Admin, Role::Viewer, Role::Operator].iter().max(),
+            Some(&Role::Admin)
+        );
+    }
+
+    #[test]
+    fn accepts_fractional_exp() {
+        let token = hs256(json!({"exp": in_an_hour() as f64 + 0.5}));
+        assert!(verify(&config(), &Jwks::default(), &token).is_ok());
+    }
+
+    #[test]
+    fn rejects_expired_token() {
+        let exp = now_millis() / 1000 - 2 * LEEWAY_SECS;
+        for exp in [json!(exp), json!(exp as f64 + 0.5)] {
+            let token = hs256(json!({ "exp": exp }));
+            assert_eq!(
+                verify(&config(), &Jwks::default(), &token).unwrap_err(),
+                "Invalid token: expired"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_token_without_exp() {
+        let token = hs256(json!({"sub": "alice"}));
+        assert_eq!(
+            verify(&config(), &Jwks::default(), &token).unwrap_err(),
+            "Invalid token: no expiry"
+        );
+    }
+
+    #[test]
+    fn rejects_non_numeric_exp_and_nbf() {
+        let token = hs256(json!({"exp": "tomorrow"}));
+        assert_eq!(
+            verify(&config(), &Jwks::default(), &token).unwrap_err(),
+            "Invalid token: malformed exp"
+        );
+        let token = hs256(json!({"exp": in_an_hour(), "nbf": "now"}));
+        assert_eq!(
+            verify(&config(), &Jwks::default(), &token).unwrap_err(),
+            "Invalid token: malformed nbf"
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_audience() {
+        let config = JwtConfig {
+            audience: Some("process-viewer".to_owned()),
+            ..config()
+        };
+        let token = hs256(json!({"aud": ["other"], "exp": in_an_hour()}));
+        assert_eq!(
+            verify(&config, &Jwks::default(), &token).unwrap_err(),
+            "Invalid token: wrong audience"
+        );
+        let token = hs256(json!({"aud": ["other", "process-viewer"], "exp": in_an_hour()}));
+        assert!(verify(&config, &Jwks::default(), &token).is_ok());
+    }
+
+    #[test]
+    fn rejects_bad_signature() {
+        let token = hs256(json!({"role": "viewer", "exp": in_an_hour()}));
+        let (signed, _) = token.rsplit_once('.').unwrap();
+        // The claims of another token under the first one's signature.
+        let forged = hs256(json!({"role": "admin", "exp": in_an_hour()}));
+        let (_, signature) = forged.rsplit_once('.').unwrap();
+        assert_eq!(
+            verify(
+                &config(),
+                &Jwks::default(),
+                &format!("{signed}.{signature}")
+            )
+            .unwrap_err(),
+            "Invalid token: bad signature"
+        );
+    }
+
+    /// A JWK set holding one Ed25519 key with id `key-1`, and its key pair.
+    fn ed25519_jwks() -> (JwtConfig, Jwks, Ed25519KeyPair) {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let jwks = Jwks::default();
+        jwks.keys.write().unwrap().push(Jwk {
+            kid: Some("key-1".to_owned()),
+            alg: None,
+            key: PublicKey::Ed25519(pair.public_key().as_ref().to_vec()),
+        });
+        let config = JwtConfig {
+            secret: None,
+            jwks: Some(JwksSource::Url("https://idp.example/jwks".parse().unwrap())),
+            ..config()
+        };
+        (config, jwks, pair)
+    }
+
+    fn eddsa(pair: &Ed25519KeyPair, kid: &str, claims: Value) -> String {
+        let header = json!({"alg": "EdDSA", "kid": kid});
+        let signed = format!("{}.{}", encode(&header), encode(&claims));
+        let signature = pair.sign(signed.as_bytes());
+        format!("{signed}.{}", URL_SAFE_NO_PAD.encode(signature.as_ref()))
+    }
+
+    #[test]
+    fn checks_jwks_key_id() {
+        let (config, jwks, pair) = ed25519_jwks();
+        let claims = json!({"sub": "bob", "exp": in_an_hour()});
+        let token = eddsa(&pair, "key-1", claims.clone());
+        assert_eq!(verify(&config, &jwks, &token).unwrap().name, "bob");
+        let token = eddsa(&pair, "key-2", claims);
+        assert_eq!(
+            verify(&config, &jwks, &token).unwrap_err(),
+            "Invalid token: unknown signing key"
+        );
+    }
+
+    #[test]
+    fn rejects_token_signed_by_another_key() {
+        let (config, jwks, _) = ed25519_jwks();
+        let (_, _, other) = ed25519_jwks();
+        let token = eddsa(&other, "key-1", json!({"exp": in_an_hour()}));
+        assert_eq!(
+            verify(&config, &jwks, &token).unwrap_err(),
+            "Invalid token: bad signature"
+        );
+    }
+}
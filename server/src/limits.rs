@@ -0,0 +1,205 @@
+//! Request and connection limits. Per client IP, a token bucket for requests
+//! per second and a cap on concurrent realtime WebSockets keep a single
+//! misbehaving dashboard from saturating the server; a global cap on realtime
+//! WebSockets protects it as a whole. All of them are off unless configured.
+//!
+//! Clients are told apart by the address `access.rs` resolves: the peer
+//! address, or behind a trusted reverse proxy the one it forwards for.
+
+use crate::access::ClientIp;
+use crate::config::LimitsConfig;
+use crate::{api_error, AppError, AppState};
+use axum::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts, State};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Idle buckets are dropped once this many clients are tracked.
+const PRUNE_THRESHOLD: usize = 1024;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Default)]
+struct Inner {
+    buckets: HashMap<IpAddr, Bucket>,
+    sockets: HashMap<IpAddr, usize>,
+    open_sockets: usize,
+    peak_sockets: usize,
+    rejected_sockets: u64,
+}
+
+/// Realtime connection counts for `/api/v1/self`.
+#[derive(Debug, Clone, Copy)]
+pub struct SocketStats {
+    pub peak: usize,
+    pub rejected: u64,
+    pub max: Option<usize>,
+}
+
+#[derive(Clone)]
+pub struct Limiter {
+    config: LimitsConfig,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Limiter {
+    pub fn new(config: LimitsConfig) -> Self {
+        Self {
+            config,
+            inner: Arc::default(),
+        }
+    }
+
+    /// Takes a token from the client's bucket, or returns how many seconds
+    /// until the next one is available.
+    fn check(&self, ip: IpAddr) -> Result<(), u64> {
+        let Some(rate) = self.config.requests_per_second else {
+            return Ok(());
+        };
+        let rate = f64::from(rate);
+        let burst = f64::from(self.config.burst.unwrap_or(rate as u32).max(1));
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        if inner.buckets.len() >= PRUNE_THRESHOLD {
+            inner.buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst
+            });
+        }
+        let bucket = inner.buckets.entry(ip).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refilled).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1f64 {
+            bucket.tokens -= 1f64;
+            Ok(())
+        } else {
+            Err(((1f64 - bucket.tokens) / rate).ceil() as u64)
+        }
+    }
+
+    fn open_socket(&self, ip: IpAddr) -> Result<SocketSlot, AppError> {
+        let mut inner = self.inner.lock().unwrap();
+        let rejection = if self
+            .config
+            .websockets
+            .is_some_and(|max| inner.open_sockets >= max)
+        {
+            Some(api_error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Too many realtime connections",
+            ))
+        } else if self
+            .config
+            .websockets_per_ip
+            .is_some_and(|max| inner.sockets.get(&ip).is_some_and(|open| *open >= max))
+        {
+            Some(api_error(
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many realtime connections from this address",
+            ))
+        } else {
+            None
+        };
+        if let Some(rejection) = rejection {
+            inner.rejected_sockets += 1;
+            return Err(rejection);
+        }
+        *inner.sockets.entry(ip).or_default() += 1;
+        inner.open_sockets += 1;
+        inner.peak_sockets = inner.peak_sockets.max(inner.open_sockets);
+        Ok(SocketSlot {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+
+    pub fn socket_stats(&self) -> SocketStats {
+        let inner = self.inner.lock().unwrap();
+        SocketStats {
+            peak: inner.peak_sockets,
+            rejected: inner.rejected_sockets,
+            max: self.config.websockets,
+        }
+    }
+}
+
+/// Rejects requests over the client's rate with 429 and `Retry-After`.
+pub async fn limit_requests<B>(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let client = request
+        .extensions()
+        .get::<ClientIp>()
+        .map_or(peer.ip(), |client| client.0);
+    match state.limiter.check(client) {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => {
+            let error: AppError = api_error(StatusCode::TOO_MANY_REQUESTS, "Rate limit exceeded");
+            let mut response = error.into_response();
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after.max(1)));
+            response
+        }
+    }
+}
+
+/// One of the client's realtime connections, released when dropped. As an
+/// extractor it rejects the upgrade with 503 once `websockets` sockets are open
+/// in total, or with 429 once the client has `websockets_per_ip` open.
+pub struct SocketSlot {
+    limiter: Limiter,
+    ip: IpAddr,
+}
+
+impl Drop for SocketSlot {
+    fn drop(&mut self) {
+        let mut inner = self.limiter.inner.lock().unwrap();
+        inner.open_sockets -= 1;
+        if let Some(open) = inner.sockets.get_mut(&self.ip) {
+            *open -= 1;
+            if *open == 0 {
+                inner.sockets.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for SocketSlot {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(peer) = ConnectInfo::<SocketAddr>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| {
+                api_error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Peer address unavailable",
+                )
+            })?;
+        let client = parts
+            .extensions
+            .get::<ClientIp>()
+            .map_or(peer.ip(), |client| client.0);
+        state.limiter.open_socket(client)
+    }
+}
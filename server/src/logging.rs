@@ -0,0 +1,69 @@
+//! Logging through `tracing`, filtered by `--log-level` and written to stdout
+//! either as human readable lines or, with `--log-format json`, one JSON
+//! object per event for log shippers. When `RUST_LOG` is set it replaces
+//! `--log-level` as the filter.
+
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::fmt;
+use std::io::{self, IsTerminal};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        })
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// `extra` is an additional layer that receives the same events, such as the
+/// OpenTelemetry span exporter.
+pub fn init(
+    level: LogLevel,
+    format: LogFormat,
+    extra: Option<Box<dyn Layer<Registry> + Send + Sync>>,
+) {
+    // Dependencies only get to log warnings, otherwise `debug` drowns in
+    // connection level noise from hyper and rustls.
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        let dependencies = level.min(LogLevel::Warn);
+        EnvFilter::new(format!(
+            "{dependencies},{}={level},tower_http={level}",
+            env!("CARGO_CRATE_NAME")
+        ))
+    });
+    let output = tracing_subscriber::fmt::layer().with_ansi(io::stdout().is_terminal());
+    let mut layers = vec![match format {
+        LogFormat::Text => output.boxed(),
+        LogFormat::Json => output.json().boxed(),
+    }];
+    layers.extend(extra);
+    tracing_subscriber::registry()
+        .with(layers)
+        .with(filter)
+        .init();
+}
@@ -1,188 +1,1024 @@
-use axum::extract::ws::{Message, WebSocket};
-use axum::extract::{State, WebSocketUpgrade};
+mod access;
+mod agent;
+mod alerts;
+mod audit;
+mod auth;
+mod cgroup;
+mod collector;
+mod config;
+mod connections;
+mod control;
+mod cpu_times;
+mod dashboard;
+mod diskio;
+mod docker;
+mod encoding;
+mod error;
+mod export;
+mod federation;
+mod format;
+mod gpu;
+mod grafana;
+mod graphql;
+mod grpc;
+mod health;
+mod history;
+mod history_db;
+mod hub;
+mod idle;
+mod influx;
+mod jwt;
+mod limits;
+mod logging;
+mod mdns;
+mod meminfo;
+mod metrics;
+mod models;
+mod mqtt;
+mod open_files;
+mod openapi;
+mod overview;
+mod plugin;
+mod ports;
+mod power;
+mod pressure;
+mod process_delta;
+mod process_watch;
+mod processes;
+mod protobuf;
+mod realtime;
+mod recording;
+mod request_id;
+mod sampler;
+mod self_monitor;
+mod sessions;
+mod snapshots;
+mod sse;
+mod stats;
+mod statsd;
+mod system;
+mod systemd;
+mod telemetry;
+mod topology;
+mod users;
+mod versions;
+mod watchdog;
+mod webhooks;
+
+use alerts::AlertEngine;
+use audit::AuditLog;
+use axum::extract::{Query, State};
+use axum::handler::Handler;
+use axum::http::header;
+use axum::middleware;
 use axum::response::IntoResponse;
-use axum::routing::get;
-use axum::{Json, Router, Server};
+use axum::routing::{get, post};
+use axum::Router;
 use axum_macros::debug_handler;
-use serde::{Deserialize, Serialize};
-use std::env;
-use std::sync::{Arc, Mutex};
-use sysinfo::{CpuExt, System, SystemExt};
-use tokio::sync::broadcast;
-const DEFAULT_PORT: u16 = 7070;
-
-trait HumanReadable: Sized {
-    fn to_human(self, precision: Option<u8>) -> String;
-}
-
-impl HumanReadable for u64 {
-    fn to_human(self, precision: Option<u8>) -> String {
-        let precision = if let Some(precision) = precision {
-            precision
-        } else {
-            2
-        };
-        match self {
-            0..=999 => self.to_string(),
-            1000..=999_999 => {
-                format!("{:.*} KB", precision as usize, self as f64 / 1000f64)
-            }
-            1_000_000..=999_999_999 => {
-                format!("{:.*} MB", precision as usize, self as f64 / 1_000_000f64)
-            }
-            1_000_000_000.. => {
-                format!(
-                    "{:.*} GB",
-                    precision as usize,
-                    self as f64 / 1_000_000_000f64
-                )
-            }
-        }
-    }
-}
-
-fn get_address() -> String {
-    let port = env::var("PORT").unwrap_or(DEFAULT_PORT.to_string());
-    format!("{}:{port}", "0.0.0.0")
-}
+use axum_server::tls_rustls::RustlsConfig;
+use axum_server::Handle;
+use collector::{Registry, Retain, Sampled};
+use config::Config;
+use encoding::{Encoded, Encoding};
+use error::{api_error, AppError};
+use format::FormatQuery;
+use futures_util::future;
+use history_db::HistoryDb;
+use hub::HostRegistry;
+use idle::Activity;
+use jwt::Jwks;
+use limits::Limiter;
+use mdns::Discovery;
+use models::{
+    ConnectionEvent, ConnectionInfo, ContainerInfo, CpuInfo, CpuLimit, CpuSummary, DiskInfo,
+    DiskIoInfo, GpuInfo, LoadAverage, Memory, NetworkInfo, PowerInfo, Pressure, ProcessEvent,
+    ProcessInfo, TemperatureInfo,
+};
+use recording::{Recorder, Replay};
+use request_id::RequestSpan;
+use sampler::{Sampler, Topic};
+use snapshots::SnapshotStore;
+use std::net::{SocketAddr, TcpListener};
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+use sysinfo::System;
+use telemetry::Telemetry;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tower_http::catch_panic::CatchPanicLayer;
+use tower_http::compression::predicate::{NotForContentType, Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
+use tower_http::trace::TraceLayer;
+use watchdog::Watchdog;
+use webhooks::Notifier;
+/// How long realtime connections get to receive their close frame on shutdown.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(5);
+/// Smaller REST responses aren't worth compressing.
+const COMPRESSION_MIN_SIZE: u16 = 1024;
 
 fn router(app_state: AppState) -> Router {
+    let require_api_key = middleware::from_fn_with_state(app_state.clone(), auth::require_api_key);
+    // Around single handlers, inside `require_api_key`.
+    let operator = middleware::from_fn(auth::require_operator);
+    let admin = middleware::from_fn(auth::require_admin);
+    // Around every write, outermost so nothing is recorded or checked.
+    let read_only = middleware::from_fn_with_state(app_state.clone(), auth::reject_writes);
+    // Outside the role checks, so refused attempts are recorded too.
+    let audit = middleware::from_fn_with_state(app_state.clone(), audit::record);
+    // A route layer, so it runs after routing and sees the matched path.
+    // Nested routers match on their own, hence one per router.
+    let limit_requests = middleware::from_fn_with_state(app_state.clone(), limits::limit_requests);
+    let record_request =
+        middleware::from_fn_with_state(app_state.clone(), telemetry::record_request);
+    let filter_ips = middleware::from_fn_with_state(app_state.clone(), access::filter_ips);
+    let problem_json = middleware::from_fn(error::problem_json);
+    let record_activity = middleware::from_fn_with_state(app_state.clone(), idle::record_activity);
+    let api = Router::new()
+        .route("/system", get(system::get_system))
+        .route("/overview", get(overview::get_overview))
+        .route("/cpus", get(get_cpus))
+        .route("/cpus/summary", get(get_cpu_summary))
+        .route("/cpus/history", get(history::get_cpu_history))
+        .route("/cpus/topology", get(topology::get_topology))
+        .route("/memory", get(get_memory))
+        .route("/memory/history", get(history::get_memory_history))
+        .route("/memory/detail", get(meminfo::get_memory_detail))
+        .route("/processes", get(processes::get_processes))
+        .route("/processes/top", get(processes::get_top_processes))
+        .route("/watch", get(watchdog::get_watch))
+        .route(
+            "/processes/:pid/signal",
+            post(
+                control::send_signal
+                    .layer(operator.clone())
+                    .layer(audit.clone())
+                    .layer(read_only.clone()),
+            ),
+        )
+        .route(
+            "/processes/:pid/priority",
+            post(
+                control::set_priority
+                    .layer(operator.clone())
+                    .layer(audit.clone())
+                    .layer(read_only.clone()),
+            ),
+        )
+        .route("/processes/:pid/files", get(open_files::get_files))
+        .route("/processes/:pid/sockets", get(open_files::get_sockets))
+        .route("/users", get(users::get_users))
+        .route("/sessions", get(sessions::get_sessions))
+        .route("/ports", get(ports::get_ports))
+        .route("/connections", get(connections::get_connections))
+        .route("/containers", get(docker::get_containers))
+        .route("/gpus", get(gpu::get_gpus))
+        .route("/power", get(power::get_power))
+        .route("/disks", get(get_disks))
+        .route("/diskio", get(diskio::get_diskio))
+        .route("/networks", get(get_networks))
+        .route("/temperatures", get(get_temperatures))
+        .route("/loadavg", get(get_loadavg))
+        .route("/pressure", get(pressure::get_pressure))
+        .route("/stats", get(stats::get_stats))
+        .route("/export", get(export::get_export))
+        .route(
+            "/snapshots",
+            get(snapshots::list_snapshots).post(
+                snapshots::create_snapshot
+                    .layer(operator.clone())
+                    .layer(audit.clone())
+                    .layer(read_only.clone()),
+            ),
+        )
+        .route(
+            "/snapshots/:id",
+            get(snapshots::get_snapshot).delete(
+                snapshots::delete_snapshot
+                    .layer(operator.clone())
+                    .layer(audit.clone())
+                    .layer(read_only.clone()),
+            ),
+        )
+        .route("/snapshots/:id/diff/:other", get(snapshots::diff_snapshots))
+        .route("/hosts", get(hub::list_hosts))
+        .route(
+            "/hosts/:id",
+            get(hub::get_host)
+                // Not audited: agents report every few seconds.
+                .put(
+                    hub::put_host
+                        .layer(operator.clone())
+                        .layer(read_only.clone()),
+                )
+                .delete(
+                    hub::delete_host
+                        .layer(operator)
+                        .layer(audit.clone())
+                        .layer(read_only.clone()),
+                ),
+        )
+        .route("/hosts/:id/*path", get(hub::get_host_path))
+        .route("/discovered", get(mdns::get_discovered))
+        .route(
+            "/alerts/rules",
+            get(alerts::list_rules).post(
+                alerts::create_rule
+                    .layer(admin.clone())
+                    .layer(audit.clone())
+                    .layer(read_only.clone()),
+            ),
+        )
+        .route(
+            "/alerts/rules/:id",
+            get(alerts::get_rule)
+                .put(
+                    alerts::update_rule
+                        .layer(admin.clone())
+                        .layer(audit.clone())
+                        .layer(read_only.clone()),
+                )
+                .delete(
+                    alerts::delete_rule
+                        .layer(admin.clone())
+                        .layer(audit)
+                        .layer(read_only),
+                ),
+        )
+        .route("/alerts/active", get(alerts::list_active))
+        .route("/audit", get(audit::get_audit.layer(admin)))
+        .route("/version", get(versions::get_version))
+        .route("/self", get(self_monitor::get_self))
+        .route("/collectors", get(collector::list_collectors))
+        .route("/collectors/:name", get(collector::get_collector))
+        .route(
+            "/collectors/:name/history",
+            get(collector::get_collector_history),
+        )
+        .route("/custom/:name", get(plugin::get_custom))
+        .fallback(error::not_found)
+        .layer(compression())
+        .route_layer(problem_json.clone())
+        .route_layer(require_api_key.clone())
+        .route_layer(limit_requests.clone())
+        .route_layer(record_activity.clone())
+        .route_layer(record_request.clone());
+    let grafana = Router::new()
+        .route("/", get(grafana::test))
+        .route("/search", post(grafana::search))
+        .route("/query", post(grafana::query))
+        .layer(compression())
+        .route_layer(problem_json)
+        .route_layer(require_api_key.clone())
+        .route_layer(limit_requests.clone())
+        .route_layer(record_activity.clone())
+        .route_layer(record_request.clone());
+    let realtime = Router::new()
+        .route("/realtime", get(realtime::realtime_get))
+        .route("/realtime/cpus", get(realtime::realtime_cpus_get))
+        .route("/realtime/memory", get(realtime::realtime_memory_get))
+        .route("/realtime/processes", get(realtime::realtime_processes_get))
+        .route(
+            "/realtime/processes/:pid",
+            get(process_watch::realtime_process_get),
+        )
+        .route(
+            "/realtime/process_events",
+            get(realtime::realtime_process_events_get),
+        )
+        .route(
+            "/realtime/connection_events",
+            get(realtime::realtime_connection_events_get),
+        )
+        .route("/realtime/disks", get(realtime::realtime_disks_get))
+        .route("/realtime/diskio", get(realtime::realtime_diskio_get))
+        .route("/realtime/networks", get(realtime::realtime_networks_get))
+        .route(
+            "/realtime/temperatures",
+            get(realtime::realtime_temperatures_get),
+        )
+        .route("/realtime/loadavg", get(realtime::realtime_loadavg_get))
+        .route("/realtime/pressure", get(realtime::realtime_pressure_get))
+        .route(
+            "/realtime/containers",
+            get(realtime::realtime_containers_get),
+        )
+        .route("/realtime/gpus", get(realtime::realtime_gpus_get))
+        .route("/realtime/power", get(realtime::realtime_power_get))
+        .route("/sse/:topic", get(sse::sse_get))
+        .route(
+            "/graphql",
+            get(graphql::graphql_ws).post(graphql::graphql_post),
+        )
+        .route_layer(require_api_key)
+        .route_layer(limit_requests)
+        .route_layer(record_activity.clone())
+        .route_layer(record_request.clone());
+    let deprecated_api = api
+        .clone()
+        .layer(middleware::from_fn(versions::deprecated))
+        .route("/", get(versions::get_versions));
     Router::new()
-        .route("/api/cpus", get(get_cpus))
-        .route("/api/memory", get(get_memory))
-        .route("/realtime/cpus", get(realtime_cpus_get))
-        .route("/realtime/memory", get(realtime_memory_get))
-        .route("/health", get(health))
+        .merge(openapi::routes(&app_state.config))
+        .route("/metrics", get(get_metrics).layer(record_activity))
+        .route("/health", get(health::health))
+        .route("/health/live", get(health::live))
+        .route("/health/ready", get(health::ready))
+        .route_layer(record_request)
+        .nest(&format!("/api/{}", versions::CURRENT), api)
+        .nest("/api", deprecated_api)
+        .nest("/grafana", grafana)
+        .merge(realtime)
+        .fallback(get(dashboard::serve).layer(compression()))
+        .layer(filter_ips)
+        .layer(CatchPanicLayer::custom(error::handle_panic))
+        .layer(TraceLayer::new_for_http().make_span_with(RequestSpan))
+        .layer(middleware::from_fn(request_id::propagate))
         .with_state(app_state)
 }
 
+/// Gzip or Brotli for REST responses, whichever the client prefers. Realtime
+/// routes live in their own router, so upgrades and event streams are left
+/// alone.
+fn compression() -> CompressionLayer<impl Predicate> {
+    let predicate = SizeAbove::new(COMPRESSION_MIN_SIZE)
+        .and(NotForContentType::GRPC)
+        .and(NotForContentType::IMAGES)
+        .and(NotForContentType::const_new("text/event-stream"));
+    CompressionLayer::new().compress_when(predicate)
+}
+
 #[tokio::main]
 async fn main() {
-    let (tx_cpu, _) = broadcast::channel::<Vec<CpuInfo>>(1);
-    let (tx_memory, _) = broadcast::channel::<Memory>(1);
-    let app_state = AppState {
-        tx_cpu: tx_cpu.clone(),
-        tx_memory: tx_memory.clone(),
-        cpu_info: Arc::new(Mutex::new(vec![])),
-        memory: Arc::new(Mutex::new(Memory::default())),
+    let mut config = Config::load().unwrap_or_else(|err| {
+        eprintln!("{err}");
+        process::exit(2);
+    });
+    let exporters = config.otlp_endpoint.as_deref().map(|endpoint| {
+        telemetry::Exporters::start(endpoint, &config.labels).unwrap_or_else(|err| {
+            eprintln!("{err}");
+            process::exit(2);
+        })
+    });
+    logging::init(
+        config.log_level,
+        config.log_format,
+        exporters.as_ref().map(telemetry::Exporters::tracing_layer),
+    );
+    format::set_defaults(config.units, config.precision);
+    let replay = config.replay.as_ref().map(|path| {
+        let replay = Replay::open(path).unwrap_or_else(|err| {
+            tracing::error!("{err}");
+            process::exit(1);
+        });
+        // Histories and staleness checks go by the pace samples arrive at.
+        config.sample_interval = replay
+            .interval()
+            .div_f64(config.replay_speed)
+            .max(Duration::from_millis(1));
+        replay
+    });
+    // Replayed samples would otherwise be persisted as if they were new.
+    let history_db = config.history_db.as_ref().filter(|_| replay.is_none());
+    let history_db = history_db.map(|path| {
+        let db = HistoryDb::open(
+            path,
+            config.history_db_retention,
+            config.history_db_rollups.clone(),
+        )
+        .unwrap_or_else(|err| {
+            tracing::error!("Failed to open history database {}: {err}", path.display());
+            process::exit(1);
+        });
+        Arc::new(db)
+    });
+    let audit = AuditLog::open(
+        config.audit_log.as_deref(),
+        config
+            .history_db
+            .as_deref()
+            .filter(|_| history_db.is_some()),
+    )
+    .unwrap_or_else(|err| {
+        tracing::error!("{err}");
+        process::exit(1);
+    });
+    let alerts = AlertEngine::with_rules(config.alert_rules.clone()).unwrap_or_else(|err| {
+        eprintln!("{err}");
+        process::exit(2);
+    });
+    let recorder = config.record.as_ref().map(|path| {
+        Recorder::create(path, config.sample_interval).unwrap_or_else(|err| {
+            tracing::error!("{err}");
+            process::exit(1);
+        })
+    });
+    let sampler = Sampler::new(recorder);
+    let tls = match &config.tls {
+        Some(tls) => Some(
+            RustlsConfig::from_pem_file(&tls.cert, &tls.key)
+                .await
+                .unwrap_or_else(|err| {
+                    tracing::error!("Failed to load TLS certificate: {err}");
+                    process::exit(1);
+                }),
+        ),
+        None => None,
     };
-    start_cpu_info_task(app_state.clone());
-    start_memory_data_collection_task(app_state.clone());
-    let server = Server::bind(&get_address().parse().expect("Invalid host given"))
-        .serve(router(app_state).into_make_service());
-    let addr = server.local_addr();
-    println!("Listening on {addr}");
-    server.await.expect("Failed while waiting for the server");
-    println!("Hello, world!");
-}
-
-fn start_cpu_info_task(app_state: AppState) {
-    tokio::task::spawn_blocking(move || {
-        let mut sys = System::new();
-        loop {
-            sys.refresh_cpu();
-            let cpus: Vec<CpuInfo> = sys
-                .cpus()
-                .iter()
-                .map(|cpu| CpuInfo {
-                    cpu_usage: cpu.cpu_usage(),
-                    frequency: cpu.frequency(),
-                    vendor_id: cpu.vendor_id().to_owned(),
-                    brand: cpu.brand().to_owned(),
+    let listeners = systemd::listeners().unwrap_or_else(|err| {
+        tracing::error!("{err}");
+        process::exit(1);
+    });
+    let listeners = match listeners {
+        Some(listeners) => {
+            tracing::info!(sockets = listeners.len(), "Using sockets passed by systemd");
+            listeners
+        }
+        None => config
+            .bind
+            .iter()
+            .map(|addr| {
+                TcpListener::bind(addr).unwrap_or_else(|err| {
+                    tracing::error!("Failed to bind {addr}: {err}");
+                    process::exit(1);
                 })
-                .collect();
-            app_state.tx_cpu.send(cpus).unwrap_or_default();
-            std::thread::sleep(System::MINIMUM_CPU_UPDATE_INTERVAL);
+            })
+            .collect(),
+    };
+    let servers: Arc<[Handle]> = listeners.iter().map(|_| Handle::new()).collect();
+    let app_state = app_state(
+        config,
+        sampler.system(),
+        history_db,
+        audit,
+        alerts,
+        servers.clone(),
+    );
+    let shutdown = app_state.shutdown.clone();
+    let connections = app_state.connections.clone();
+    match replay {
+        Some(replay) => {
+            tracing::info!(
+                speed = app_state.config.replay_speed,
+                "Replaying a recording"
+            );
+            replay.spawn(app_state.config.replay_speed, app_state.clone());
         }
-    });
+        None => sampler.spawn(app_state.clone()),
+    }
+    app_state.registry.start(&app_state);
+    if let Some(influxdb) = &app_state.config.influxdb {
+        influx::spawn(influxdb.clone(), app_state.clone());
+    }
+    if let Some(mqtt) = &app_state.config.mqtt {
+        mqtt::spawn(mqtt.clone(), app_state.clone());
+    }
+    if let Some(statsd) = &app_state.config.statsd {
+        statsd::spawn(statsd.clone(), app_state.clone());
+    }
+    if !app_state.config.remotes.is_empty() {
+        federation::spawn(
+            app_state.config.remotes.clone(),
+            app_state.config.remote_interval,
+            app_state.clone(),
+        );
+    }
+    if let Some(agent) = &app_state.config.agent {
+        agent::spawn(agent.clone(), app_state.clone());
+    }
+    if let Some(jwks) = app_state
+        .config
+        .jwt
+        .as_ref()
+        .and_then(|jwt| jwt.jwks.clone())
+    {
+        jwt::spawn(jwks, app_state.clone());
+    }
+    if let Some(mdns) = &app_state.config.mdns {
+        let listening = listeners
+            .iter()
+            .filter_map(|listener| listener.local_addr().ok())
+            .collect();
+        mdns::spawn(mdns.clone(), listening, app_state.clone());
+    }
+    if let Some(addr) = app_state.config.grpc_bind {
+        grpc::spawn(addr, app_state.clone());
+    }
+    tokio::spawn(cancel_on_signal(shutdown.clone()));
+    tokio::spawn(notify_ready(servers.clone()));
+    // One server per listener, all sharing the same router and state.
+    let app = router(app_state.clone());
+    let served = listeners
+        .into_iter()
+        .zip(servers.iter())
+        .map(|(listener, handle)| {
+            tokio::spawn(stop_server_on_shutdown(handle.clone(), shutdown.clone()));
+            tokio::spawn(announce_listening(handle.clone()));
+            let app = app
+                .clone()
+                .into_make_service_with_connect_info::<SocketAddr>();
+            let handle = handle.clone();
+            let tls = tls.clone();
+            async move {
+                match tls {
+                    Some(tls) => {
+                        axum_server::from_tcp_rustls(listener, tls)
+                            .handle(handle)
+                            .serve(app)
+                            .await
+                    }
+                    None => {
+                        axum_server::from_tcp(listener)
+                            .handle(handle)
+                            .serve(app)
+                            .await
+                    }
+                }
+            }
+        });
+    future::try_join_all(served)
+        .await
+        .expect("Failed while waiting for the server");
+    connections.close();
+    if tokio::time::timeout(SHUTDOWN_GRACE_PERIOD, connections.wait())
+        .await
+        .is_err()
+    {
+        tracing::warn!("Timed out closing realtime connections");
+    }
+    if let Some(exporters) = exporters {
+        exporters.shutdown();
+    }
+    tracing::info!("Server stopped");
 }
 
-fn start_memory_data_collection_task(app_state: AppState) {
-    tokio::task::spawn_blocking(move || {
-        let mut sys = System::new();
-        loop {
-            sys.refresh_memory();
-            let memory_data = Memory {
-                total_memory: sys.total_memory().to_human(None),
-                used_memory: sys.used_memory().to_human(None),
-                total_swap: sys.total_swap().to_human(None),
-                used_swap: sys.used_swap().to_human(None),
-            };
-            app_state.tx_memory.send(memory_data).unwrap_or_default();
-            std::thread::sleep(System::MINIMUM_CPU_UPDATE_INTERVAL);
-        }
-    });
+/// The shared state with every source registered, before anything samples
+/// or serves.
+fn app_state(
+    config: Config,
+    system: Arc<tokio::sync::Mutex<System>>,
+    history_db: Option<Arc<HistoryDb>>,
+    audit: AuditLog,
+    alerts: AlertEngine,
+    servers: Arc<[Handle]>,
+) -> AppState {
+    let collectors = config.collectors;
+    let containers = Topic::new();
+    let gpus = Topic::enabled_if(collectors.gpu);
+    let mut registry = Registry::new(config.history_retention, config.sample_interval);
+    if let Some(socket) = &config.docker_socket {
+        docker::register(
+            socket.clone(),
+            config.sample_interval,
+            &mut registry,
+            containers.clone(),
+        );
+    }
+    if collectors.gpu {
+        gpu::register(config.sample_interval, &mut registry, gpus.clone());
+    }
+    plugin::register(&config.plugins, &mut registry);
+    // The sampler refreshes these together and publishes to them.
+    let cpus = registry.register_sampled("cpus", true, Retain::History);
+    let memory = registry.register_sampled("memory", true, Retain::History);
+    let processes = registry.register_sampled("processes", collectors.processes, Retain::Latest);
+    let process_events =
+        registry.register_sampled("process_events", collectors.processes, Retain::Latest);
+    let connection_events =
+        registry.register_sampled("connection_events", collectors.connections, Retain::Latest);
+    let disks = registry.register_sampled("disks", collectors.disks, Retain::History);
+    let diskio = registry.register_sampled("diskio", collectors.diskio, Retain::History);
+    let networks = registry.register_sampled("networks", collectors.networks, Retain::History);
+    let temperatures =
+        registry.register_sampled("temperatures", collectors.temperatures, Retain::History);
+    let loadavg = registry.register_sampled("loadavg", true, Retain::History);
+    let pressure = registry.register_sampled("pressure", collectors.pressure, Retain::History);
+    let power = registry.register_sampled("power", collectors.power, Retain::History);
+    AppState {
+        servers,
+        shutdown: CancellationToken::new(),
+        connections: TaskTracker::new(),
+        alerts,
+        snapshots: SnapshotStore::default(),
+        hosts: HostRegistry::new(),
+        discovery: Discovery::default(),
+        jwks: Jwks::default(),
+        telemetry: Telemetry::new(),
+        limiter: Limiter::new(config.limits.clone()),
+        activity: Activity::new(config.idle_after),
+        registry: Arc::new(registry),
+        notifier: Notifier::new(config.webhook_urls.clone()),
+        watchdog: Watchdog::new(&config.watches),
+        history_db,
+        audit,
+        config: Arc::new(config),
+        system,
+        cpus,
+        cpu_limit: Topic::new(),
+        memory,
+        processes,
+        process_events,
+        tcp_connections: Topic::enabled_if(collectors.connections),
+        connection_events,
+        disks,
+        diskio,
+        networks,
+        temperatures,
+        loadavg,
+        pressure,
+        containers,
+        gpus,
+        power,
+    }
 }
 
-#[derive(Clone)]
-struct AppState {
-    tx_cpu: broadcast::Sender<Vec<CpuInfo>>,
-    tx_memory: broadcast::Sender<Memory>,
-    cpu_info: Arc<Mutex<Vec<CpuInfo>>>,
-    memory: Arc<Mutex<Memory>>,
+async fn announce_listening(handle: Handle) {
+    if let Some(addr) = handle.listening().await {
+        tracing::info!(%addr, "Listening");
+    }
+}
+
+/// Tells systemd the service is up once every listener accepts connections.
+async fn notify_ready(servers: Arc<[Handle]>) {
+    future::join_all(servers.iter().map(Handle::listening)).await;
+    systemd::notify("READY=1");
+}
+
+pub async fn stop_server_on_shutdown(handle: Handle, shutdown: CancellationToken) {
+    shutdown.cancelled().await;
+    handle.graceful_shutdown(Some(SHUTDOWN_GRACE_PERIOD));
+}
+
+async fn cancel_on_signal(shutdown: CancellationToken) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for Ctrl+C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+    tracing::info!("Shutting down");
+    systemd::notify("STOPPING=1");
+    shutdown.cancel();
 }
 
-#[derive(Default, Debug, Clone, Serialize, Deserialize)]
-struct CpuInfo {
-    cpu_usage: f32,
-    frequency: u64,
-    vendor_id: String,
-    brand: String,
+#[derive(Clone)]
+pub struct AppState {
+    config: Arc<Config>,
+    shutdown: CancellationToken,
+    /// One per listener, tracking its open HTTP connections.
+    servers: Arc<[Handle]>,
+    /// Tracks realtime sessions so shutdown can wait for their close frames.
+    connections: TaskTracker,
+    system: Arc<tokio::sync::Mutex<System>>,
+    cpus: Sampled<Vec<CpuInfo>>,
+    /// Published with every sample, before `cpus`; `None` without a cgroup
+    /// CPU limit.
+    cpu_limit: Topic<Option<CpuLimit>>,
+    memory: Sampled<Memory>,
+    processes: Sampled<Vec<ProcessInfo>>,
+    /// Only published when processes started or exited since the last sample.
+    process_events: Sampled<Vec<ProcessEvent>>,
+    tcp_connections: Topic<Vec<ConnectionInfo>>,
+    /// Only published when connections opened or closed since the last
+    /// sample.
+    connection_events: Sampled<Vec<ConnectionEvent>>,
+    disks: Sampled<Vec<DiskInfo>>,
+    diskio: Sampled<Vec<DiskIoInfo>>,
+    networks: Sampled<Vec<NetworkInfo>>,
+    temperatures: Sampled<Vec<TemperatureInfo>>,
+    loadavg: Sampled<LoadAverage>,
+    pressure: Sampled<Pressure>,
+    /// Only published when the Docker integration is enabled.
+    containers: Topic<Vec<ContainerInfo>>,
+    /// Only published with the `gpu` feature and an NVIDIA driver.
+    gpus: Topic<Vec<GpuInfo>>,
+    /// Published on the first sample and whenever the AC status, a
+    /// battery's status or its rounded charge changes.
+    power: Sampled<PowerInfo>,
+    history_db: Option<Arc<HistoryDb>>,
+    audit: AuditLog,
+    alerts: AlertEngine,
+    snapshots: SnapshotStore,
+    hosts: HostRegistry,
+    /// Only filled in with `--mdns`.
+    discovery: Discovery,
+    /// Only filled in when JWTs are checked against a JWK set.
+    jwks: Jwks,
+    telemetry: Telemetry,
+    limiter: Limiter,
+    notifier: Notifier,
+    watchdog: Watchdog,
+    activity: Activity,
+    /// Every source above with a realtime topic, and the collectors on
+    /// loops of their own, such as Docker's.
+    registry: Arc<Registry>,
 }
 
-#[derive(Default, Debug, Clone, Serialize, Deserialize)]
-struct Memory {
-    total_memory: String,
-    used_memory: String,
-    total_swap: String,
-    used_swap: String,
+#[utoipa::path(
+    get,
+    path = "/api/v1/cpus",
+    tag = "cpus",
+    responses(
+        (status = 200, description = "Latest per-core sample", body = [CpuInfo], content_type = ["application/json", "application/msgpack"],
+            headers(("x-sample-timestamp" = u64, description = "When the sample was taken, in milliseconds since the Unix epoch"))),
+        (status = 503, description = "No sample taken yet", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+async fn get_cpus(
+    State(state): State<AppState>,
+    encoding: Encoding,
+) -> Result<impl IntoResponse, AppError> {
+    let latest = state.cpus.sampled("CPU")?;
+    Ok((
+        latest.timestamp_header(),
+        Encoded(encoding, latest.value().clone()),
+    ))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/cpus/summary",
+    tag = "cpus",
+    responses(
+        (status = 200, description = "Aggregate over all cores", body = CpuSummary, content_type = ["application/json", "application/msgpack"]),
+        (status = 503, description = "No sample taken yet", body = ErrorResponse),
+    )
+)]
 #[debug_handler]
-async fn get_cpus(State(state): State<AppState>) -> impl IntoResponse {
-    let cpu_info = state.cpu_info.lock().unwrap().clone();
-    Json(cpu_info)
+async fn get_cpu_summary(
+    State(state): State<AppState>,
+    encoding: Encoding,
+) -> Result<impl IntoResponse, AppError> {
+    let cpus = state.cpus.sampled("CPU")?;
+    let limit = state.cpu_limit.latest();
+    Ok(Encoded(
+        encoding,
+        CpuSummary::from_cpus(cpus.value(), limit.value().as_ref()),
+    ))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/memory",
+    tag = "memory",
+    params(FormatQuery),
+    responses(
+        (status = 200, description = "Latest memory sample; `format` drops one side of each raw/human pair, `units` and `precision` render the human side", body = Memory, content_type = ["application/json", "application/msgpack"]),
+        (status = 400, description = "Precision out of range", body = ErrorResponse),
+        (status = 503, description = "No sample taken yet", body = ErrorResponse),
+    )
+)]
 #[debug_handler]
-async fn get_memory(State(state): State<AppState>) -> impl IntoResponse {
-    let memory = state.memory.lock().unwrap().clone();
-    Json(memory)
+async fn get_memory(
+    State(state): State<AppState>,
+    encoding: Encoding,
+    Query(query): Query<FormatQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let memory = serde_json::to_value(state.memory.sampled("memory")?.value()).unwrap();
+    Ok(Encoded(encoding, query.apply(memory)?))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/disks",
+    tag = "disks",
+    params(FormatQuery),
+    responses(
+        (status = 200, description = "Mounted disks", body = [DiskInfo], content_type = ["application/json", "application/msgpack"],
+            headers(("x-sample-timestamp" = u64, description = "When the sample was taken, in milliseconds since the Unix epoch"))),
+        (status = 400, description = "Precision out of range", body = ErrorResponse),
+        (status = 404, description = "The disk collector is disabled", body = ErrorResponse),
+        (status = 503, description = "No sample taken yet", body = ErrorResponse),
+    )
+)]
 #[debug_handler]
-async fn health() -> &'static str {
-    "Ok"
+async fn get_disks(
+    State(state): State<AppState>,
+    encoding: Encoding,
+    Query(query): Query<FormatQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let latest = state.disks.sampled("disk")?;
+    let disks = serde_json::to_value(latest.value()).unwrap();
+    Ok((
+        latest.timestamp_header(),
+        Encoded(encoding, query.apply(disks)?),
+    ))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/networks",
+    tag = "networks",
+    responses(
+        (status = 200, description = "Network interfaces", body = [NetworkInfo], content_type = ["application/json", "application/msgpack"],
+            headers(("x-sample-timestamp" = u64, description = "When the sample was taken, in milliseconds since the Unix epoch"))),
+        (status = 404, description = "The network collector is disabled", body = ErrorResponse),
+        (status = 503, description = "No sample taken yet", body = ErrorResponse),
+    )
+)]
 #[debug_handler]
-async fn realtime_cpus_get(
-    ws: WebSocketUpgrade,
+async fn get_networks(
     State(state): State<AppState>,
-) -> impl IntoResponse {
-    ws.on_upgrade(|ws| async { realtime_cpu_stream(state, ws).await })
+    encoding: Encoding,
+) -> Result<impl IntoResponse, AppError> {
+    let latest = state.networks.sampled("network")?;
+    Ok((
+        latest.timestamp_header(),
+        Encoded(encoding, latest.value().clone()),
+    ))
 }
 
-async fn realtime_cpu_stream(app_state: AppState, mut ws: WebSocket) {
-    let mut rx = app_state.tx_cpu.subscribe();
-    while let Ok(msg) = rx.recv().await {
-        let payload = serde_json::to_string(&msg).unwrap();
-        ws.send(Message::Text(payload)).await.unwrap_or_default();
-    }
+#[utoipa::path(
+    get,
+    path = "/api/v1/temperatures",
+    tag = "sensors",
+    responses(
+        (status = 200, description = "Temperature sensors", body = [TemperatureInfo], content_type = ["application/json", "application/msgpack"],
+            headers(("x-sample-timestamp" = u64, description = "When the sample was taken, in milliseconds since the Unix epoch"))),
+        (status = 404, description = "The temperature collector is disabled", body = ErrorResponse),
+        (status = 503, description = "No sample taken yet", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+async fn get_temperatures(
+    State(state): State<AppState>,
+    encoding: Encoding,
+) -> Result<impl IntoResponse, AppError> {
+    let latest = state.temperatures.sampled("temperature")?;
+    Ok((
+        latest.timestamp_header(),
+        Encoded(encoding, latest.value().clone()),
+    ))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/v1/loadavg",
+    tag = "system",
+    responses(
+        (status = 200, description = "Load averages", body = LoadAverage, content_type = ["application/json", "application/msgpack"]),
+        (status = 503, description = "No sample taken yet", body = ErrorResponse),
+    )
+)]
 #[debug_handler]
-async fn realtime_memory_get(
-    ws: WebSocketUpgrade,
+async fn get_loadavg(
     State(state): State<AppState>,
-) -> impl IntoResponse {
-    ws.on_upgrade(|ws| async { crate::realtime_memory_stream(state, ws).await })
+    encoding: Encoding,
+) -> Result<impl IntoResponse, AppError> {
+    let latest = state.loadavg.sampled("load average")?;
+    Ok(Encoded(encoding, latest.value().clone()))
 }
 
-async fn realtime_memory_stream(app_state: AppState, mut ws: WebSocket) {
-    let mut rx = app_state.tx_memory.subscribe();
-    while let Ok(msg) = rx.recv().await {
-        let payload = serde_json::to_string(&msg).unwrap();
-        ws.send(Message::Text(payload)).await.unwrap_or_default();
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "monitoring",
+    security(()),
+    responses((status = 200, description = "Prometheus text exposition format", body = String, content_type = "text/plain"))
+)]
+#[debug_handler]
+async fn get_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let mut body = metrics::render(
+        state.cpus.latest().value(),
+        state.memory.latest().value(),
+        &state.config.labels,
+    );
+    metrics::render_collectors(&mut body, state.registry.latest(), &state.config.labels);
+    ([(header::CONTENT_TYPE, metrics::CONTENT_TYPE)], body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use auth::Role;
+    use axum::body::Body;
+    use axum::extract::ConnectInfo;
+    use axum::http::{Method, Request, StatusCode};
+    use config::ApiKey;
+    use hyper::service::Service;
+    use sysinfo::SystemExt;
+
+    /// A router whose clients authenticate with the name of their role.
+    fn app() -> Router {
+        let mut config = Config::defaults();
+        config.api_keys = [Role::Viewer, Role::Operator, Role::Admin]
+            .into_iter()
+            .map(|role| ApiKey {
+                key: role.to_string(),
+                role,
+            })
+            .collect();
+        let state = app_state(
+            config,
+            Arc::new(tokio::sync::Mutex::new(System::new())),
+            None,
+            AuditLog::open(None, None).unwrap(),
+            AlertEngine::with_rules(vec![]).unwrap(),
+            Arc::new([]),
+        );
+        router(state)
+    }
+
+    fn request(role: Role, method: Method, path: &str) -> Request<Body> {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::AUTHORIZATION, format!("Bearer {role}"))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"signal": "TERM", "nice": 10}"#))
+            .unwrap();
+        let peer = SocketAddr::from(([127, 0, 0, 1], 40000));
+        request.extensions_mut().insert(ConnectInfo(peer));
+        request
+    }
+
+    async fn status(role: Role, method: Method, path: &str) -> StatusCode {
+        app()
+            .call(request(role, method, path))
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn viewers_cant_act_on_processes() {
+        for path in ["/api/v1/processes/1/signal", "/api/v1/processes/1/priority"] {
+            assert_eq!(
+                status(Role::Viewer, Method::POST, path).await,
+                StatusCode::FORBIDDEN,
+                "{path}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn operators_cant_change_alert_rules_or_read_the_audit_log() {
+        for (method, path) in [
+            (Method::POST, "/api/v1/alerts/rules"),
+            (Method::PUT, "/api/v1/alerts/rules/cpu"),
+            (Method::DELETE, "/api/v1/alerts/rules/cpu"),
+            (Method::GET, "/api/v1/audit"),
+        ] {
+            assert_eq!(
+                status(Role::Operator, method.clone(), path).await,
+                StatusCode::FORBIDDEN,
+                "{method} {path}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn snapshot_and_host_changes_are_audited() {
+        let mut app = app();
+        for (role, method, path) in [
+            (Role::Viewer, Method::POST, "/api/v1/snapshots"),
+            (Role::Operator, Method::DELETE, "/api/v1/snapshots/7"),
+            (Role::Operator, Method::DELETE, "/api/v1/hosts/web-1"),
+        ] {
+            app.call(request(role, method, path)).await.unwrap();
+        }
+        let response = app
+            .call(request(Role::Admin, Method::GET, "/api/v1/audit"))
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let entries: Vec<audit::AuditEntry> = serde_json::from_slice(&body).unwrap();
+        let recorded: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.action, entry.target.as_deref(), entry.status))
+            .collect();
+        assert_eq!(
+            recorded,
+            [
+                (audit::AuditAction::DeleteHost, Some("web-1"), 404),
+                (audit::AuditAction::DeleteSnapshot, Some("7"), 404),
+                (audit::AuditAction::CreateSnapshot, None, 403),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn roles_include_the_ones_below() {
+        assert_eq!(
+            status(Role::Viewer, Method::GET, "/api/v1/version").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(Role::Admin, Method::GET, "/api/v1/audit").await,
+            StatusCode::OK
+        );
     }
 }
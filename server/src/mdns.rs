@@ -0,0 +1,717 @@
+//! Zeroconf, enabled with `--mdns`: announces the server on the LAN as a
+//! `_process-viewer._tcp` DNS-SD service over multicast DNS, answers
+//! queries for it, and browses for the other instances, which
+//! `/api/v1/discovered` lists. IPv4 only.
+//!
+//! Only the small part of DNS this needs is implemented: PTR, SRV, TXT and
+//! A/AAAA records, with compressed names understood but never written.
+
+use crate::config::MdnsConfig;
+use crate::{api_error, AppError, AppState};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use axum_macros::debug_handler;
+use serde::Serialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, UdpSocket as StdUdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use sysinfo::SystemExt;
+use tokio::net::UdpSocket;
+use utoipa::ToSchema;
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+const SERVICE_TYPE: &str = "_process-viewer._tcp.local";
+/// Asked by browsers enumerating every service type on the network.
+const SERVICES_META: &str = "_services._dns-sd._udp.local";
+/// Of every record announced; instances not heard from for this long are
+/// dropped from the discovered list.
+const TTL: u32 = 120;
+/// Well inside `TTL`, so live instances never expire.
+const QUERY_INTERVAL: Duration = Duration::from_secs(45);
+/// Answers to queries are sent at most this often.
+const MIN_ANSWER_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_PACKET: usize = 9000;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Set on records only this host answers for.
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// Another instance found on the LAN.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DiscoveredInstance {
+    /// Its instance name, the hostname unless set with `--mdns-name`.
+    pub name: String,
+    /// Its `.local` hostname.
+    pub hostname: String,
+    pub port: u16,
+    pub addresses: Vec<String>,
+    /// Where to reach it, e.g. for `--remote`; unset until an address is
+    /// known.
+    pub url: Option<String>,
+    pub version: Option<String>,
+    /// Milliseconds since it was last heard from.
+    pub age_ms: u64,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Instance {
+    name: String,
+    target: String,
+    port: u16,
+    addresses: Vec<IpAddr>,
+    txt: HashMap<String, String>,
+    seen: Option<Instant>,
+}
+
+impl Instance {
+    fn view(&self) -> DiscoveredInstance {
+        let scheme = match self.txt.get("tls").map(String::as_str) {
+            Some("1") => "https",
+            _ => "http",
+        };
+        DiscoveredInstance {
+            name: self
+                .name
+                .strip_suffix(&format!(".{SERVICE_TYPE}"))
+                .unwrap_or(&self.name)
+                .to_owned(),
+            hostname: self.target.clone(),
+            port: self.port,
+            addresses: self.addresses.iter().map(IpAddr::to_string).collect(),
+            url: self
+                .addresses
+                .first()
+                .map(|address| format!("{scheme}://{}", SocketAddr::new(*address, self.port))),
+            version: self.txt.get("version").cloned(),
+            age_ms: self
+                .seen
+                .map_or(0, |seen| seen.elapsed().as_millis() as u64),
+        }
+    }
+}
+
+/// Instances found so far, by lowercased instance name.
+#[derive(Clone, Default)]
+pub struct Discovery {
+    instances: Arc<Mutex<HashMap<String, Instance>>>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/discovered",
+    tag = "hosts",
+    responses(
+        (status = 200, description = "Other instances announcing themselves on the LAN, ordered by name", body = [DiscoveredInstance]),
+        (status = 404, description = "mDNS is disabled", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_discovered(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<DiscoveredInstance>>, AppError> {
+    if state.config.mdns.is_none() {
+        return Err(api_error(
+            StatusCode::NOT_FOUND,
+            "mDNS is disabled, start the server with --mdns",
+        ));
+    }
+    let mut instances = state.discovery.instances.lock().unwrap();
+    instances.retain(|_, instance| {
+        instance
+            .seen
+            .is_some_and(|seen| seen.elapsed() < Duration::from_secs(TTL.into()))
+    });
+    let mut discovered: Vec<DiscoveredInstance> = instances.values().map(Instance::view).collect();
+    discovered.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(Json(discovered))
+}
+
+/// `listening` are the addresses actually listened on, which can differ
+/// from the configured ones with systemd sockets or port 0.
+pub fn spawn(config: MdnsConfig, listening: Vec<SocketAddr>, app_state: AppState) {
+    tokio::spawn(async move {
+        let socket = match open_socket() {
+            Ok(socket) => socket,
+            Err(err) => {
+                tracing::warn!(%err, "Failed to open the mDNS socket, not announcing");
+                return;
+            }
+        };
+        let hostname = app_state
+            .system
+            .lock()
+            .await
+            .host_name()
+            .unwrap_or_else(|| "process-viewer".to_owned());
+        let service = Service::new(&config, &hostname, &listening, &app_state);
+        tracing::info!(instance = %service.instance, "Announcing over mDNS");
+        run(socket, service, app_state).await;
+    });
+}
+
+fn open_socket() -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // Shared with any other responder on the host, such as Avahi.
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, MDNS_PORT).into())?;
+    socket.join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// What this server announces.
+struct Service {
+    instance: String,
+    target: String,
+    port: u16,
+    address: Option<Ipv4Addr>,
+    txt: Vec<String>,
+}
+
+impl Service {
+    fn new(
+        config: &MdnsConfig,
+        hostname: &str,
+        listening: &[SocketAddr],
+        app_state: &AppState,
+    ) -> Self {
+        // Dots would split the names into more labels.
+        let label = |name: &str| name.replace('.', "-");
+        let name = config.name.as_deref().unwrap_or(hostname);
+        // Only IPv4 is announced, so prefer a listener reachable over it.
+        let bind = listening
+            .iter()
+            .find(|addr| addr.is_ipv4())
+            .or(listening.first())
+            .copied();
+        let address = match bind.map(|bind| bind.ip()) {
+            Some(IpAddr::V4(ip)) if !ip.is_unspecified() => Some(ip),
+            _ => local_address(),
+        };
+        let txt = vec![
+            format!("version={}", env!("CARGO_PKG_VERSION")),
+            format!("tls={}", u8::from(app_state.config.tls.is_some())),
+            format!("auth={}", u8::from(app_state.config.requires_auth())),
+            "path=/api/v1".to_owned(),
+        ];
+        Self {
+            instance: format!("{}.{SERVICE_TYPE}", label(name)),
+            target: format!("{}.local", label(hostname)),
+            port: bind.map_or(0, |bind| bind.port()),
+            address,
+            txt,
+        }
+    }
+
+    /// The records announcing the service, with `ttl` 0 to withdraw them.
+    fn announcement(&self, ttl: u32) -> Message {
+        let unique = |name: &str, data| Record {
+            name: name.to_owned(),
+            class: CLASS_IN | CACHE_FLUSH,
+            ttl,
+            data,
+        };
+        let mut records = vec![
+            Record {
+                name: SERVICE_TYPE.to_owned(),
+                class: CLASS_IN,
+                ttl,
+                data: RecordData::Ptr(self.instance.clone()),
+            },
+            unique(
+                &self.instance,
+                RecordData::Srv {
+                    port: self.port,
+                    target: self.target.clone(),
+                },
+            ),
+            unique(&self.instance, RecordData::Txt(self.txt.clone())),
+        ];
+        if let Some(address) = self.address {
+            records.push(unique(&self.target, RecordData::A(address)));
+        }
+        Message {
+            response: true,
+            questions: vec![],
+            records,
+        }
+    }
+
+    fn is_asked_for(&self, (name, qtype): &(String, u16)) -> bool {
+        let is = |expected: &str, types: &[u16]| {
+            name.eq_ignore_ascii_case(expected) && (types.contains(qtype) || *qtype == TYPE_ANY)
+        };
+        is(SERVICE_TYPE, &[TYPE_PTR])
+            || is(SERVICES_META, &[TYPE_PTR])
+            || is(&self.instance, &[TYPE_SRV, TYPE_TXT])
+            || is(&self.target, &[TYPE_A])
+    }
+}
+
+/// Address of the interface multicast leaves through; connecting a UDP
+/// socket sends nothing.
+fn local_address() -> Option<Ipv4Addr> {
+    let socket = StdUdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((MDNS_GROUP, MDNS_PORT)).ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() => Some(ip),
+        _ => None,
+    }
+}
+
+async fn run(socket: UdpSocket, service: Service, app_state: AppState) {
+    let group = SocketAddr::from((MDNS_GROUP, MDNS_PORT));
+    let browse = Message {
+        response: false,
+        questions: vec![(SERVICE_TYPE.to_owned(), TYPE_PTR)],
+        records: vec![],
+    }
+    .encode();
+    let announcement = service.announcement(TTL).encode();
+    // Announced twice, a second apart, as the first may be lost.
+    let _ = socket.send_to(&announcement, group).await;
+    let mut last_answer = Instant::now();
+    let mut announced_again = false;
+    let mut query = tokio::time::interval(QUERY_INTERVAL);
+    let mut buf = vec![0u8; MAX_PACKET];
+    loop {
+        tokio::select! {
+            _ = query.tick() => {
+                let _ = socket.send_to(&browse, group).await;
+            }
+            _ = tokio::time::sleep(MIN_ANSWER_INTERVAL), if !announced_again => {
+                let _ = socket.send_to(&announcement, group).await;
+                announced_again = true;
+            }
+            received = socket.recv_from(&mut buf) => {
+                let Ok((len, _)) = received else {
+                    continue;
+                };
+                let Some(message) = Message::parse(&buf[..len]) else {
+                    continue;
+                };
+                if message.response {
+                    learn(&app_state.discovery, &service, &message);
+                } else if message.questions.iter().any(|question| service.is_asked_for(question))
+                    && last_answer.elapsed() >= MIN_ANSWER_INTERVAL
+                {
+                    let _ = socket.send_to(&announcement, group).await;
+                    last_answer = Instant::now();
+                }
+            }
+            _ = app_state.shutdown.cancelled() => break,
+        }
+    }
+    // Tells the others to forget this instance right away.
+    let _ = socket
+        .send_to(&service.announcement(0).encode(), group)
+        .await;
+}
+
+/// Adds the instances announced in `message` to `discovery`, and removes
+/// withdrawn ones.
+fn learn(discovery: &Discovery, service: &Service, message: &Message) {
+    let mut instances = discovery.instances.lock().unwrap();
+    let now = Instant::now();
+    for record in &message.records {
+        if let RecordData::Ptr(instance) = &record.data {
+            if !record.name.eq_ignore_ascii_case(SERVICE_TYPE)
+                || instance.eq_ignore_ascii_case(&service.instance)
+            {
+                continue;
+            }
+            let key = instance.to_ascii_lowercase();
+            if record.ttl == 0 {
+                instances.remove(&key);
+                continue;
+            }
+            let entry = instances.entry(key).or_default();
+            entry.name.clone_from(instance);
+            entry.seen = Some(now);
+        }
+    }
+    for record in &message.records {
+        let Some(entry) = instances.get_mut(&record.name.to_ascii_lowercase()) else {
+            continue;
+        };
+        match &record.data {
+            RecordData::Srv { port, target } => {
+                entry.port = *port;
+                entry.target.clone_from(target);
+                entry.seen = Some(now);
+            }
+            RecordData::Txt(strings) => {
+                entry.txt = strings
+                    .iter()
+                    .filter_map(|string| string.split_once('='))
+                    .map(|(key, value)| (key.to_ascii_lowercase(), value.to_owned()))
+                    .collect();
+            }
+            _ => {}
+        }
+    }
+    // Addresses belong to the hostname the SRV record points at.
+    for record in &message.records {
+        let address = match record.data {
+            RecordData::A(ip) => IpAddr::V4(ip),
+            RecordData::Aaaa(ip) => IpAddr::V6(ip),
+            _ => continue,
+        };
+        for entry in instances.values_mut() {
+            if entry.target.eq_ignore_ascii_case(&record.name) {
+                if record.class & CACHE_FLUSH != 0 && entry.seen == Some(now) {
+                    entry
+                        .addresses
+                        .retain(|known| known.is_ipv4() != address.is_ipv4());
+                }
+                if !entry.addresses.contains(&address) {
+                    entry.addresses.push(address);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Message {
+    response: bool,
+    /// Name and type of every question.
+    questions: Vec<(String, u16)>,
+    /// Answers, authority and additional records alike.
+    records: Vec<Record>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Record {
+    name: String,
+    /// With the cache flush bit.
+    class: u16,
+    ttl: u32,
+    data: RecordData,
+}
+
+#[derive(Debug, PartialEq)]
+enum RecordData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ptr(String),
+    Srv { port: u16, target: String },
+    Txt(Vec<String>),
+    Other(u16),
+}
+
+impl Message {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        let flags: u16 = if self.response { 0x8400 } else { 0 };
+        for value in [
+            0,
+            flags,
+            self.questions.len() as u16,
+            self.records.len() as u16,
+            0,
+            0,
+        ] {
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+        for (name, qtype) in &self.questions {
+            write_name(&mut out, name);
+            out.extend_from_slice(&qtype.to_be_bytes());
+            out.extend_from_slice(&CLASS_IN.to_be_bytes());
+        }
+        for record in &self.records {
+            write_name(&mut out, &record.name);
+            let (rtype, data) = match &record.data {
+                RecordData::A(ip) => (TYPE_A, ip.octets().to_vec()),
+                RecordData::Aaaa(ip) => (TYPE_AAAA, ip.octets().to_vec()),
+                RecordData::Ptr(name) => {
+                    let mut data = vec![];
+                    write_name(&mut data, name);
+                    (TYPE_PTR, data)
+                }
+                RecordData::Srv { port, target } => {
+                    // Priority and weight, unused here.
+                    let mut data = vec![0, 0, 0, 0];
+                    data.extend_from_slice(&port.to_be_bytes());
+                    write_name(&mut data, target);
+                    (TYPE_SRV, data)
+                }
+                RecordData::Txt(strings) => {
+                    let mut data = vec![];
+                    for string in strings {
+                        let bytes = &string.as_bytes()[..string.len().min(255)];
+                        data.push(bytes.len() as u8);
+                        data.extend_from_slice(bytes);
+                    }
+                    (TYPE_TXT, data)
+                }
+                RecordData::Other(rtype) => (*rtype, vec![]),
+            };
+            out.extend_from_slice(&rtype.to_be_bytes());
+            out.extend_from_slice(&record.class.to_be_bytes());
+            out.extend_from_slice(&record.ttl.to_be_bytes());
+            out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            out.extend_from_slice(&data);
+        }
+        out
+    }
+
+    /// `None` for anything malformed.
+    fn parse(packet: &[u8]) -> Option<Self> {
+        let mut reader = Reader { packet, pos: 0 };
+        let _id = reader.u16()?;
+        let flags = reader.u16()?;
+        let counts = [reader.u16()?, reader.u16()?, reader.u16()?, reader.u16()?];
+        let mut questions = vec![];
+        for _ in 0..counts[0] {
+            let name = reader.name()?;
+            let qtype = reader.u16()?;
+            let _class = reader.u16()?;
+            questions.push((name, qtype));
+        }
+        let mut records = vec![];
+        for _ in 0..counts[1..]
+            .iter()
+            .map(|&count| usize::from(count))
+            .sum::<usize>()
+        {
+            let name = reader.name()?;
+            let rtype = reader.u16()?;
+            let class = reader.u16()?;
+            let ttl = reader.u32()?;
+            let len = usize::from(reader.u16()?);
+            let end = reader
+                .pos
+                .checked_add(len)
+                .filter(|&end| end <= packet.len())?;
+            // Nothing in the data may run past it; pointers only go
+            // backwards, so names still resolve.
+            let mut rdata = Reader {
+                packet: &packet[..end],
+                pos: reader.pos,
+            };
+            let data = match rtype {
+                TYPE_A => RecordData::A(Ipv4Addr::from(
+                    <[u8; 4]>::try_from(&packet[reader.pos..end]).ok()?,
+                )),
+                TYPE_AAAA => RecordData::Aaaa(Ipv6Addr::from(
+                    <[u8; 16]>::try_from(&packet[reader.pos..end]).ok()?,
+                )),
+                TYPE_PTR => RecordData::Ptr(rdata.name()?),
+                TYPE_SRV => {
+                    let _priority = rdata.u16()?;
+                    let _weight = rdata.u16()?;
+                    let port = rdata.u16()?;
+                    RecordData::Srv {
+                        port,
+                        target: rdata.name()?,
+                    }
+                }
+                TYPE_TXT => {
+                    let mut strings = vec![];
+                    while rdata.pos < end {
+                        let len = usize::from(rdata.u8()?);
+                        let bytes = rdata.take(len)?;
+                        strings.push(String::from_utf8_lossy(bytes).into_owned());
+                    }
+                    RecordData::Txt(strings)
+                }
+                rtype => RecordData::Other(rtype),
+            };
+            reader.pos = end;
+            records.push(Record {
+                name,
+                class,
+                ttl,
+                data,
+            });
+        }
+        Some(Self {
+            response: flags & 0x8000 != 0,
+            questions,
+            records,
+        })
+    }
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        let bytes = &label.as_bytes()[..label.len().min(63)];
+        out.push(bytes.len() as u8);
+        out.extend_from_slice(bytes);
+    }
+    out.push(0);
+}
+
+struct Reader<'a> {
+    packet: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.packet.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.take(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    /// A dotted name, following compression pointers; reading continues
+    /// after the first pointer.
+    fn name(&mut self) -> Option<String> {
+        let mut labels: Vec<String> = vec![];
+        let mut pos = self.pos;
+        let mut resume = None;
+        // Every pointer must go backwards, so a loop can't go on forever.
+        let mut limit = pos;
+        loop {
+            let len = *self.packet.get(pos)?;
+            match len {
+                0 => {
+                    pos += 1;
+                    break;
+                }
+                len if len & 0xc0 == 0xc0 => {
+                    let target =
+                        usize::from(u16::from_be_bytes([len & 0x3f, *self.packet.get(pos + 1)?]));
+                    if target >= limit {
+                        return None;
+                    }
+                    resume.get_or_insert(pos + 2);
+                    limit = target;
+                    pos = target;
+                }
+                len => {
+                    let len = usize::from(len);
+                    let label = self.packet.get(pos + 1..pos + 1 + len)?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    pos += 1 + len;
+                }
+            }
+        }
+        self.pos = resume.unwrap_or(pos);
+        Some(labels.join("."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A header with one question and no records.
+    const ONE_QUESTION: [u8; 12] = [0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+
+    fn announcement() -> Message {
+        let service = Service {
+            instance: format!("web-1.{SERVICE_TYPE}"),
+            target: "web-1.local".to_owned(),
+            port: 7070,
+            address: Some(Ipv4Addr::new(192, 168, 1, 20)),
+            txt: vec!["version=1".to_owned(), "tls=0".to_owned()],
+        };
+        let mut message = service.announcement(TTL);
+        message.questions.push((SERVICE_TYPE.to_owned(), TYPE_PTR));
+        message.records.push(Record {
+            name: "web-1.local".to_owned(),
+            class: CLASS_IN,
+            ttl: TTL,
+            data: RecordData::Aaaa(Ipv6Addr::LOCALHOST),
+        });
+        message
+    }
+
+    /// One record of type `rtype` named `a`, with `rdata` claiming `len`
+    /// bytes, followed by `trailer`.
+    fn record(rtype: u16, len: u16, rdata: &[u8], trailer: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0, 1, b'a', 0];
+        packet.extend_from_slice(&rtype.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        packet.extend_from_slice(&TTL.to_be_bytes());
+        packet.extend_from_slice(&len.to_be_bytes());
+        packet.extend_from_slice(rdata);
+        packet.extend_from_slice(trailer);
+        packet
+    }
+
+    #[test]
+    fn encoded_messages_parse_back() {
+        let message = announcement();
+        assert_eq!(Message::parse(&message.encode()), Some(message));
+    }
+
+    #[test]
+    fn follows_backward_pointers() {
+        let mut packet = ONE_QUESTION.to_vec();
+        packet[5] = 2;
+        // "b.local", then "a" continuing at "local".
+        packet.extend_from_slice(&[1, b'b', 5, b'l', b'o', b'c', b'a', b'l', 0]);
+        packet.extend_from_slice(&[0, 1, 0, 1]);
+        packet.extend_from_slice(&[1, b'a', 0xc0, 14]);
+        packet.extend_from_slice(&[0, 12, 0, 1]);
+        let message = Message::parse(&packet).unwrap();
+        assert_eq!(
+            message.questions,
+            vec![
+                ("b.local".to_owned(), TYPE_A),
+                ("a.local".to_owned(), TYPE_PTR)
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        let pointing = |target: u8| {
+            let mut packet = ONE_QUESTION.to_vec();
+            packet.extend_from_slice(&[0xc0, target, 0, 1, 0, 1, 1, b'a', 0]);
+            packet
+        };
+        let encoded = announcement().encode();
+        let cases: &[(&str, Vec<u8>)] = &[
+            ("self pointer", pointing(12)),
+            ("forward pointer", pointing(18)),
+            ("truncated header", ONE_QUESTION[..11].to_vec()),
+            ("truncated record", encoded[..encoded.len() - 1].to_vec()),
+            ("data past the end", record(TYPE_A, 8, &[10, 0, 0, 1], &[])),
+            ("short A", record(TYPE_A, 3, &[10, 0, 0], &[])),
+            (
+                "short SRV",
+                record(TYPE_SRV, 4, &[0, 0, 0, 0], &[0x1f, 0x90, 0]),
+            ),
+            (
+                "TXT past its data",
+                record(TYPE_TXT, 3, &[5, b'a', b'b'], b"cde"),
+            ),
+            (
+                "TXT past the end",
+                record(TYPE_TXT, 3, &[5, b'a', b'b'], &[]),
+            ),
+        ];
+        for (case, packet) in cases {
+            assert!(Message::parse(packet).is_none(), "{case}");
+        }
+    }
+}
@@ -0,0 +1,118 @@
+//! Breakdown of memory use beyond used and total, read on request. Linux
+//! reports it from `/proc/meminfo`; other platforms only know total, free
+//! and available memory.
+
+use crate::encoding::{Encoded, Encoding};
+use crate::format::FormatQuery;
+use crate::models::{HumanReadable, MemoryDetail};
+use crate::{AppError, AppState};
+use axum::extract::{Query, State};
+use axum::response::IntoResponse;
+use axum_macros::debug_handler;
+use std::collections::HashMap;
+use sysinfo::SystemExt;
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/memory/detail",
+    tag = "memory",
+    params(FormatQuery),
+    responses(
+        (status = 200, description = "Memory by use; fields the platform doesn't report are `null`", body = MemoryDetail, content_type = ["application/json", "application/msgpack"]),
+        (status = 400, description = "Precision out of range", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_memory_detail(
+    State(state): State<AppState>,
+    encoding: Encoding,
+    Query(query): Query<FormatQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let detail = match read_meminfo() {
+        Some(meminfo) => from_meminfo(&meminfo),
+        None => {
+            let sys = state.system.lock().await;
+            let mut detail = MemoryDetail::default();
+            set(
+                &mut detail.total,
+                &mut detail.total_bytes,
+                sys.total_memory(),
+            );
+            set(&mut detail.free, &mut detail.free_bytes, sys.free_memory());
+            set(
+                &mut detail.available,
+                &mut detail.available_bytes,
+                sys.available_memory(),
+            );
+            detail
+        }
+    };
+    let detail = serde_json::to_value(detail).unwrap();
+    Ok(Encoded(encoding, query.apply(detail)?))
+}
+
+fn set(human: &mut Option<String>, raw: &mut Option<u64>, bytes: u64) {
+    *human = Some(bytes.to_human(None));
+    *raw = Some(bytes);
+}
+
+fn from_meminfo(meminfo: &HashMap<String, u64>) -> MemoryDetail {
+    let mut detail = MemoryDetail::default();
+    // Sizes are in KiB, page counts have no unit.
+    let field = |human: &mut Option<String>, raw: &mut Option<u64>, key: &str| {
+        if let Some(kib) = meminfo.get(key) {
+            set(human, raw, kib * 1024);
+        }
+    };
+    field(&mut detail.total, &mut detail.total_bytes, "MemTotal");
+    field(&mut detail.free, &mut detail.free_bytes, "MemFree");
+    field(
+        &mut detail.available,
+        &mut detail.available_bytes,
+        "MemAvailable",
+    );
+    field(&mut detail.buffers, &mut detail.buffers_bytes, "Buffers");
+    field(&mut detail.cached, &mut detail.cached_bytes, "Cached");
+    field(&mut detail.dirty, &mut detail.dirty_bytes, "Dirty");
+    field(
+        &mut detail.writeback,
+        &mut detail.writeback_bytes,
+        "Writeback",
+    );
+    field(&mut detail.slab, &mut detail.slab_bytes, "Slab");
+    field(
+        &mut detail.slab_reclaimable,
+        &mut detail.slab_reclaimable_bytes,
+        "SReclaimable",
+    );
+    field(&mut detail.shmem, &mut detail.shmem_bytes, "Shmem");
+    field(
+        &mut detail.hugepage_size,
+        &mut detail.hugepage_size_bytes,
+        "Hugepagesize",
+    );
+    detail.hugepages_total = meminfo.get("HugePages_Total").copied();
+    detail.hugepages_free = meminfo.get("HugePages_Free").copied();
+    detail
+}
+
+/// `Key:   value [kB]` lines by key.
+#[cfg(target_os = "linux")]
+fn read_meminfo() -> Option<HashMap<String, u64>> {
+    let content = std::fs::read_to_string("/proc/meminfo").ok()?;
+    Some(
+        content
+            .lines()
+            .filter_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                let value = value.split_whitespace().next()?.parse().ok()?;
+                Some((key.to_owned(), value))
+            })
+            .collect(),
+    )
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_meminfo() -> Option<HashMap<String, u64>> {
+    None
+}
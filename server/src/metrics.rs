@@ -0,0 +1,163 @@
+//! Prometheus text exposition of the latest sample.
+//!
+//! Exported metrics (all gauges):
+//!
+//! | name                                | labels                    | unit    |
+//! |-------------------------------------|---------------------------|---------|
+//! | `process_viewer_cpu_usage`          | `core`, `vendor`, `brand` | percent |
+//! | `process_viewer_cpu_frequency_hz`   | `core`, `vendor`, `brand` | hertz   |
+//! | `process_viewer_memory_total_bytes` |                           | bytes   |
+//! | `process_viewer_memory_used_bytes`  |                           | bytes   |
+//! | `process_viewer_swap_total_bytes`   |                           | bytes   |
+//! | `process_viewer_swap_used_bytes`    |                           | bytes   |
+//! | `process_viewer_collector_value`    | `collector`, `field`      |         |
+//!
+//! `core` is the zero-based index of the core as reported by sysinfo.
+//! `process_viewer_collector_value` has a series for every numeric field of
+//! the latest sample of each collector on a loop of its own (Docker, GPUs,
+//! plugins), see `collector.rs`; `field` is the dotted path to it, with list
+//! elements named by their id or name, e.g. `GPU-5d1c.gpu_usage`. The
+//! host's `--label`s are added to every series.
+//! These names and labels are part of the public API; don't rename them.
+
+use crate::collector::numeric_fields;
+use crate::models::{CpuInfo, Memory};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+pub fn render(cpus: &[CpuInfo], memory: &Memory, labels: &BTreeMap<String, String>) -> String {
+    // `,key="value"` for each label, appended to the series' own.
+    let labels: String = labels
+        .iter()
+        .map(|(key, value)| format!(",{key}=\"{}\"", escape_label(value)))
+        .collect();
+    let mut out = String::new();
+    write_header(
+        &mut out,
+        "process_viewer_cpu_usage",
+        "CPU usage per core in percent.",
+    );
+    for (core, cpu) in cpus.iter().enumerate() {
+        write_cpu_sample(
+            &mut out,
+            "process_viewer_cpu_usage",
+            core,
+            cpu,
+            &labels,
+            cpu.cpu_usage as f64,
+        );
+    }
+    write_header(
+        &mut out,
+        "process_viewer_cpu_frequency_hz",
+        "CPU frequency per core in hertz.",
+    );
+    for (core, cpu) in cpus.iter().enumerate() {
+        let hz = cpu.frequency as f64 * 1_000_000f64;
+        write_cpu_sample(
+            &mut out,
+            "process_viewer_cpu_frequency_hz",
+            core,
+            cpu,
+            &labels,
+            hz,
+        );
+    }
+    write_gauge(
+        &mut out,
+        "process_viewer_memory_total_bytes",
+        "Total memory in bytes.",
+        &labels,
+        memory.total_memory_bytes,
+    );
+    write_gauge(
+        &mut out,
+        "process_viewer_memory_used_bytes",
+        "Used memory in bytes.",
+        &labels,
+        memory.used_memory_bytes,
+    );
+    write_gauge(
+        &mut out,
+        "process_viewer_swap_total_bytes",
+        "Total swap in bytes.",
+        &labels,
+        memory.total_swap_bytes,
+    );
+    write_gauge(
+        &mut out,
+        "process_viewer_swap_used_bytes",
+        "Used swap in bytes.",
+        &labels,
+        memory.used_swap_bytes,
+    );
+    out
+}
+
+/// Appended to `render`'s output.
+pub fn render_collectors<'a>(
+    out: &mut String,
+    samples: impl Iterator<Item = (&'a str, Value)>,
+    labels: &BTreeMap<String, String>,
+) {
+    let labels: String = labels
+        .iter()
+        .map(|(key, value)| format!(",{key}=\"{}\"", escape_label(value)))
+        .collect();
+    let name = "process_viewer_collector_value";
+    write_header(
+        out,
+        name,
+        "Numeric fields of the Docker, GPU and plugin collectors' samples.",
+    );
+    for (collector, sample) in samples {
+        for (field, value) in numeric_fields(&sample) {
+            writeln!(
+                out,
+                "{name}{{collector=\"{collector}\",field=\"{}\"{labels}}} {value}",
+                escape_label(&field)
+            )
+            .unwrap();
+        }
+    }
+}
+
+fn write_header(out: &mut String, name: &str, help: &str) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} gauge").unwrap();
+}
+
+fn write_cpu_sample(
+    out: &mut String,
+    name: &str,
+    core: usize,
+    cpu: &CpuInfo,
+    labels: &str,
+    value: f64,
+) {
+    writeln!(
+        out,
+        "{name}{{core=\"{core}\",vendor=\"{}\",brand=\"{}\"{labels}}} {value}",
+        escape_label(&cpu.vendor_id),
+        escape_label(&cpu.brand)
+    )
+    .unwrap();
+}
+
+fn write_gauge(out: &mut String, name: &str, help: &str, labels: &str, value: u64) {
+    write_header(out, name, help);
+    match labels.strip_prefix(',') {
+        Some(labels) => writeln!(out, "{name}{{{labels}}} {value}").unwrap(),
+        None => writeln!(out, "{name} {value}").unwrap(),
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
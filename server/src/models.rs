@@ -0,0 +1,627 @@
+use crate::format::{self, Units};
+use async_graphql::SimpleObject;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use utoipa::ToSchema;
+
+pub trait HumanReadable: Sized {
+    /// In the configured units, see `format.rs`. `None` stands for the
+    /// configured precision.
+    fn to_human(self, precision: Option<u8>) -> String {
+        self.to_human_in(format::default_units(), precision)
+    }
+
+    fn to_human_in(self, units: Units, precision: Option<u8>) -> String;
+}
+
+impl HumanReadable for u64 {
+    fn to_human_in(self, units: Units, precision: Option<u8>) -> String {
+        let precision = precision.unwrap_or_else(format::default_precision) as usize;
+        let (base, prefixes) = match units {
+            Units::Si => (1000f64, ["KB", "MB", "GB"]),
+            Units::Iec => (1024f64, ["KiB", "MiB", "GiB"]),
+        };
+        let mut value = self as f64;
+        if value < base {
+            return self.to_string();
+        }
+        let mut prefix = 0;
+        value /= base;
+        while value >= base && prefix + 1 < prefixes.len() {
+            value /= base;
+            prefix += 1;
+        }
+        format!("{value:.precision$} {}", prefixes[prefix])
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema, SimpleObject)]
+#[graphql(rename_fields = "snake_case")]
+pub struct CpuInfo {
+    pub cpu_usage: f32,
+    pub frequency: u64,
+    pub vendor_id: String,
+    pub brand: String,
+    /// Linux only.
+    pub times: Option<CpuTimes>,
+}
+
+/// Share of time spent in each state since the previous sample, in percent.
+/// They add up to 100.
+#[derive(Default, Debug, Clone, Copy, Serialize, Deserialize, ToSchema, SimpleObject)]
+#[graphql(rename_fields = "snake_case")]
+pub struct CpuTimes {
+    pub user: f32,
+    /// User time of processes with a positive nice value.
+    pub nice: f32,
+    pub system: f32,
+    pub idle: f32,
+    /// Idle while waiting for IO to complete.
+    pub iowait: f32,
+    pub irq: f32,
+    pub softirq: f32,
+    /// Time the hypervisor gave to other guests while this VM wanted to run.
+    pub steal: f32,
+}
+
+/// Aggregate over all cores, for clients that don't need per-core data.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema, SimpleObject)]
+#[graphql(rename_fields = "snake_case")]
+pub struct CpuSummary {
+    /// Mean usage over all cores in percent.
+    pub cpu_usage: f32,
+    pub min_cpu_usage: f32,
+    pub max_cpu_usage: f32,
+    /// Mean frequency in MHz.
+    pub frequency: u64,
+    pub core_count: usize,
+    /// Cores granted by the server's cgroup (`cpu.max`), `null` without a
+    /// limit.
+    pub cpu_limit: Option<f64>,
+    /// Usage of the cgroup in percent of `cpu_limit`; the host-wide
+    /// `cpu_usage` without a limit.
+    pub effective_cpu_usage: f32,
+    /// Mean over all cores, Linux only.
+    pub times: Option<CpuTimes>,
+}
+
+/// Layout of the online logical CPUs.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CpuTopology {
+    pub sockets: usize,
+    pub physical_cores: usize,
+    pub logical_cpus: usize,
+    /// Ordered by `cpu`.
+    pub cpus: Vec<LogicalCpu>,
+    /// Each cache once, smallest level first.
+    pub caches: Vec<CpuCache>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct LogicalCpu {
+    /// Kernel CPU number, the same as the index in `/api/v1/cpus`.
+    pub cpu: u32,
+    pub socket: u32,
+    /// Core id within the socket. Ids need not be contiguous.
+    pub core: u32,
+    /// Logical CPUs on the same core, this one included.
+    pub siblings: Vec<u32>,
+    /// `performance` or `efficiency` on hybrid CPUs, otherwise `null`.
+    pub core_type: Option<String>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CpuCache {
+    pub level: u8,
+    /// `data`, `instruction` or `unified`.
+    pub kind: String,
+    pub size: String,
+    pub size_bytes: u64,
+    /// Logical CPUs sharing this cache.
+    pub shared_cpus: Vec<u32>,
+}
+
+/// The CPU limit of the server's cgroup and how much of it is used.
+#[derive(Default, Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct CpuLimit {
+    pub cores: f64,
+    /// Percent of `cores`.
+    pub cpu_usage: f32,
+}
+
+impl CpuSummary {
+    pub fn from_cpus(cpus: &[CpuInfo], limit: Option<&CpuLimit>) -> Self {
+        if cpus.is_empty() {
+            return Self::default();
+        }
+        let usages = cpus.iter().map(|cpu| cpu.cpu_usage);
+        let cpu_usage = usages.clone().sum::<f32>() / cpus.len() as f32;
+        Self {
+            cpu_usage,
+            min_cpu_usage: usages.clone().fold(f32::INFINITY, f32::min),
+            max_cpu_usage: usages.fold(f32::NEG_INFINITY, f32::max),
+            frequency: cpus.iter().map(|cpu| cpu.frequency).sum::<u64>() / cpus.len() as u64,
+            core_count: cpus.len(),
+            cpu_limit: limit.map(|limit| limit.cores),
+            effective_cpu_usage: limit.map_or(cpu_usage, |limit| limit.cpu_usage),
+            times: mean_times(cpus),
+        }
+    }
+}
+
+fn mean_times(cpus: &[CpuInfo]) -> Option<CpuTimes> {
+    let times: Vec<&CpuTimes> = cpus
+        .iter()
+        .map(|cpu| cpu.times.as_ref())
+        .collect::<Option<_>>()?;
+    let mean = |state: fn(&CpuTimes) -> f32| {
+        times.iter().map(|t| state(t)).sum::<f32>() / times.len() as f32
+    };
+    Some(CpuTimes {
+        user: mean(|times| times.user),
+        nice: mean(|times| times.nice),
+        system: mean(|times| times.system),
+        idle: mean(|times| times.idle),
+        iowait: mean(|times| times.iowait),
+        irq: mean(|times| times.irq),
+        softirq: mean(|times| times.softirq),
+        steal: mean(|times| times.steal),
+    })
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema, SimpleObject)]
+#[graphql(rename_fields = "snake_case")]
+pub struct Memory {
+    pub total_memory: String,
+    pub used_memory: String,
+    pub total_swap: String,
+    pub used_swap: String,
+    pub total_memory_bytes: u64,
+    pub used_memory_bytes: u64,
+    pub total_swap_bytes: u64,
+    pub used_swap_bytes: u64,
+    /// The server's cgroup memory limit (`memory.max`) where it is below
+    /// the host's memory, otherwise the same as `total_memory`.
+    #[serde(default)]
+    pub effective_total_memory: String,
+    /// Usage of the limiting cgroup without inactive page cache, otherwise
+    /// the same as `used_memory`.
+    #[serde(default)]
+    pub effective_used_memory: String,
+    #[serde(default)]
+    pub effective_total_memory_bytes: u64,
+    #[serde(default)]
+    pub effective_used_memory_bytes: u64,
+    /// Whether a cgroup limit applies.
+    #[serde(default)]
+    pub cgroup_limited: bool,
+    /// When the sample was taken, in milliseconds since the Unix epoch.
+    #[serde(default)]
+    pub timestamp: u64,
+}
+
+/// Where memory goes, in raw/human pairs like `Memory`.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct MemoryDetail {
+    pub total: Option<String>,
+    pub total_bytes: Option<u64>,
+    pub free: Option<String>,
+    pub free_bytes: Option<u64>,
+    /// Free memory plus what can be reclaimed without swapping, mostly
+    /// page cache. What new applications can actually use.
+    pub available: Option<String>,
+    pub available_bytes: Option<u64>,
+    pub buffers: Option<String>,
+    pub buffers_bytes: Option<u64>,
+    /// Page cache, including `shmem`.
+    pub cached: Option<String>,
+    pub cached_bytes: Option<u64>,
+    /// Modified pages waiting to be written back to disk.
+    pub dirty: Option<String>,
+    pub dirty_bytes: Option<u64>,
+    /// Pages being written back right now.
+    pub writeback: Option<String>,
+    pub writeback_bytes: Option<u64>,
+    /// Kernel data structures.
+    pub slab: Option<String>,
+    pub slab_bytes: Option<u64>,
+    /// The part of `slab` that can be reclaimed.
+    pub slab_reclaimable: Option<String>,
+    pub slab_reclaimable_bytes: Option<u64>,
+    /// Shared memory and tmpfs.
+    pub shmem: Option<String>,
+    pub shmem_bytes: Option<u64>,
+    pub hugepages_total: Option<u64>,
+    pub hugepages_free: Option<u64>,
+    pub hugepage_size: Option<String>,
+    pub hugepage_size_bytes: Option<u64>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema, SimpleObject)]
+#[graphql(rename_fields = "snake_case")]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub name: String,
+    pub cpu_usage: f32,
+    pub memory: String,
+    pub memory_bytes: u64,
+    pub status: String,
+    /// Name of the owning user, when it can be resolved.
+    pub user: Option<String>,
+    /// Cgroup path, Linux only.
+    pub cgroup: Option<String>,
+    /// Short id of the container the process runs in, detected from its
+    /// cgroup.
+    pub container_id: Option<String>,
+    /// Only resolved with the Docker integration enabled.
+    pub container_name: Option<String>,
+}
+
+/// An open file descriptor of a process.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct OpenFile {
+    pub fd: u32,
+    /// `file`, `directory`, `device`, `socket`, `pipe` or `anon_inode`.
+    pub kind: String,
+    /// Target of the descriptor, e.g. `socket:[12345]` for a socket.
+    pub path: String,
+}
+
+/// A TCP or UDP socket held by a process.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct SocketInfo {
+    pub fd: u32,
+    /// `tcp`, `tcp6`, `udp` or `udp6`.
+    pub protocol: String,
+    pub local_address: String,
+    /// `null` for listening and unconnected sockets.
+    pub remote_address: Option<String>,
+    /// Kernel TCP state in lowercase, e.g. `listen` or `established`;
+    /// `established` or `unconnected` for UDP.
+    pub state: String,
+}
+
+/// A listening TCP socket or a bound, unconnected UDP one.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ListeningPort {
+    /// `tcp`, `tcp6`, `udp` or `udp6`.
+    pub protocol: String,
+    /// Bound address, `0.0.0.0` or `::` for all interfaces.
+    pub address: String,
+    pub port: u16,
+    /// `null` when the owner's descriptors can't be read.
+    pub pid: Option<u32>,
+    pub process: Option<String>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct DiskInfo {
+    pub name: String,
+    pub mount_point: String,
+    pub file_system: String,
+    pub total_space: String,
+    pub total_space_bytes: u64,
+    pub available_space: String,
+    pub available_space_bytes: u64,
+    pub is_removable: bool,
+}
+
+/// IO of one block device since the previous sample.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct DiskIoInfo {
+    pub device: String,
+    /// Totals since boot.
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+    pub read_bytes_per_sec: f64,
+    pub write_bytes_per_sec: f64,
+    pub read_iops: f64,
+    pub write_iops: f64,
+    /// Percent of the time the device was busy; near 100 it is saturated.
+    pub utilization: f32,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct NetworkInfo {
+    pub interface: String,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_packets: u64,
+    pub rx_errors: u64,
+    pub tx_errors: u64,
+    pub rx_bytes_per_sec: f64,
+    pub tx_bytes_per_sec: f64,
+    pub rx_packets_per_sec: f64,
+    pub tx_packets_per_sec: f64,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct TemperatureInfo {
+    pub label: String,
+    pub current: f32,
+    pub max: f32,
+    pub critical: Option<f32>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema, SimpleObject)]
+#[graphql(rename_fields = "snake_case")]
+pub struct SystemInfo {
+    pub hostname: Option<String>,
+    pub os_name: Option<String>,
+    pub os_version: Option<String>,
+    pub kernel_version: Option<String>,
+    pub architecture: String,
+    /// Milliseconds since the Unix epoch.
+    pub boot_time: u64,
+    pub uptime_seconds: u64,
+    pub physical_cores: Option<usize>,
+    pub logical_cores: usize,
+    /// Set with `--label`.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Resources used by all processes of one user.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct UserUsage {
+    /// `null` groups the processes whose owner couldn't be resolved.
+    pub user: Option<String>,
+    pub processes: usize,
+    /// Sum over the user's processes, so it can exceed 100 on multi-core hosts.
+    pub cpu_usage: f32,
+    pub memory: String,
+    pub memory_bytes: u64,
+}
+
+/// A running Docker container.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ContainerInfo {
+    /// Short, 12 character id.
+    pub id: String,
+    pub name: String,
+    pub image: String,
+    pub state: String,
+    /// Percent of one core, so it can exceed 100 like a process's.
+    pub cpu_usage: f32,
+    /// Usage without reclaimable page cache, as `docker stats` shows it.
+    pub memory: String,
+    pub memory_bytes: u64,
+    /// The container's memory limit, or the host's memory without one.
+    pub memory_limit_bytes: u64,
+    pub pids: u64,
+    /// Totals over all of the container's interfaces.
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_bytes_per_sec: f64,
+    pub tx_bytes_per_sec: f64,
+    pub restart_count: u64,
+}
+
+/// An NVIDIA GPU, as reported by NVML.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct GpuInfo {
+    pub index: u32,
+    pub name: String,
+    pub uuid: String,
+    /// Percent of time a kernel was running.
+    pub gpu_usage: f32,
+    /// Percent of time VRAM was read or written.
+    pub memory_usage: f32,
+    pub memory_total: String,
+    pub memory_total_bytes: u64,
+    pub memory_used: String,
+    pub memory_used_bytes: u64,
+    /// Degrees Celsius.
+    pub temperature: Option<f32>,
+    pub power_draw_watts: Option<f64>,
+    pub power_limit_watts: Option<f64>,
+    pub processes: Vec<GpuProcess>,
+}
+
+/// A process holding memory on a GPU.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct GpuProcess {
+    pub pid: u32,
+    /// From the latest process sample; `null` for processes in other pid
+    /// namespaces.
+    pub name: Option<String>,
+    pub memory_used: String,
+    pub memory_used_bytes: u64,
+}
+
+/// Batteries and AC adapter of the host.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct PowerInfo {
+    /// Whether an AC adapter is plugged in, `null` without one.
+    pub ac_online: Option<bool>,
+    pub batteries: Vec<BatteryInfo>,
+    /// When the power state was last published, which happens only when it
+    /// changed, in milliseconds since the Unix epoch.
+    #[serde(default)]
+    pub timestamp: u64,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct BatteryInfo {
+    pub name: String,
+    /// `charging`, `discharging`, `full`, `not_charging` or `unknown`.
+    pub status: String,
+    /// Percent of the current full capacity.
+    pub charge: f32,
+    /// Full capacity in percent of the design capacity.
+    pub health: Option<f32>,
+    /// Only while discharging, estimated from the current draw.
+    pub time_to_empty_seconds: Option<u64>,
+    /// Only while charging.
+    pub time_to_full_seconds: Option<u64>,
+    pub power_watts: Option<f64>,
+    pub cycle_count: Option<u64>,
+}
+
+/// A login session from utmp.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Session {
+    pub user: String,
+    /// Terminal line, e.g. `pts/0` or `tty1`.
+    pub terminal: String,
+    /// Milliseconds since the Unix epoch.
+    pub login_time: u64,
+    /// Remote host for network logins, `null` for local ones.
+    pub host: Option<String>,
+    /// Pid of the login process.
+    pub pid: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ProcessEventKind {
+    Started,
+    Exited,
+}
+
+/// A process that appeared or disappeared between two samples. A pid that
+/// is reused under another name counts as an exit followed by a start.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ProcessEvent {
+    pub event: ProcessEventKind,
+    pub pid: u32,
+    pub name: String,
+    pub user: Option<String>,
+    /// Milliseconds since the Unix epoch of the sample that noticed it.
+    pub timestamp: u64,
+}
+
+/// A TCP connection, i.e. a socket with a peer.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ConnectionInfo {
+    /// `tcp` or `tcp6`.
+    pub protocol: String,
+    pub local_address: String,
+    pub local_port: u16,
+    pub remote_address: String,
+    pub remote_port: u16,
+    /// Kernel TCP state in lowercase, e.g. `established` or `time_wait`.
+    pub state: String,
+    /// `null` when the owner's descriptors can't be read, and for sockets
+    /// no process holds anymore, such as those in `time_wait`.
+    pub pid: Option<u32>,
+    pub process: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionEventKind {
+    Opened,
+    Closed,
+}
+
+/// A connection that appeared or disappeared between two samples.
+/// Connections shorter than the sampling interval go unnoticed.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ConnectionEvent {
+    pub event: ConnectionEventKind,
+    pub connection: ConnectionInfo,
+    /// Milliseconds since the Unix epoch of the sample that noticed it.
+    pub timestamp: u64,
+}
+
+/// Pressure stall information per resource, `null` where the kernel
+/// doesn't report it.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Pressure {
+    pub cpu: Option<PressureInfo>,
+    pub memory: Option<PressureInfo>,
+    pub io: Option<PressureInfo>,
+    /// When the sample was taken, in milliseconds since the Unix epoch.
+    #[serde(default)]
+    pub timestamp: u64,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct PressureInfo {
+    /// At least one task was stalled.
+    pub some: PressureStall,
+    /// All non-idle tasks were stalled at once. Missing for CPU on kernels
+    /// before 5.13.
+    pub full: Option<PressureStall>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct PressureStall {
+    /// Percent of the last 10 seconds spent stalled.
+    pub avg10: f32,
+    pub avg60: f32,
+    pub avg300: f32,
+    /// Total stall time in microseconds.
+    pub total_us: u64,
+}
+
+/// 1, 5 and 15 minute load averages. Always zero on Windows.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct LoadAverage {
+    pub one: f64,
+    pub five: f64,
+    pub fifteen: f64,
+    /// When the sample was taken, in milliseconds since the Unix epoch.
+    #[serde(default)]
+    pub timestamp: u64,
+}
+
+/// Timing of the sampling loop.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct SamplerTiming {
+    pub samples: u64,
+    pub interval_ms: u64,
+    pub last_duration_ms: f64,
+    pub mean_duration_ms: f64,
+    pub max_duration_ms: f64,
+}
+
+/// One update of a `/realtime/processes/{pid}` watch.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ProcessWatch {
+    pub pid: u32,
+    pub name: String,
+    pub cpu_usage: f32,
+    pub memory: String,
+    pub memory_bytes: u64,
+    pub virtual_memory: String,
+    pub virtual_memory_bytes: u64,
+    pub disk_read_bytes_per_sec: f64,
+    pub disk_write_bytes_per_sec: f64,
+    /// Bytes read since the process started.
+    pub disk_read_bytes: u64,
+    /// Bytes written since the process started.
+    pub disk_written_bytes: u64,
+    /// `null` where the platform doesn't report threads.
+    pub threads: Option<usize>,
+}
+
+/// What the viewer itself costs and how busy it is.
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct SelfInfo {
+    pub pid: u32,
+    pub uptime_seconds: u64,
+    /// Resident set size.
+    pub memory: String,
+    pub memory_bytes: u64,
+    pub cpu_usage: f32,
+    /// Open HTTP connections, not counting upgraded WebSockets.
+    pub http_connections: usize,
+    /// Running WebSocket sessions.
+    pub realtime_connections: usize,
+    /// Most realtime connections open at once since startup.
+    pub realtime_connections_peak: usize,
+    /// The configured cap on realtime connections, if any.
+    pub realtime_connections_max: Option<usize>,
+    /// Upgrades turned away by the global or per-IP connection caps.
+    pub rejected_realtime_connections: u64,
+    /// Realtime clients dropped for not answering pings.
+    pub reaped_realtime_connections: u64,
+    /// Receivers per topic, i.e. WebSocket and SSE streams plus internal ones.
+    pub subscribers: BTreeMap<String, usize>,
+    pub sampler: SamplerTiming,
+    /// Samples skipped per topic because a realtime client fell behind.
+    pub lagged_samples: BTreeMap<String, u64>,
+}
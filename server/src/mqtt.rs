@@ -0,0 +1,307 @@
+//! Optional MQTT publisher for home automation, enabled with `--mqtt-host`.
+//! Samples go to `<prefix>/<topic>` as JSON, and Home Assistant discovery
+//! messages turn the host into a device with CPU, memory, load and
+//! temperature sensors.
+//!
+//! Speaks just enough MQTT 3.1.1 for that: a plain TCP connection, QoS 0
+//! publishes and a last will that marks the device unavailable.
+
+use crate::config::MqttConfig;
+use crate::AppState;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::io;
+use std::time::Duration;
+use sysinfo::SystemExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::MissedTickBehavior;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+/// The broker drops the connection after one and a half keep alive periods
+/// without a packet, so it's kept well above the publish interval.
+const MIN_KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+/// The flat summary published to `<prefix>/state`, which the discovered
+/// sensors read their values from.
+#[derive(Debug, Serialize)]
+struct State {
+    cpu_usage: f32,
+    memory_used_percent: f64,
+    memory_used_bytes: u64,
+    swap_used_bytes: u64,
+    load_1: f64,
+    /// Hottest sensor in °C.
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    labels: BTreeMap<String, String>,
+}
+
+/// A Home Assistant sensor: key in `State`, name, unit and device class.
+const SENSORS: &[(&str, &str, &str, Option<&str>)] = &[
+    ("cpu_usage", "CPU usage", "%", None),
+    ("memory_used_percent", "Memory usage", "%", None),
+    ("memory_used_bytes", "Memory used", "B", Some("data_size")),
+    ("swap_used_bytes", "Swap used", "B", Some("data_size")),
+    ("load_1", "Load average", "", None),
+    ("temperature", "Temperature", "°C", Some("temperature")),
+];
+
+pub fn spawn(config: MqttConfig, app_state: AppState) {
+    tokio::spawn(run(config, app_state));
+}
+
+async fn run(config: MqttConfig, app_state: AppState) {
+    let host = app_state
+        .system
+        .lock()
+        .await
+        .host_name()
+        .unwrap_or_else(|| "unknown".to_owned());
+    // Topic levels and discovery ids can't hold every character a hostname
+    // might.
+    let node_id: String = host
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let prefix = config
+        .topic_prefix
+        .clone()
+        .unwrap_or_else(|| format!("process-viewer/{node_id}"));
+    let mut delay = MIN_RECONNECT_DELAY;
+    loop {
+        // Returns `Ok` once shut down, having marked the device unavailable.
+        match publish_loop(&config, &app_state, &host, &node_id, &prefix, &mut delay).await {
+            Ok(()) => break,
+            Err(err) => {
+                tracing::warn!(host = %config.host, %err, ?delay, "MQTT connection failed, retrying");
+            }
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = app_state.shutdown.cancelled() => break,
+        }
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+async fn publish_loop(
+    config: &MqttConfig,
+    app_state: &AppState,
+    host: &str,
+    node_id: &str,
+    prefix: &str,
+    delay: &mut Duration,
+) -> io::Result<()> {
+    let availability = format!("{prefix}/availability");
+    let client_id = config
+        .client_id
+        .clone()
+        .unwrap_or_else(|| format!("process-viewer-{node_id}"));
+    let keep_alive = (config.interval * 2).max(MIN_KEEP_ALIVE);
+    let connecting = tokio::time::timeout(
+        CONNECT_TIMEOUT,
+        connect(config, &client_id, &availability, keep_alive),
+    );
+    let mut stream = tokio::select! {
+        stream = connecting => stream
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Timed out connecting"))??,
+        _ = app_state.shutdown.cancelled() => return Ok(()),
+    };
+    tracing::info!(host = %config.host, %prefix, "Connected to MQTT broker");
+    *delay = MIN_RECONNECT_DELAY;
+    publish(&mut stream, &availability, b"online", true).await?;
+    if let Some(discovery_prefix) = &config.discovery_prefix {
+        for (key, message) in discovery(host, node_id, prefix, &availability) {
+            let topic = format!("{discovery_prefix}/sensor/{node_id}/{key}/config");
+            publish(&mut stream, &topic, message.to_string().as_bytes(), true).await?;
+        }
+    }
+    let mut ticker = tokio::time::interval(config.interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // Only PINGRESPs are expected back; reading is how a closed connection
+    // is noticed.
+    let mut incoming = [0u8; 256];
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            read = stream.read(&mut incoming) => match read? {
+                0 => return Err(io::ErrorKind::ConnectionAborted.into()),
+                _ => continue,
+            },
+            _ = app_state.shutdown.cancelled() => {
+                // A clean disconnect discards the last will, so the device
+                // is marked unavailable explicitly.
+                publish(&mut stream, &availability, b"offline", true).await?;
+                stream.write_all(&[0xE0, 0x00]).await?;
+                return Ok(());
+            }
+        }
+        if app_state.cpus.latest().seq == 0 {
+            continue;
+        }
+        let cpus = app_state.cpus.latest();
+        let memory = app_state.memory.latest();
+        let temperatures = app_state.temperatures.latest();
+        let loadavg = app_state.loadavg.latest();
+        let memory = memory.value();
+        let state = State {
+            cpu_usage: cpus.value().iter().map(|cpu| cpu.cpu_usage).sum::<f32>()
+                / cpus.value().len().max(1) as f32,
+            memory_used_percent: if memory.total_memory_bytes > 0 {
+                memory.used_memory_bytes as f64 / memory.total_memory_bytes as f64 * 100f64
+            } else {
+                0f64
+            },
+            memory_used_bytes: memory.used_memory_bytes,
+            swap_used_bytes: memory.used_swap_bytes,
+            load_1: loadavg.value().one,
+            temperature: temperatures
+                .value()
+                .iter()
+                .map(|sensor| sensor.current)
+                .reduce(f32::max),
+            labels: app_state.config.labels.clone(),
+        };
+        let messages = [
+            ("state", serde_json::to_vec(&state)),
+            ("cpus", serde_json::to_vec(cpus.value())),
+            ("memory", serde_json::to_vec(memory)),
+            ("temperatures", serde_json::to_vec(temperatures.value())),
+            ("loadavg", serde_json::to_vec(loadavg.value())),
+        ];
+        for (topic, payload) in messages {
+            let payload = payload.expect("Samples always serialize");
+            publish(&mut stream, &format!("{prefix}/{topic}"), &payload, false).await?;
+        }
+    }
+}
+
+/// Discovery messages by sensor key.
+fn discovery(
+    host: &str,
+    node_id: &str,
+    prefix: &str,
+    availability: &str,
+) -> Vec<(&'static str, serde_json::Value)> {
+    SENSORS
+        .iter()
+        .map(|(key, name, unit, device_class)| {
+            let mut message = json!({
+                "name": name,
+                "unique_id": format!("{node_id}_{key}"),
+                "state_topic": format!("{prefix}/state"),
+                "value_template": format!("{{{{ value_json.{key} }}}}"),
+                "state_class": "measurement",
+                "availability_topic": availability,
+                "device": {
+                    "identifiers": [format!("process_viewer_{node_id}")],
+                    "name": host,
+                    "model": "process-viewer",
+                    "sw_version": env!("CARGO_PKG_VERSION"),
+                },
+            });
+            if !unit.is_empty() {
+                message["unit_of_measurement"] = json!(unit);
+            }
+            if let Some(device_class) = device_class {
+                message["device_class"] = json!(device_class);
+            }
+            (*key, message)
+        })
+        .collect()
+}
+
+/// Connects with a clean session and waits for the broker to accept.
+async fn connect(
+    config: &MqttConfig,
+    client_id: &str,
+    will_topic: &str,
+    keep_alive: Duration,
+) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect((config.host.as_str(), config.port)).await?;
+    // Clean session, a retained will at QoS 0.
+    let mut flags = 0x02 | 0x04 | 0x20;
+    let mut body = vec![];
+    put_bytes(&mut body, b"MQTT");
+    body.push(4);
+    let flags_at = body.len();
+    body.push(0);
+    body.extend_from_slice(&(keep_alive.as_secs().min(u64::from(u16::MAX)) as u16).to_be_bytes());
+    put_bytes(&mut body, client_id.as_bytes());
+    put_bytes(&mut body, will_topic.as_bytes());
+    put_bytes(&mut body, b"offline");
+    if let Some(username) = &config.username {
+        flags |= 0x80;
+        put_bytes(&mut body, username.as_bytes());
+        if let Some(password) = &config.password {
+            flags |= 0x40;
+            put_bytes(&mut body, password.as_bytes());
+        }
+    }
+    body[flags_at] = flags;
+    write_packet(&mut stream, 0x10, &body).await?;
+    let mut connack = [0u8; 4];
+    stream.read_exact(&mut connack).await?;
+    match connack {
+        [0x20, 0x02, _, 0] => Ok(stream),
+        [0x20, 0x02, _, 4 | 5] => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "The broker rejected the credentials",
+        )),
+        [0x20, 0x02, _, code] => Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("The broker refused the connection with code {code}"),
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Expected a CONNACK from the broker",
+        )),
+    }
+}
+
+async fn publish(
+    stream: &mut TcpStream,
+    topic: &str,
+    payload: &[u8],
+    retain: bool,
+) -> io::Result<()> {
+    let mut body = Vec::with_capacity(topic.len() + payload.len() + 2);
+    put_bytes(&mut body, topic.as_bytes());
+    body.extend_from_slice(payload);
+    write_packet(stream, 0x30 | u8::from(retain), &body).await
+}
+
+/// A length prefixed string or blob.
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+/// The fixed header encodes the remaining length in 7 bit groups.
+async fn write_packet(stream: &mut TcpStream, header: u8, body: &[u8]) -> io::Result<()> {
+    let mut packet = Vec::with_capacity(body.len() + 5);
+    packet.push(header);
+    let mut length = body.len();
+    loop {
+        let byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            packet.push(byte | 0x80);
+        } else {
+            packet.push(byte);
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    stream.write_all(&packet).await
+}
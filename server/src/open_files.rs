@@ -0,0 +1,290 @@
+//! Open file descriptors and sockets of a single process, lsof style. Read
+//! from `/proc` on Linux; other platforms answer 501.
+
+use crate::encoding::{Encoded, Encoding};
+use crate::models::{OpenFile, SocketInfo};
+use crate::{api_error, AppError};
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum_macros::debug_handler;
+use std::io;
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/processes/{pid}/files",
+    tag = "processes",
+    params(("pid" = u32, Path, description = "Process id")),
+    responses(
+        (status = 200, description = "Open file descriptors, ordered by fd", body = [OpenFile], content_type = ["application/json", "application/msgpack"]),
+        (status = 403, description = "Not permitted to inspect the process", body = ErrorResponse),
+        (status = 404, description = "No process with this pid", body = ErrorResponse),
+        (status = 501, description = "Not available on this platform", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_files(
+    Path(pid): Path<u32>,
+    encoding: Encoding,
+) -> Result<Encoded<Vec<OpenFile>>, AppError> {
+    let files = tokio::task::spawn_blocking(move || read_files(pid))
+        .await
+        .expect("Reading open files panicked");
+    files
+        .map(|files| Encoded(encoding, files))
+        .map_err(|err| error(pid, err))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/processes/{pid}/sockets",
+    tag = "processes",
+    params(("pid" = u32, Path, description = "Process id")),
+    responses(
+        (status = 200, description = "TCP and UDP sockets, ordered by fd", body = [SocketInfo], content_type = ["application/json", "application/msgpack"]),
+        (status = 403, description = "Not permitted to inspect the process", body = ErrorResponse),
+        (status = 404, description = "No process with this pid", body = ErrorResponse),
+        (status = 501, description = "Not available on this platform", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_sockets(
+    Path(pid): Path<u32>,
+    encoding: Encoding,
+) -> Result<Encoded<Vec<SocketInfo>>, AppError> {
+    let sockets = tokio::task::spawn_blocking(move || read_sockets(pid))
+        .await
+        .expect("Reading sockets panicked");
+    sockets
+        .map(|sockets| Encoded(encoding, sockets))
+        .map_err(|err| error(pid, err))
+}
+
+fn error(pid: u32, err: io::Error) -> AppError {
+    match err.kind() {
+        io::ErrorKind::NotFound => {
+            api_error(StatusCode::NOT_FOUND, format!("No process with pid {pid}"))
+        }
+        io::ErrorKind::PermissionDenied => api_error(
+            StatusCode::FORBIDDEN,
+            format!("Permission denied inspecting pid {pid}"),
+        ),
+        io::ErrorKind::Unsupported => api_error(
+            StatusCode::NOT_IMPLEMENTED,
+            "Open files are not available on this platform",
+        ),
+        _ => api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to inspect pid {pid}: {err}"),
+        ),
+    }
+}
+
+#[cfg(target_os = "linux")]
+use linux::{read_files, read_sockets};
+#[cfg(target_os = "linux")]
+pub use linux::{socket_owners, socket_table};
+
+#[cfg(not(target_os = "linux"))]
+fn read_files(_: u32) -> io::Result<Vec<OpenFile>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_sockets(_: u32) -> io::Result<Vec<SocketInfo>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::*;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::path::PathBuf;
+
+    /// Targets of the process's descriptors, ordered by fd. Descriptors
+    /// closed while listing are skipped.
+    fn descriptors(pid: u32) -> io::Result<Vec<(u32, PathBuf)>> {
+        let mut descriptors: Vec<_> = fs::read_dir(format!("/proc/{pid}/fd"))?
+            .filter_map(Result::ok)
+            .filter_map(|entry| {
+                let fd = entry.file_name().to_str()?.parse().ok()?;
+                Some((fd, fs::read_link(entry.path()).ok()?))
+            })
+            .collect();
+        descriptors.sort_by_key(|(fd, _)| *fd);
+        Ok(descriptors)
+    }
+
+    pub fn read_files(pid: u32) -> io::Result<Vec<OpenFile>> {
+        Ok(descriptors(pid)?
+            .into_iter()
+            .map(|(fd, target)| {
+                let path = target.to_string_lossy().into_owned();
+                // Sockets, pipes and the like link to `type:[inode]`,
+                // anonymous inodes to `anon_inode:type`.
+                let kind = if path.starts_with("socket:") {
+                    "socket"
+                } else if path.starts_with("pipe:") {
+                    "pipe"
+                } else if path.starts_with("anon_inode:") {
+                    "anon_inode"
+                } else if path.starts_with("/dev/") {
+                    "device"
+                } else {
+                    match fs::metadata(&target) {
+                        Ok(metadata) if metadata.is_dir() => "directory",
+                        _ => "file",
+                    }
+                };
+                OpenFile {
+                    fd,
+                    kind: kind.to_owned(),
+                    path,
+                }
+            })
+            .collect())
+    }
+
+    /// The pid holding each socket inode, for every process whose
+    /// descriptors the server may read.
+    pub fn socket_owners() -> io::Result<HashMap<u64, u32>> {
+        let mut owners = HashMap::new();
+        for entry in fs::read_dir("/proc")?.filter_map(Result::ok) {
+            let Some(pid) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse::<u32>().ok())
+            else {
+                continue;
+            };
+            // Sockets shared by forked workers go to the lowest pid, usually
+            // the parent.
+            for inode in socket_inodes(pid).into_iter().flat_map(HashMap::into_keys) {
+                owners
+                    .entry(inode)
+                    .and_modify(|owner: &mut u32| *owner = (*owner).min(pid))
+                    .or_insert(pid);
+            }
+        }
+        Ok(owners)
+    }
+
+    /// Socket inodes of the process and the fd holding each.
+    fn socket_inodes(pid: u32) -> io::Result<HashMap<u64, u32>> {
+        Ok(descriptors(pid)?
+            .into_iter()
+            .filter_map(|(fd, target)| {
+                let inode = target
+                    .to_str()?
+                    .strip_prefix("socket:[")?
+                    .strip_suffix(']')?
+                    .parse()
+                    .ok()?;
+                Some((inode, fd))
+            })
+            .collect())
+    }
+
+    /// A row of the kernel's TCP and UDP socket tables.
+    pub struct TableEntry {
+        pub protocol: &'static str,
+        pub inode: u64,
+        pub local: SocketAddr,
+        /// `None` for listening and unconnected sockets.
+        pub remote: Option<SocketAddr>,
+        pub state: &'static str,
+    }
+
+    /// Every TCP and UDP socket of a network namespace, from the tables in
+    /// `net_dir`: `/proc/net` for the server's own, `/proc/<pid>/net` for
+    /// the namespace of a process.
+    pub fn socket_table(net_dir: &str) -> Vec<TableEntry> {
+        let mut entries = vec![];
+        for protocol in ["tcp", "tcp6", "udp", "udp6"] {
+            let Ok(table) = fs::read_to_string(format!("{net_dir}/{protocol}")) else {
+                continue;
+            };
+            for line in table.lines().skip(1) {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                let (Some(local), Some(remote), Some(state), Some(inode)) = (
+                    fields.get(1).and_then(|address| parse_address(address)),
+                    fields.get(2).and_then(|address| parse_address(address)),
+                    fields
+                        .get(3)
+                        .and_then(|state| u8::from_str_radix(state, 16).ok()),
+                    fields.get(9).and_then(|inode| inode.parse().ok()),
+                ) else {
+                    continue;
+                };
+                entries.push(TableEntry {
+                    protocol,
+                    inode,
+                    local,
+                    remote: (!remote.ip().is_unspecified() || remote.port() != 0).then_some(remote),
+                    state: state_name(state, protocol.starts_with("tcp")),
+                });
+            }
+        }
+        entries
+    }
+
+    pub fn read_sockets(pid: u32) -> io::Result<Vec<SocketInfo>> {
+        let inodes = socket_inodes(pid)?;
+        let mut sockets: Vec<SocketInfo> = socket_table(&format!("/proc/{pid}/net"))
+            .into_iter()
+            .filter_map(|entry| {
+                Some(SocketInfo {
+                    fd: *inodes.get(&entry.inode)?,
+                    protocol: entry.protocol.to_owned(),
+                    local_address: entry.local.to_string(),
+                    remote_address: entry.remote.map(|remote| remote.to_string()),
+                    state: entry.state.to_owned(),
+                })
+            })
+            .collect();
+        sockets.sort_by_key(|socket| socket.fd);
+        Ok(sockets)
+    }
+
+    /// `ADDRESS:PORT` in hex, the address as 32 bit words in host order.
+    fn parse_address(field: &str) -> Option<SocketAddr> {
+        let (address, port) = field.split_once(':')?;
+        let port = u16::from_str_radix(port, 16).ok()?;
+        let word = |index: usize| {
+            let hex = address.get(index * 8..index * 8 + 8)?;
+            Some(u32::from_str_radix(hex, 16).ok()?.to_ne_bytes())
+        };
+        let ip = match address.len() {
+            8 => Ipv4Addr::from(word(0)?).into(),
+            32 => {
+                let mut octets = [0u8; 16];
+                for index in 0..4 {
+                    octets[index * 4..index * 4 + 4].copy_from_slice(&word(index)?);
+                }
+                Ipv6Addr::from(octets).into()
+            }
+            _ => return None,
+        };
+        Some(SocketAddr::new(ip, port))
+    }
+
+    /// The kernel's TCP states. UDP sockets only use two of them.
+    fn state_name(state: u8, tcp: bool) -> &'static str {
+        match (state, tcp) {
+            (0x01, _) => "established",
+            (0x07, false) => "unconnected",
+            (0x02, true) => "syn_sent",
+            (0x03, true) => "syn_recv",
+            (0x04, true) => "fin_wait1",
+            (0x05, true) => "fin_wait2",
+            (0x06, true) => "time_wait",
+            (0x07, true) => "close",
+            (0x08, true) => "close_wait",
+            (0x09, true) => "last_ack",
+            (0x0A, true) => "listen",
+            (0x0B, true) => "closing",
+            _ => "unknown",
+        }
+    }
+}
@@ -0,0 +1,231 @@
+//! OpenAPI description of the REST API, served as `/api/openapi.json` with a
+//! Swagger UI at `/api/docs`. Realtime WebSocket and SSE routes are not part
+//! of the spec; see the README for their protocol.
+
+use crate::alerts::{ActiveAlert, AlertMetric, AlertRule, AlertState, Comparison};
+use crate::audit::{AuditAction, AuditEntry};
+use crate::auth::Role;
+use crate::collector::CollectorInfo;
+use crate::config::Config;
+use crate::control::{
+    PriorityClass, PriorityRequest, PriorityResponse, SignalName, SignalRequest, SignalResponse,
+};
+use crate::export::ExportFormat;
+use crate::format::{Format, Units};
+use crate::grafana::{QueryRange, QueryRequest, QueryTarget, SearchRequest, TimeSeries};
+use crate::health::{CollectorHealth, HealthStatus, Liveness, Readiness};
+use crate::history::{CpuHistoryEntry, MemoryHistoryEntry};
+use crate::hub::{HostReport, HostSource, HostSummary};
+use crate::models::{
+    BatteryInfo, ConnectionInfo, ContainerInfo, CpuCache, CpuInfo, CpuSummary, CpuTimes,
+    CpuTopology, DiskInfo, DiskIoInfo, GpuInfo, GpuProcess, ListeningPort, LoadAverage, LogicalCpu,
+    Memory, MemoryDetail, NetworkInfo, OpenFile, PowerInfo, Pressure, PressureInfo, PressureStall,
+    ProcessInfo, SamplerTiming, SelfInfo, Session, SocketInfo, SystemInfo, TemperatureInfo,
+    UserUsage,
+};
+use crate::processes::SortKey;
+use crate::snapshots::{
+    ProcessChange, SnapshotDiff, SnapshotRequest, SnapshotSummary, SystemSnapshot, ValueChange,
+};
+use crate::stats::{Stats, StatsBucket};
+use crate::versions::BuildInfo;
+use axum::Router;
+use serde::Serialize;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityRequirement, SecurityScheme};
+use utoipa::{OpenApi, ToSchema};
+use utoipa_swagger_ui::SwaggerUi;
+
+const SECURITY_SCHEME: &str = "api_key";
+
+/// Body of every error response, an RFC 7807 problem served as
+/// `application/problem+json`; see `error.rs`.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorResponse {
+    /// Always `about:blank`.
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// The status's reason phrase, e.g. `Not Found`.
+    pub title: String,
+    pub status: u16,
+    /// What went wrong, e.g. `No process with pid 42`.
+    pub detail: String,
+    /// The request's `x-request-id`, to quote when reporting the error.
+    pub request_id: Option<String>,
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "process-viewer",
+        description = "Live system and process metrics. Data endpoints answer in MessagePack instead of JSON for `Accept: application/msgpack`."
+    ),
+    paths(
+        crate::system::get_system,
+        crate::get_cpus,
+        crate::get_cpu_summary,
+        crate::history::get_cpu_history,
+        crate::topology::get_topology,
+        crate::get_memory,
+        crate::history::get_memory_history,
+        crate::meminfo::get_memory_detail,
+        crate::processes::get_processes,
+        crate::processes::get_top_processes,
+        crate::watchdog::get_watch,
+        crate::snapshots::create_snapshot,
+        crate::snapshots::list_snapshots,
+        crate::snapshots::get_snapshot,
+        crate::snapshots::delete_snapshot,
+        crate::snapshots::diff_snapshots,
+        crate::hub::list_hosts,
+        crate::hub::put_host,
+        crate::hub::get_host,
+        crate::hub::delete_host,
+        crate::hub::get_host_path,
+        crate::mdns::get_discovered,
+        crate::control::send_signal,
+        crate::control::set_priority,
+        crate::open_files::get_files,
+        crate::open_files::get_sockets,
+        crate::users::get_users,
+        crate::sessions::get_sessions,
+        crate::ports::get_ports,
+        crate::connections::get_connections,
+        crate::docker::get_containers,
+        crate::gpu::get_gpus,
+        crate::power::get_power,
+        crate::get_disks,
+        crate::diskio::get_diskio,
+        crate::get_networks,
+        crate::get_temperatures,
+        crate::get_loadavg,
+        crate::pressure::get_pressure,
+        crate::overview::get_overview,
+        crate::stats::get_stats,
+        crate::export::get_export,
+        crate::alerts::list_rules,
+        crate::alerts::create_rule,
+        crate::alerts::get_rule,
+        crate::alerts::update_rule,
+        crate::alerts::delete_rule,
+        crate::alerts::list_active,
+        crate::audit::get_audit,
+        crate::versions::get_version,
+        crate::self_monitor::get_self,
+        crate::collector::list_collectors,
+        crate::collector::get_collector,
+        crate::collector::get_collector_history,
+        crate::plugin::get_custom,
+        crate::get_metrics,
+        crate::grafana::test,
+        crate::grafana::search,
+        crate::grafana::query,
+        crate::health::health,
+        crate::health::live,
+        crate::health::ready,
+    ),
+    components(schemas(
+        SystemInfo,
+        crate::overview::Overview,
+        CpuInfo,
+        CpuTimes,
+        CpuSummary,
+        CpuHistoryEntry,
+        CpuTopology,
+        LogicalCpu,
+        CpuCache,
+        Memory,
+        MemoryHistoryEntry,
+        MemoryDetail,
+        Format,
+        Units,
+        ProcessInfo,
+        SortKey,
+        crate::watchdog::WatchStatus,
+        SnapshotRequest,
+        SnapshotSummary,
+        SystemSnapshot,
+        SnapshotDiff,
+        ProcessChange,
+        ValueChange,
+        HostReport,
+        HostSummary,
+        crate::mdns::DiscoveredInstance,
+        HostSource,
+        AuditEntry,
+        AuditAction,
+        Role,
+        SignalName,
+        SignalRequest,
+        SignalResponse,
+        PriorityClass,
+        PriorityRequest,
+        PriorityResponse,
+        OpenFile,
+        SocketInfo,
+        UserUsage,
+        Session,
+        ListeningPort,
+        ConnectionInfo,
+        ContainerInfo,
+        GpuInfo,
+        GpuProcess,
+        PowerInfo,
+        BatteryInfo,
+        DiskInfo,
+        DiskIoInfo,
+        NetworkInfo,
+        TemperatureInfo,
+        LoadAverage,
+        Pressure,
+        PressureInfo,
+        PressureStall,
+        Stats,
+        StatsBucket,
+        ExportFormat,
+        AlertRule,
+        AlertMetric,
+        Comparison,
+        AlertState,
+        ActiveAlert,
+        BuildInfo,
+        SelfInfo,
+        SamplerTiming,
+        CollectorInfo,
+        HealthStatus,
+        Liveness,
+        CollectorHealth,
+        Readiness,
+        SearchRequest,
+        QueryRequest,
+        QueryRange,
+        QueryTarget,
+        TimeSeries,
+        ErrorResponse,
+    ))
+)]
+struct ApiDoc;
+
+/// The spec only demands a bearer token when the server actually checks one.
+pub fn routes<S>(config: &Config) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let mut spec = ApiDoc::openapi();
+    // Filled from Cargo.toml, which doesn't declare one.
+    spec.info.license = None;
+    if config.requires_auth() {
+        if let Some(components) = spec.components.as_mut() {
+            components.add_security_scheme(
+                SECURITY_SCHEME,
+                SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+            );
+        }
+        spec.security = Some(vec![SecurityRequirement::new(
+            SECURITY_SCHEME,
+            Vec::<String>::new(),
+        )]);
+    }
+    SwaggerUi::new("/api/docs")
+        .url("/api/openapi.json", spec)
+        .into()
+}
@@ -0,0 +1,82 @@
+//! `/api/v1/overview`, the figures a status bar or phone widget shows, in a
+//! single request: the CPU summary, memory, load average, uptime, the
+//! busiest processes and disk usage, all from the latest sample.
+
+use crate::encoding::{Encoded, Encoding};
+use crate::format::FormatQuery;
+use crate::models::{CpuSummary, DiskInfo, LoadAverage, Memory, ProcessInfo};
+use crate::processes::{sort_processes, SortKey};
+use crate::{AppError, AppState};
+use axum::extract::{Query, State};
+use axum_macros::debug_handler;
+use serde::Serialize;
+use serde_json::Value;
+use sysinfo::SystemExt;
+use utoipa::ToSchema;
+
+const TOP_PROCESSES: usize = 5;
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Overview {
+    hostname: Option<String>,
+    uptime_seconds: u64,
+    cpu: CpuSummary,
+    memory: Memory,
+    load_average: LoadAverage,
+    /// The five busiest by CPU usage, `null` when the process collector is
+    /// disabled.
+    top_processes: Option<Vec<ProcessInfo>>,
+    /// `null` when the disk collector is disabled.
+    disks: Option<Vec<DiskInfo>>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/overview",
+    tag = "system",
+    params(FormatQuery),
+    responses(
+        (status = 200, description = "Summary of the latest sample", body = Overview, content_type = ["application/json", "application/msgpack"]),
+        (status = 400, description = "Precision out of range", body = ErrorResponse),
+        (status = 503, description = "No sample taken yet", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_overview(
+    State(state): State<AppState>,
+    encoding: Encoding,
+    Query(format): Query<FormatQuery>,
+) -> Result<Encoded<Value>, AppError> {
+    let cpus = state.cpus.sampled("CPU")?;
+    let limit = state.cpu_limit.latest();
+    let memory = state.memory.sampled("memory")?;
+    // Published after processes and disks, so they are sampled too.
+    let loadavg = state.loadavg.sampled("load average")?;
+    let top_processes = state.processes.is_enabled().then(|| {
+        let latest = state.processes.latest();
+        let mut processes: Vec<&ProcessInfo> = latest.value().iter().collect();
+        sort_processes(&mut processes, SortKey::Cpu);
+        processes.into_iter().take(TOP_PROCESSES).cloned().collect()
+    });
+    let disks = state
+        .disks
+        .is_enabled()
+        .then(|| state.disks.latest().value().clone());
+    let (hostname, uptime_seconds) = {
+        let sys = state.system.lock().await;
+        (sys.host_name(), sys.uptime())
+    };
+    let overview = Overview {
+        hostname,
+        uptime_seconds,
+        cpu: CpuSummary::from_cpus(cpus.value(), limit.value().as_ref()),
+        memory: memory.value().clone(),
+        load_average: loadavg.value().clone(),
+        top_processes,
+        disks,
+    };
+    Ok(Encoded(
+        encoding,
+        format.apply(serde_json::to_value(overview).unwrap())?,
+    ))
+}
@@ -0,0 +1,116 @@
+//! Plugin collectors: executables listed under `[[plugins]]` in the config
+//! file, run every interval, whose standard output is parsed as one JSON
+//! document. They are registered like the Docker and GPU collectors (see
+//! `collector.rs`), so a plugin named `queue` is also the realtime and SSE
+//! topic `queue` and gets gauges on `/metrics`; its latest sample is served
+//! at `/api/v1/custom/queue`.
+//!
+//! The command runs without a shell, with the server's environment and
+//! working directory. A run that exits with an error, prints something other
+//! than JSON or outlasts its timeout is logged and leaves the previous sample
+//! in place.
+
+use crate::collector::{sampled_response, Collector, Registry};
+use crate::config::PluginConfig;
+use crate::encoding::Encoding;
+use crate::sampler::Topic;
+use crate::{AppError, AppState};
+use axum::async_trait;
+use axum::extract::{Path, State};
+use axum::response::IntoResponse;
+use axum_macros::debug_handler;
+use serde_json::Value;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+
+struct Plugin {
+    name: &'static str,
+    command: Vec<String>,
+    interval: Duration,
+    timeout: Duration,
+}
+
+#[async_trait]
+impl Collector for Plugin {
+    type Output = Value;
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    async fn sample(&mut self, _: &AppState) -> Result<Value, String> {
+        let (program, args) = self
+            .command
+            .split_first()
+            .expect("Plugin commands aren't empty");
+        // Dropping the future on timeout kills the child.
+        let run = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output();
+        let output = tokio::time::timeout(self.timeout, run)
+            .await
+            .map_err(|_| {
+                format!(
+                    "{program} didn't finish within {} ms",
+                    self.timeout.as_millis()
+                )
+            })?
+            .map_err(|err| format!("Failed to run {program}: {err}"))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(format!("{program} {}: {}", output.status, stderr.trim()));
+        }
+        serde_json::from_slice(&output.stdout)
+            .map_err(|err| format!("{program} printed invalid JSON: {err}"))
+    }
+}
+
+pub fn register(plugins: &[PluginConfig], registry: &mut Registry) {
+    for plugin in plugins {
+        let collector = Plugin {
+            // Once per plugin at startup, for the registry's static names.
+            name: Box::leak(plugin.name.clone().into_boxed_str()),
+            command: plugin.command.clone(),
+            interval: plugin.interval,
+            timeout: plugin.timeout,
+        };
+        registry.register(collector, Topic::new());
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/custom/{name}",
+    tag = "collectors",
+    params(("name" = String, Path, description = "Plugin name")),
+    responses(
+        (status = 200, description = "The JSON the plugin printed last", body = Object, content_type = ["application/json", "application/msgpack"],
+            headers(("x-sample-timestamp" = u64, description = "When the plugin printed it, in milliseconds since the Unix epoch"))),
+        (status = 404, description = "No such plugin", body = ErrorResponse),
+        (status = 503, description = "The plugin hasn't succeeded yet", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_custom(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    encoding: Encoding,
+) -> Result<impl IntoResponse, AppError> {
+    if !state
+        .config
+        .plugins
+        .iter()
+        .any(|plugin| plugin.name == name)
+    {
+        return Err(AppError::NotFound(format!("No plugin named {name}")));
+    }
+    let latest = state.registry.latest_of(&name)?;
+    Ok(sampled_response(encoding, latest))
+}
@@ -0,0 +1,98 @@
+//! Listening TCP and UDP sockets of the host and the processes owning them.
+//! Linux only, from the socket tables and file descriptors in `/proc`.
+
+use crate::encoding::{Encoded, Encoding};
+use crate::models::ListeningPort;
+use crate::{api_error, AppError, AppState};
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum_macros::debug_handler;
+use serde::Deserialize;
+use std::io;
+use utoipa::IntoParams;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PortQuery {
+    /// Only sockets bound to this port.
+    port: Option<u16>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/ports",
+    tag = "system",
+    params(PortQuery),
+    responses(
+        (status = 200, description = "Listening sockets, ordered by port", body = [ListeningPort], content_type = ["application/json", "application/msgpack"]),
+        (status = 501, description = "Not available on this platform", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_ports(
+    State(state): State<AppState>,
+    encoding: Encoding,
+    Query(query): Query<PortQuery>,
+) -> Result<Encoded<Vec<ListeningPort>>, AppError> {
+    let ports = tokio::task::spawn_blocking(read_ports)
+        .await
+        .expect("Reading listening ports panicked");
+    let mut ports = ports.map_err(|err| match err.kind() {
+        io::ErrorKind::Unsupported => api_error(
+            StatusCode::NOT_IMPLEMENTED,
+            "Listening ports are not available on this platform",
+        ),
+        _ => api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to read listening ports: {err}"),
+        ),
+    })?;
+    if let Some(port) = query.port {
+        ports.retain(|listening| listening.port == port);
+    }
+    // The sample is ordered by pid.
+    let latest = state.processes.latest();
+    for listening in &mut ports {
+        listening.process = listening.pid.and_then(|pid| {
+            latest
+                .value()
+                .binary_search_by_key(&pid, |process| process.pid)
+                .ok()
+                .map(|index| latest.value()[index].name.clone())
+        });
+    }
+    Ok(Encoded(encoding, ports))
+}
+
+/// Sockets of the server's network namespace. The owner stays unknown for
+/// processes whose descriptors the server may not read.
+#[cfg(target_os = "linux")]
+fn read_ports() -> io::Result<Vec<ListeningPort>> {
+    use crate::open_files::{socket_owners, socket_table};
+
+    let listening: Vec<_> = socket_table("/proc/net")
+        .into_iter()
+        .filter(|entry| match entry.protocol {
+            "tcp" | "tcp6" => entry.state == "listen",
+            _ => entry.remote.is_none(),
+        })
+        .collect();
+    let owners = socket_owners()?;
+    let mut ports: Vec<ListeningPort> = listening
+        .into_iter()
+        .map(|entry| ListeningPort {
+            protocol: entry.protocol.to_owned(),
+            address: entry.local.ip().to_string(),
+            port: entry.local.port(),
+            pid: owners.get(&entry.inode).copied(),
+            process: None,
+        })
+        .collect();
+    ports.sort_by(|a, b| (a.port, &a.protocol, &a.address).cmp(&(b.port, &b.protocol, &b.address)));
+    Ok(ports)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_ports() -> io::Result<Vec<ListeningPort>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
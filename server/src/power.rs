@@ -0,0 +1,134 @@
+//! Battery and AC status. Read from `/sys/class/power_supply` on Linux;
+//! other platforms report no batteries.
+
+use crate::encoding::{Encoded, Encoding};
+use crate::models::PowerInfo;
+use crate::{AppError, AppState};
+use axum::extract::State;
+use axum_macros::debug_handler;
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/power",
+    tag = "system",
+    responses(
+        (status = 200, description = "Batteries and AC adapter", body = PowerInfo, content_type = ["application/json", "application/msgpack"]),
+        (status = 404, description = "The power collector is disabled", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_power(
+    State(state): State<AppState>,
+    encoding: Encoding,
+) -> Result<Encoded<PowerInfo>, AppError> {
+    state.power.require("power")?;
+    Ok(Encoded(encoding, state.power.latest().value().clone()))
+}
+
+/// Whether anything but the estimates and the power draw, which move with
+/// every sample, changed.
+pub fn changed(previous: &PowerInfo, current: &PowerInfo) -> bool {
+    previous.ac_online != current.ac_online
+        || previous.batteries.len() != current.batteries.len()
+        || previous
+            .batteries
+            .iter()
+            .zip(&current.batteries)
+            .any(|(old, new)| {
+                old.name != new.name
+                    || old.status != new.status
+                    || old.charge.round() != new.charge.round()
+            })
+}
+
+#[cfg(target_os = "linux")]
+pub fn read() -> PowerInfo {
+    use crate::models::BatteryInfo;
+    use std::fs;
+    use std::path::Path;
+
+    const POWER_SUPPLIES: &str = "/sys/class/power_supply";
+
+    let read = |dir: &Path, file: &str| {
+        fs::read_to_string(dir.join(file))
+            .ok()
+            .map(|value| value.trim().to_owned())
+    };
+    let number = |dir: &Path, file: &str| read(dir, file)?.parse::<u64>().ok();
+    let mut power = PowerInfo::default();
+    let Ok(entries) = fs::read_dir(POWER_SUPPLIES) else {
+        return power;
+    };
+    let mut supplies: Vec<_> = entries.filter_map(Result::ok).collect();
+    supplies.sort_by_key(|entry| entry.file_name());
+    for supply in supplies {
+        let dir = supply.path();
+        match read(&dir, "type").as_deref() {
+            Some("Mains") => {
+                let online = number(&dir, "online") == Some(1);
+                power.ac_online = Some(power.ac_online.unwrap_or(false) || online);
+            }
+            // Peripherals such as wireless mice report their batteries too.
+            Some("Battery") if read(&dir, "scope").as_deref() != Some("Device") => {
+                // Batteries report either energy in µWh and power in µW, or
+                // charge in µAh and current in µA.
+                let (now, full, design, rate) = match number(&dir, "energy_now") {
+                    Some(now) => (
+                        Some(now),
+                        number(&dir, "energy_full"),
+                        number(&dir, "energy_full_design"),
+                        number(&dir, "power_now"),
+                    ),
+                    None => (
+                        number(&dir, "charge_now"),
+                        number(&dir, "charge_full"),
+                        number(&dir, "charge_full_design"),
+                        number(&dir, "current_now"),
+                    ),
+                };
+                let status = read(&dir, "status")
+                    .unwrap_or_default()
+                    .to_lowercase()
+                    .replace(' ', "_");
+                let charge = number(&dir, "capacity")
+                    .map(|capacity| capacity as f32)
+                    .or_else(|| Some(now? as f32 / full.filter(|full| *full > 0)? as f32 * 100f32));
+                let seconds = |amount: u64| match rate {
+                    Some(rate) if rate > 0 => Some(amount * 3600 / rate),
+                    _ => None,
+                };
+                let power_watts = number(&dir, "power_now")
+                    .map(|microwatts| microwatts as f64 / 1_000_000f64)
+                    .or_else(|| {
+                        let microvolts = number(&dir, "voltage_now")?;
+                        let microamps = number(&dir, "current_now")?;
+                        Some(microvolts as f64 * microamps as f64 / 1e12)
+                    });
+                power.batteries.push(BatteryInfo {
+                    name: supply.file_name().to_string_lossy().into_owned(),
+                    charge: charge.unwrap_or_default(),
+                    health: full
+                        .zip(design)
+                        .filter(|(_, design)| *design > 0)
+                        .map(|(full, design)| full as f32 / design as f32 * 100f32),
+                    time_to_empty_seconds: (status == "discharging")
+                        .then(|| seconds(now?))
+                        .flatten(),
+                    time_to_full_seconds: (status == "charging")
+                        .then(|| seconds(full?.saturating_sub(now?)))
+                        .flatten(),
+                    power_watts,
+                    cycle_count: number(&dir, "cycle_count").filter(|count| *count > 0),
+                    status,
+                });
+            }
+            _ => {}
+        }
+    }
+    power
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn read() -> PowerInfo {
+    PowerInfo::default()
+}
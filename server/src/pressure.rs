@@ -0,0 +1,68 @@
+//! Pressure stall information from `/proc/pressure`: how much time tasks
+//! spent waiting on CPU, memory or IO. Needs Linux 4.20 with PSI enabled;
+//! otherwise every resource is `null`.
+
+use crate::encoding::{Encoded, Encoding};
+use crate::models::{Pressure, PressureInfo, PressureStall};
+use crate::{AppError, AppState};
+use axum::extract::State;
+use axum::response::IntoResponse;
+use axum_macros::debug_handler;
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/pressure",
+    tag = "system",
+    responses(
+        (status = 200, description = "CPU, memory and IO pressure", body = Pressure, content_type = ["application/json", "application/msgpack"]),
+        (status = 404, description = "The pressure collector is disabled", body = ErrorResponse),
+        (status = 503, description = "No sample taken yet", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_pressure(
+    State(state): State<AppState>,
+    encoding: Encoding,
+) -> Result<impl IntoResponse, AppError> {
+    let latest = state.pressure.sampled("pressure")?;
+    Ok(Encoded(encoding, latest.value().clone()))
+}
+
+pub fn read() -> Pressure {
+    Pressure {
+        cpu: read_resource("cpu"),
+        memory: read_resource("memory"),
+        io: read_resource("io"),
+        timestamp: 0,
+    }
+}
+
+/// `some` and `full` lines of `avg10=0.00 avg60=0.00 avg300=0.00 total=0`.
+fn read_resource(resource: &str) -> Option<PressureInfo> {
+    let content = std::fs::read_to_string(format!("/proc/pressure/{resource}")).ok()?;
+    let mut info = PressureInfo::default();
+    for line in content.lines() {
+        let Some((kind, values)) = line.split_once(' ') else {
+            continue;
+        };
+        let mut stall = PressureStall::default();
+        for (key, value) in values
+            .split_whitespace()
+            .filter_map(|pair| pair.split_once('='))
+        {
+            match key {
+                "avg10" => stall.avg10 = value.parse().ok()?,
+                "avg60" => stall.avg60 = value.parse().ok()?,
+                "avg300" => stall.avg300 = value.parse().ok()?,
+                "total" => stall.total_us = value.parse().ok()?,
+                _ => {}
+            }
+        }
+        match kind {
+            "some" => info.some = stall,
+            "full" => info.full = Some(stall),
+            _ => {}
+        }
+    }
+    Some(info)
+}
@@ -0,0 +1,259 @@
+//! Delta encoding of `/realtime/processes`, opted into with `?delta=true`.
+//! A busy host's process list is large and mostly unchanged from one sample
+//! to the next, so after a full snapshot only the differences are sent,
+//! keyed by PID:
+//!
+//! ```json
+//! {"type": "snapshot", "seq": 42, "timestamp": 1700000000000, "processes": [...]}
+//! {"type": "delta", "seq": 43, "timestamp": 1700000001000, "added": [...],
+//!  "changed": [{"pid": 1, "cpu_usage": 2.5}], "removed": [4242]}
+//! ```
+//!
+//! `added` carries whole entries, `changed` the `pid` and only the fields that
+//! changed, so a process whose CPU usage moved costs a few bytes rather than
+//! its whole entry. `seq` is the sequence number of the process sample the
+//! message catches up to and `timestamp` the time it was taken at. Samples that change nothing
+//! send nothing, and every `KEYFRAME_EVERY`th message is a snapshot again, so
+//! a client whose copy went wrong resyncs; it can also ask for a snapshot
+//! right away by sending `{"resync": true}`.
+
+use crate::encoding::Encoding;
+use crate::models::ProcessInfo;
+use crate::realtime::{close_for_shutdown, notice, ping, Heartbeat, Throttle};
+use crate::AppState;
+use axum::extract::ws::{Message, WebSocket};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+const KEYFRAME_EVERY: u32 = 60;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DeltaQuery {
+    #[serde(default)]
+    pub delta: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct Resync {
+    resync: bool,
+}
+
+/// The client's copy of the process list, as of the last message sent, as
+/// the fields of each entry by PID.
+#[derive(Default)]
+struct Mirror {
+    processes: HashMap<u32, Map<String, Value>>,
+    since_keyframe: u32,
+}
+
+impl Mirror {
+    /// The message bringing the client up to `processes`, sample `seq` of
+    /// the topic taken at `timestamp`, if anything changed.
+    fn update(&mut self, seq: u64, timestamp: u64, processes: &[ProcessInfo]) -> Option<Value> {
+        let current = by_pid(processes);
+        if self.since_keyframe == 0 {
+            self.processes = current;
+            self.since_keyframe = KEYFRAME_EVERY - 1;
+            return Some(json!({
+                "type": "snapshot",
+                "seq": seq,
+                "timestamp": timestamp,
+                "processes": processes,
+            }));
+        }
+        let mut added = vec![];
+        let mut changed = vec![];
+        for process in processes {
+            let Some(previous) = self.processes.get(&process.pid) else {
+                added.push(process);
+                continue;
+            };
+            let mut fields: Map<String, Value> = current[&process.pid]
+                .iter()
+                .filter(|(field, value)| previous.get(*field) != Some(value))
+                .map(|(field, value)| (field.clone(), value.clone()))
+                .collect();
+            if !fields.is_empty() {
+                fields.insert("pid".to_owned(), process.pid.into());
+                changed.push(fields);
+            }
+        }
+        let mut removed: Vec<u32> = self
+            .processes
+            .keys()
+            .filter(|pid| !current.contains_key(pid))
+            .copied()
+            .collect();
+        if added.is_empty() && changed.is_empty() && removed.is_empty() {
+            return None;
+        }
+        removed.sort_unstable();
+        let message = json!({
+            "type": "delta",
+            "seq": seq,
+            "timestamp": timestamp,
+            "added": added,
+            "changed": changed,
+            "removed": removed,
+        });
+        self.processes = current;
+        self.since_keyframe -= 1;
+        Some(message)
+    }
+}
+
+fn by_pid(processes: &[ProcessInfo]) -> HashMap<u32, Map<String, Value>> {
+    processes
+        .iter()
+        .map(|process| {
+            let Value::Object(fields) = serde_json::to_value(process).unwrap() else {
+                unreachable!("Processes serialize to objects");
+            };
+            (process.pid, fields)
+        })
+        .collect()
+}
+
+pub async fn delta_stream(
+    state: AppState,
+    mut ws: WebSocket,
+    mut throttle: Throttle,
+    encoding: Encoding,
+) {
+    let mut rx = state.processes.subscribe();
+    if rx.borrow().seq > 0 {
+        rx.mark_changed();
+    }
+    let mut mirror = Mirror::default();
+    let mut heartbeat = Heartbeat::new(state.telemetry.clone());
+    loop {
+        tokio::select! {
+            _ = state.shutdown.cancelled() => {
+                close_for_shutdown(ws).await;
+                break;
+            }
+            changed = rx.changed() => if changed.is_err() {
+                break;
+            },
+            alive = heartbeat.tick() => {
+                if !alive || ws.send(ping()).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+            msg = ws.recv() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(Message::Text(text))) => {
+                    heartbeat.seen();
+                    let wants_resync = serde_json::from_str::<Resync>(&text)
+                        .is_ok_and(|resync| resync.resync);
+                    if !wants_resync || rx.borrow().seq == 0 {
+                        continue;
+                    }
+                    // Starting over makes the next message a snapshot, sent
+                    // right away with the latest sample.
+                    mirror = Mirror::default();
+                    throttle.reset();
+                }
+                Some(Ok(_)) => {
+                    heartbeat.seen();
+                    continue;
+                }
+            },
+        }
+        let snapshot = rx.borrow_and_update().clone();
+        if !throttle.ready() {
+            continue;
+        }
+        let Some(message) = mirror.update(snapshot.seq, snapshot.timestamp, snapshot.value())
+        else {
+            continue;
+        };
+        if ws.send(notice(encoding, message)).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(pid: u32, cpu_usage: f32) -> ProcessInfo {
+        ProcessInfo {
+            pid,
+            name: format!("process-{pid}"),
+            cpu_usage,
+            memory: "1.00 MB".to_owned(),
+            memory_bytes: 1_000_000,
+            status: "Sleeping".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn starts_with_snapshot() {
+        let mut mirror = Mirror::default();
+        let message = mirror.update(1, 1000, &[process(1, 0.0)]).unwrap();
+        assert_eq!(message["type"], "snapshot");
+        assert_eq!(message["seq"], 1);
+        assert_eq!(message["timestamp"], 1000);
+        assert_eq!(message["processes"][0]["pid"], 1);
+    }
+
+    #[test]
+    fn unchanged_list_sends_nothing() {
+        let mut mirror = Mirror::default();
+        let processes = [process(1, 0.0), process(2, 5.0)];
+        mirror.update(1, 1000, &processes).unwrap();
+        assert_eq!(mirror.update(2, 2000, &processes), None);
+    }
+
+    #[test]
+    fn cpu_change_sends_only_that_field() {
+        let mut mirror = Mirror::default();
+        mirror.update(1, 1000, &[process(1, 0.0), process(2, 5.0)]);
+        let message = mirror
+            .update(2, 2000, &[process(1, 0.0), process(2, 7.5)])
+            .unwrap();
+        assert_eq!(
+            message,
+            json!({
+                "type": "delta",
+                "seq": 2,
+                "timestamp": 2000,
+                "added": [],
+                "changed": [{"pid": 2, "cpu_usage": 7.5}],
+                "removed": [],
+            })
+        );
+    }
+
+    #[test]
+    fn added_and_removed_processes() {
+        let mut mirror = Mirror::default();
+        mirror.update(1, 1000, &[process(1, 0.0), process(2, 0.0)]);
+        let message = mirror
+            .update(2, 2000, &[process(1, 0.0), process(3, 0.0)])
+            .unwrap();
+        assert_eq!(message["added"][0]["pid"], 3);
+        assert_eq!(message["added"][0]["name"], "process-3");
+        assert_eq!(message["changed"], json!([]));
+        assert_eq!(message["removed"], json!([2]));
+    }
+
+    #[test]
+    fn keyframes_recur() {
+        let mut mirror = Mirror::default();
+        mirror.update(1, 0, &[process(1, 0.0)]);
+        for seq in 2..=KEYFRAME_EVERY as u64 {
+            let message = mirror.update(seq, 0, &[process(1, seq as f32)]).unwrap();
+            assert_eq!(message["type"], "delta");
+        }
+        let message = mirror
+            .update(KEYFRAME_EVERY as u64 + 1, 0, &[process(1, 0.0)])
+            .unwrap();
+        assert_eq!(message["type"], "snapshot");
+    }
+}
@@ -0,0 +1,169 @@
+//! `/realtime/processes/:pid`, a high frequency stream of a single process.
+//!
+//! Instead of waiting for the sampler the process is refreshed on its own
+//! timer, every `?interval_ms` (250 ms by default, at least 100 ms), through a
+//! private `System` so the shared sample isn't disturbed. Every update is a
+//! `ProcessWatch`; once the process is gone the client receives
+//! `{"event": "exited", "pid": 42}` and the socket is closed. `?format=msgpack`
+//! works as on the other realtime routes.
+
+use crate::encoding::Encoding;
+use crate::limits::SocketSlot;
+use crate::models::{HumanReadable, ProcessWatch};
+use crate::realtime::{accept, close_for_shutdown, notice, ping, Heartbeat};
+use crate::{api_error, AppError, AppState};
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
+use axum::extract::{Path, Query, State, WebSocketUpgrade};
+use axum::http::StatusCode;
+use axum::response::Response;
+use axum_macros::debug_handler;
+use serde::Deserialize;
+use serde_json::json;
+use std::time::{Duration, Instant};
+use sysinfo::{Pid, PidExt, ProcessExt, ProcessStatus, System, SystemExt};
+use tokio::time::MissedTickBehavior;
+
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(250);
+const MIN_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Default, Deserialize)]
+pub struct WatchQuery {
+    interval_ms: Option<u64>,
+    #[serde(default)]
+    format: Encoding,
+}
+
+struct Watch {
+    pid: Pid,
+    system: System,
+    last_refresh: Option<Instant>,
+}
+
+impl Watch {
+    fn new(pid: u32) -> Self {
+        Self {
+            pid: Pid::from_u32(pid),
+            system: System::new(),
+            last_refresh: None,
+        }
+    }
+
+    /// Reads the process again, `None` once it has exited.
+    fn refresh(&mut self) -> Option<ProcessWatch> {
+        let now = Instant::now();
+        let elapsed = self.last_refresh.replace(now).map(|last| now - last);
+        if !self.system.refresh_process(self.pid) {
+            return None;
+        }
+        let process = self.system.process(self.pid)?;
+        if process.status() == ProcessStatus::Zombie {
+            return None;
+        }
+        let per_sec = |delta: u64| match elapsed {
+            Some(elapsed) if !elapsed.is_zero() => delta as f64 / elapsed.as_secs_f64(),
+            _ => 0f64,
+        };
+        let disk = process.disk_usage();
+        // sysinfo lists the threads besides the main one as tasks.
+        #[cfg(target_os = "linux")]
+        let threads = Some(process.tasks.len() + 1);
+        #[cfg(not(target_os = "linux"))]
+        let threads = None;
+        Some(ProcessWatch {
+            pid: self.pid.as_u32(),
+            name: process.name().to_owned(),
+            cpu_usage: process.cpu_usage(),
+            memory: process.memory().to_human(None),
+            memory_bytes: process.memory(),
+            virtual_memory: process.virtual_memory().to_human(None),
+            virtual_memory_bytes: process.virtual_memory(),
+            disk_read_bytes_per_sec: per_sec(disk.read_bytes),
+            disk_write_bytes_per_sec: per_sec(disk.written_bytes),
+            disk_read_bytes: disk.total_read_bytes,
+            disk_written_bytes: disk.total_written_bytes,
+            threads,
+        })
+    }
+}
+
+#[debug_handler]
+pub async fn realtime_process_get(
+    ws: WebSocketUpgrade,
+    slot: SocketSlot,
+    State(state): State<AppState>,
+    Path(pid): Path<u32>,
+    Query(query): Query<WatchQuery>,
+) -> Result<Response, AppError> {
+    if query.format == Encoding::Protobuf {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "Process watches have no protobuf encoding",
+        ));
+    }
+    let mut watch = Watch::new(pid);
+    // Also the baseline the first update's CPU usage and disk rates are
+    // measured against.
+    if watch.refresh().is_none() {
+        return Err(api_error(
+            StatusCode::NOT_FOUND,
+            format!("No process with pid {pid}"),
+        ));
+    }
+    let interval = query
+        .interval_ms
+        .map_or(DEFAULT_INTERVAL, Duration::from_millis)
+        .max(MIN_INTERVAL);
+    Ok(accept(ws, slot, state.clone(), move |ws| {
+        watch_stream(state, ws, watch, interval, query.format)
+    }))
+}
+
+async fn watch_stream(
+    state: AppState,
+    mut ws: WebSocket,
+    mut watch: Watch,
+    interval: Duration,
+    encoding: Encoding,
+) {
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut heartbeat = Heartbeat::new(state.telemetry.clone());
+    loop {
+        tokio::select! {
+            _ = state.shutdown.cancelled() => {
+                close_for_shutdown(ws).await;
+                return;
+            }
+            alive = heartbeat.tick() => {
+                if !alive || ws.send(ping()).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+            msg = ws.recv() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {
+                    heartbeat.seen();
+                    continue;
+                }
+            },
+            _ = ticker.tick() => {}
+        }
+        let Some(update) = watch.refresh() else {
+            break;
+        };
+        if ws.send(notice(encoding, json!(update))).await.is_err() {
+            return;
+        }
+    }
+    let exited = json!({ "event": "exited", "pid": watch.pid.as_u32() });
+    if ws.send(notice(encoding, exited)).await.is_ok() {
+        let frame = CloseFrame {
+            code: close_code::NORMAL,
+            reason: "Process exited".into(),
+        };
+        ws.send(Message::Close(Some(frame)))
+            .await
+            .unwrap_or_default();
+    }
+}
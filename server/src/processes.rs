@@ -0,0 +1,207 @@
+//! Process list queries, answered from the latest sample.
+
+use crate::encoding::{Encoded, Encoding};
+use crate::format::FormatQuery;
+use crate::models::ProcessInfo;
+use crate::{api_error, AppError, AppState};
+use async_graphql::Enum;
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum_macros::debug_handler;
+use regex::Regex;
+use serde::Deserialize;
+use std::cmp::Reverse;
+use utoipa::{IntoParams, ToSchema};
+
+const DEFAULT_TOP_LIMIT: usize = 10;
+/// Number of matching processes before pagination, set on `/api/processes`.
+const TOTAL_COUNT_HEADER: &str = "x-total-count";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema, Enum)]
+#[serde(rename_all = "lowercase")]
+#[graphql(rename_items = "lowercase")]
+pub enum SortKey {
+    #[default]
+    Cpu,
+    Memory,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TopQuery {
+    /// Sort key, CPU usage by default.
+    #[serde(default)]
+    by: SortKey,
+    /// Number of processes to return, 10 by default.
+    limit: Option<usize>,
+}
+
+/// Slice of the (pid ordered) process list. Without a `limit` everything
+/// from `offset` on is returned.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PageQuery {
+    /// Maximum number of processes to return.
+    limit: Option<usize>,
+    /// Number of matching processes to skip.
+    #[serde(default)]
+    offset: usize,
+}
+
+/// Filters applied to the latest sample before anything is returned. All
+/// given conditions must match.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ProcessFilter {
+    /// Case-insensitive substring of the process name.
+    pub name: Option<String>,
+    /// Regular expression matched against the process name.
+    pub name_regex: Option<String>,
+    /// Exact name of the owning user.
+    pub user: Option<String>,
+    /// Minimum CPU usage in percent.
+    pub min_cpu: Option<f32>,
+    /// Case-insensitive; `running` matches sysinfo's `Runnable`.
+    pub status: Option<String>,
+    /// Container name, or a prefix of its id.
+    pub container: Option<String>,
+}
+
+impl ProcessFilter {
+    pub fn apply<'a>(
+        &self,
+        processes: &'a [ProcessInfo],
+    ) -> Result<Vec<&'a ProcessInfo>, AppError> {
+        let name = self.name.as_deref().map(str::to_lowercase);
+        let name_regex = match &self.name_regex {
+            Some(pattern) => Some(Regex::new(pattern).map_err(|err| {
+                api_error(
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid name_regex: {err}"),
+                )
+            })?),
+            None => None,
+        };
+        Ok(processes
+            .iter()
+            .filter(|process| {
+                name.as_ref()
+                    .is_none_or(|name| process.name.to_lowercase().contains(name))
+                    && name_regex
+                        .as_ref()
+                        .is_none_or(|regex| regex.is_match(&process.name))
+                    && self
+                        .user
+                        .as_ref()
+                        .is_none_or(|user| process.user.as_ref() == Some(user))
+                    && self
+                        .min_cpu
+                        .is_none_or(|min_cpu| process.cpu_usage >= min_cpu)
+                    && self
+                        .status
+                        .as_deref()
+                        .is_none_or(|status| status_matches(&process.status, status))
+                    && self
+                        .container
+                        .as_deref()
+                        .is_none_or(|container| container_matches(process, container))
+            })
+            .collect())
+    }
+}
+
+fn status_matches(status: &str, wanted: &str) -> bool {
+    status.eq_ignore_ascii_case(wanted)
+        || (wanted.eq_ignore_ascii_case("running") && status == "Runnable")
+}
+
+fn container_matches(process: &ProcessInfo, wanted: &str) -> bool {
+    process.container_name.as_deref() == Some(wanted)
+        || process.container_id.as_deref().is_some_and(|id| {
+            !wanted.is_empty() && (id.starts_with(wanted) || wanted.starts_with(id))
+        })
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/processes",
+    tag = "processes",
+    params(ProcessFilter, PageQuery, FormatQuery),
+    responses(
+        (status = 200, description = "Matching processes ordered by pid", body = [ProcessInfo], content_type = ["application/json", "application/msgpack"],
+            headers(("x-total-count" = usize, description = "Number of matching processes before paging"),
+                ("x-sample-timestamp" = u64, description = "When the sample was taken, in milliseconds since the Unix epoch"))),
+        (status = 400, description = "Invalid filter or precision", body = ErrorResponse),
+        (status = 404, description = "The process collector is disabled", body = ErrorResponse),
+        (status = 503, description = "No sample taken yet", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_processes(
+    State(state): State<AppState>,
+    encoding: Encoding,
+    Query(filter): Query<ProcessFilter>,
+    Query(page): Query<PageQuery>,
+    Query(format): Query<FormatQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let latest = state.processes.sampled("process")?;
+    let processes = filter.apply(latest.value())?;
+    let total = processes.len();
+    let processes: Vec<ProcessInfo> = processes
+        .into_iter()
+        .skip(page.offset)
+        .take(page.limit.unwrap_or(usize::MAX))
+        .cloned()
+        .collect();
+    Ok((
+        [(TOTAL_COUNT_HEADER, total.to_string())],
+        latest.timestamp_header(),
+        Encoded(
+            encoding,
+            format.apply(serde_json::to_value(processes).unwrap())?,
+        ),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/processes/top",
+    tag = "processes",
+    params(ProcessFilter, TopQuery, FormatQuery),
+    responses(
+        (status = 200, description = "Heaviest matching processes first", body = [ProcessInfo], content_type = ["application/json", "application/msgpack"],
+            headers(("x-sample-timestamp" = u64, description = "When the sample was taken, in milliseconds since the Unix epoch"))),
+        (status = 400, description = "Invalid filter or precision", body = ErrorResponse),
+        (status = 404, description = "The process collector is disabled", body = ErrorResponse),
+        (status = 503, description = "No sample taken yet", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_top_processes(
+    State(state): State<AppState>,
+    encoding: Encoding,
+    Query(filter): Query<ProcessFilter>,
+    Query(query): Query<TopQuery>,
+    Query(format): Query<FormatQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let latest = state.processes.sampled("process")?;
+    let mut processes = filter.apply(latest.value())?;
+    sort_processes(&mut processes, query.by);
+    processes.truncate(query.limit.unwrap_or(DEFAULT_TOP_LIMIT));
+    Ok((
+        latest.timestamp_header(),
+        Encoded(
+            encoding,
+            format.apply(serde_json::to_value(processes).unwrap())?,
+        ),
+    ))
+}
+
+/// Sorts heaviest first.
+pub fn sort_processes(processes: &mut [&ProcessInfo], by: SortKey) {
+    match by {
+        SortKey::Cpu => processes.sort_by(|a, b| b.cpu_usage.total_cmp(&a.cpu_usage)),
+        SortKey::Memory => processes.sort_by_key(|process| Reverse(process.memory_bytes)),
+    }
+}
@@ -0,0 +1,419 @@
+//! The messages of `proto/process_viewer.proto`, written out by hand rather
+//! than generated by `prost-build`. The gRPC API serves them, and so do the
+//! realtime sockets of the topics they cover when opened with
+//! `?format=protobuf`.
+
+use crate::models;
+use crate::sampler::Snapshot;
+use prost::encoding::{encode_key, encode_varint, WireType};
+use prost::Message;
+
+/// Topics with a message, and the field of `RealtimeFrame` carrying it.
+const FRAME_FIELDS: &[(&str, u32)] = &[
+    ("cpus", 2),
+    ("memory", 3),
+    ("processes", 4),
+    ("disks", 5),
+    ("networks", 6),
+    ("temperatures", 7),
+    ("loadavg", 8),
+];
+
+pub fn supports(topic: &str) -> bool {
+    FRAME_FIELDS.iter().any(|(name, _)| *name == topic)
+}
+
+/// Field of `RealtimeFrame` carrying the sequence number.
+const SEQ_FIELD: u32 = 9;
+
+/// Wraps an encoded sample in a `RealtimeFrame` without decoding it again.
+pub fn frame(topic: &str, seq: u64, data: &[u8]) -> Vec<u8> {
+    let (_, field) = FRAME_FIELDS
+        .iter()
+        .find(|(name, _)| *name == topic)
+        .expect("Topic has no protobuf message");
+    let mut frame = Vec::with_capacity(data.len() + topic.len() + 24);
+    encode_key(1, WireType::LengthDelimited, &mut frame);
+    encode_varint(topic.len() as u64, &mut frame);
+    frame.extend_from_slice(topic.as_bytes());
+    encode_key(*field, WireType::LengthDelimited, &mut frame);
+    encode_varint(data.len() as u64, &mut frame);
+    frame.extend_from_slice(data);
+    encode_key(SEQ_FIELD, WireType::Varint, &mut frame);
+    encode_varint(seq, &mut frame);
+    frame
+}
+
+/// A topic's sample encoded as its message, `None` for the topics the
+/// schema has no message for.
+pub trait ToProtobuf: Sized {
+    fn to_protobuf(_snapshot: &Snapshot<Self>) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+impl ToProtobuf for Vec<models::CpuInfo> {
+    fn to_protobuf(snapshot: &Snapshot<Self>) -> Option<Vec<u8>> {
+        Some(CpuList::from(snapshot).encode_to_vec())
+    }
+}
+
+impl ToProtobuf for models::Memory {
+    fn to_protobuf(snapshot: &Snapshot<Self>) -> Option<Vec<u8>> {
+        Some(Memory::from(snapshot).encode_to_vec())
+    }
+}
+
+impl ToProtobuf for Vec<models::ProcessInfo> {
+    fn to_protobuf(snapshot: &Snapshot<Self>) -> Option<Vec<u8>> {
+        Some(ProcessList::from(snapshot).encode_to_vec())
+    }
+}
+
+impl ToProtobuf for Vec<models::DiskInfo> {
+    fn to_protobuf(snapshot: &Snapshot<Self>) -> Option<Vec<u8>> {
+        Some(DiskList::from(snapshot).encode_to_vec())
+    }
+}
+
+impl ToProtobuf for Vec<models::NetworkInfo> {
+    fn to_protobuf(snapshot: &Snapshot<Self>) -> Option<Vec<u8>> {
+        Some(NetworkList::from(snapshot).encode_to_vec())
+    }
+}
+
+impl ToProtobuf for Vec<models::TemperatureInfo> {
+    fn to_protobuf(snapshot: &Snapshot<Self>) -> Option<Vec<u8>> {
+        Some(TemperatureList::from(snapshot).encode_to_vec())
+    }
+}
+
+impl ToProtobuf for models::LoadAverage {
+    fn to_protobuf(snapshot: &Snapshot<Self>) -> Option<Vec<u8>> {
+        Some(LoadAverage::from(snapshot).encode_to_vec())
+    }
+}
+
+impl ToProtobuf for Vec<models::ProcessEvent> {}
+impl ToProtobuf for Vec<models::ConnectionEvent> {}
+impl ToProtobuf for Vec<models::DiskIoInfo> {}
+impl ToProtobuf for models::Pressure {}
+impl ToProtobuf for Vec<models::ContainerInfo> {}
+impl ToProtobuf for Vec<models::GpuInfo> {}
+impl ToProtobuf for models::PowerInfo {}
+impl ToProtobuf for serde_json::Value {}
+
+#[cfg(feature = "grpc")]
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Empty {}
+
+#[cfg(feature = "grpc")]
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SystemInfo {
+    #[prost(string, optional, tag = "1")]
+    pub hostname: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub os_name: Option<String>,
+    #[prost(string, optional, tag = "3")]
+    pub os_version: Option<String>,
+    #[prost(string, optional, tag = "4")]
+    pub kernel_version: Option<String>,
+    #[prost(string, tag = "5")]
+    pub architecture: String,
+    #[prost(uint64, tag = "6")]
+    pub boot_time: u64,
+    #[prost(uint64, tag = "7")]
+    pub uptime_seconds: u64,
+    #[prost(uint32, optional, tag = "8")]
+    pub physical_cores: Option<u32>,
+    #[prost(uint32, tag = "9")]
+    pub logical_cores: u32,
+    #[prost(btree_map = "string, string", tag = "10")]
+    pub labels: std::collections::BTreeMap<String, String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Cpu {
+    #[prost(float, tag = "1")]
+    pub cpu_usage: f32,
+    #[prost(uint64, tag = "2")]
+    pub frequency: u64,
+    #[prost(string, tag = "3")]
+    pub vendor_id: String,
+    #[prost(string, tag = "4")]
+    pub brand: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CpuList {
+    #[prost(message, repeated, tag = "1")]
+    pub cpus: Vec<Cpu>,
+    #[prost(uint64, tag = "2")]
+    pub timestamp: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Memory {
+    #[prost(uint64, tag = "1")]
+    pub total_memory_bytes: u64,
+    #[prost(uint64, tag = "2")]
+    pub used_memory_bytes: u64,
+    #[prost(uint64, tag = "3")]
+    pub total_swap_bytes: u64,
+    #[prost(uint64, tag = "4")]
+    pub used_swap_bytes: u64,
+    #[prost(uint64, tag = "5")]
+    pub effective_total_memory_bytes: u64,
+    #[prost(uint64, tag = "6")]
+    pub effective_used_memory_bytes: u64,
+    #[prost(bool, tag = "7")]
+    pub cgroup_limited: bool,
+    #[prost(uint64, tag = "8")]
+    pub timestamp: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Process {
+    #[prost(uint32, tag = "1")]
+    pub pid: u32,
+    #[prost(string, tag = "2")]
+    pub name: String,
+    #[prost(float, tag = "3")]
+    pub cpu_usage: f32,
+    #[prost(uint64, tag = "4")]
+    pub memory_bytes: u64,
+    #[prost(string, tag = "5")]
+    pub status: String,
+    #[prost(string, optional, tag = "6")]
+    pub user: Option<String>,
+    #[prost(string, optional, tag = "7")]
+    pub container_id: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ProcessList {
+    #[prost(message, repeated, tag = "1")]
+    pub processes: Vec<Process>,
+    #[prost(uint64, tag = "2")]
+    pub timestamp: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Disk {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub mount_point: String,
+    #[prost(string, tag = "3")]
+    pub file_system: String,
+    #[prost(uint64, tag = "4")]
+    pub total_space_bytes: u64,
+    #[prost(uint64, tag = "5")]
+    pub available_space_bytes: u64,
+    #[prost(bool, tag = "6")]
+    pub is_removable: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DiskList {
+    #[prost(message, repeated, tag = "1")]
+    pub disks: Vec<Disk>,
+    #[prost(uint64, tag = "2")]
+    pub timestamp: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Network {
+    #[prost(string, tag = "1")]
+    pub interface: String,
+    #[prost(uint64, tag = "2")]
+    pub rx_bytes: u64,
+    #[prost(uint64, tag = "3")]
+    pub tx_bytes: u64,
+    #[prost(double, tag = "4")]
+    pub rx_bytes_per_sec: f64,
+    #[prost(double, tag = "5")]
+    pub tx_bytes_per_sec: f64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct NetworkList {
+    #[prost(message, repeated, tag = "1")]
+    pub networks: Vec<Network>,
+    #[prost(uint64, tag = "2")]
+    pub timestamp: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Temperature {
+    #[prost(string, tag = "1")]
+    pub label: String,
+    #[prost(float, tag = "2")]
+    pub current: f32,
+    #[prost(float, tag = "3")]
+    pub max: f32,
+    #[prost(float, optional, tag = "4")]
+    pub critical: Option<f32>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TemperatureList {
+    #[prost(message, repeated, tag = "1")]
+    pub temperatures: Vec<Temperature>,
+    #[prost(uint64, tag = "2")]
+    pub timestamp: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct LoadAverage {
+    #[prost(double, tag = "1")]
+    pub one: f64,
+    #[prost(double, tag = "2")]
+    pub five: f64,
+    #[prost(double, tag = "3")]
+    pub fifteen: f64,
+    #[prost(uint64, tag = "4")]
+    pub timestamp: u64,
+}
+
+#[cfg(feature = "grpc")]
+impl From<&models::SystemInfo> for SystemInfo {
+    fn from(system: &models::SystemInfo) -> Self {
+        Self {
+            hostname: system.hostname.clone(),
+            os_name: system.os_name.clone(),
+            os_version: system.os_version.clone(),
+            kernel_version: system.kernel_version.clone(),
+            architecture: system.architecture.clone(),
+            boot_time: system.boot_time,
+            uptime_seconds: system.uptime_seconds,
+            physical_cores: system.physical_cores.map(|cores| cores as u32),
+            logical_cores: system.logical_cores as u32,
+            labels: system.labels.clone(),
+        }
+    }
+}
+
+impl From<&Snapshot<Vec<models::CpuInfo>>> for CpuList {
+    fn from(snapshot: &Snapshot<Vec<models::CpuInfo>>) -> Self {
+        let cpus = snapshot.value();
+        Self {
+            cpus: cpus
+                .iter()
+                .map(|cpu| Cpu {
+                    cpu_usage: cpu.cpu_usage,
+                    frequency: cpu.frequency,
+                    vendor_id: cpu.vendor_id.clone(),
+                    brand: cpu.brand.clone(),
+                })
+                .collect(),
+            timestamp: snapshot.timestamp,
+        }
+    }
+}
+
+impl From<&Snapshot<models::Memory>> for Memory {
+    fn from(snapshot: &Snapshot<models::Memory>) -> Self {
+        let memory = snapshot.value();
+        Self {
+            total_memory_bytes: memory.total_memory_bytes,
+            used_memory_bytes: memory.used_memory_bytes,
+            total_swap_bytes: memory.total_swap_bytes,
+            used_swap_bytes: memory.used_swap_bytes,
+            effective_total_memory_bytes: memory.effective_total_memory_bytes,
+            effective_used_memory_bytes: memory.effective_used_memory_bytes,
+            cgroup_limited: memory.cgroup_limited,
+            timestamp: snapshot.timestamp,
+        }
+    }
+}
+
+impl From<&Snapshot<Vec<models::ProcessInfo>>> for ProcessList {
+    fn from(snapshot: &Snapshot<Vec<models::ProcessInfo>>) -> Self {
+        let processes = snapshot.value();
+        Self {
+            processes: processes
+                .iter()
+                .map(|process| Process {
+                    pid: process.pid,
+                    name: process.name.clone(),
+                    cpu_usage: process.cpu_usage,
+                    memory_bytes: process.memory_bytes,
+                    status: process.status.clone(),
+                    user: process.user.clone(),
+                    container_id: process.container_id.clone(),
+                })
+                .collect(),
+            timestamp: snapshot.timestamp,
+        }
+    }
+}
+
+impl From<&Snapshot<Vec<models::DiskInfo>>> for DiskList {
+    fn from(snapshot: &Snapshot<Vec<models::DiskInfo>>) -> Self {
+        let disks = snapshot.value();
+        Self {
+            disks: disks
+                .iter()
+                .map(|disk| Disk {
+                    name: disk.name.clone(),
+                    mount_point: disk.mount_point.clone(),
+                    file_system: disk.file_system.clone(),
+                    total_space_bytes: disk.total_space_bytes,
+                    available_space_bytes: disk.available_space_bytes,
+                    is_removable: disk.is_removable,
+                })
+                .collect(),
+            timestamp: snapshot.timestamp,
+        }
+    }
+}
+
+impl From<&Snapshot<Vec<models::NetworkInfo>>> for NetworkList {
+    fn from(snapshot: &Snapshot<Vec<models::NetworkInfo>>) -> Self {
+        let networks = snapshot.value();
+        Self {
+            networks: networks
+                .iter()
+                .map(|network| Network {
+                    interface: network.interface.clone(),
+                    rx_bytes: network.rx_bytes,
+                    tx_bytes: network.tx_bytes,
+                    rx_bytes_per_sec: network.rx_bytes_per_sec,
+                    tx_bytes_per_sec: network.tx_bytes_per_sec,
+                })
+                .collect(),
+            timestamp: snapshot.timestamp,
+        }
+    }
+}
+
+impl From<&Snapshot<Vec<models::TemperatureInfo>>> for TemperatureList {
+    fn from(snapshot: &Snapshot<Vec<models::TemperatureInfo>>) -> Self {
+        let temperatures = snapshot.value();
+        Self {
+            temperatures: temperatures
+                .iter()
+                .map(|sensor| Temperature {
+                    label: sensor.label.clone(),
+                    current: sensor.current,
+                    max: sensor.max,
+                    critical: sensor.critical,
+                })
+                .collect(),
+            timestamp: snapshot.timestamp,
+        }
+    }
+}
+
+impl From<&Snapshot<models::LoadAverage>> for LoadAverage {
+    fn from(snapshot: &Snapshot<models::LoadAverage>) -> Self {
+        let loadavg = snapshot.value();
+        Self {
+            one: loadavg.one,
+            five: loadavg.five,
+            fifteen: loadavg.fifteen,
+            timestamp: snapshot.timestamp,
+        }
+    }
+}
@@ -0,0 +1,786 @@
+//! WebSocket streaming of sampled topics, either one socket per topic under
+//! `/realtime/<topic>` or several topics over the multiplexed `/realtime`
+//! socket.
+//!
+//! On `/realtime` the client sends `{"subscribe": ["cpus", "memory"]}` or
+//! `{"unsubscribe": ["memory"]}` and receives
+//! `{"topic": "cpus", "seq": 42, "timestamp": 1700000000000, "data": ...}`
+//! for each sample of every subscribed topic. `seq` counts the topic's
+//! samples, so a gap shows how many were skipped, and `timestamp` is when the
+//! sample was taken, in milliseconds since the Unix epoch; `{"resync":
+//! ["cpus"]}` sends the latest sample again.
+//! Per-topic sockets send the bare data unless opened with `?seq=true`, which
+//! wraps samples the same way.
+//!
+//! Every realtime route accepts `?interval_ms=2000` to receive at most one
+//! update per topic per interval, independent of the sampling interval. On
+//! `/realtime` the rate can also be changed later with `{"interval_ms": 2000}`.
+//! Event topics such as `process_events` are never throttled.
+//! With `?format=msgpack` every message is sent as a binary MessagePack frame
+//! of the same shape; control messages stay JSON text.
+//!
+//! With `?format=protobuf`, or the `protobuf` subprotocol, samples are sent
+//! as binary frames of the messages in `proto/process_viewer.proto`: the
+//! topic's message on `/realtime/<topic>` and a `RealtimeFrame` on
+//! `/realtime`, while errors and lag notices stay JSON text. Only the topics
+//! the schema has a message for can be streamed this way.
+//!
+//! A client that reads slower than samples are published skips ahead to the
+//! newest sample instead of being disconnected. The multiplexed socket tells
+//! it so with `{"topic": "cpus", "lagged": 3}`, the number of skipped samples.
+//!
+//! `/realtime/processes?delta=true` sends changes to the process list instead
+//! of the whole list, see `process_delta.rs`.
+//!
+//! Every socket is pinged every `PING_INTERVAL`, and dropped when the client
+//! sent nothing, not even the pong, by the next ping. Otherwise a client that
+//! vanished without closing would hold its subscriptions forever.
+
+use crate::encoding::Encoding;
+use crate::limits::SocketSlot;
+use crate::process_delta::{delta_stream, DeltaQuery};
+use crate::protobuf::{self, ToProtobuf};
+use crate::sampler::{Payload, Topic};
+use crate::telemetry::Telemetry;
+use crate::{api_error, AppState};
+use axum::async_trait;
+use axum::extract::rejection::QueryRejection;
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
+use axum::extract::{FromRequestParts, Query, State, WebSocketUpgrade};
+use axum::http::request::Parts;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum_macros::debug_handler;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::time::MissedTickBehavior;
+use tokio_stream::wrappers::WatchStream;
+use tokio_stream::StreamMap;
+use tracing::{Instrument, Span};
+
+/// The built-in topics, known by name even when their source isn't
+/// registered, as Docker and GPUs aren't unless they're sampled.
+pub const TOPICS: &[&str] = &[
+    "cpus",
+    "memory",
+    "processes",
+    "process_events",
+    "connection_events",
+    "disks",
+    "diskio",
+    "networks",
+    "temperatures",
+    "loadavg",
+    "pressure",
+    "containers",
+    "gpus",
+    "power",
+];
+
+/// What a subscriber receives from a topic stream.
+pub enum Update {
+    /// A serialized sample, its sequence number on the topic and the time
+    /// it was taken at.
+    Sample {
+        seq: u64,
+        timestamp: u64,
+        payload: Payload,
+    },
+    /// The subscriber fell behind and this many samples were skipped; the
+    /// next update is the newest sample.
+    Lagged(u64),
+}
+
+/// Topics carrying events rather than samples. Dropping an update would lose
+/// its events, so `interval_ms` doesn't apply to them.
+const EVENT_TOPICS: &[&str] = &["process_events", "connection_events", "power"];
+/// Topics whose latest update is news rather than the current state, so a new
+/// subscriber isn't sent it. Every other topic starts with its latest sample,
+/// so a chart needn't wait a whole interval for its first point.
+const NEWS_TOPICS: &[&str] = &["process_events", "connection_events"];
+
+pub type PayloadStream = BoxStream<'static, Update>;
+
+/// The subprotocol that selects protobuf frames, for clients that can't add
+/// a query parameter.
+const PROTOBUF_PROTOCOL: &str = "protobuf";
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+
+/// The topic called `name`, built in or of a registered collector.
+pub fn topic_name(state: &AppState, name: &str) -> Option<&'static str> {
+    TOPICS
+        .iter()
+        .find(|topic| **topic == name)
+        .copied()
+        .or_else(|| state.registry.name(name))
+}
+
+/// False for the topics of collectors disabled in `[collectors]`, and for
+/// Docker and GPUs when they aren't sampled.
+pub fn topic_enabled(state: &AppState, topic: &str) -> bool {
+    state.registry.is_enabled(topic)
+}
+
+pub fn topic_stream(state: &AppState, topic: &str, encoding: Encoding) -> Option<PayloadStream> {
+    state
+        .registry
+        .stream(topic, encoding, state.telemetry.clone())
+}
+
+pub fn payloads<T>(
+    topic: &Topic<T>,
+    name: &'static str,
+    encoding: Encoding,
+    telemetry: Telemetry,
+) -> PayloadStream
+where
+    T: Serialize + ToProtobuf + Send + Sync + 'static,
+{
+    let rx = topic.subscribe();
+    let seq = rx.borrow().seq;
+    let (updates, mut last_seq) = if seq > 0 && !NEWS_TOPICS.contains(&name) {
+        (WatchStream::new(rx), seq - 1)
+    } else {
+        (WatchStream::from_changes(rx), seq)
+    };
+    updates
+        .flat_map(move |snapshot| {
+            let skipped = snapshot.seq - last_seq - 1;
+            last_seq = snapshot.seq;
+            let lagged = (skipped > 0).then(|| {
+                telemetry.record_lag(name, skipped);
+                Update::Lagged(skipped)
+            });
+            stream::iter(lagged.into_iter().chain([Update::Sample {
+                seq: snapshot.seq,
+                timestamp: snapshot.timestamp,
+                payload: snapshot.payload(encoding),
+            }]))
+        })
+        .boxed()
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RealtimeQuery {
+    interval_ms: Option<u64>,
+    #[serde(default)]
+    format: Encoding,
+    /// Wrap samples on per-topic sockets with their sequence numbers and
+    /// timestamps.
+    #[serde(default)]
+    seq: bool,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for RealtimeQuery {
+    type Rejection = QueryRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(mut query) = Query::<Self>::from_request_parts(parts, state).await?;
+        let asks_for_protobuf = parts
+            .headers
+            .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|protocol| protocol.trim() == PROTOBUF_PROTOCOL);
+        if asks_for_protobuf {
+            query.format = Encoding::Protobuf;
+        }
+        Ok(query)
+    }
+}
+
+impl RealtimeQuery {
+    fn throttle(&self, topic: &str) -> Throttle {
+        throttle_for(topic, self.interval_ms)
+    }
+}
+
+/// Drops updates that arrive sooner than `interval` after the last one sent.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Throttle {
+    interval: Option<Duration>,
+    last_sent: Option<Instant>,
+}
+
+impl Throttle {
+    fn new(interval_ms: Option<u64>) -> Self {
+        Self {
+            interval: interval_ms.map(Duration::from_millis),
+            last_sent: None,
+        }
+    }
+
+    /// Lets the next update through whenever it comes.
+    pub fn reset(&mut self) {
+        self.last_sent = None;
+    }
+
+    pub fn ready(&mut self) -> bool {
+        let now = Instant::now();
+        let ready = match (self.interval, self.last_sent) {
+            (Some(interval), Some(last_sent)) => now - last_sent >= interval,
+            _ => true,
+        };
+        if ready {
+            self.last_sent = Some(now);
+        }
+        ready
+    }
+}
+
+fn throttle_for(topic: &str, interval_ms: Option<u64>) -> Throttle {
+    if EVENT_TOPICS.contains(&topic) {
+        Throttle::default()
+    } else {
+        Throttle::new(interval_ms)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ControlMessage {
+    #[serde(default)]
+    subscribe: Vec<String>,
+    #[serde(default)]
+    unsubscribe: Vec<String>,
+    interval_ms: Option<u64>,
+    /// Subscribed topics to send the latest sample of again.
+    #[serde(default)]
+    resync: Vec<String>,
+}
+
+/// Per-connection state of the multiplexed socket.
+struct Subscriptions {
+    streams: StreamMap<&'static str, PayloadStream>,
+    throttles: HashMap<&'static str, Throttle>,
+    interval_ms: Option<u64>,
+    encoding: Encoding,
+}
+
+#[debug_handler]
+pub async fn realtime_get(
+    ws: WebSocketUpgrade,
+    slot: SocketSlot,
+    State(state): State<AppState>,
+    query: RealtimeQuery,
+) -> impl IntoResponse {
+    accept(
+        ws.protocols([PROTOBUF_PROTOCOL]),
+        slot,
+        state.clone(),
+        move |ws| multiplexed_stream(state, ws, query),
+    )
+}
+
+/// Upgrades the connection and runs `handle` on the socket inside the span of
+/// the request that opened it, logging when the client connects and leaves.
+/// `slot` is held until the socket closes.
+pub fn accept<F, Fut>(
+    ws: WebSocketUpgrade,
+    slot: SocketSlot,
+    state: AppState,
+    handle: F,
+) -> Response
+where
+    F: FnOnce(WebSocket) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let span = Span::current();
+    let AppState {
+        connections,
+        telemetry,
+        ..
+    } = state;
+    ws.on_upgrade(move |ws| {
+        let connection = async move {
+            tracing::info!("Realtime client connected");
+            telemetry.client_connected();
+            let connected = Instant::now();
+            handle(ws).await;
+            telemetry.client_disconnected();
+            drop(slot);
+            tracing::info!(duration = ?connected.elapsed(), "Realtime client disconnected");
+        };
+        connections.track_future(connection.instrument(span))
+    })
+}
+
+/// Pings a realtime client and notices when it stopped answering.
+pub struct Heartbeat {
+    ticker: tokio::time::Interval,
+    answered: bool,
+    telemetry: Telemetry,
+}
+
+impl Heartbeat {
+    pub fn new(telemetry: Telemetry) -> Self {
+        let start = tokio::time::Instant::now() + PING_INTERVAL;
+        let mut ticker = tokio::time::interval_at(start, PING_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Self {
+            ticker,
+            answered: true,
+            telemetry,
+        }
+    }
+
+    /// To be called for every message the client sends.
+    pub fn seen(&mut self) {
+        self.answered = true;
+    }
+
+    /// Waits until the next ping is due; false, and the client counted as
+    /// reaped, when it didn't answer the last one.
+    pub async fn tick(&mut self) -> bool {
+        self.ticker.tick().await;
+        if std::mem::replace(&mut self.answered, false) {
+            return true;
+        }
+        tracing::info!("Realtime client stopped answering pings");
+        self.telemetry.record_reaped();
+        false
+    }
+}
+
+/// A ping to send when `Heartbeat::tick` says so.
+pub fn ping() -> Message {
+    Message::Ping(Vec::new())
+}
+
+async fn multiplexed_stream(state: AppState, mut ws: WebSocket, query: RealtimeQuery) {
+    let mut subscriptions = Subscriptions {
+        streams: StreamMap::new(),
+        throttles: HashMap::new(),
+        interval_ms: query.interval_ms,
+        encoding: query.format,
+    };
+    let mut heartbeat = Heartbeat::new(state.telemetry.clone());
+    loop {
+        tokio::select! {
+            _ = state.shutdown.cancelled() => {
+                close_for_shutdown(ws).await;
+                break;
+            }
+            alive = heartbeat.tick() => {
+                if !alive || ws.send(ping()).await.is_err() {
+                    break;
+                }
+            }
+            msg = ws.recv() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    heartbeat.seen();
+                    if let Err(error) = subscriptions.apply(&state, &text) {
+                        let reply = notice(subscriptions.encoding, json!({ "error": error }));
+                        if ws.send(reply).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => heartbeat.seen(),
+            },
+            Some((topic, update)) = subscriptions.streams.next(), if !subscriptions.streams.is_empty() => {
+                let msg = match update {
+                    Update::Sample { seq, timestamp, payload } => {
+                        let throttle = subscriptions.throttles.entry(topic).or_default();
+                        if !throttle.ready() {
+                            continue;
+                        }
+                        envelope(topic, seq, timestamp, payload)
+                    }
+                    Update::Lagged(skipped) => notice(
+                        subscriptions.encoding,
+                        json!({ "topic": topic, "lagged": skipped }),
+                    ),
+                };
+                if ws.send(msg).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl Subscriptions {
+    fn apply(&mut self, state: &AppState, text: &str) -> Result<(), String> {
+        let control: ControlMessage =
+            serde_json::from_str(text).map_err(|err| format!("Invalid message: {err}"))?;
+        if control.interval_ms.is_some() {
+            self.interval_ms = control.interval_ms;
+            for (topic, throttle) in &mut self.throttles {
+                throttle.interval = throttle_for(topic, control.interval_ms).interval;
+            }
+        }
+        let mut unknown = vec![];
+        let mut disabled = vec![];
+        let mut unencodable = vec![];
+        for name in &control.subscribe {
+            match topic_name(state, name) {
+                Some(topic) if !topic_enabled(state, topic) => disabled.push(topic),
+                Some(topic)
+                    if self.encoding == Encoding::Protobuf && !protobuf::supports(topic) =>
+                {
+                    unencodable.push(topic);
+                }
+                Some(topic) if !self.streams.contains_key(topic) => {
+                    self.streams
+                        .insert(topic, topic_stream(state, topic, self.encoding).unwrap());
+                    self.throttles
+                        .insert(topic, throttle_for(topic, self.interval_ms));
+                }
+                Some(_) => {}
+                None => unknown.push(name.as_str()),
+            }
+        }
+        for name in &control.unsubscribe {
+            self.streams.remove(name.as_str());
+            self.throttles.remove(name.as_str());
+        }
+        let mut unsubscribed = vec![];
+        for name in &control.resync {
+            match topic_name(state, name) {
+                // A new stream starts with the latest sample, and a new
+                // throttle lets it through.
+                Some(topic) if self.streams.contains_key(topic) => {
+                    self.streams
+                        .insert(topic, topic_stream(state, topic, self.encoding).unwrap());
+                    self.throttles
+                        .insert(topic, throttle_for(topic, self.interval_ms));
+                }
+                _ => unsubscribed.push(name.as_str()),
+            }
+        }
+        if !unknown.is_empty() {
+            Err(format!("Unknown topics: {}", unknown.join(", ")))
+        } else if !disabled.is_empty() {
+            Err(format!(
+                "The collectors of these topics are disabled: {}",
+                disabled.join(", ")
+            ))
+        } else if !unencodable.is_empty() {
+            Err(format!(
+                "No protobuf encoding for topics: {}",
+                unencodable.join(", ")
+            ))
+        } else if !unsubscribed.is_empty() {
+            Err(format!(
+                "Not subscribed to topics: {}",
+                unsubscribed.join(", ")
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[debug_handler]
+pub async fn realtime_cpus_get(
+    ws: WebSocketUpgrade,
+    slot: SocketSlot,
+    State(state): State<AppState>,
+    query: RealtimeQuery,
+) -> impl IntoResponse {
+    let topic = Topic::clone(&state.cpus);
+    upgrade(ws, slot, state, topic, "cpus", query)
+}
+
+#[debug_handler]
+pub async fn realtime_memory_get(
+    ws: WebSocketUpgrade,
+    slot: SocketSlot,
+    State(state): State<AppState>,
+    query: RealtimeQuery,
+) -> impl IntoResponse {
+    let topic = Topic::clone(&state.memory);
+    upgrade(ws, slot, state, topic, "memory", query)
+}
+
+#[debug_handler]
+pub async fn realtime_processes_get(
+    ws: WebSocketUpgrade,
+    slot: SocketSlot,
+    State(state): State<AppState>,
+    query: RealtimeQuery,
+    Query(DeltaQuery { delta }): Query<DeltaQuery>,
+) -> impl IntoResponse {
+    if !delta {
+        let topic = Topic::clone(&state.processes);
+        return upgrade(ws, slot, state, topic, "processes", query);
+    }
+    if let Err(err) = state.processes.require("process") {
+        return err.into_response();
+    }
+    if query.format == Encoding::Protobuf {
+        return api_error(
+            StatusCode::BAD_REQUEST,
+            "Delta updates have no protobuf encoding",
+        )
+        .into_response();
+    }
+    let throttle = query.throttle("processes");
+    accept(ws, slot, state.clone(), move |ws| {
+        delta_stream(state, ws, throttle, query.format)
+    })
+}
+
+#[debug_handler]
+pub async fn realtime_process_events_get(
+    ws: WebSocketUpgrade,
+    slot: SocketSlot,
+    State(state): State<AppState>,
+    query: RealtimeQuery,
+) -> impl IntoResponse {
+    let topic = Topic::clone(&state.process_events);
+    upgrade(ws, slot, state, topic, "process_events", query)
+}
+
+#[debug_handler]
+pub async fn realtime_connection_events_get(
+    ws: WebSocketUpgrade,
+    slot: SocketSlot,
+    State(state): State<AppState>,
+    query: RealtimeQuery,
+) -> impl IntoResponse {
+    let topic = Topic::clone(&state.connection_events);
+    upgrade(ws, slot, state, topic, "connection_events", query)
+}
+
+#[debug_handler]
+pub async fn realtime_disks_get(
+    ws: WebSocketUpgrade,
+    slot: SocketSlot,
+    State(state): State<AppState>,
+    query: RealtimeQuery,
+) -> impl IntoResponse {
+    let topic = Topic::clone(&state.disks);
+    upgrade(ws, slot, state, topic, "disks", query)
+}
+
+#[debug_handler]
+pub async fn realtime_diskio_get(
+    ws: WebSocketUpgrade,
+    slot: SocketSlot,
+    State(state): State<AppState>,
+    query: RealtimeQuery,
+) -> impl IntoResponse {
+    let topic = Topic::clone(&state.diskio);
+    upgrade(ws, slot, state, topic, "diskio", query)
+}
+
+#[debug_handler]
+pub async fn realtime_networks_get(
+    ws: WebSocketUpgrade,
+    slot: SocketSlot,
+    State(state): State<AppState>,
+    query: RealtimeQuery,
+) -> impl IntoResponse {
+    let topic = Topic::clone(&state.networks);
+    upgrade(ws, slot, state, topic, "networks", query)
+}
+
+#[debug_handler]
+pub async fn realtime_temperatures_get(
+    ws: WebSocketUpgrade,
+    slot: SocketSlot,
+    State(state): State<AppState>,
+    query: RealtimeQuery,
+) -> impl IntoResponse {
+    let topic = Topic::clone(&state.temperatures);
+    upgrade(ws, slot, state, topic, "temperatures", query)
+}
+
+#[debug_handler]
+pub async fn realtime_loadavg_get(
+    ws: WebSocketUpgrade,
+    slot: SocketSlot,
+    State(state): State<AppState>,
+    query: RealtimeQuery,
+) -> impl IntoResponse {
+    let topic = Topic::clone(&state.loadavg);
+    upgrade(ws, slot, state, topic, "loadavg", query)
+}
+
+#[debug_handler]
+pub async fn realtime_pressure_get(
+    ws: WebSocketUpgrade,
+    slot: SocketSlot,
+    State(state): State<AppState>,
+    query: RealtimeQuery,
+) -> impl IntoResponse {
+    let topic = Topic::clone(&state.pressure);
+    upgrade(ws, slot, state, topic, "pressure", query)
+}
+
+#[debug_handler]
+pub async fn realtime_containers_get(
+    ws: WebSocketUpgrade,
+    slot: SocketSlot,
+    State(state): State<AppState>,
+    query: RealtimeQuery,
+) -> impl IntoResponse {
+    let topic = state.containers.clone();
+    upgrade(ws, slot, state, topic, "containers", query)
+}
+
+#[debug_handler]
+pub async fn realtime_gpus_get(
+    ws: WebSocketUpgrade,
+    slot: SocketSlot,
+    State(state): State<AppState>,
+    query: RealtimeQuery,
+) -> impl IntoResponse {
+    let topic = state.gpus.clone();
+    upgrade(ws, slot, state, topic, "gpus", query)
+}
+
+#[debug_handler]
+pub async fn realtime_power_get(
+    ws: WebSocketUpgrade,
+    slot: SocketSlot,
+    State(state): State<AppState>,
+    query: RealtimeQuery,
+) -> impl IntoResponse {
+    let topic = Topic::clone(&state.power);
+    upgrade(ws, slot, state, topic, "power", query)
+}
+
+fn upgrade<T>(
+    ws: WebSocketUpgrade,
+    slot: SocketSlot,
+    state: AppState,
+    topic: Topic<T>,
+    name: &'static str,
+    query: RealtimeQuery,
+) -> Response
+where
+    T: Serialize + ToProtobuf + Send + Sync + 'static,
+{
+    if !topic.is_enabled() {
+        return api_error(
+            StatusCode::NOT_FOUND,
+            format!("The collector of the {name} topic is disabled"),
+        )
+        .into_response();
+    }
+    if query.format == Encoding::Protobuf && !protobuf::supports(name) {
+        return api_error(
+            StatusCode::BAD_REQUEST,
+            format!("The {name} topic has no protobuf encoding"),
+        )
+        .into_response();
+    }
+    let throttle = query.throttle(name);
+    accept(
+        ws.protocols([PROTOBUF_PROTOCOL]),
+        slot,
+        state.clone(),
+        move |ws| realtime_stream(state, topic, name, ws, throttle, query),
+    )
+}
+
+async fn realtime_stream<T>(
+    state: AppState,
+    topic: Topic<T>,
+    name: &'static str,
+    mut ws: WebSocket,
+    mut throttle: Throttle,
+    query: RealtimeQuery,
+) where
+    T: Serialize + ToProtobuf,
+{
+    let mut rx = topic.subscribe();
+    if rx.borrow().seq > 0 && !NEWS_TOPICS.contains(&name) {
+        rx.mark_changed();
+    }
+    let mut heartbeat = Heartbeat::new(state.telemetry.clone());
+    loop {
+        tokio::select! {
+            _ = state.shutdown.cancelled() => {
+                close_for_shutdown(ws).await;
+                break;
+            }
+            changed = rx.changed() => if changed.is_err() {
+                break;
+            },
+            alive = heartbeat.tick() => {
+                if !alive || ws.send(ping()).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+            // Read only for pongs and the close frame.
+            msg = ws.recv() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {
+                    heartbeat.seen();
+                    continue;
+                }
+            },
+        }
+        // Plain topic sockets only carry data, so a client that fell behind
+        // silently continues with the newest sample; with `seq` it can tell.
+        let snapshot = rx.borrow_and_update().clone();
+        if !throttle.ready() {
+            continue;
+        }
+        let payload = snapshot.payload(query.format);
+        let msg = match payload {
+            _ if query.seq => envelope(name, snapshot.seq, snapshot.timestamp, payload),
+            Payload::Json(payload) => Message::Text(payload.to_string()),
+            Payload::Msgpack(payload) | Payload::Protobuf(payload) => {
+                Message::Binary(payload.to_vec())
+            }
+        };
+        if ws.send(msg).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Wraps a sample as `{"topic": ..., "seq": ..., "timestamp": ..., "data":
+/// ...}` without re-serializing it. Protobuf messages carry their own
+/// timestamp.
+fn envelope(topic: &str, seq: u64, timestamp: u64, payload: Payload) -> Message {
+    match payload {
+        Payload::Json(data) => Message::Text(format!(
+            r#"{{"topic":"{topic}","seq":{seq},"timestamp":{timestamp},"data":{data}}}"#
+        )),
+        Payload::Msgpack(data) => {
+            let mut msg = Vec::with_capacity(data.len() + topic.len() + 48);
+            rmp::encode::write_map_len(&mut msg, 4).unwrap();
+            rmp::encode::write_str(&mut msg, "topic").unwrap();
+            rmp::encode::write_str(&mut msg, topic).unwrap();
+            rmp::encode::write_str(&mut msg, "seq").unwrap();
+            rmp::encode::write_uint(&mut msg, seq).unwrap();
+            rmp::encode::write_str(&mut msg, "timestamp").unwrap();
+            rmp::encode::write_uint(&mut msg, timestamp).unwrap();
+            rmp::encode::write_str(&mut msg, "data").unwrap();
+            msg.extend_from_slice(&data);
+            Message::Binary(msg)
+        }
+        Payload::Protobuf(data) => Message::Binary(protobuf::frame(topic, seq, &data)),
+    }
+}
+
+/// A message generated by the server itself rather than a sample.
+pub fn notice(encoding: Encoding, value: Value) -> Message {
+    match encoding {
+        Encoding::Json | Encoding::Protobuf => Message::Text(value.to_string()),
+        Encoding::Msgpack => Message::Binary(rmp_serde::to_vec_named(&value).unwrap()),
+    }
+}
+
+pub async fn close_for_shutdown(mut ws: WebSocket) {
+    let frame = CloseFrame {
+        code: close_code::AWAY,
+        reason: "Server is shutting down".into(),
+    };
+    ws.send(Message::Close(Some(frame)))
+        .await
+        .unwrap_or_default();
+}
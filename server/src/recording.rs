@@ -0,0 +1,164 @@
+//! Record and replay, for demos and postmortems: `--record <file>` appends
+//! every sample to a log while serving as usual, and `--replay <file>`
+//! serves a log through the same REST, realtime and gRPC APIs instead of
+//! sampling, at the recorded pace divided by `--replay-speed`.
+//!
+//! The log is a header followed by one `(timestamp, Sample)` pair per
+//! sample, each a MessagePack array without field names to keep it compact.
+//! Samples are flushed as they are written, so a recording cut short by a
+//! crash is still readable up to its last whole sample.
+
+use crate::history::now_millis;
+use crate::sampler::{self, Sample};
+use crate::AppState;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tokio::sync::mpsc as async_mpsc;
+use tokio::time::Instant;
+
+const MAGIC: &str = "process-viewer-recording";
+/// Bumped whenever `Sample` changes shape, as old recordings can no longer
+/// be read then.
+const VERSION: u32 = 1;
+/// Samples read ahead of the one being replayed.
+const READ_AHEAD: usize = 16;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    magic: String,
+    version: u32,
+    interval_ms: u64,
+}
+
+pub struct Recorder {
+    writer: mpsc::Sender<Vec<u8>>,
+}
+
+impl Recorder {
+    /// Creates or truncates `path`. Samples are written by a thread of their
+    /// own so the sampler never waits on disk.
+    pub fn create(path: &Path, interval: Duration) -> Result<Self, String> {
+        let failed = |err: &dyn std::fmt::Display| {
+            format!("Failed to create recording {}: {err}", path.display())
+        };
+        let mut file = BufWriter::new(File::create(path).map_err(|err| failed(&err))?);
+        let header = Header {
+            magic: MAGIC.to_owned(),
+            version: VERSION,
+            interval_ms: interval.as_millis() as u64,
+        };
+        rmp_serde::encode::write(&mut file, &header).map_err(|err| failed(&err))?;
+        file.flush().map_err(|err| failed(&err))?;
+        let (writer, rx) = mpsc::channel::<Vec<u8>>();
+        let name = path.display().to_string();
+        thread::spawn(move || {
+            for record in rx {
+                if let Err(err) = file.write_all(&record).and_then(|()| file.flush()) {
+                    tracing::error!("Failed to write recording {name}: {err}");
+                    return;
+                }
+            }
+        });
+        Ok(Self { writer })
+    }
+
+    pub fn record(&self, timestamp: u64, sample: &Sample) {
+        let record = rmp_serde::to_vec(&(timestamp, sample)).unwrap();
+        self.writer.send(record).unwrap_or_default();
+    }
+}
+
+pub struct Replay {
+    reader: BufReader<File>,
+    name: String,
+    interval: Duration,
+}
+
+impl Replay {
+    /// Opens `path` and checks its header; the samples are only read once
+    /// the replay is spawned.
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let name = path.display().to_string();
+        let file =
+            File::open(path).map_err(|err| format!("Failed to open recording {name}: {err}"))?;
+        let mut reader = BufReader::new(file);
+        let header = rmp_serde::from_read::<_, Header>(&mut reader)
+            .ok()
+            .filter(|header| header.magic == MAGIC)
+            .ok_or_else(|| format!("{name} is not a process-viewer recording"))?;
+        if header.version != VERSION {
+            return Err(format!(
+                "{name} is a version {} recording, this server reads version {VERSION}",
+                header.version
+            ));
+        }
+        Ok(Self {
+            reader,
+            name,
+            interval: Duration::from_millis(header.interval_ms),
+        })
+    }
+
+    /// Sampling interval the recording was made with.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Publishes the recorded samples, stamped with the time they are
+    /// replayed at, until the recording ends or the server shuts down. The
+    /// last sample stays on display afterwards.
+    pub fn spawn(self, speed: f64, app_state: AppState) {
+        let (tx, mut rx) = async_mpsc::channel(READ_AHEAD);
+        let Self {
+            mut reader, name, ..
+        } = self;
+        thread::spawn(move || loop {
+            match reader.fill_buf() {
+                Ok([]) => return,
+                Ok(_) => {}
+                Err(err) => {
+                    tracing::error!("Failed to read recording {name}: {err}");
+                    return;
+                }
+            }
+            let record: (u64, Sample) = match rmp_serde::from_read(&mut reader) {
+                Ok(record) => record,
+                Err(err) => {
+                    tracing::warn!("Recording {name} ends in an unreadable sample: {err}");
+                    return;
+                }
+            };
+            if tx.blocking_send(record).is_err() {
+                return;
+            }
+        });
+        tokio::spawn(async move {
+            let started = Instant::now();
+            let mut first = None;
+            let mut replayed = 0u64;
+            loop {
+                let record = tokio::select! {
+                    record = rx.recv() => record,
+                    _ = app_state.shutdown.cancelled() => return,
+                };
+                let Some((timestamp, sample)) = record else {
+                    break;
+                };
+                let first = *first.get_or_insert(timestamp);
+                let offset = Duration::from_millis(timestamp.saturating_sub(first));
+                tokio::select! {
+                    _ = tokio::time::sleep_until(started + offset.div_f64(speed)) => {}
+                    _ = app_state.shutdown.cancelled() => return,
+                }
+                sampler::publish(&app_state, now_millis(), sample);
+                replayed += 1;
+            }
+            tracing::info!(samples = replayed, "Replay finished");
+        });
+    }
+}
@@ -0,0 +1,82 @@
+//! Request ids, to correlate a client's report with the server's logs. A
+//! client's `x-request-id` is kept when it looks sane, otherwise a random
+//! UUID is made up. Either way it's echoed in the response, recorded on the
+//! request's tracing span, and included in error bodies (see `error.rs`).
+
+use axum::http::{HeaderValue, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use ring::rand::{SecureRandom, SystemRandom};
+use tower_http::trace::MakeSpan;
+use tracing::{Level, Span};
+
+pub const X_REQUEST_ID: &str = "x-request-id";
+/// Longer ids from clients are replaced rather than logged.
+const MAX_LEN: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// The id of the request being handled, if called while handling one.
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Outermost layer, so every other layer and the handler see the id.
+pub async fn propagate<B>(mut request: Request<B>, next: Next<B>) -> Response {
+    let id = request
+        .headers()
+        .get(X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| {
+            !id.is_empty() && id.len() <= MAX_LEN && id.bytes().all(|byte| byte.is_ascii_graphic())
+        })
+        .map_or_else(generate, str::to_owned);
+    let value = HeaderValue::from_str(&id).expect("Request ids are visible ASCII");
+    request.headers_mut().insert(X_REQUEST_ID, value.clone());
+    let mut response = REQUEST_ID.scope(id, next.run(request)).await;
+    response.headers_mut().insert(X_REQUEST_ID, value);
+    response
+}
+
+/// A random version 4 UUID.
+fn generate() -> String {
+    let mut bytes = [0u8; 16];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("Failed to generate a request id");
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// The request span, as `DefaultMakeSpan` makes it plus the request id.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestSpan;
+
+impl<B> MakeSpan<B> for RequestSpan {
+    fn make_span(&mut self, request: &Request<B>) -> Span {
+        let id = request
+            .headers()
+            .get(X_REQUEST_ID)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        tracing::span!(
+            Level::INFO,
+            "request",
+            method = %request.method(),
+            uri = %request.uri(),
+            version = ?request.version(),
+            request_id = %id,
+        )
+    }
+}
@@ -0,0 +1,666 @@
+use crate::cgroup;
+use crate::config::Collectors;
+use crate::connections;
+use crate::cpu_times;
+use crate::diskio;
+use crate::encoding::Encoding;
+use crate::error::AppError;
+use crate::history::now_millis;
+use crate::idle;
+use crate::models::{
+    ConnectionInfo, CpuInfo, CpuLimit, DiskInfo, DiskIoInfo, HumanReadable, LoadAverage, Memory,
+    NetworkInfo, PowerInfo, Pressure, ProcessEvent, ProcessEventKind, ProcessInfo, TemperatureInfo,
+};
+use crate::power;
+use crate::pressure;
+use crate::protobuf::ToProtobuf;
+use crate::recording::Recorder;
+use crate::watchdog::WatchEventKind;
+use crate::AppState;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use sysinfo::{
+    ComponentExt, CpuExt, DiskExt, NetworkExt, NetworksExt, PidExt, ProcessExt, System, SystemExt,
+    UserExt,
+};
+use tokio::sync::watch;
+use tokio::time::MissedTickBehavior;
+
+/// A serialized sample, shared by every realtime subscriber that asked for
+/// the same encoding.
+#[derive(Clone)]
+pub enum Payload {
+    Json(Arc<str>),
+    Msgpack(Arc<[u8]>),
+    Protobuf(Arc<[u8]>),
+}
+
+/// Header of REST responses giving the time of the sample they were
+/// answered from, since lists carry no time of their own.
+pub const SAMPLE_TIMESTAMP_HEADER: &str = "x-sample-timestamp";
+
+/// One published sample of a topic.
+pub struct Snapshot<T> {
+    /// Increases by one with every publish, so subscribers can tell how many
+    /// samples they missed.
+    pub seq: u64,
+    /// When the sample was taken, in milliseconds since the Unix epoch; 0
+    /// for the placeholder.
+    pub timestamp: u64,
+    /// `None` for the placeholder a topic starts out with.
+    published: Option<Instant>,
+    value: T,
+    json: OnceLock<Arc<str>>,
+    msgpack: OnceLock<Arc<[u8]>>,
+    protobuf: OnceLock<Arc<[u8]>>,
+}
+
+impl<T> Snapshot<T> {
+    fn new(seq: u64, timestamp: u64, value: T) -> Self {
+        Self {
+            seq,
+            timestamp,
+            published: (seq > 0).then(Instant::now),
+            value,
+            json: OnceLock::new(),
+            msgpack: OnceLock::new(),
+            protobuf: OnceLock::new(),
+        }
+    }
+
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+
+    /// Time since the sample was published, `None` before the first sample.
+    pub fn age(&self) -> Option<Duration> {
+        self.published.map(|published| published.elapsed())
+    }
+
+    /// The `x-sample-timestamp` header for a response built from the sample.
+    pub fn timestamp_header(&self) -> [(&'static str, String); 1] {
+        [(SAMPLE_TIMESTAMP_HEADER, self.timestamp.to_string())]
+    }
+}
+
+impl<T: Serialize + ToProtobuf> Snapshot<T> {
+    /// The sample in the given encoding, serialized on first use however
+    /// many clients are connected. Protobuf is only asked for on topics
+    /// that have a message.
+    pub fn payload(&self, encoding: Encoding) -> Payload {
+        match encoding {
+            Encoding::Json => Payload::Json(
+                self.json
+                    .get_or_init(|| serde_json::to_string(&self.value).unwrap().into())
+                    .clone(),
+            ),
+            Encoding::Msgpack => Payload::Msgpack(
+                self.msgpack
+                    .get_or_init(|| rmp_serde::to_vec_named(&self.value).unwrap().into())
+                    .clone(),
+            ),
+            Encoding::Protobuf => Payload::Protobuf(
+                self.protobuf
+                    .get_or_init(|| {
+                        let encoded = T::to_protobuf(self);
+                        encoded.expect("Topic has no protobuf message").into()
+                    })
+                    .clone(),
+            ),
+        }
+    }
+}
+
+/// A single kind of sampled data. Only the latest sample is kept: REST
+/// handlers read it directly and realtime subscribers wait for the next one,
+/// so both always serve the same sample.
+pub struct Topic<T> {
+    tx: Arc<watch::Sender<Arc<Snapshot<T>>>>,
+    /// False when its collector is switched off in `[collectors]`.
+    enabled: bool,
+}
+
+impl<T> Clone for Topic<T> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            enabled: self.enabled,
+        }
+    }
+}
+
+impl<T: Default> Topic<T> {
+    pub fn new() -> Self {
+        Self::enabled_if(true)
+    }
+
+    /// A disabled topic ignores what is published to it.
+    pub fn enabled_if(enabled: bool) -> Self {
+        let (tx, _) = watch::channel(Arc::new(Snapshot::new(0, 0, T::default())));
+        Self {
+            tx: Arc::new(tx),
+            enabled,
+        }
+    }
+}
+
+impl<T> Topic<T> {
+    /// Publishes `value`, sampled at `timestamp` (milliseconds since the
+    /// Unix epoch).
+    pub fn publish(&self, timestamp: u64, value: T) {
+        if !self.enabled {
+            return;
+        }
+        let seq = self.tx.borrow().seq + 1;
+        self.tx
+            .send_replace(Arc::new(Snapshot::new(seq, timestamp, value)));
+    }
+
+    pub fn latest(&self) -> Arc<Snapshot<T>> {
+        self.tx.borrow().clone()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// A 404 when the topic's collector is disabled.
+    pub fn require(&self, what: &str) -> Result<(), AppError> {
+        if !self.enabled {
+            return Err(AppError::NotFound(format!(
+                "The {what} collector is disabled"
+            )));
+        }
+        Ok(())
+    }
+
+    /// The latest sample for REST handlers, a 503 before the first one and a
+    /// 404 when the collector is disabled.
+    pub fn sampled(&self, what: &str) -> Result<Arc<Snapshot<T>>, AppError> {
+        self.require(what)?;
+        let latest = self.latest();
+        if latest.seq == 0 {
+            return Err(AppError::NotReady(format!(
+                "No {what} sample has been taken yet"
+            )));
+        }
+        Ok(latest)
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Arc<Snapshot<T>>> {
+        self.tx.subscribe()
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.tx.receiver_count()
+    }
+}
+
+/// Owns the one `System` instance shared by every collector and refreshes
+/// it on a single tick loop.
+pub struct Sampler {
+    system: Arc<tokio::sync::Mutex<System>>,
+    last_sample: Option<Instant>,
+    carried: Carried,
+    /// The server's own cgroup, for its limits.
+    own_cgroup: Option<cgroup::OwnCgroup>,
+    last_cgroup_cpu_usec: Option<u64>,
+    /// Appends every sample to the `--record` file.
+    recorder: Option<Recorder>,
+}
+
+/// Cgroups of the live processes by pid and start time, since processes
+/// rarely move and reading procfs for every process each tick adds up.
+type CgroupCache = HashMap<(u32, u64), Option<String>>;
+
+/// State kept from one tick to the next by the blocking collectors.
+#[derive(Default)]
+struct Carried {
+    cgroups: CgroupCache,
+    cpu_times: cpu_times::Previous,
+    diskio: diskio::Previous,
+}
+
+/// Everything collected during one tick, as written to and read back from
+/// recordings.
+#[derive(Serialize, Deserialize)]
+pub struct Sample {
+    cpus: Vec<CpuInfo>,
+    cpu_limit: Option<CpuLimit>,
+    memory: Memory,
+    processes: Vec<ProcessInfo>,
+    connections: Vec<ConnectionInfo>,
+    disks: Vec<DiskInfo>,
+    networks: Vec<NetworkInfo>,
+    diskio: Vec<DiskIoInfo>,
+    temperatures: Vec<TemperatureInfo>,
+    loadavg: LoadAverage,
+    pressure: Pressure,
+    power: PowerInfo,
+}
+
+impl Sample {
+    /// Here rather than in the collectors, so samples replayed from
+    /// recordings made before payloads had timestamps get one too. Lists
+    /// aren't stamped: their time is that of the topic's snapshot.
+    fn stamp(&mut self, timestamp: u64) {
+        self.memory.timestamp = timestamp;
+        self.loadavg.timestamp = timestamp;
+        self.pressure.timestamp = timestamp;
+        self.power.timestamp = timestamp;
+    }
+}
+
+impl Sampler {
+    pub fn new(recorder: Option<Recorder>) -> Self {
+        // Sensors and users rarely come and go at runtime, so their lists are
+        // only built once.
+        let mut system = System::new();
+        system.refresh_components_list();
+        system.refresh_users_list();
+        Self {
+            system: Arc::new(tokio::sync::Mutex::new(system)),
+            last_sample: None,
+            carried: Carried::default(),
+            own_cgroup: cgroup::OwnCgroup::detect(),
+            last_cgroup_cpu_usec: None,
+            recorder,
+        }
+    }
+
+    /// Shared handle to the sampled `System`, for handlers that need to look
+    /// up or act on live processes between ticks.
+    pub fn system(&self) -> Arc<tokio::sync::Mutex<System>> {
+        self.system.clone()
+    }
+
+    pub fn spawn(self, app_state: AppState) {
+        tokio::spawn(async move { self.run(app_state).await });
+    }
+
+    async fn run(mut self, app_state: AppState) {
+        let mut ticker = tokio::time::interval(app_state.config.sample_interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = app_state.shutdown.cancelled() => break,
+            }
+            if !idle::wait_while_idle(&app_state, "sampler").await {
+                break;
+            }
+            let started = Instant::now();
+            let mut sample = self.sample(app_state.config.collectors).await;
+            let elapsed = started.elapsed();
+            tracing::debug!(?elapsed, "Sampled");
+            app_state.telemetry.record_sample(elapsed);
+            name_containers(&mut sample.processes, &app_state);
+            let timestamp = now_millis();
+            if let Some(recorder) = &self.recorder {
+                recorder.record(timestamp, &sample);
+            }
+            publish(&app_state, timestamp, sample);
+        }
+    }
+
+    /// Disabled collectors are skipped and leave their part empty.
+    async fn sample(&mut self, collectors: Collectors) -> Sample {
+        let mut sys = self.system.clone().lock_owned().await;
+        let now = Instant::now();
+        let elapsed = self.last_sample.replace(now).map(|last| now - last);
+        let mut carried = std::mem::take(&mut self.carried);
+        let own_cgroup = self.own_cgroup.clone();
+        let (mut sample, carried, limits) = tokio::task::spawn_blocking(move || {
+            let limits = own_cgroup.map(|cgroup| cgroup.limits()).unwrap_or_default();
+            sys.refresh_cpu();
+            sys.refresh_memory();
+            if collectors.processes {
+                sys.refresh_processes();
+            }
+            if collectors.disks {
+                sys.refresh_disks_list();
+                sys.refresh_disks();
+            }
+            if collectors.networks {
+                sys.refresh_networks_list();
+            }
+            if collectors.temperatures {
+                sys.refresh_components();
+            }
+            let mut cpus = collect_cpus(&sys);
+            cpu_times::apply(&mut cpus, &mut carried.cpu_times);
+            let processes = collect_if(collectors.processes, || {
+                collect_processes(&sys, &mut carried.cgroups)
+            });
+            let sample = Sample {
+                cpus,
+                cpu_limit: None,
+                memory: collect_memory(&sys, &limits),
+                connections: collect_if(collectors.connections, || {
+                    connections::collect(&processes)
+                }),
+                processes,
+                disks: collect_if(collectors.disks, || collect_disks(&sys)),
+                networks: collect_if(collectors.networks, || collect_networks(&sys, elapsed)),
+                diskio: collect_if(collectors.diskio, || {
+                    diskio::collect(&mut carried.diskio, elapsed)
+                }),
+                temperatures: collect_if(collectors.temperatures, || collect_temperatures(&sys)),
+                loadavg: collect_load_average(&sys),
+                pressure: collect_if(collectors.pressure, pressure::read),
+                power: collect_if(collectors.power, power::read),
+            };
+            (sample, carried, limits)
+        })
+        .await
+        .expect("Sampling task panicked");
+        self.carried = carried;
+        let usec = limits.cpu_usage_usec;
+        let last_usec = std::mem::replace(&mut self.last_cgroup_cpu_usec, usec);
+        sample.cpu_limit = limits.cpu_max.map(|cores| CpuLimit {
+            cores,
+            cpu_usage: cgroup_cpu_usage(last_usec, usec, elapsed, cores),
+        });
+        sample
+    }
+}
+
+/// Hands a sample to the alert engine and every source, which keep their
+/// histories, both for live samples and for those replayed from a recording.
+pub fn publish(app_state: &AppState, timestamp: u64, mut sample: Sample) {
+    sample.stamp(timestamp);
+    if let Some(db) = &app_state.history_db {
+        db.record("cpus", timestamp, &sample.cpus);
+        db.record("memory", timestamp, &sample.memory);
+    }
+    for event in app_state
+        .alerts
+        .evaluate(timestamp, &sample.cpus, &sample.memory)
+    {
+        tracing::info!(
+            rule = %event.rule.id,
+            state = ?event.state,
+            value = event.value,
+            "Alert changed state"
+        );
+        app_state.notifier.notify(&event);
+    }
+    app_state.cpu_limit.publish(timestamp, sample.cpu_limit);
+    app_state.cpus.publish(timestamp, sample.cpus);
+    app_state.memory.publish(timestamp, sample.memory);
+    let previous = app_state.processes.latest();
+    // The placeholder before the first sample would report every
+    // running process as started.
+    let events = if previous.seq > 0 {
+        diff_processes(previous.value(), &sample.processes, timestamp)
+    } else {
+        vec![]
+    };
+    for event in app_state.watchdog.evaluate(timestamp, &sample.processes) {
+        match event.event {
+            WatchEventKind::Disappeared => {
+                tracing::warn!(watch = %event.watch, pid = event.pid, "Watched process disappeared")
+            }
+            WatchEventKind::Recovered => {
+                tracing::info!(watch = %event.watch, pid = event.pid, "Watched process is back")
+            }
+        }
+        app_state.notifier.notify(&event);
+    }
+    app_state.processes.publish(timestamp, sample.processes);
+    // Only batches with events are published, so subscribers don't get
+    // an empty update every tick.
+    if !events.is_empty() {
+        app_state.process_events.publish(timestamp, events);
+    }
+    let previous = app_state.tcp_connections.latest();
+    let events = if previous.seq > 0 {
+        connections::diff(previous.value(), &sample.connections, timestamp)
+    } else {
+        vec![]
+    };
+    app_state
+        .tcp_connections
+        .publish(timestamp, sample.connections);
+    if !events.is_empty() {
+        app_state.connection_events.publish(timestamp, events);
+    }
+    app_state.disks.publish(timestamp, sample.disks);
+    app_state.networks.publish(timestamp, sample.networks);
+    app_state.diskio.publish(timestamp, sample.diskio);
+    app_state
+        .temperatures
+        .publish(timestamp, sample.temperatures);
+    app_state.loadavg.publish(timestamp, sample.loadavg);
+    app_state.pressure.publish(timestamp, sample.pressure);
+    let previous = app_state.power.latest();
+    if previous.seq == 0 || power::changed(previous.value(), &sample.power) {
+        app_state.power.publish(timestamp, sample.power);
+    }
+    app_state.activity.sampled();
+}
+
+/// An empty part for a disabled collector.
+fn collect_if<T: Default>(enabled: bool, collect: impl FnOnce() -> T) -> T {
+    if enabled {
+        collect()
+    } else {
+        T::default()
+    }
+}
+
+fn collect_cpus(sys: &System) -> Vec<CpuInfo> {
+    sys.cpus()
+        .iter()
+        .map(|cpu| CpuInfo {
+            cpu_usage: cpu.cpu_usage(),
+            frequency: cpu.frequency(),
+            vendor_id: cpu.vendor_id().to_owned(),
+            brand: cpu.brand().to_owned(),
+            times: None,
+        })
+        .collect()
+}
+
+fn collect_memory(sys: &System, limits: &cgroup::Limits) -> Memory {
+    let (effective_total, effective_used) = match limits.memory_max {
+        Some(max) if max < sys.total_memory() => {
+            (max, limits.memory_used.unwrap_or(sys.used_memory()))
+        }
+        _ => (sys.total_memory(), sys.used_memory()),
+    };
+    Memory {
+        total_memory: sys.total_memory().to_human(None),
+        used_memory: sys.used_memory().to_human(None),
+        total_swap: sys.total_swap().to_human(None),
+        used_swap: sys.used_swap().to_human(None),
+        total_memory_bytes: sys.total_memory(),
+        used_memory_bytes: sys.used_memory(),
+        total_swap_bytes: sys.total_swap(),
+        used_swap_bytes: sys.used_swap(),
+        effective_total_memory: effective_total.to_human(None),
+        effective_used_memory: effective_used.to_human(None),
+        effective_total_memory_bytes: effective_total,
+        effective_used_memory_bytes: effective_used,
+        cgroup_limited: effective_total < sys.total_memory(),
+        timestamp: 0,
+    }
+}
+
+/// Percent of `cores` the cgroup used since the previous sample; zero on
+/// the first one.
+fn cgroup_cpu_usage(
+    last_usec: Option<u64>,
+    usec: Option<u64>,
+    elapsed: Option<Duration>,
+    cores: f64,
+) -> f32 {
+    match (last_usec, usec, elapsed) {
+        (Some(last), Some(now), Some(elapsed)) if !elapsed.is_zero() && cores > 0f64 => {
+            let used = now.saturating_sub(last) as f64 / 1_000_000f64;
+            (used / elapsed.as_secs_f64() / cores * 100f64) as f32
+        }
+        _ => 0f32,
+    }
+}
+
+/// Exited processes are dropped from `cgroups`.
+fn collect_processes(sys: &System, cgroups: &mut CgroupCache) -> Vec<ProcessInfo> {
+    let mut live = CgroupCache::with_capacity(sys.processes().len());
+    let mut processes: Vec<ProcessInfo> = sys
+        .processes()
+        .iter()
+        .map(|(pid, process)| {
+            let key = (pid.as_u32(), process.start_time());
+            let cgroup = cgroups
+                .remove(&key)
+                .unwrap_or_else(|| cgroup::process_cgroup(pid.as_u32()));
+            live.insert(key, cgroup.clone());
+            ProcessInfo {
+                pid: pid.as_u32(),
+                name: process.name().to_owned(),
+                cpu_usage: process.cpu_usage(),
+                memory: process.memory().to_human(None),
+                memory_bytes: process.memory(),
+                status: process.status().to_string(),
+                user: process
+                    .user_id()
+                    .and_then(|uid| sys.get_user_by_id(uid))
+                    .map(|user| user.name().to_owned()),
+                container_id: cgroup.as_deref().and_then(cgroup::container_id),
+                container_name: None,
+                cgroup,
+            }
+        })
+        .collect();
+    *cgroups = live;
+    processes.sort_by_key(|process| process.pid);
+    processes
+}
+
+/// Names are only known for containers the Docker collector has seen.
+fn name_containers(processes: &mut [ProcessInfo], app_state: &AppState) {
+    let containers = app_state.containers.latest();
+    if containers.value().is_empty() {
+        return;
+    }
+    let names: HashMap<&str, &str> = containers
+        .value()
+        .iter()
+        .map(|container| (container.id.as_str(), container.name.as_str()))
+        .collect();
+    for process in processes {
+        if let Some(id) = &process.container_id {
+            process.container_name = names.get(id.as_str()).map(|name| (*name).to_owned());
+        }
+    }
+}
+
+/// Both lists are ordered by pid, as `collect_processes` returns them.
+fn diff_processes(
+    previous: &[ProcessInfo],
+    current: &[ProcessInfo],
+    timestamp: u64,
+) -> Vec<ProcessEvent> {
+    let event = |event, process: &ProcessInfo| ProcessEvent {
+        event,
+        pid: process.pid,
+        name: process.name.clone(),
+        user: process.user.clone(),
+        timestamp,
+    };
+    let mut events = vec![];
+    let mut previous = previous.iter().peekable();
+    let mut current = current.iter().peekable();
+    loop {
+        match (previous.peek(), current.peek()) {
+            (Some(old), Some(new)) if old.pid == new.pid => {
+                if old.name != new.name {
+                    events.push(event(ProcessEventKind::Exited, old));
+                    events.push(event(ProcessEventKind::Started, new));
+                }
+                previous.next();
+                current.next();
+            }
+            (Some(old), new) if new.is_none_or(|new| old.pid < new.pid) => {
+                events.push(event(ProcessEventKind::Exited, old));
+                previous.next();
+            }
+            (_, Some(new)) => {
+                events.push(event(ProcessEventKind::Started, new));
+                current.next();
+            }
+            (_, None) => break,
+        }
+    }
+    events
+}
+
+fn collect_disks(sys: &System) -> Vec<DiskInfo> {
+    sys.disks()
+        .iter()
+        .map(|disk| DiskInfo {
+            name: disk.name().to_string_lossy().into_owned(),
+            mount_point: disk.mount_point().to_string_lossy().into_owned(),
+            file_system: String::from_utf8_lossy(disk.file_system()).into_owned(),
+            total_space: disk.total_space().to_human(None),
+            total_space_bytes: disk.total_space(),
+            available_space: disk.available_space().to_human(None),
+            available_space_bytes: disk.available_space(),
+            is_removable: disk.is_removable(),
+        })
+        .collect()
+}
+
+/// `elapsed` is the time since the previous refresh, used to turn the
+/// per-refresh deltas reported by sysinfo into per-second rates. Rates are
+/// zero on the first sample.
+fn collect_networks(sys: &System, elapsed: Option<Duration>) -> Vec<NetworkInfo> {
+    let per_sec = |delta: u64| match elapsed {
+        Some(elapsed) if !elapsed.is_zero() => delta as f64 / elapsed.as_secs_f64(),
+        _ => 0f64,
+    };
+    let mut networks: Vec<NetworkInfo> = sys
+        .networks()
+        .iter()
+        .map(|(interface, data)| NetworkInfo {
+            interface: interface.to_owned(),
+            rx_bytes: data.total_received(),
+            tx_bytes: data.total_transmitted(),
+            rx_packets: data.total_packets_received(),
+            tx_packets: data.total_packets_transmitted(),
+            rx_errors: data.total_errors_on_received(),
+            tx_errors: data.total_errors_on_transmitted(),
+            rx_bytes_per_sec: per_sec(data.received()),
+            tx_bytes_per_sec: per_sec(data.transmitted()),
+            rx_packets_per_sec: per_sec(data.packets_received()),
+            tx_packets_per_sec: per_sec(data.packets_transmitted()),
+        })
+        .collect();
+    networks.sort_by(|a, b| a.interface.cmp(&b.interface));
+    networks
+}
+
+fn collect_temperatures(sys: &System) -> Vec<TemperatureInfo> {
+    sys.components()
+        .iter()
+        .map(|component| TemperatureInfo {
+            label: component.label().to_owned(),
+            current: component.temperature(),
+            max: component.max(),
+            critical: component.critical(),
+        })
+        .collect()
+}
+
+fn collect_load_average(sys: &System) -> LoadAverage {
+    let load = sys.load_average();
+    LoadAverage {
+        one: load.one,
+        five: load.five,
+        fifteen: load.fifteen,
+        timestamp: 0,
+    }
+}
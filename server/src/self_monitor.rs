@@ -0,0 +1,82 @@
+//! `/api/v1/self`: resource usage and load of the viewer itself, so operators
+//! can tell what the monitor costs.
+
+use crate::encoding::{Encoded, Encoding};
+use crate::models::{HumanReadable, SelfInfo};
+use crate::AppState;
+use axum::extract::State;
+use axum::response::IntoResponse;
+use axum_macros::debug_handler;
+use std::collections::BTreeMap;
+use sysinfo::{PidExt, ProcessExt, SystemExt};
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/self",
+    tag = "monitoring",
+    responses((status = 200, description = "Usage of the server process", body = SelfInfo, content_type = ["application/json", "application/msgpack"]))
+)]
+#[debug_handler]
+pub async fn get_self(State(state): State<AppState>, encoding: Encoding) -> impl IntoResponse {
+    let pid = sysinfo::get_current_pid().ok();
+    // The sampler refreshes every process each tick, this one included.
+    let (memory_bytes, cpu_usage) = {
+        let sys = state.system.lock().await;
+        pid.and_then(|pid| sys.process(pid))
+            .map_or((0, 0f32), |process| (process.memory(), process.cpu_usage()))
+    };
+    let subscribers = BTreeMap::from([
+        ("cpus".to_owned(), state.cpus.subscriber_count()),
+        ("memory".to_owned(), state.memory.subscriber_count()),
+        ("processes".to_owned(), state.processes.subscriber_count()),
+        (
+            "process_events".to_owned(),
+            state.process_events.subscriber_count(),
+        ),
+        (
+            "connection_events".to_owned(),
+            state.connection_events.subscriber_count(),
+        ),
+        ("disks".to_owned(), state.disks.subscriber_count()),
+        ("diskio".to_owned(), state.diskio.subscriber_count()),
+        ("networks".to_owned(), state.networks.subscriber_count()),
+        (
+            "temperatures".to_owned(),
+            state.temperatures.subscriber_count(),
+        ),
+        ("loadavg".to_owned(), state.loadavg.subscriber_count()),
+        ("pressure".to_owned(), state.pressure.subscriber_count()),
+        ("containers".to_owned(), state.containers.subscriber_count()),
+        ("gpus".to_owned(), state.gpus.subscriber_count()),
+        ("power".to_owned(), state.power.subscriber_count()),
+    ]);
+    let telemetry = &state.telemetry;
+    let sockets = state.limiter.socket_stats();
+    Encoded(
+        encoding,
+        SelfInfo {
+            pid: pid.map_or(0, |pid| pid.as_u32()),
+            uptime_seconds: telemetry.uptime().as_secs(),
+            memory: memory_bytes.to_human(None),
+            memory_bytes,
+            cpu_usage,
+            http_connections: state
+                .servers
+                .iter()
+                .map(|server| server.connection_count())
+                .sum(),
+            realtime_connections: state.connections.len(),
+            realtime_connections_peak: sockets.peak,
+            realtime_connections_max: sockets.max,
+            rejected_realtime_connections: sockets.rejected,
+            reaped_realtime_connections: telemetry.reaped_clients(),
+            subscribers,
+            sampler: telemetry.sampler_timing(state.config.sample_interval),
+            lagged_samples: telemetry
+                .lagged_samples()
+                .into_iter()
+                .map(|(topic, skipped)| (topic.to_owned(), skipped))
+                .collect(),
+        },
+    )
+}
@@ -0,0 +1,91 @@
+//! Logged-in users, read from the utmp database.
+
+use crate::encoding::{Encoded, Encoding};
+use crate::models::Session;
+use crate::{api_error, AppError};
+use axum::http::StatusCode;
+use axum_macros::debug_handler;
+use std::io;
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/sessions",
+    tag = "system",
+    responses(
+        (status = 200, description = "Active login sessions, oldest first", body = [Session], content_type = ["application/json", "application/msgpack"]),
+        (status = 501, description = "Sessions are not available on this platform", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_sessions(encoding: Encoding) -> Result<Encoded<Vec<Session>>, AppError> {
+    let sessions = tokio::task::spawn_blocking(read_sessions)
+        .await
+        .expect("Reading sessions panicked");
+    match sessions {
+        Ok(mut sessions) => {
+            sessions.sort_by_key(|session| session.login_time);
+            Ok(Encoded(encoding, sessions))
+        }
+        Err(err) if err.kind() == io::ErrorKind::Unsupported => Err(api_error(
+            StatusCode::NOT_IMPLEMENTED,
+            "Sessions are not available on this platform",
+        )),
+        Err(err) => Err(api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to read sessions: {err}"),
+        )),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn read_sessions() -> io::Result<Vec<Session>> {
+    use std::ffi::c_char;
+    use std::sync::Mutex;
+
+    /// `getutxent` walks a process wide cursor.
+    static UTMP: Mutex<()> = Mutex::new(());
+
+    /// utmp strings are fixed size and only NUL terminated when shorter.
+    fn field(chars: &[c_char]) -> String {
+        let bytes: Vec<u8> = chars
+            .iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| c as u8)
+            .collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    let _guard = UTMP.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut sessions = Vec::new();
+    // SAFETY: the entries are only read until the next getutxent call, and
+    // the lock keeps other threads from moving the cursor meanwhile.
+    unsafe {
+        libc::setutxent();
+        loop {
+            let entry = libc::getutxent();
+            let Some(entry) = entry.as_ref() else {
+                break;
+            };
+            if entry.ut_type != libc::USER_PROCESS {
+                continue;
+            }
+            let host = field(&entry.ut_host);
+            let seconds = u64::try_from(entry.ut_tv.tv_sec).unwrap_or_default();
+            let micros = u64::try_from(entry.ut_tv.tv_usec).unwrap_or_default();
+            sessions.push(Session {
+                user: field(&entry.ut_user),
+                terminal: field(&entry.ut_line),
+                login_time: seconds * 1000 + micros / 1000,
+                host: (!host.is_empty()).then_some(host),
+                pid: u32::try_from(entry.ut_pid).unwrap_or_default(),
+            });
+        }
+        libc::endutxent();
+    }
+    Ok(sessions)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn read_sessions() -> io::Result<Vec<Session>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
@@ -0,0 +1,453 @@
+//! Point-in-time captures of the system and its processes, for before/after
+//! comparisons such as around a deployment: `POST /api/v1/snapshots` takes
+//! one from the latest sample and `GET /api/v1/snapshots/:id/diff/:other`
+//! tells which processes started or exited in between and how CPU and
+//! memory shifted. Snapshots are kept in memory, the oldest dropped beyond
+//! `MAX_SNAPSHOTS`.
+
+use crate::models::{CpuInfo, LoadAverage, Memory, ProcessInfo, SystemInfo};
+use crate::processes::SortKey;
+use crate::system::collect_system;
+use crate::{api_error, AppError, AppState};
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::Json;
+use axum_macros::debug_handler;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use utoipa::{IntoParams, ToSchema};
+
+const MAX_SNAPSHOTS: usize = 50;
+const DEFAULT_DIFF_LIMIT: usize = 20;
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SystemSnapshot {
+    pub id: u64,
+    pub label: Option<String>,
+    /// Milliseconds since the epoch of the sample it was taken from.
+    pub timestamp: u64,
+    pub system: SystemInfo,
+    pub cpus: Vec<CpuInfo>,
+    pub memory: Memory,
+    pub loadavg: LoadAverage,
+    pub processes: Vec<ProcessInfo>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SnapshotSummary {
+    pub id: u64,
+    pub label: Option<String>,
+    pub timestamp: u64,
+    pub process_count: usize,
+}
+
+impl From<&SystemSnapshot> for SnapshotSummary {
+    fn from(snapshot: &SystemSnapshot) -> Self {
+        Self {
+            id: snapshot.id,
+            label: snapshot.label.clone(),
+            timestamp: snapshot.timestamp,
+            process_count: snapshot.processes.len(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnapshotRequest {
+    /// Free text to recognize the snapshot by, e.g. `before deploy`.
+    label: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+pub struct ValueChange {
+    pub before: f64,
+    pub after: f64,
+    /// `after - before`.
+    pub delta: f64,
+}
+
+impl ValueChange {
+    fn new(before: f64, after: f64) -> Self {
+        Self {
+            before,
+            after,
+            delta: after - before,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ProcessChange {
+    pub pid: u32,
+    pub name: String,
+    pub cpu_usage: ValueChange,
+    pub memory_bytes: ValueChange,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SnapshotDiff {
+    pub from: SnapshotSummary,
+    pub to: SnapshotSummary,
+    /// Mean over all cores.
+    pub cpu_usage: ValueChange,
+    pub memory_used_bytes: ValueChange,
+    pub swap_used_bytes: ValueChange,
+    pub load_one: ValueChange,
+    /// Processes only in `to`. A pid reused under another name counts as an
+    /// exit and a start.
+    pub started: Vec<ProcessInfo>,
+    /// Processes only in `from`.
+    pub exited: Vec<ProcessInfo>,
+    /// Processes in both, the largest shifts first.
+    pub changed: Vec<ProcessChange>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DiffQuery {
+    /// Orders `changed` by the size of the CPU or memory shift, CPU by
+    /// default.
+    #[serde(default)]
+    sort: SortKey,
+    /// Number of `changed` processes to return, 20 by default.
+    limit: Option<usize>,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: u64,
+    snapshots: VecDeque<Arc<SystemSnapshot>>,
+}
+
+#[derive(Clone, Default)]
+pub struct SnapshotStore {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl SnapshotStore {
+    /// Stores a snapshot under the next id, dropping the oldest when full.
+    fn add(&self, build: impl FnOnce(u64) -> SystemSnapshot) -> Arc<SystemSnapshot> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let snapshot = Arc::new(build(inner.next_id));
+        if inner.snapshots.len() == MAX_SNAPSHOTS {
+            inner.snapshots.pop_front();
+        }
+        inner.snapshots.push_back(snapshot.clone());
+        snapshot
+    }
+
+    fn list(&self) -> Vec<SnapshotSummary> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .snapshots
+            .iter()
+            .map(|snapshot| SnapshotSummary::from(&**snapshot))
+            .collect()
+    }
+
+    fn get(&self, id: u64) -> Option<Arc<SystemSnapshot>> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .snapshots
+            .iter()
+            .find(|snapshot| snapshot.id == id)
+            .cloned()
+    }
+
+    fn remove(&self, id: u64) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let before = inner.snapshots.len();
+        inner.snapshots.retain(|snapshot| snapshot.id != id);
+        inner.snapshots.len() < before
+    }
+}
+
+fn not_found(id: u64) -> AppError {
+    api_error(StatusCode::NOT_FOUND, format!("No snapshot with id {id}"))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/snapshots",
+    tag = "processes",
+    request_body(content = Option<SnapshotRequest>, description = "An optional label"),
+    responses(
+        (status = 201, description = "The snapshot was taken", body = SnapshotSummary),
+        (status = 403, description = "Requires the operator role, or the server is read-only", body = ErrorResponse),
+        (status = 503, description = "Nothing has been sampled yet", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn create_snapshot(
+    State(state): State<AppState>,
+    request: Option<Json<SnapshotRequest>>,
+) -> Result<(StatusCode, Json<SnapshotSummary>), AppError> {
+    let processes = state.processes.latest();
+    if processes.seq == 0 {
+        return Err(api_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Nothing has been sampled yet",
+        ));
+    }
+    let system = collect_system(&*state.system.lock().await, &state.config.labels);
+    let label = request.and_then(|Json(request)| request.label);
+    let snapshot = state.snapshots.add(|id| SystemSnapshot {
+        id,
+        label,
+        timestamp: processes.timestamp,
+        system,
+        cpus: state.cpus.latest().value().clone(),
+        memory: state.memory.latest().value().clone(),
+        loadavg: state.loadavg.latest().value().clone(),
+        processes: processes.value().clone(),
+    });
+    Ok((StatusCode::CREATED, Json(SnapshotSummary::from(&*snapshot))))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/snapshots",
+    tag = "processes",
+    responses((status = 200, description = "Kept snapshots, oldest first", body = [SnapshotSummary]))
+)]
+#[debug_handler]
+pub async fn list_snapshots(State(state): State<AppState>) -> Json<Vec<SnapshotSummary>> {
+    Json(state.snapshots.list())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/snapshots/{id}",
+    tag = "processes",
+    params(("id" = u64, Path, description = "Snapshot id")),
+    responses(
+        (status = 200, description = "The full snapshot", body = SystemSnapshot),
+        (status = 404, description = "No snapshot with this id", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_snapshot(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<Json<SystemSnapshot>, AppError> {
+    state
+        .snapshots
+        .get(id)
+        .map(|snapshot| Json((*snapshot).clone()))
+        .ok_or_else(|| not_found(id))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/snapshots/{id}",
+    tag = "processes",
+    params(("id" = u64, Path, description = "Snapshot id")),
+    responses(
+        (status = 204, description = "The snapshot was deleted"),
+        (status = 403, description = "Requires the operator role, or the server is read-only", body = ErrorResponse),
+        (status = 404, description = "No snapshot with this id", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn delete_snapshot(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<StatusCode, AppError> {
+    if state.snapshots.remove(id) {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(not_found(id))
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/snapshots/{id}/diff/{other}",
+    tag = "processes",
+    params(
+        ("id" = u64, Path, description = "The earlier snapshot"),
+        ("other" = u64, Path, description = "The later snapshot"),
+        DiffQuery,
+    ),
+    responses(
+        (status = 200, description = "What changed from `id` to `other`", body = SnapshotDiff),
+        (status = 404, description = "No snapshot with one of the ids", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn diff_snapshots(
+    State(state): State<AppState>,
+    Path((a, b)): Path<(u64, u64)>,
+    Query(query): Query<DiffQuery>,
+) -> Result<Json<SnapshotDiff>, AppError> {
+    let from = state.snapshots.get(a).ok_or_else(|| not_found(a))?;
+    let to = state.snapshots.get(b).ok_or_else(|| not_found(b))?;
+    Ok(Json(diff(
+        &from,
+        &to,
+        query.sort,
+        query.limit.unwrap_or(DEFAULT_DIFF_LIMIT),
+    )))
+}
+
+fn diff(from: &SystemSnapshot, to: &SystemSnapshot, sort: SortKey, limit: usize) -> SnapshotDiff {
+    let key = |process: &ProcessInfo| (process.pid, process.name.clone());
+    let before: HashMap<_, &ProcessInfo> = from.processes.iter().map(|p| (key(p), p)).collect();
+    let after: HashMap<_, &ProcessInfo> = to.processes.iter().map(|p| (key(p), p)).collect();
+    let started = to
+        .processes
+        .iter()
+        .filter(|process| !before.contains_key(&key(process)))
+        .cloned()
+        .collect();
+    let exited = from
+        .processes
+        .iter()
+        .filter(|process| !after.contains_key(&key(process)))
+        .cloned()
+        .collect();
+    let mut changed: Vec<ProcessChange> = to
+        .processes
+        .iter()
+        .filter_map(|process| {
+            let previous = before.get(&key(process))?;
+            Some(ProcessChange {
+                pid: process.pid,
+                name: process.name.clone(),
+                cpu_usage: ValueChange::new(
+                    f64::from(previous.cpu_usage),
+                    f64::from(process.cpu_usage),
+                ),
+                memory_bytes: ValueChange::new(
+                    previous.memory_bytes as f64,
+                    process.memory_bytes as f64,
+                ),
+            })
+        })
+        .collect();
+    let shift = |change: &ProcessChange| match sort {
+        SortKey::Cpu => change.cpu_usage.delta.abs(),
+        SortKey::Memory => change.memory_bytes.delta.abs(),
+    };
+    changed.sort_by(|a, b| shift(b).total_cmp(&shift(a)));
+    changed.truncate(limit);
+    SnapshotDiff {
+        from: SnapshotSummary::from(from),
+        to: SnapshotSummary::from(to),
+        cpu_usage: ValueChange::new(mean_usage(&from.cpus), mean_usage(&to.cpus)),
+        memory_used_bytes: ValueChange::new(
+            from.memory.used_memory_bytes as f64,
+            to.memory.used_memory_bytes as f64,
+        ),
+        swap_used_bytes: ValueChange::new(
+            from.memory.used_swap_bytes as f64,
+            to.memory.used_swap_bytes as f64,
+        ),
+        load_one: ValueChange::new(from.loadavg.one, to.loadavg.one),
+        started,
+        exited,
+        changed,
+    }
+}
+
+fn mean_usage(cpus: &[CpuInfo]) -> f64 {
+    if cpus.is_empty() {
+        return 0f64;
+    }
+    cpus.iter().map(|cpu| f64::from(cpu.cpu_usage)).sum::<f64>() / cpus.len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(pid: u32, name: &str, cpu_usage: f32, memory_bytes: u64) -> ProcessInfo {
+        ProcessInfo {
+            pid,
+            name: name.to_owned(),
+            cpu_usage,
+            memory_bytes,
+            ..Default::default()
+        }
+    }
+
+    fn snapshot(id: u64, processes: Vec<ProcessInfo>) -> SystemSnapshot {
+        SystemSnapshot {
+            id,
+            label: None,
+            timestamp: id * 1000,
+            system: SystemInfo::default(),
+            cpus: vec![],
+            memory: Memory::default(),
+            loadavg: LoadAverage::default(),
+            processes,
+        }
+    }
+
+    fn pids(processes: &[ProcessInfo]) -> Vec<u32> {
+        processes.iter().map(|process| process.pid).collect()
+    }
+
+    #[test]
+    fn keys_processes_by_pid_and_name() {
+        let from = snapshot(
+            1,
+            vec![process(1, "init", 0.0, 0), process(2, "old", 0.0, 0)],
+        );
+        // Pid 2 was reused by another program.
+        let to = snapshot(
+            2,
+            vec![
+                process(1, "init", 0.0, 0),
+                process(2, "new", 0.0, 0),
+                process(3, "web", 0.0, 0),
+            ],
+        );
+        let diff = diff(&from, &to, SortKey::Cpu, 10);
+        assert_eq!(pids(&diff.started), [2, 3]);
+        assert_eq!(diff.started[0].name, "new");
+        assert_eq!(pids(&diff.exited), [2]);
+        assert_eq!(diff.exited[0].name, "old");
+        let changed: Vec<_> = diff.changed.iter().map(|change| change.pid).collect();
+        assert_eq!(changed, [1]);
+        assert_eq!((diff.from.id, diff.to.id), (1, 2));
+    }
+
+    #[test]
+    fn sorts_and_truncates_by_the_largest_shift() {
+        let from = snapshot(
+            1,
+            vec![
+                process(1, "a", 10.0, 1000),
+                process(2, "b", 50.0, 1000),
+                process(3, "c", 20.0, 9000),
+            ],
+        );
+        let to = snapshot(
+            2,
+            vec![
+                process(1, "a", 15.0, 5000),
+                process(2, "b", 20.0, 2000),
+                process(3, "c", 20.0, 1000),
+            ],
+        );
+        let order = |sort, limit| -> Vec<u32> {
+            diff(&from, &to, sort, limit)
+                .changed
+                .iter()
+                .map(|change| change.pid)
+                .collect()
+        };
+        // Drops count as much as rises.
+        assert_eq!(order(SortKey::Cpu, 10), [2, 1, 3]);
+        assert_eq!(order(SortKey::Memory, 10), [3, 1, 2]);
+        assert_eq!(order(SortKey::Memory, 2), [3, 1]);
+        assert!(order(SortKey::Cpu, 0).is_empty());
+        let change = &diff(&from, &to, SortKey::Cpu, 1).changed[0];
+        assert_eq!(change.cpu_usage.before, 50.0);
+        assert_eq!(change.cpu_usage.delta, -30.0);
+    }
+}
@@ -0,0 +1,50 @@
+//! Server-Sent Events mirror of the realtime WebSocket topics, for proxies
+//! and clients that handle `text/event-stream` better than WebSockets.
+
+use crate::encoding::Encoding;
+use crate::realtime::{topic_enabled, topic_name, topic_stream, Update};
+use crate::sampler::Payload;
+use crate::{api_error, AppError, AppState};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum_macros::debug_handler;
+use futures_util::stream::{BoxStream, StreamExt};
+use std::convert::Infallible;
+
+type EventStream = BoxStream<'static, Result<Event, Infallible>>;
+
+#[debug_handler]
+pub async fn sse_get(
+    State(state): State<AppState>,
+    Path(topic): Path<String>,
+) -> Result<Sse<EventStream>, AppError> {
+    let name = topic_name(&state, &topic)
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, format!("Unknown topic {topic}")))?;
+    if !topic_enabled(&state, name) {
+        return Err(api_error(
+            StatusCode::NOT_FOUND,
+            format!("The collector of the {name} topic is disabled"),
+        ));
+    }
+    let stream = topic_stream(&state, name, Encoding::Json)
+        .unwrap()
+        .map(move |update| {
+            Ok(match update {
+                Update::Sample {
+                    seq,
+                    payload: Payload::Json(payload),
+                    ..
+                } => Event::default()
+                    .event(name)
+                    .id(seq.to_string())
+                    .data(&*payload),
+                Update::Sample { .. } => unreachable!("SSE streams are JSON"),
+                // Comments are ignored by `EventSource` but visible to raw readers.
+                Update::Lagged(skipped) => Event::default().comment(format!("lagged {skipped}")),
+            })
+        })
+        .take_until(state.shutdown.clone().cancelled_owned())
+        .boxed();
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
@@ -0,0 +1,174 @@
+//! `/api/v1/stats`, aggregates of a history metric per time bucket so long
+//! ranges can be charted without fetching every sample:
+//! `?metric=cpu&window=1h&step=1m` answers the minimum, maximum, mean and
+//! percentiles of every minute of the last hour. Served from the same
+//! history as `/grafana/query`.
+
+use crate::encoding::{Encoded, Encoding};
+use crate::grafana::{self, Metric};
+use crate::history::{self, now_millis};
+use crate::{api_error, AppError, AppState};
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum_macros::debug_handler;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use utoipa::{IntoParams, ToSchema};
+
+const DEFAULT_WINDOW: Duration = Duration::from_secs(60 * 60);
+/// Keeps a small step over a long window from producing a huge response.
+const MAX_BUCKETS: u64 = 10_000;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct StatsQuery {
+    /// A metric name as listed by `/grafana/search`, or `cpu` and `memory`
+    /// for `cpu.usage` and `memory.used_percent`.
+    metric: String,
+    /// Trailing window such as `90s`, `30m`, `1h` or `7d`, 1h by default.
+    window: Option<String>,
+    /// Width of each bucket in the same form, the whole window by default.
+    step: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Stats {
+    metric: String,
+    /// Milliseconds since the epoch.
+    from: u64,
+    to: u64,
+    step_ms: u64,
+    /// Oldest first; buckets without samples are left out.
+    buckets: Vec<StatsBucket>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct StatsBucket {
+    /// Start of the bucket in milliseconds since the epoch, a multiple of the
+    /// step so repeated queries line up.
+    timestamp: u64,
+    samples: usize,
+    min: f64,
+    max: f64,
+    avg: f64,
+    p50: f64,
+    p90: f64,
+    p95: f64,
+    p99: f64,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/stats",
+    tag = "system",
+    params(StatsQuery),
+    responses(
+        (status = 200, description = "Aggregates per bucket", body = Stats, content_type = ["application/json", "application/msgpack"]),
+        (status = 400, description = "Unknown metric, or invalid window or step", body = ErrorResponse),
+        (status = 500, description = "The history database query failed", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_stats(
+    State(state): State<AppState>,
+    encoding: Encoding,
+    Query(query): Query<StatsQuery>,
+) -> Result<Encoded<Stats>, AppError> {
+    let name = match query.metric.as_str() {
+        "cpu" => "cpu.usage",
+        "memory" => "memory.used_percent",
+        name => name,
+    };
+    let metric = Metric::parse(name).ok_or_else(|| {
+        api_error(
+            StatusCode::BAD_REQUEST,
+            format!("Unknown metric {:?}", query.metric),
+        )
+    })?;
+    let duration = |value: Option<&str>, default: Duration| match value {
+        Some(value) => parse_duration(value)
+            .filter(|duration| !duration.is_zero())
+            .ok_or_else(|| {
+                api_error(
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid duration {value:?}, expected e.g. 90s, 30m, 1h or 7d"),
+                )
+            }),
+        None => Ok(default),
+    };
+    let window = duration(query.window.as_deref(), DEFAULT_WINDOW)?;
+    let step = duration(query.step.as_deref(), window)?;
+    let window_ms = window.as_millis() as u64;
+    let step_ms = (step.as_millis() as u64).min(window_ms);
+    if window_ms / step_ms > MAX_BUCKETS {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("The window holds more than {MAX_BUCKETS} steps"),
+        ));
+    }
+    let to = now_millis();
+    let from = to.saturating_sub(window_ms);
+    let points = if metric.is_memory() {
+        let entries = history::range(&state, state.memory.history(), "memory", from, to).await?;
+        grafana::points(&entries, |data| metric.memory_value(data))
+    } else {
+        let entries = history::range(&state, state.cpus.history(), "cpus", from, to).await?;
+        grafana::points(&entries, |data| metric.cpu_value(data))
+    };
+    let mut buckets: BTreeMap<u64, Vec<f64>> = BTreeMap::new();
+    for (value, timestamp) in points {
+        let start = timestamp - timestamp % step_ms;
+        buckets.entry(start).or_default().push(value);
+    }
+    let buckets = buckets
+        .into_iter()
+        .map(|(timestamp, values)| aggregate(timestamp, values))
+        .collect();
+    Ok(Encoded(
+        encoding,
+        Stats {
+            metric: name.to_owned(),
+            from,
+            to,
+            step_ms,
+            buckets,
+        },
+    ))
+}
+
+fn aggregate(timestamp: u64, mut values: Vec<f64>) -> StatsBucket {
+    values.sort_by(f64::total_cmp);
+    // Nearest rank, so every percentile is an actual sample.
+    let percentile = |p: f64| {
+        let rank = (p / 100f64 * values.len() as f64).ceil() as usize;
+        values[rank.clamp(1, values.len()) - 1]
+    };
+    StatsBucket {
+        timestamp,
+        samples: values.len(),
+        min: values[0],
+        max: values[values.len() - 1],
+        avg: values.iter().sum::<f64>() / values.len() as f64,
+        p50: percentile(50f64),
+        p90: percentile(90f64),
+        p95: percentile(95f64),
+        p99: percentile(99f64),
+    }
+}
+
+/// `<number><unit>` with a unit of `s`, `m`, `h` or `d`.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let split = value.find(|c: char| !c.is_ascii_digit())?;
+    let (number, unit) = value.split_at(split);
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(
+        number.parse::<u64>().ok()?.checked_mul(seconds)?,
+    ))
+}
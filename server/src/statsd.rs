@@ -0,0 +1,195 @@
+//! Optional exporter that pushes gauges over UDP in StatsD or Graphite
+//! plaintext format, enabled with `--statsd-address`. The host's `--label`s
+//! are sent as DogStatsD tags, or Graphite 1.1 tags.
+
+use crate::config::StatsdConfig;
+use crate::history::now_millis;
+use crate::models::{CpuInfo, LoadAverage, Memory};
+use crate::AppState;
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use sysinfo::SystemExt;
+use tokio::net::UdpSocket;
+use tokio::time::MissedTickBehavior;
+
+/// Stays under the common 1500 byte MTU with IP and UDP headers to spare, so
+/// datagrams aren't fragmented.
+const MAX_DATAGRAM: usize = 1432;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsdFormat {
+    /// `name:value|g`
+    #[default]
+    Statsd,
+    /// `name value timestamp`
+    Graphite,
+}
+
+pub fn spawn(config: StatsdConfig, app_state: AppState) {
+    tokio::spawn(run(config, app_state));
+}
+
+async fn run(config: StatsdConfig, app_state: AppState) {
+    let prefix = match config.prefix.clone() {
+        Some(prefix) => prefix,
+        None => {
+            let host = app_state
+                .system
+                .lock()
+                .await
+                .host_name()
+                .unwrap_or_else(|| "unknown".to_owned());
+            // Dots separate path segments, so they can't be part of a name.
+            format!("process_viewer.{}", host.replace('.', "_"))
+        }
+    };
+    let tags = tags(&app_state.config.labels, config.format);
+    let mut ticker = tokio::time::interval(config.interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut reachable = true;
+    // Resolved again after a failed push, in case the address moved.
+    let mut socket = None;
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = app_state.shutdown.cancelled() => break,
+        }
+        // Nothing has been sampled yet.
+        if app_state.cpus.latest().seq == 0 {
+            continue;
+        }
+        let gauges = gauges(
+            app_state.cpus.latest().value(),
+            app_state.memory.latest().value(),
+            app_state.loadavg.latest().value(),
+        );
+        let timestamp = now_millis() / 1000;
+        let result = async {
+            let (bound, target) = match socket.take() {
+                Some(socket) => socket,
+                None => bind(&config.address).await?,
+            };
+            for datagram in datagrams(&prefix, &gauges, &tags, config.format, timestamp) {
+                bound.send_to(datagram.as_bytes(), target).await?;
+            }
+            socket = Some((bound, target));
+            io::Result::Ok(())
+        }
+        .await;
+        match result {
+            Ok(()) if !reachable => {
+                tracing::info!(address = %config.address, "StatsD is reachable again");
+                reachable = true;
+            }
+            Ok(()) => {}
+            // Logged once per outage rather than every push.
+            Err(err) if reachable => {
+                tracing::warn!(address = %config.address, %err, "Failed to push to StatsD");
+                reachable = false;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// A socket of the same address family as `address`, bound to any port,
+/// and where to send to. It isn't connected, as that would report ICMP
+/// errors from a collector that's down on every other push.
+async fn bind(address: &str) -> io::Result<(UdpSocket, SocketAddr)> {
+    let target = tokio::net::lookup_host(address)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "The address didn't resolve"))?;
+    let local: SocketAddr = match target {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    Ok((UdpSocket::bind(local).await?, target))
+}
+
+/// Names relative to the prefix with their values.
+fn gauges(cpus: &[CpuInfo], memory: &Memory, loadavg: &LoadAverage) -> Vec<(String, f64)> {
+    let mut gauges = vec![];
+    if !cpus.is_empty() {
+        let usage =
+            cpus.iter().map(|cpu| f64::from(cpu.cpu_usage)).sum::<f64>() / cpus.len() as f64;
+        gauges.push(("cpu.usage".to_owned(), usage));
+    }
+    for (core, cpu) in cpus.iter().enumerate() {
+        gauges.push((format!("cpu.{core}.usage"), f64::from(cpu.cpu_usage)));
+    }
+    gauges.extend([
+        (
+            "memory.used_bytes".to_owned(),
+            memory.used_memory_bytes as f64,
+        ),
+        (
+            "memory.total_bytes".to_owned(),
+            memory.total_memory_bytes as f64,
+        ),
+        ("swap.used_bytes".to_owned(), memory.used_swap_bytes as f64),
+        (
+            "swap.total_bytes".to_owned(),
+            memory.total_swap_bytes as f64,
+        ),
+        ("load.1".to_owned(), loadavg.one),
+        ("load.5".to_owned(), loadavg.five),
+        ("load.15".to_owned(), loadavg.fifteen),
+    ]);
+    if memory.total_memory_bytes > 0 {
+        gauges.push((
+            "memory.used_percent".to_owned(),
+            memory.used_memory_bytes as f64 / memory.total_memory_bytes as f64 * 100f64,
+        ));
+    }
+    gauges
+}
+
+/// The suffix `labels` add to every metric: `|#key:value,..` for StatsD,
+/// `;key=value..` for Graphite. Characters that would end a tag become `_`.
+fn tags(labels: &BTreeMap<String, String>, format: StatsdFormat) -> String {
+    let clean = |value: &str| value.replace([',', '|', ';', ' ', '~'], "_");
+    let tags = labels.iter().map(|(key, value)| match format {
+        StatsdFormat::Statsd => format!("{key}:{}", clean(value)),
+        StatsdFormat::Graphite => format!(";{key}={}", clean(value)),
+    });
+    match format {
+        _ if labels.is_empty() => String::new(),
+        StatsdFormat::Statsd => format!("|#{}", tags.collect::<Vec<_>>().join(",")),
+        StatsdFormat::Graphite => tags.collect(),
+    }
+}
+
+/// One line per gauge, packed into as few datagrams as fit.
+fn datagrams(
+    prefix: &str,
+    gauges: &[(String, f64)],
+    tags: &str,
+    format: StatsdFormat,
+    timestamp: u64,
+) -> Vec<String> {
+    let mut datagrams = vec![];
+    let mut current = String::new();
+    for (name, value) in gauges {
+        let mut line = String::new();
+        let _ = match format {
+            StatsdFormat::Statsd => writeln!(line, "{prefix}.{name}:{value}|g{tags}"),
+            StatsdFormat::Graphite => {
+                writeln!(line, "{prefix}.{name}{tags} {value} {timestamp}")
+            }
+        };
+        if !current.is_empty() && current.len() + line.len() > MAX_DATAGRAM {
+            datagrams.push(std::mem::take(&mut current));
+        }
+        current.push_str(&line);
+    }
+    if !current.is_empty() {
+        datagrams.push(current);
+    }
+    datagrams
+}
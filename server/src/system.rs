@@ -0,0 +1,37 @@
+//! Static host information for dashboard headers.
+
+use crate::encoding::{Encoded, Encoding};
+use crate::models::SystemInfo;
+use crate::AppState;
+use axum::extract::State;
+use axum::response::IntoResponse;
+use axum_macros::debug_handler;
+use std::collections::BTreeMap;
+use sysinfo::{System, SystemExt};
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/system",
+    tag = "system",
+    responses((status = 200, description = "Host information", body = SystemInfo, content_type = ["application/json", "application/msgpack"]))
+)]
+#[debug_handler]
+pub async fn get_system(State(state): State<AppState>, encoding: Encoding) -> impl IntoResponse {
+    let sys = state.system.lock().await;
+    Encoded(encoding, collect_system(&sys, &state.config.labels))
+}
+
+pub fn collect_system(sys: &System, labels: &BTreeMap<String, String>) -> SystemInfo {
+    SystemInfo {
+        hostname: sys.host_name(),
+        os_name: sys.name(),
+        os_version: sys.os_version(),
+        kernel_version: sys.kernel_version(),
+        architecture: std::env::consts::ARCH.to_owned(),
+        boot_time: sys.boot_time() * 1000,
+        uptime_seconds: sys.uptime(),
+        physical_cores: sys.physical_core_count(),
+        logical_cores: sys.cpus().len(),
+        labels: labels.clone(),
+    }
+}
@@ -0,0 +1,92 @@
+//! systemd integration. When started from a `.socket` unit the listening
+//! sockets are inherited through `LISTEN_FDS` instead of bound from `--bind`,
+//! so the server can be launched on demand. With `Type=notify` the service
+//! manager is told once every listener is up and again when stopping.
+//!
+//! Both are no-ops when the variables are not set, and always off outside
+//! of Unix.
+
+use std::env;
+use std::net::TcpListener;
+
+/// Descriptor of the first inherited socket, `SD_LISTEN_FDS_START`.
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Takes over the sockets passed by systemd, `None` when not socket
+/// activated. The variables are cleared so they don't leak to children.
+#[cfg(unix)]
+pub fn listeners() -> Result<Option<Vec<TcpListener>>, String> {
+    use std::os::fd::FromRawFd;
+
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(None);
+    };
+    // The variables are meant for another process, e.g. a shell script that
+    // exec'd into us after forking.
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok(None);
+    }
+    let count: i32 = fds
+        .parse()
+        .map_err(|_| format!("Invalid LISTEN_FDS {fds}"))?;
+    if count <= 0 {
+        return Ok(None);
+    }
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // SAFETY: systemd passes these descriptors for this process to own,
+            // nothing else in the process refers to them.
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            // Fails for anything but an inet socket, such as `ListenDatagram`
+            // or a Unix socket.
+            listener
+                .local_addr()
+                .map(|_| listener)
+                .map_err(|err| format!("Inherited socket {fd} is not a TCP listener: {err}"))
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+#[cfg(not(unix))]
+pub fn listeners() -> Result<Option<Vec<TcpListener>>, String> {
+    Ok(None)
+}
+
+/// Sends a state such as `READY=1` to the service manager, if it is waiting
+/// for one on `NOTIFY_SOCKET`.
+pub fn notify(state: &str) {
+    #[cfg(unix)]
+    if let Some(path) = env::var_os("NOTIFY_SOCKET") {
+        if let Err(err) = send(&path, state) {
+            tracing::warn!(%err, state, "Failed to notify systemd");
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = state;
+}
+
+#[cfg(unix)]
+fn send(path: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        // A leading `@` names a socket in the abstract namespace.
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?
+        }
+        _ => socket.send_to(state.as_bytes(), path)?,
+    };
+    Ok(())
+}
@@ -0,0 +1,231 @@
+//! Optional OpenTelemetry export, enabled with `--otlp-endpoint`. The request
+//! spans from the `tracing` instrumentation and a handful of server metrics
+//! are pushed to the collector over OTLP/HTTP. Without an endpoint the
+//! metric instruments are no-ops.
+//!
+//! The same measurements are also kept in process for `/api/v1/self`.
+
+use crate::models::SamplerTiming;
+use crate::AppState;
+use axum::extract::{MatchedPath, State};
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use opentelemetry::metrics::{Counter, Histogram, UpDownCounter};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing_subscriber::{Layer, Registry};
+
+const SERVICE_NAME: &str = "process-viewer";
+const METER_NAME: &str = env!("CARGO_CRATE_NAME");
+
+/// The running OTLP pipelines; `shutdown` flushes whatever is still buffered.
+pub struct Exporters {
+    tracer: SdkTracerProvider,
+    meter: SdkMeterProvider,
+}
+
+impl Exporters {
+    /// `endpoint` is the collector's base URL, e.g. `http://localhost:4318`.
+    /// `labels` become resource attributes of everything exported.
+    pub fn start(endpoint: &str, labels: &BTreeMap<String, String>) -> Result<Self, String> {
+        let endpoint = endpoint.trim_end_matches('/');
+        let resource = Resource::builder()
+            .with_service_name(SERVICE_NAME)
+            .with_attributes(
+                labels
+                    .iter()
+                    .map(|(key, value)| KeyValue::new(key.clone(), value.clone())),
+            )
+            .build();
+        let spans = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{endpoint}/v1/traces"))
+            .build()
+            .map_err(|err| format!("Failed to create the OTLP span exporter: {err}"))?;
+        let metrics = MetricExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{endpoint}/v1/metrics"))
+            .build()
+            .map_err(|err| format!("Failed to create the OTLP metric exporter: {err}"))?;
+        let tracer = SdkTracerProvider::builder()
+            .with_resource(resource.clone())
+            .with_batch_exporter(spans)
+            .build();
+        let meter = SdkMeterProvider::builder()
+            .with_resource(resource)
+            .with_periodic_exporter(metrics)
+            .build();
+        global::set_meter_provider(meter.clone());
+        Ok(Self { tracer, meter })
+    }
+
+    /// Forwards `tracing` spans to the span exporter.
+    pub fn tracing_layer(&self) -> Box<dyn Layer<Registry> + Send + Sync> {
+        tracing_opentelemetry::layer()
+            .with_tracer(self.tracer.tracer(METER_NAME))
+            .boxed()
+    }
+
+    pub fn shutdown(self) {
+        if let Err(err) = self.tracer.shutdown() {
+            tracing::warn!(%err, "Failed to flush OTLP spans");
+        }
+        if let Err(err) = self.meter.shutdown() {
+            tracing::warn!(%err, "Failed to flush OTLP metrics");
+        }
+    }
+}
+
+/// Metric instruments, bound to the global meter provider. Must be created
+/// after `Exporters::start`, instruments created before stay no-ops.
+#[derive(Clone)]
+pub struct Telemetry {
+    request_duration: Histogram<f64>,
+    realtime_clients: UpDownCounter<i64>,
+    sample_duration: Histogram<f64>,
+    lagged_samples: Counter<u64>,
+    reaped_clients: Counter<u64>,
+    started: Instant,
+    counters: Arc<Counters>,
+}
+
+#[derive(Default)]
+struct Counters {
+    sampler: Mutex<SampleDurations>,
+    lagged: Mutex<BTreeMap<&'static str, u64>>,
+    reaped: AtomicU64,
+}
+
+#[derive(Default)]
+struct SampleDurations {
+    count: u64,
+    total: Duration,
+    last: Duration,
+    max: Duration,
+}
+
+impl Telemetry {
+    pub fn new() -> Self {
+        let meter = global::meter(METER_NAME);
+        Self {
+            request_duration: meter
+                .f64_histogram("http.server.request.duration")
+                .with_unit("s")
+                .with_description("Duration of HTTP requests")
+                .build(),
+            realtime_clients: meter
+                .i64_up_down_counter("realtime.clients")
+                .with_description("Connected WebSocket clients")
+                .build(),
+            sample_duration: meter
+                .f64_histogram("sampler.duration")
+                .with_unit("s")
+                .with_description("Time taken to collect one sample")
+                .build(),
+            lagged_samples: meter
+                .u64_counter("realtime.lagged_samples")
+                .with_description("Samples skipped by realtime clients that fell behind")
+                .build(),
+            reaped_clients: meter
+                .u64_counter("realtime.reaped_clients")
+                .with_description("Realtime clients dropped for not answering pings")
+                .build(),
+            started: Instant::now(),
+            counters: Arc::default(),
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn record_sample(&self, elapsed: Duration) {
+        self.sample_duration.record(elapsed.as_secs_f64(), &[]);
+        let mut durations = self.counters.sampler.lock().unwrap();
+        durations.count += 1;
+        durations.total += elapsed;
+        durations.last = elapsed;
+        durations.max = durations.max.max(elapsed);
+    }
+
+    pub fn sampler_timing(&self, interval: Duration) -> SamplerTiming {
+        let durations = self.counters.sampler.lock().unwrap();
+        let millis = |duration: Duration| duration.as_secs_f64() * 1000f64;
+        SamplerTiming {
+            samples: durations.count,
+            interval_ms: interval.as_millis() as u64,
+            last_duration_ms: millis(durations.last),
+            mean_duration_ms: millis(durations.total) / durations.count.max(1) as f64,
+            max_duration_ms: millis(durations.max),
+        }
+    }
+
+    pub fn client_connected(&self) {
+        self.realtime_clients.add(1, &[]);
+    }
+
+    pub fn client_disconnected(&self) {
+        self.realtime_clients.add(-1, &[]);
+    }
+
+    pub fn record_lag(&self, topic: &'static str, skipped: u64) {
+        self.lagged_samples
+            .add(skipped, &[KeyValue::new("topic", topic)]);
+        *self
+            .counters
+            .lagged
+            .lock()
+            .unwrap()
+            .entry(topic)
+            .or_default() += skipped;
+    }
+
+    pub fn lagged_samples(&self) -> BTreeMap<&'static str, u64> {
+        self.counters.lagged.lock().unwrap().clone()
+    }
+
+    pub fn record_reaped(&self) {
+        self.reaped_clients.add(1, &[]);
+        self.counters.reaped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reaped_clients(&self) -> u64 {
+        self.counters.reaped.load(Ordering::Relaxed)
+    }
+}
+
+/// Records the request duration by method, matched route and status.
+pub async fn record_request<B>(
+    State(state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_owned(), |path| path.as_str().to_owned());
+    let started = Instant::now();
+    let response = next.run(request).await;
+    state.telemetry.request_duration.record(
+        started.elapsed().as_secs_f64(),
+        &[
+            KeyValue::new("http.request.method", method),
+            KeyValue::new("http.route", route),
+            KeyValue::new(
+                "http.response.status_code",
+                i64::from(response.status().as_u16()),
+            ),
+        ],
+    );
+    response
+}
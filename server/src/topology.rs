@@ -0,0 +1,149 @@
+//! How logical CPUs map onto sockets, cores and caches, read from sysfs on
+//! request. Linux only.
+
+use crate::encoding::{Encoded, Encoding};
+use crate::models::CpuTopology;
+use crate::{api_error, AppError};
+use axum::http::StatusCode;
+use axum_macros::debug_handler;
+use std::io;
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/cpus/topology",
+    tag = "cpus",
+    responses(
+        (status = 200, description = "Sockets, cores, SMT siblings and caches of the online CPUs", body = CpuTopology, content_type = ["application/json", "application/msgpack"]),
+        (status = 501, description = "Not available on this platform", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_topology(encoding: Encoding) -> Result<Encoded<CpuTopology>, AppError> {
+    let topology = tokio::task::spawn_blocking(read_topology)
+        .await
+        .expect("Reading the CPU topology panicked");
+    match topology {
+        Ok(topology) => Ok(Encoded(encoding, topology)),
+        Err(err) if err.kind() == io::ErrorKind::Unsupported => Err(api_error(
+            StatusCode::NOT_IMPLEMENTED,
+            "The CPU topology is not available on this platform",
+        )),
+        Err(err) => Err(api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to read the CPU topology: {err}"),
+        )),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn read_topology() -> io::Result<CpuTopology> {
+    use crate::models::{CpuCache, HumanReadable, LogicalCpu};
+    use std::collections::BTreeSet;
+    use std::fs;
+    use std::path::Path;
+
+    const CPUS: &str = "/sys/devices/system/cpu";
+
+    let read = |path: &Path| fs::read_to_string(path).map(|value| value.trim().to_owned());
+    let number = |path: &Path| read(path).ok()?.parse::<u32>().ok();
+    // Hybrid Intel CPUs list their P- and E-cores under separate PMUs.
+    let performance = read(Path::new("/sys/devices/cpu_core/cpus")).map(|list| parse_list(&list));
+    let efficiency = read(Path::new("/sys/devices/cpu_atom/cpus")).map(|list| parse_list(&list));
+
+    let mut cpus = vec![];
+    let mut caches = vec![];
+    for entry in fs::read_dir(CPUS)?.filter_map(Result::ok) {
+        let Some(cpu) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix("cpu"))
+            .and_then(|index| index.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        let dir = entry.path();
+        // Offline CPUs have no topology.
+        let topology = dir.join("topology");
+        let (Some(socket), Some(core)) = (
+            number(&topology.join("physical_package_id")),
+            number(&topology.join("core_id")),
+        ) else {
+            continue;
+        };
+        let core_type = if performance.as_ref().is_ok_and(|cpus| cpus.contains(&cpu)) {
+            Some("performance".to_owned())
+        } else if efficiency.as_ref().is_ok_and(|cpus| cpus.contains(&cpu)) {
+            Some("efficiency".to_owned())
+        } else {
+            None
+        };
+        cpus.push(LogicalCpu {
+            cpu,
+            socket,
+            core,
+            siblings: read(&topology.join("thread_siblings_list"))
+                .map(|list| parse_list(&list))
+                .unwrap_or_else(|_| vec![cpu]),
+            core_type,
+        });
+        let Ok(indexes) = fs::read_dir(dir.join("cache")) else {
+            continue;
+        };
+        for index in indexes.filter_map(Result::ok) {
+            let index = index.path();
+            let (Ok(level), Ok(kind), Ok(size), Ok(shared)) = (
+                read(&index.join("level")),
+                read(&index.join("type")),
+                read(&index.join("size")),
+                read(&index.join("shared_cpu_list")),
+            ) else {
+                continue;
+            };
+            // Sizes are given as e.g. `48K`.
+            let size_bytes = match size.strip_suffix('K') {
+                Some(kib) => kib.parse::<u64>().unwrap_or_default() * 1024,
+                None => size.parse().unwrap_or_default(),
+            };
+            caches.push(CpuCache {
+                level: level.parse().unwrap_or_default(),
+                kind: kind.to_lowercase(),
+                size: size_bytes.to_human(None),
+                size_bytes,
+                shared_cpus: parse_list(&shared),
+            });
+        }
+    }
+    cpus.sort_by_key(|cpu| cpu.cpu);
+    // Every CPU sharing a cache lists it; keep one entry per cache.
+    caches.sort_by(|a, b| {
+        (a.level, &a.kind, &a.shared_cpus).cmp(&(b.level, &b.kind, &b.shared_cpus))
+    });
+    caches
+        .dedup_by(|a, b| a.level == b.level && a.kind == b.kind && a.shared_cpus == b.shared_cpus);
+    let sockets: BTreeSet<u32> = cpus.iter().map(|cpu| cpu.socket).collect();
+    let cores: BTreeSet<(u32, u32)> = cpus.iter().map(|cpu| (cpu.socket, cpu.core)).collect();
+    Ok(CpuTopology {
+        sockets: sockets.len(),
+        physical_cores: cores.len(),
+        logical_cpus: cpus.len(),
+        cpus,
+        caches,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_topology() -> io::Result<CpuTopology> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// A sysfs CPU list such as `0-3,8,10-11`.
+#[cfg(target_os = "linux")]
+fn parse_list(list: &str) -> Vec<u32> {
+    list.split(',')
+        .filter_map(|range| match range.split_once('-') {
+            Some((start, end)) => Some((start.parse().ok()?..=end.parse().ok()?).collect()),
+            None => Some(vec![range.parse().ok()?]),
+        })
+        .flatten()
+        .collect()
+}
@@ -0,0 +1,66 @@
+//! Per-user totals, aggregated from the latest process sample.
+
+use crate::encoding::{Encoded, Encoding};
+use crate::models::{HumanReadable, UserUsage};
+use crate::processes::SortKey;
+use crate::{AppError, AppState};
+use axum::extract::{Query, State};
+use axum_macros::debug_handler;
+use serde::Deserialize;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use utoipa::IntoParams;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UsersQuery {
+    /// Sort key, CPU usage by default.
+    #[serde(default)]
+    by: SortKey,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/users",
+    tag = "processes",
+    params(UsersQuery),
+    responses(
+        (status = 200, description = "Heaviest users first", body = [UserUsage], content_type = ["application/json", "application/msgpack"]),
+        (status = 404, description = "The process collector is disabled", body = ErrorResponse),
+    )
+)]
+#[debug_handler]
+pub async fn get_users(
+    State(state): State<AppState>,
+    encoding: Encoding,
+    Query(query): Query<UsersQuery>,
+) -> Result<Encoded<Vec<UserUsage>>, AppError> {
+    state.processes.require("process")?;
+    let latest = state.processes.latest();
+    let mut users: HashMap<Option<&str>, UserUsage> = HashMap::new();
+    for process in latest.value() {
+        let usage = users
+            .entry(process.user.as_deref())
+            .or_insert_with(|| UserUsage {
+                user: process.user.clone(),
+                ..UserUsage::default()
+            });
+        usage.processes += 1;
+        usage.cpu_usage += process.cpu_usage;
+        usage.memory_bytes += process.memory_bytes;
+    }
+    let mut users: Vec<UserUsage> = users
+        .into_values()
+        .map(|usage| UserUsage {
+            memory: usage.memory_bytes.to_human(None),
+            ..usage
+        })
+        .collect();
+    // By name first, so ties come out in a stable order.
+    users.sort_by(|a, b| a.user.cmp(&b.user));
+    match query.by {
+        SortKey::Cpu => users.sort_by(|a, b| b.cpu_usage.total_cmp(&a.cpu_usage)),
+        SortKey::Memory => users.sort_by_key(|usage| Reverse(usage.memory_bytes)),
+    }
+    Ok(Encoded(encoding, users))
+}
@@ -0,0 +1,85 @@
+//! API versioning. The REST API lives under `/api/v1`; the unversioned
+//! `/api/...` routes are deprecated aliases that answer the same but point
+//! clients at their successor with `Deprecation` and `Link` headers.
+//!
+//! `/api/v1/version` reports what build of the server is running, as recorded
+//! by `build.rs`.
+
+use crate::AppState;
+use axum::extract::State;
+use axum::http::{HeaderValue, Request};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use axum_macros::debug_handler;
+use serde::Serialize;
+use serde_json::json;
+use utoipa::ToSchema;
+
+pub const CURRENT: &str = "v1";
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    /// Abbreviated commit hash, `null` when built outside a git checkout.
+    pub git_commit: Option<&'static str>,
+    /// Milliseconds since the Unix epoch at which the binary was built.
+    pub build_timestamp: u64,
+    pub target: &'static str,
+    pub features: Vec<&'static str>,
+    /// Whether the server was started with `--read-only`, refusing every
+    /// write.
+    pub read_only: bool,
+}
+
+impl BuildInfo {
+    pub fn current(read_only: bool) -> Self {
+        let commit = env!("BUILD_GIT_COMMIT");
+        let features = env!("BUILD_FEATURES");
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: (!commit.is_empty()).then_some(commit),
+            build_timestamp: env!("BUILD_TIMESTAMP").parse().unwrap_or(0),
+            target: env!("BUILD_TARGET"),
+            features: features.split(',').filter(|f| !f.is_empty()).collect(),
+            read_only,
+        }
+    }
+}
+
+/// Version discovery document served at `/api`.
+#[debug_handler]
+pub async fn get_versions() -> impl IntoResponse {
+    Json(json!({
+        "current": CURRENT,
+        "versions": [
+            { "version": CURRENT, "path": format!("/api/{CURRENT}"), "status": "stable" },
+        ],
+    }))
+}
+
+/// Runs inside the `/api` alias router, so the path has the prefix stripped.
+pub async fn deprecated<B>(request: Request<B>, next: Next<B>) -> Response {
+    let successor = format!(
+        "</api/{CURRENT}{}>; rel=\"successor-version\"",
+        request.uri().path()
+    );
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert("deprecation", HeaderValue::from_static("true"));
+    if let Ok(link) = HeaderValue::from_str(&successor) {
+        headers.insert("link", link);
+    }
+    response
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/version",
+    tag = "monitoring",
+    responses((status = 200, description = "Build of the running server", body = BuildInfo))
+)]
+#[debug_handler]
+pub async fn get_version(State(state): State<AppState>) -> Json<BuildInfo> {
+    Json(BuildInfo::current(state.config.read_only))
+}
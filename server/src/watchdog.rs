@@ -0,0 +1,148 @@
+//! Watchdog for named processes, configured with `[[watch]]` tables. Every
+//! process sample is searched for each watch's pattern, and
+//! `/api/v1/watch` tells whether the process is running, under which PID,
+//! when it was last seen and how often it was restarted. When a watched
+//! process disappears, and when it is back, a `WatchEvent` is logged and
+//! POSTed to the webhooks like an alert transition.
+//!
+//! A restart is counted when the process shows up again after it was gone,
+//! or when its PID changes between two samples because it was restarted
+//! within the sampling interval.
+
+use crate::config::WatchConfig;
+use crate::encoding::{Encoded, Encoding};
+use crate::models::ProcessInfo;
+use crate::AppState;
+use axum::extract::State;
+use axum_macros::debug_handler;
+use regex::Regex;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct WatchStatus {
+    pub name: String,
+    /// Regular expression matched against process names.
+    pub pattern: String,
+    pub running: bool,
+    /// The lowest matching PID, usually the main process; `null` while not
+    /// running.
+    pub pid: Option<u32>,
+    /// Number of matching processes.
+    pub process_count: usize,
+    pub restarts: u32,
+    /// Milliseconds since the Unix epoch of the last sample the process was
+    /// running in, `null` if it never was.
+    pub last_seen: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WatchEventKind {
+    Disappeared,
+    Recovered,
+}
+
+/// A watched process that disappeared or came back during an evaluation.
+#[derive(Debug, Clone, Serialize)]
+pub struct WatchEvent {
+    pub watch: String,
+    pub event: WatchEventKind,
+    /// The PID that disappeared, or the one that showed up.
+    pub pid: u32,
+    pub timestamp: u64,
+}
+
+struct Watched {
+    pattern: Regex,
+    status: WatchStatus,
+}
+
+#[derive(Clone)]
+pub struct Watchdog {
+    watches: Arc<Mutex<Vec<Watched>>>,
+}
+
+impl Watchdog {
+    pub fn new(watches: &[WatchConfig]) -> Self {
+        let watches = watches
+            .iter()
+            .map(|watch| Watched {
+                pattern: watch.pattern.clone(),
+                status: WatchStatus {
+                    name: watch.name.clone(),
+                    pattern: watch.pattern.to_string(),
+                    running: false,
+                    pid: None,
+                    process_count: 0,
+                    restarts: 0,
+                    last_seen: None,
+                },
+            })
+            .collect();
+        Self {
+            watches: Arc::new(Mutex::new(watches)),
+        }
+    }
+
+    pub fn statuses(&self) -> Vec<WatchStatus> {
+        let watches = self.watches.lock().unwrap();
+        watches.iter().map(|watch| watch.status.clone()).collect()
+    }
+
+    pub fn evaluate(&self, timestamp: u64, processes: &[ProcessInfo]) -> Vec<WatchEvent> {
+        let mut watches = self.watches.lock().unwrap();
+        let mut events = vec![];
+        for watch in watches.iter_mut() {
+            let pids: Vec<u32> = processes
+                .iter()
+                .filter(|process| watch.pattern.is_match(&process.name))
+                .map(|process| process.pid)
+                .collect();
+            let pid = pids.iter().min().copied();
+            let status = &mut watch.status;
+            let transition = match (status.pid, pid) {
+                (Some(previous), None) => Some((WatchEventKind::Disappeared, previous)),
+                (None, Some(pid)) if status.last_seen.is_some() => {
+                    status.restarts += 1;
+                    Some((WatchEventKind::Recovered, pid))
+                }
+                (Some(previous), Some(_)) if !pids.contains(&previous) => {
+                    status.restarts += 1;
+                    None
+                }
+                _ => None,
+            };
+            if let Some((event, pid)) = transition {
+                events.push(WatchEvent {
+                    watch: status.name.clone(),
+                    event,
+                    pid,
+                    timestamp,
+                });
+            }
+            status.running = pid.is_some();
+            status.pid = pid;
+            status.process_count = pids.len();
+            if pid.is_some() {
+                status.last_seen = Some(timestamp);
+            }
+        }
+        events
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/watch",
+    tag = "processes",
+    responses((status = 200, description = "Watched processes in configuration order", body = [WatchStatus], content_type = ["application/json", "application/msgpack"]))
+)]
+#[debug_handler]
+pub async fn get_watch(
+    State(state): State<AppState>,
+    encoding: Encoding,
+) -> Encoded<Vec<WatchStatus>> {
+    Encoded(encoding, state.watchdog.statuses())
+}
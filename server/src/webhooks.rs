@@ -0,0 +1,63 @@
+//! Delivers alert transitions and watchdog events to the configured webhook
+//! URLs.
+
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone)]
+pub struct Notifier {
+    client: reqwest::Client,
+    urls: Arc<[String]>,
+}
+
+impl Notifier {
+    pub fn new(urls: Vec<String>) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("Failed to build webhook client");
+        Self {
+            client,
+            urls: urls.into(),
+        }
+    }
+
+    /// Posts the event to every webhook in the background; each delivery is
+    /// retried with exponential backoff independently of the others.
+    pub fn notify(&self, event: &impl Serialize) {
+        let event = serde_json::to_value(event).unwrap();
+        for url in self.urls.iter() {
+            let client = self.client.clone();
+            let url = url.clone();
+            let event = event.clone();
+            tokio::spawn(async move { deliver(client, url, event).await });
+        }
+    }
+}
+
+async fn deliver(client: reqwest::Client, url: String, event: Value) {
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 1..=MAX_ATTEMPTS {
+        match client.post(&url).json(&event).send().await {
+            Ok(response) if response.status().is_success() => return,
+            Ok(response) => tracing::warn!(
+                %url,
+                status = %response.status(),
+                attempt,
+                "Webhook delivery rejected"
+            ),
+            Err(err) => tracing::warn!(%url, %err, attempt, "Webhook delivery failed"),
+        }
+        if attempt < MAX_ATTEMPTS {
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+    tracing::error!(%url, %event, "Giving up delivering event");
+}